@@ -19,6 +19,12 @@ fn main() {
         .create_disk_image(&bios_path)
         .unwrap();
 
+    let uefi_path = out_dir.join("uefi.img");
+    bootloader::UefiBoot::new(&kernel)
+        .create_disk_image(&uefi_path)
+        .unwrap();
+
     println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
+    println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
     println!("cargo:rustc-env=KERNEL_SYM_PATH={}", sym.display());
 }
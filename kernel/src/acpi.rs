@@ -1,14 +1,62 @@
+use alloc::{vec, vec::Vec};
 use core::ptr::NonNull;
 
-use acpi::{AcpiResult, AcpiTables, PhysicalMapping};
+use acpi::{
+    address::{AddressSpace, GenericAddress},
+    fadt::Fadt,
+    madt::Madt,
+    AcpiError, AcpiResult, AcpiTables, PhysicalMapping,
+};
 use spin::Once;
+use x86_64::{
+    instructions::{
+        interrupts,
+        port::{PortRead, PortWrite},
+        tables::lidt,
+    },
+    structures::DescriptorTablePointer,
+    PhysAddr, VirtAddr,
+};
 
-use crate::memory::PAGE_TABLE;
+use crate::{
+    kprintln,
+    memory::{phys_read, phys_read_slice, phys_write, PAGE_TABLE},
+};
+
+/// Sleep enable bit of the PM1 control registers
+const SLP_EN: u16 = 1 << 13;
+/// Offset of the sleep type field in the PM1 control registers
+const SLP_TYP_SHIFT: u16 = 10;
+/// Sleep type field of the PM1 control registers
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+/// PM1 control bit set while ACPI mode is enabled, with power management events raising SCIs
+const SCI_EN: u16 = 1 << 0;
+/// Polls of `PM1a_CNT` waiting for the firmware to enable ACPI mode
+const ACPI_ENABLE_RETRIES: usize = 1_000_000;
+/// Size of an ACPI table header, the table length is at offset 4
+const SDT_HEADER_SIZE: usize = 36;
+/// Offset of the MADT entries, after the header, local APIC address and flags
+const MADT_ENTRIES_OFFSET: usize = SDT_HEADER_SIZE + 8;
+/// MADT entry type of a processor's local APIC
+const MADT_LOCAL_APIC: u8 = 0;
+/// MADT entry type of a processor's local x2APIC, for APIC IDs past 255
+const MADT_LOCAL_X2APIC: u8 = 9;
+/// MADT processor flag set if the processor is enabled
+const MADT_CPU_ENABLED: u32 = 1 << 0;
 
 pub static RDSP_ADDRESS: Once<usize> = Once::new();
 
 /// Get the ACPI tables from the BIOS.
+///
+/// # Panics
+///
+/// Panics if the ACPI reclaimable memory was already given back to the frame allocator.
 pub fn get_acpi() -> AcpiResult<AcpiTables<ACPIHandler>> {
+    assert!(
+        !crate::memory::acpi_reclaimed(),
+        "ACPI tables accessed after being reclaimed"
+    );
+
     let rsdp = RDSP_ADDRESS.try_call_once(|| {
         let mapping = unsafe { acpi::rsdp::Rsdp::search_for_on_bios(ACPIHandler)? };
         Ok(mapping.physical_start())
@@ -18,6 +66,320 @@ pub fn get_acpi() -> AcpiResult<AcpiTables<ACPIHandler>> {
     unsafe { AcpiTables::from_rsdp(ACPIHandler, *rsdp) }
 }
 
+/// A processor listed in the MADT
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct CpuInfo {
+    /// ACPI processor UID, matching the processor objects in the DSDT
+    pub processor_uid: u32,
+    pub local_apic_id: u32,
+    /// Whether this is the bootstrap processor, running the kernel
+    pub is_bsp: bool,
+    /// Whether the processor can be started. Processors that are only online capable aren't.
+    pub enabled: bool,
+}
+
+/// Lists the processors in the MADT, starting with the bootstrap processor.
+///
+/// Fails if the firmware has no MADT.
+pub fn cpus() -> AcpiResult<Vec<CpuInfo>> {
+    let madt = get_acpi()?.find_table::<Madt>()?;
+    let header = madt.virtual_start().as_ptr().cast::<u8>().cast_const();
+    // SAFETY: The mapping covers the whole table, whose length is in its header
+    let table = unsafe {
+        let len = header.add(4).cast::<u32>().read_unaligned() as usize;
+        core::slice::from_raw_parts(header, len.max(MADT_ENTRIES_OFFSET))
+    };
+    Ok(parse_madt_cpus(&table[MADT_ENTRIES_OFFSET..]))
+}
+
+/// Parses the local APIC and x2APIC entries out of the MADT entries.
+///
+/// The first processor listed is the bootstrap processor. Parsing stops at a truncated entry.
+fn parse_madt_cpus(mut entries: &[u8]) -> Vec<CpuInfo> {
+    let u32_at =
+        |entry: &[u8], off: usize| u32::from_le_bytes(entry[off..off + 4].try_into().unwrap());
+
+    let mut cpus = Vec::new();
+    while let [tp, len, ..] = *entries {
+        let len = usize::from(len);
+        let Some(entry) = entries.get(..len).filter(|_| len >= 2) else {
+            break;
+        };
+        let cpu = match tp {
+            MADT_LOCAL_APIC if len >= 8 => {
+                Some((u32::from(entry[2]), u32::from(entry[3]), u32_at(entry, 4)))
+            }
+            MADT_LOCAL_X2APIC if len >= 16 => {
+                Some((u32_at(entry, 12), u32_at(entry, 4), u32_at(entry, 8)))
+            }
+            _ => None,
+        };
+        if let Some((processor_uid, local_apic_id, flags)) = cpu {
+            cpus.push(CpuInfo {
+                processor_uid,
+                local_apic_id,
+                is_bsp: cpus.is_empty(),
+                enabled: flags & MADT_CPU_ENABLED != 0,
+            });
+        }
+        entries = &entries[len..];
+    }
+    cpus
+}
+
+/// Failure to change the power state through ACPI
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum PowerError {
+    Acpi(AcpiError),
+    /// The ACPI tables were reclaimed as free memory
+    Reclaimed,
+    /// The DSDT has no `\_S5` package
+    NoS5,
+    /// The register isn't in I/O or memory space
+    UnsupportedAddress(AddressSpace),
+    /// The firmware didn't enable ACPI mode when asked to
+    AcpiModeTimeout,
+}
+
+impl From<AcpiError> for PowerError {
+    fn from(e: AcpiError) -> Self {
+        Self::Acpi(e)
+    }
+}
+
+/// What [`shutdown`] and [`reboot`] need from the FADT and DSDT
+#[derive(Debug, Clone, Copy)]
+struct PowerInfo {
+    /// Port the OS writes [`acpi_enable`](Self::acpi_enable) to for the firmware to hand over
+    /// power management, 0 if the machine is always in ACPI mode
+    smi_cmd_port: u16,
+    acpi_enable: u8,
+    pm1a_control: GenericAddress,
+    pm1b_control: Option<GenericAddress>,
+    /// Sleep types of `\_S5` for the `PM1a` and `PM1b` control registers
+    s5: Result<(u16, u16), PowerError>,
+    reset: Result<GenericAddress, PowerError>,
+    reset_value: u8,
+}
+
+/// Read once, so powering off still works after the tables are reclaimed
+static POWER: Once<Result<PowerInfo, PowerError>> = Once::new();
+
+/// Reads the power management registers out of the ACPI tables.
+///
+/// Must be called before the ACPI reclaimable memory is reclaimed for [`shutdown`] and
+/// [`reboot`] to work afterward.
+pub fn init_power() {
+    if let Err(e) = power() {
+        kprintln!("WARNING: ACPI power management unavailable: {e:?}");
+    }
+}
+
+fn power() -> Result<PowerInfo, PowerError> {
+    *POWER.call_once(read_power_info)
+}
+
+fn read_power_info() -> Result<PowerInfo, PowerError> {
+    // Don't panic in get_acpi, the caller falls back to something else
+    if crate::memory::acpi_reclaimed() {
+        return Err(PowerError::Reclaimed);
+    }
+    let fadt = get_acpi()?.find_table::<Fadt>()?;
+    Ok(PowerInfo {
+        // The field is 32 bits wide, but I/O ports are 16 bits
+        #[allow(clippy::cast_possible_truncation)]
+        smi_cmd_port: fadt.smi_cmd_port as u16,
+        acpi_enable: fadt.acpi_enable,
+        pm1a_control: fadt.pm1a_control_block()?,
+        pm1b_control: fadt.pm1b_control_block()?,
+        s5: fadt
+            .dsdt_address()
+            .map_err(PowerError::from)
+            .and_then(s5_sleep_types),
+        reset: fadt.reset_register().map_err(PowerError::from),
+        reset_value: fadt.reset_value,
+    })
+}
+
+/// Powers off the machine by entering the S5 sleep state.
+///
+/// Halts if that fails.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    if let Err(e) = try_shutdown() {
+        kprintln!("WARNING: ACPI shutdown failed: {e:?}");
+    }
+    crate::panic::halt_and_never_return();
+}
+
+fn try_shutdown() -> Result<(), PowerError> {
+    let power = power()?;
+    let (slp_typ_a, slp_typ_b) = power.s5?;
+
+    // SAFETY: The PM1 control blocks are the registers the FADT designates for entering sleep
+    // states, and S5 doesn't return. The SMI command port and value come from the FADT too.
+    unsafe {
+        let pm1a = read_register(&power.pm1a_control)?;
+        if pm1a & SCI_EN == 0 && power.smi_cmd_port != 0 {
+            enable_acpi_mode(&power)?;
+        }
+
+        // Only the sleep fields may change, the rest of the register is live configuration
+        let pm1a = read_register(&power.pm1a_control)?;
+        write_register(&power.pm1a_control, u64::from(pm1_sleep(pm1a, slp_typ_a)))?;
+        if let Some(pm1b) = power.pm1b_control {
+            let value = read_register(&pm1b)?;
+            write_register(&pm1b, u64::from(pm1_sleep(value, slp_typ_b)))?;
+        }
+    }
+    Ok(())
+}
+
+/// Asks the firmware to hand power management over to the OS, and waits for `SCI_EN` to be set.
+///
+/// # Safety
+///
+/// `power` must come from the FADT.
+unsafe fn enable_acpi_mode(power: &PowerInfo) -> Result<(), PowerError> {
+    u8::write_to_port(power.smi_cmd_port, power.acpi_enable);
+    for _ in 0..ACPI_ENABLE_RETRIES {
+        if read_register(&power.pm1a_control)? & SCI_EN != 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(PowerError::AcpiModeTimeout)
+}
+
+/// Value of a PM1 control register holding `current` that enters sleep type `slp_typ`
+const fn pm1_sleep(current: u16, slp_typ: u16) -> u16 {
+    current & !(SLP_TYP_MASK | SLP_EN) | (slp_typ << SLP_TYP_SHIFT) & SLP_TYP_MASK | SLP_EN
+}
+
+/// Reboots the machine through the FADT reset register.
+///
+/// Falls back to a triple fault if there is no reset register or it doesn't work.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    if let Err(e) = try_reboot() {
+        kprintln!("WARNING: ACPI reboot failed: {e:?}");
+    }
+
+    // SAFETY: With an empty IDT, the breakpoint can't be delivered and the CPU triple faults,
+    // which resets the machine.
+    unsafe {
+        lidt(&DescriptorTablePointer {
+            limit: 0,
+            base: VirtAddr::zero(),
+        });
+        core::arch::asm!("int3", options(nomem, nostack));
+    }
+    crate::panic::halt_and_never_return();
+}
+
+fn try_reboot() -> Result<(), PowerError> {
+    let power = power()?;
+    // SAFETY: Writing the reset value to the reset register is how the FADT says to reset the
+    // machine.
+    unsafe { write_register(&power.reset?, u64::from(power.reset_value)) }
+}
+
+/// Finds the `\_S5` package in the DSDT at physical address `dsdt`, returning the sleep types
+/// for the `PM1a` and `PM1b` control registers.
+///
+/// The acpi crate can't evaluate AML, so this looks for the name definition byte pattern
+/// instead, which is how firmware encodes `\_S5` in practice.
+fn s5_sleep_types(dsdt: usize) -> Result<(u16, u16), PowerError> {
+    let dsdt = PhysAddr::new(dsdt as u64);
+    // SAFETY: The DSDT address comes from the FADT, and reading a table has no side effects.
+    // The table length is in its header.
+    let table = unsafe {
+        let mut len = [0; 4];
+        phys_read_slice(dsdt + 4u64, &mut len);
+        let mut table = vec![0; (u32::from_le_bytes(len) as usize).max(SDT_HEADER_SIZE)];
+        phys_read_slice(dsdt, &mut table);
+        table
+    };
+    parse_s5(&table[SDT_HEADER_SIZE..]).ok_or(PowerError::NoS5)
+}
+
+/// Parses `NameOp _S5_ PackageOp PkgLength NumElements SLP_TYPa SLP_TYPb` out of AML.
+fn parse_s5(aml: &[u8]) -> Option<(u16, u16)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const BYTE_PREFIX: u8 = 0x0A;
+
+    let pos = aml.windows(4).enumerate().position(|(i, name)| {
+        // The name may be rooted with a backslash
+        let named = match i {
+            0 => false,
+            1 => aml[0] == NAME_OP,
+            _ => aml[i - 1] == NAME_OP || (aml[i - 2] == NAME_OP && aml[i - 1] == b'\\'),
+        };
+        name == b"_S5_" && named && aml.get(i + 4) == Some(&PACKAGE_OP)
+    })?;
+
+    // Skip the name and package op, then the package length, whose top 2 bits are the number
+    // of extra length bytes, then the element count
+    let mut pos = pos + 5;
+    pos += usize::from(aml.get(pos)? >> 6) + 2;
+
+    let mut element = || {
+        if *aml.get(pos)? == BYTE_PREFIX {
+            pos += 1;
+        }
+        let value = *aml.get(pos)?;
+        pos += 1;
+        Some(u16::from(value))
+    };
+    Some((element()?, element()?))
+}
+
+/// Reads the 16 bit register at `addr`.
+///
+/// # Safety
+///
+/// The caller must make sure reading the register doesn't break memory safety.
+unsafe fn read_register(addr: &GenericAddress) -> Result<u16, PowerError> {
+    match addr.address_space {
+        #[allow(clippy::cast_possible_truncation)]
+        AddressSpace::SystemIo => Ok(u16::read_from_port(addr.address as u16)),
+        AddressSpace::SystemMemory => Ok(phys_read(PhysAddr::new(addr.address))),
+        space => Err(PowerError::UnsupportedAddress(space)),
+    }
+}
+
+/// Writes `value` to the register at `addr`.
+///
+/// # Safety
+///
+/// The caller must make sure writing the register doesn't break memory safety.
+unsafe fn write_register(addr: &GenericAddress, value: u64) -> Result<(), PowerError> {
+    match addr.address_space {
+        #[allow(clippy::cast_possible_truncation)]
+        AddressSpace::SystemIo => {
+            let port = addr.address as u16;
+            match addr.bit_width {
+                8 => u8::write_to_port(port, value as u8),
+                32 => u32::write_to_port(port, value as u32),
+                _ => u16::write_to_port(port, value as u16),
+            }
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        AddressSpace::SystemMemory => {
+            let phys = PhysAddr::new(addr.address);
+            match addr.bit_width {
+                8 => phys_write(phys, value as u8),
+                32 => phys_write(phys, value as u32),
+                64 => phys_write(phys, value),
+                _ => phys_write(phys, value as u16),
+            }
+        }
+        space => return Err(PowerError::UnsupportedAddress(space)),
+    }
+    Ok(())
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 pub struct ACPIHandler;
 
@@ -43,3 +405,127 @@ impl acpi::AcpiHandler for ACPIHandler {
         // Do nothing
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pm1_sleep_keeps_the_other_bits() {
+        // SCI_EN and a few reserved bits, with a stale sleep type
+        let current = 0b1000_0101_0000_0001;
+        assert_eq!(pm1_sleep(current, 5), 0b1011_0101_0000_0001);
+        assert_eq!(pm1_sleep(0, 0), SLP_EN);
+        // Sleep types past 3 bits don't spill into SLP_EN
+        assert_eq!(pm1_sleep(0, 0xF), SLP_TYP_MASK | SLP_EN);
+    }
+
+    fn local_apic(uid: u8, apic_id: u8, flags: u32) -> Vec<u8> {
+        let mut entry = vec![MADT_LOCAL_APIC, 8, uid, apic_id];
+        entry.extend(flags.to_le_bytes());
+        entry
+    }
+
+    fn local_x2apic(uid: u32, apic_id: u32, flags: u32) -> Vec<u8> {
+        let mut entry = vec![MADT_LOCAL_X2APIC, 16, 0, 0];
+        entry.extend(apic_id.to_le_bytes());
+        entry.extend(flags.to_le_bytes());
+        entry.extend(uid.to_le_bytes());
+        entry
+    }
+
+    #[test]
+    fn parses_the_processors_of_a_madt() {
+        let entries = [
+            local_apic(0, 0, MADT_CPU_ENABLED),
+            // An IOAPIC, skipped
+            vec![1, 12, 0, 0, 0, 0, 0xC0, 0xFE, 0, 0, 0, 0],
+            local_apic(1, 2, MADT_CPU_ENABLED),
+            // Online capable, but not enabled
+            local_apic(2, 4, 1 << 1),
+            local_x2apic(3, 300, MADT_CPU_ENABLED),
+        ]
+        .concat();
+
+        let cpu = |processor_uid, local_apic_id, is_bsp, enabled| CpuInfo {
+            processor_uid,
+            local_apic_id,
+            is_bsp,
+            enabled,
+        };
+        assert_eq!(
+            parse_madt_cpus(&entries),
+            [
+                cpu(0, 0, true, true),
+                cpu(1, 2, false, true),
+                cpu(2, 4, false, false),
+                cpu(3, 300, false, true),
+            ]
+        );
+    }
+
+    /// MADT as QEMU builds it for `cpus` processors on a q35 machine
+    fn qemu_madt(cpus: u8) -> Vec<u8> {
+        let mut entries: Vec<u8> = (0..cpus)
+            .flat_map(|i| local_apic(i, i, MADT_CPU_ENABLED))
+            .collect();
+        // IOAPIC 0 at 0xFEC00000, GSI base 0
+        entries.extend([1, 12, 0, 0, 0x00, 0x00, 0xC0, 0xFE, 0, 0, 0, 0]);
+        // Interrupt source overrides, the timer and the PCI interrupts
+        entries.extend([2, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+        for irq in [5, 9, 10, 11] {
+            entries.extend([2, 10, 0, irq, irq, 0, 0, 0, 0x0D, 0]);
+        }
+        // Local APIC NMI on LINT1 of every processor
+        entries.extend([4, 6, 0xFF, 0, 0, 1]);
+
+        let len = u32::try_from(MADT_ENTRIES_OFFSET + entries.len()).unwrap();
+        let mut table = b"APIC".to_vec();
+        table.extend(len.to_le_bytes());
+        table.extend([1, 0]);
+        table.extend(b"BOCHS BXPC    ");
+        table.extend(1_u32.to_le_bytes());
+        table.extend(b"BXPC");
+        table.extend(1_u32.to_le_bytes());
+        // Local APIC address and PCAT_COMPAT
+        table.extend(0xFEE0_0000_u32.to_le_bytes());
+        table.extend(1_u32.to_le_bytes());
+        assert_eq!(table.len(), MADT_ENTRIES_OFFSET);
+        table.extend(entries);
+
+        let sum = table.iter().fold(0_u8, |sum, &byte| sum.wrapping_add(byte));
+        table[9] = sum.wrapping_neg();
+        table
+    }
+
+    #[test]
+    fn qemu_madt_reports_the_bsp_first() {
+        let table = qemu_madt(4);
+        let cpus = parse_madt_cpus(&table[MADT_ENTRIES_OFFSET..]);
+        assert_eq!(cpus.len(), 4);
+
+        assert!(cpus[0].is_bsp);
+        assert_eq!(cpus.iter().filter(|cpu| cpu.is_bsp).count(), 1);
+        assert!(cpus.iter().all(|cpu| cpu.enabled));
+
+        let mut ids: Vec<u32> = cpus.iter().map(|cpu| cpu.local_apic_id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), cpus.len(), "duplicate APIC IDs in {cpus:?}");
+    }
+
+    #[test]
+    fn stops_at_a_truncated_entry() {
+        let mut entries = [
+            local_apic(0, 0, MADT_CPU_ENABLED),
+            local_apic(1, 1, MADT_CPU_ENABLED),
+        ]
+        .concat();
+        entries.truncate(12);
+        assert_eq!(parse_madt_cpus(&entries).len(), 1);
+
+        // A zero length would never advance
+        assert_eq!(parse_madt_cpus(&[MADT_LOCAL_APIC, 0, 0, 0]), []);
+        assert_eq!(parse_madt_cpus(&[]), []);
+    }
+}
@@ -1,14 +1,21 @@
 use core::{
     arch::asm,
-    ops::{Deref, DerefMut},
+    sync::atomic::{fence, AtomicU64, Ordering},
 };
 
+use raw_cpuid::CpuId;
 use spin::{Lazy, Mutex};
-use x86::apic::{ioapic::IoApic, xapic::XAPIC};
+use x86::{
+    apic::xapic::{ApicRegister, XAPIC},
+    msr::{wrmsr, IA32_TSC_DEADLINE},
+};
 
 use crate::{
+    ioapic::{IoApic, IoApics},
+    kprintln,
     memory::PHYSICAL_MEM_START,
-    pit::{OperatingMode, PIT0},
+    pit::{ProgrammableIntervalTimer, PIT0},
+    trap::IRQ0,
 };
 
 const LAPIC_PHYS_ADDR: u64 = 0xfee0_0000;
@@ -25,7 +32,7 @@ pub static LAPIC: Lazy<Mutex<XAPIC>> = Lazy::new(|| {
     Mutex::new(XAPIC::new(apic_region))
 });
 
-pub static IOAPIC: Lazy<Mutex<IoApicWrapper>> = Lazy::new(|| {
+pub static IOAPIC: Lazy<Mutex<IoApics>> = Lazy::new(|| {
     let acpi = crate::acpi::get_acpi().expect("ACPI tables should be available");
     let platform = acpi
         .platform_info()
@@ -34,34 +41,18 @@ pub static IOAPIC: Lazy<Mutex<IoApicWrapper>> = Lazy::new(|| {
         panic!("Interrupt model should be APIC");
     };
 
-    let phys_addr = apic.io_apics[0].address;
-    let virt_addr = PHYSICAL_MEM_START + u64::from(phys_addr);
+    let chips = apic
+        .io_apics
+        .iter()
+        .map(|io_apic| {
+            let virt_addr = PHYSICAL_MEM_START + u64::from(io_apic.address);
+            unsafe { IoApic::new(virt_addr.as_mut_ptr(), io_apic.global_system_interrupt_base) }
+        })
+        .collect();
 
-    Mutex::new(IoApicWrapper(unsafe {
-        IoApic::new(virt_addr.as_u64() as usize)
-    }))
+    Mutex::new(IoApics::new(chips, &apic.interrupt_source_overrides))
 });
 
-#[repr(transparent)]
-pub struct IoApicWrapper(IoApic);
-
-#[allow(clippy::non_send_fields_in_send_ty)]
-unsafe impl Send for IoApicWrapper {}
-
-impl Deref for IoApicWrapper {
-    type Target = IoApic;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
-impl DerefMut for IoApicWrapper {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
-    }
-}
-
 /// Disable the 8259 PIC
 fn disable_8259() {
     // https://wiki.osdev.org/PIC#Disabling
@@ -75,24 +66,256 @@ fn disable_8259() {
     }
 }
 
-pub static CPU_FREQ: Lazy<u64> = Lazy::new(calc_cpu_freq);
+pub static CPU_FREQ: Lazy<u64> = Lazy::new(|| {
+    let freq = x86_64::instructions::interrupts::without_interrupts(|| calc_cpu_freq(&PIT0))
+        .unwrap_or_else(|freq| {
+            kprintln!("WARNING: PIT calibration timed out, assuming CPU frequency of {freq} Hz");
+            freq
+        });
+    crate::time::tsc::set_frequency(freq);
+    freq
+});
+
+/// CPU frequency assumed when it can neither be measured nor read from CPUID.
+const DEFAULT_CPU_FREQ: u64 = 2_000_000_000;
+
+/// Calculate the CPU clock frequency per second by timing a countdown of `pit`.
+///
+/// If the countdown times out, returns the frequency from [`fallback_cpu_freq`] as the error.
+fn calc_cpu_freq(pit: &ProgrammableIntervalTimer) -> Result<u64, u64> {
+    let start_tsc = unsafe { x86::time::rdtsc() };
+
+    // Sleep for 10ms
+    if pit.sleep_us(10_000).is_err() {
+        let cpuid = CpuId::new();
+        return Err(fallback_cpu_freq(
+            cpuid.get_tsc_info().and_then(|info| info.tsc_frequency()),
+            cpuid
+                .get_processor_frequency_info()
+                .map(|info| info.processor_base_frequency()),
+        ));
+    }
+
+    let end_tsc = unsafe { x86::time::rdtsc() };
+
+    // Calculate the CPU frequency
+    let cycles_per_10ms = end_tsc - start_tsc;
+    Ok(cycles_per_10ms * 100)
+}
+
+/// Selects the CPU frequency to use when it can't be measured against the PIT.
+///
+/// Prefers the TSC frequency from CPUID leaf 0x15, then the base frequency (in MHz) from
+/// leaf 0x16, then [`DEFAULT_CPU_FREQ`].
+const fn fallback_cpu_freq(tsc_freq: Option<u64>, base_mhz: Option<u16>) -> u64 {
+    match (tsc_freq, base_mhz) {
+        (Some(hz), _) if hz != 0 => hz,
+        (_, Some(mhz)) if mhz != 0 => mhz as u64 * 1_000_000,
+        _ => DEFAULT_CPU_FREQ,
+    }
+}
+
+/// Extracts the APIC ID from the value of the local APIC ID register, in bits 24..32
+#[allow(clippy::cast_possible_truncation)]
+pub const fn apic_id_from_register(raw: u32) -> u8 {
+    (raw >> 24) as u8
+}
+
+/// Local APIC ID of the current CPU
+pub fn apic_id() -> u8 {
+    // Interrupt handlers lock the LAPIC to acknowledge interrupts
+    let raw = x86_64::instructions::interrupts::without_interrupts(|| {
+        LAPIC.lock().read(ApicRegister::XAPIC_ID)
+    });
+    apic_id_from_register(raw)
+}
+
+/// Delivery mode of an inter-processor interrupt
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum IpiDelivery {
+    /// Raises the vector on the target
+    Fixed = 0b000,
+    Nmi = 0b100,
+    /// Resets the target into its wait-for-SIPI state
+    Init = 0b101,
+    /// Starts the target at physical address `vector << 12`
+    StartUp = 0b110,
+}
+
+/// Set in the low command register while an IPI hasn't been accepted yet
+const ICR_SEND_PENDING: u32 = 1 << 12;
+/// Level of the IPI, must be set for everything but an INIT de-assert
+const ICR_LEVEL_ASSERT: u64 = 1 << 14;
+
+/// Computes the interrupt command register value of an IPI to the APIC `dest`.
+///
+/// The destination is a physical APIC ID in bits 56..64, and the low half holds the vector in
+/// bits 0..8 and the delivery mode in bits 8..11. The IPI is edge triggered, without shorthand.
+pub const fn icr_word(dest: u8, vector: u8, delivery: IpiDelivery) -> u64 {
+    (dest as u64) << 56 | ICR_LEVEL_ASSERT | (delivery as u64) << 8 | vector as u64
+}
+
+/// Sends an interrupt with `vector` to the CPU with APIC ID `dest`
+pub fn send_ipi(dest: u8, vector: u8) {
+    send_icr(icr_word(dest, vector, IpiDelivery::Fixed));
+}
 
-/// Calculate the CPU clock frequency per second
-fn calc_cpu_freq() -> u64 {
+/// Sends an INIT IPI to the CPU with APIC ID `dest`
+pub fn send_init_ipi(dest: u8) {
+    send_icr(icr_word(dest, 0, IpiDelivery::Init));
+}
+
+/// Sends a startup IPI to the CPU with APIC ID `dest`, starting it at page `vector`
+pub fn send_startup_ipi(dest: u8, vector: u8) {
+    send_icr(icr_word(dest, vector, IpiDelivery::StartUp));
+}
+
+/// Writes an interrupt command and waits for the local APIC to accept it.
+///
+/// The destination must be written first, writing the low half sends the IPI.
+#[allow(clippy::cast_possible_truncation)]
+fn send_icr(icr: u64) {
+    // Interrupt handlers lock the LAPIC to acknowledge interrupts
     x86_64::instructions::interrupts::without_interrupts(|| {
-        // Prepare the PIT to sleep for 10ms (100 Hz)
-        PIT0.start_timer(OperatingMode::InterruptOnTerminalCount, 100)
-            .unwrap();
+        let mut lapic = LAPIC.lock();
+        lapic.write(ApicRegister::XAPIC_ICR1, (icr >> 32) as u32);
+        lapic.write(ApicRegister::XAPIC_ICR0, icr as u32);
+        while lapic.read(ApicRegister::XAPIC_ICR0) & ICR_SEND_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+/// LAPIC timer ticks per second with divider 16, measured by
+/// [`start_timer`](crate::time::start_timer). 0 until then.
+static TIMER_FREQ: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_timer_frequency(freq: u64) {
+    TIMER_FREQ.store(freq, Ordering::Relaxed);
+}
 
-        let start_tsc = unsafe { x86::time::rdtsc() };
+/// Mode of the LAPIC timer, in bits 17..19 of its LVT entry
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u32)]
+pub enum TimerMode {
+    /// Counts down once from the initial count
+    OneShot = 0b00 << 17,
+    /// Counts down from the initial count repeatedly
+    Periodic = 0b01 << 17,
+    /// Fires when the TSC reaches the `IA32_TSC_DEADLINE` MSR
+    TscDeadline = 0b10 << 17,
+}
+
+/// Computes the LVT timer entry raising `vector` in `mode`, unmasked
+pub const fn lvt_timer(vector: u8, mode: TimerMode) -> u32 {
+    mode as u32 | vector as u32
+}
+
+/// Whether the LAPIC timer supports [`TimerMode::TscDeadline`]
+pub fn tsc_deadline_supported() -> bool {
+    CpuId::new()
+        .get_feature_info()
+        .is_some_and(|info| info.has_tsc_deadline())
+}
+
+/// Raises the timer interrupt once, when the TSC reaches `tsc`.
+///
+/// Uses TSC-deadline mode when supported, otherwise a one-shot countdown converted from TSC
+/// cycles, which needs the timer calibrated by [`start_timer`](crate::time::start_timer).
+/// Either replaces the periodic tick advancing [`TICKS`](crate::time::TICKS).
+pub fn set_deadline(tsc: u64) {
+    // Interrupt handlers lock the LAPIC to acknowledge interrupts
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut lapic = LAPIC.lock();
+        if tsc_deadline_supported() {
+            lapic.write(
+                ApicRegister::XAPIC_LVT_TIMER,
+                lvt_timer(IRQ0, TimerMode::TscDeadline),
+            );
+            // The MMIO write switching modes must land before the MSR write
+            fence(Ordering::SeqCst);
+            unsafe { wrmsr(IA32_TSC_DEADLINE, tsc) };
+        } else {
+            let cycles = tsc.saturating_sub(unsafe { x86::time::rdtsc() });
+            let count = u128::from(cycles) * u128::from(TIMER_FREQ.load(Ordering::Relaxed))
+                / u128::from((*CPU_FREQ).max(1));
+            // A count of 0 stops the timer, so a deadline already past fires right away
+            let count = u32::try_from(count).unwrap_or(u32::MAX).max(1);
 
-        // Wait for the PIT to reach 0
-        while PIT0.get_count() != 0 {}
+            lapic.write(
+                ApicRegister::XAPIC_LVT_TIMER,
+                lvt_timer(IRQ0, TimerMode::OneShot),
+            );
+            lapic.write(ApicRegister::XAPIC_TIMER_DIV_CONF, 0x3);
+            lapic.write(ApicRegister::XAPIC_TIMER_INIT_COUNT, count);
+        }
+        crate::time::set_ticking(false);
+    });
+}
 
-        let end_tsc = unsafe { x86::time::rdtsc() };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pit::{tests::set_reads, Channel};
 
-        // Calculate the CPU frequency
-        let cycles_per_10ms = end_tsc - start_tsc;
-        cycles_per_10ms * 100
-    })
+    #[test]
+    fn icr_word_layout() {
+        assert_eq!(icr_word(3, 0x40, IpiDelivery::Fixed), 0x0300_0000_0000_4040);
+        assert_eq!(icr_word(1, 0, IpiDelivery::Init), 0x0100_0000_0000_4500);
+        assert_eq!(
+            icr_word(0xFF, 0x08, IpiDelivery::StartUp),
+            0xFF00_0000_0000_4608
+        );
+        assert_eq!(icr_word(0, 2, IpiDelivery::Nmi), 0x4402);
+
+        // The send pending bit is read-only, and never part of a command
+        let icr = icr_word(0xFF, 0xFF, IpiDelivery::StartUp);
+        assert_eq!(icr & u64::from(ICR_SEND_PENDING), 0);
+    }
+
+    #[test]
+    fn lvt_timer_mode_bits() {
+        assert_eq!(lvt_timer(0x20, TimerMode::OneShot), 0x20);
+        assert_eq!(lvt_timer(0x20, TimerMode::Periodic), 0x2_0020);
+        // Bit 18, with the mask bit 16 clear
+        assert_eq!(lvt_timer(0x20, TimerMode::TscDeadline), 0x4_0020);
+    }
+
+    #[test]
+    fn falls_back_when_the_pit_never_reaches_zero() {
+        let cpuid = CpuId::new();
+        let fallback = fallback_cpu_freq(
+            cpuid.get_tsc_info().and_then(|info| info.tsc_frequency()),
+            cpuid
+                .get_processor_frequency_info()
+                .map(|info| info.processor_base_frequency()),
+        );
+
+        set_reads(Channel::Channel0.port(), &[0x10]);
+        assert_eq!(calc_cpu_freq(&PIT0), Err(fallback));
+
+        set_reads(Channel::Channel0.port(), &[0]);
+        assert!(calc_cpu_freq(&PIT0).is_ok());
+    }
+
+    #[test]
+    fn fallback_prefers_tsc_then_base_frequency() {
+        assert_eq!(
+            fallback_cpu_freq(Some(3_000_000_000), Some(2_000)),
+            3_000_000_000
+        );
+        assert_eq!(fallback_cpu_freq(Some(0), Some(2_500)), 2_500_000_000);
+        assert_eq!(fallback_cpu_freq(None, Some(0)), DEFAULT_CPU_FREQ);
+        assert_eq!(fallback_cpu_freq(None, None), DEFAULT_CPU_FREQ);
+    }
+
+    #[test]
+    fn apic_id_is_the_top_byte() {
+        assert_eq!(apic_id_from_register(0), 0);
+        assert_eq!(apic_id_from_register(0x0300_0000), 3);
+        // The reserved low bits are ignored
+        assert_eq!(apic_id_from_register(0xFF00_FFFF), 0xFF);
+        assert_eq!(apic_id_from_register(0x00FF_FFFF), 0);
+    }
 }
@@ -0,0 +1,325 @@
+use alloc::vec::Vec;
+use core::{
+    borrow::Borrow,
+    fmt::{Debug, Formatter},
+    hash::Hash,
+};
+
+use hashbrown::HashMap;
+
+/// Least recently used cache.
+///
+/// Entries are kept in a slab and linked together in recency order, so lookups, insertions and
+/// removals are all O(1).
+///
+/// When the cache is full, inserting evicts the least recently used entry that isn't pinned.
+/// If every entry is pinned, the cache grows past its capacity instead.
+pub struct LruCache<K, V> {
+    map: HashMap<K, usize>,
+    nodes: Vec<Option<Node<K, V>>>,
+    /// Unused slots in `nodes`
+    free: Vec<usize>,
+    /// Most recently used entry
+    head: Option<usize>,
+    /// Least recently used entry
+    tail: Option<usize>,
+    capacity: usize,
+    /// Entries matching the predicate are never evicted
+    pin: fn(&K, &V) -> bool,
+    /// Number of entries evicted so far
+    evictions: u64,
+}
+
+struct Node<K, V> {
+    key: K,
+    value: V,
+    /// More recently used neighbor
+    prev: Option<usize>,
+    /// Less recently used neighbor
+    next: Option<usize>,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    /// Creates a cache holding up to `capacity` entries.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_pin(capacity, |_, _| false)
+    }
+
+    /// Creates a cache holding up to `capacity` entries, never evicting entries matching `pin`.
+    pub fn with_pin(capacity: usize, pin: fn(&K, &V) -> bool) -> Self {
+        Self {
+            map: HashMap::with_capacity(capacity),
+            nodes: Vec::with_capacity(capacity),
+            free: Vec::new(),
+            head: None,
+            tail: None,
+            capacity,
+            pin,
+            evictions: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub const fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of entries evicted to make room since the cache was created.
+    pub const fn evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    /// Gets an entry, marking it as most recently used.
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let idx = *self.map.get(key)?;
+        self.touch(idx);
+        Some(&self.node(idx).value)
+    }
+
+    /// Gets an entry without changing its recency.
+    pub fn peek<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let idx = *self.map.get(key)?;
+        Some(&self.node(idx).value)
+    }
+
+    /// Inserts an entry as most recently used, returning the previous value for `key`.
+    ///
+    /// Evicts the least recently used unpinned entry if the cache is full.
+    pub fn put(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(&idx) = self.map.get(&key) {
+            self.touch(idx);
+            return Some(core::mem::replace(&mut self.node_mut(idx).value, value));
+        }
+
+        if self.len() >= self.capacity {
+            self.evict();
+        }
+
+        let node = Node {
+            key: key.clone(),
+            value,
+            prev: None,
+            next: None,
+        };
+        let idx = if let Some(idx) = self.free.pop() {
+            self.nodes[idx] = Some(node);
+            idx
+        } else {
+            self.nodes.push(Some(node));
+            self.nodes.len() - 1
+        };
+        self.push_front(idx);
+        self.map.insert(key, idx);
+
+        None
+    }
+
+    /// Removes an entry, returning its value.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let idx = self.map.remove(key)?;
+        Some(self.remove_node(idx).value)
+    }
+
+    /// Removes every entry for which `f` returns `false`.
+    pub fn retain<F: FnMut(&K, &V) -> bool>(&mut self, mut f: F) {
+        let mut cur = self.head;
+        while let Some(idx) = cur {
+            let node = self.node(idx);
+            cur = node.next;
+            if !f(&node.key, &node.value) {
+                let node = self.remove_node(idx);
+                self.map.remove(&node.key);
+            }
+        }
+    }
+
+    /// Evicts the least recently used unpinned entry.
+    fn evict(&mut self) -> Option<(K, V)> {
+        let mut cur = self.tail;
+        while let Some(idx) = cur {
+            let node = self.node(idx);
+            if (self.pin)(&node.key, &node.value) {
+                cur = node.prev;
+                continue;
+            }
+
+            let node = self.remove_node(idx);
+            self.map.remove(&node.key);
+            self.evictions += 1;
+            return Some((node.key, node.value));
+        }
+        None
+    }
+
+    /// Marks a node as most recently used.
+    fn touch(&mut self, idx: usize) {
+        if self.head != Some(idx) {
+            self.unlink(idx);
+            self.push_front(idx);
+        }
+    }
+
+    fn push_front(&mut self, idx: usize) {
+        let head = self.head;
+        {
+            let node = self.node_mut(idx);
+            node.prev = None;
+            node.next = head;
+        }
+        match head {
+            Some(head) => self.node_mut(head).prev = Some(idx),
+            None => self.tail = Some(idx),
+        }
+        self.head = Some(idx);
+    }
+
+    fn unlink(&mut self, idx: usize) {
+        let (prev, next) = {
+            let node = self.node(idx);
+            (node.prev, node.next)
+        };
+        match prev {
+            Some(prev) => self.node_mut(prev).next = next,
+            None => self.head = next,
+        }
+        match next {
+            Some(next) => self.node_mut(next).prev = prev,
+            None => self.tail = prev,
+        }
+    }
+
+    /// Unlinks a node and frees its slot. The caller must remove it from `map`.
+    fn remove_node(&mut self, idx: usize) -> Node<K, V> {
+        self.unlink(idx);
+        self.free.push(idx);
+        self.nodes[idx]
+            .take()
+            .expect("linked node should be occupied")
+    }
+
+    fn node(&self, idx: usize) -> &Node<K, V> {
+        self.nodes[idx]
+            .as_ref()
+            .expect("linked node should be occupied")
+    }
+
+    fn node_mut(&mut self, idx: usize) -> &mut Node<K, V> {
+        self.nodes[idx]
+            .as_mut()
+            .expect("linked node should be occupied")
+    }
+}
+
+impl<K: Debug, V: Debug> Debug for LruCache<K, V> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let mut list = f.debug_map();
+        let mut cur = self.head;
+        while let Some(node) = cur.and_then(|idx| self.nodes[idx].as_ref()) {
+            list.entry(&node.key, &node.value);
+            cur = node.next;
+        }
+        list.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.put(1, "a");
+        cache.put(2, "b");
+        assert_eq!(cache.get(&1), Some(&"a"));
+
+        cache.put(3, "c");
+        assert_eq!(cache.peek(&2), None);
+        assert_eq!(cache.peek(&1), Some(&"a"));
+        assert_eq!(cache.peek(&3), Some(&"c"));
+        assert_eq!(cache.evictions(), 1);
+    }
+
+    #[test]
+    fn peek_keeps_recency() {
+        let mut cache = LruCache::new(2);
+        cache.put(1, "a");
+        cache.put(2, "b");
+        assert_eq!(cache.peek(&1), Some(&"a"));
+
+        cache.put(3, "c");
+        assert_eq!(cache.peek(&1), None);
+    }
+
+    #[test]
+    fn pinned_entries_are_kept() {
+        let mut cache = LruCache::with_pin(2, |&key, _| key == 1);
+        cache.put(1, "a");
+        cache.put(2, "b");
+        cache.put(3, "c");
+        assert_eq!(cache.peek(&1), Some(&"a"));
+        assert_eq!(cache.peek(&2), None);
+
+        // Grows past the capacity once everything is pinned
+        let mut cache = LruCache::with_pin(1, |_, _| true);
+        cache.put(1, "a");
+        cache.put(2, "b");
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.evictions(), 0);
+    }
+
+    #[test]
+    fn capacity_is_enforced() {
+        let mut cache = LruCache::new(4);
+        for i in 0..100 {
+            cache.put(i, i);
+            assert!(cache.len() <= cache.capacity());
+        }
+        assert_eq!(cache.remove(&99), Some(99));
+        assert_eq!(cache.len(), 3);
+        cache.retain(|&key, _| key != 97);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn eviction_checks_one_entry_per_insertion() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        static PIN_CHECKS: AtomicUsize = AtomicUsize::new(0);
+
+        let mut cache = LruCache::with_pin(1000, |_, _| {
+            PIN_CHECKS.fetch_add(1, Ordering::Relaxed);
+            false
+        });
+        for i in 0..1000 {
+            cache.put(i, i);
+        }
+        assert_eq!(PIN_CHECKS.load(Ordering::Relaxed), 0);
+
+        // Only the tail is looked at, however full the cache is
+        for i in 1000..11_000 {
+            cache.put(i, i);
+        }
+        assert_eq!(PIN_CHECKS.load(Ordering::Relaxed), 10_000);
+        assert_eq!(cache.evictions(), 10_000);
+    }
+}
@@ -0,0 +1,3 @@
+mod lru;
+
+pub use self::lru::LruCache;
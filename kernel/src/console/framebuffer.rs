@@ -0,0 +1,390 @@
+//! Text console on top of a pixel framebuffer.
+//!
+//! The console only does the text layout: lines are wrapped at the screen width, preferring to
+//! break at spaces, and the screen scrolls once the last row is full. Drawing the glyphs is left
+//! to the [`GlyphSurface`], which is a [`Framebuffer`] outside of tests.
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::fmt::Write;
+
+use bootloader_api::info::{FrameBuffer, FrameBufferInfo};
+use noto_sans_mono_bitmap::{get_raster, get_raster_width, FontWeight, RasterHeight};
+use x86_64::{structures::paging::Translate, VirtAddr};
+
+use crate::{kprintln, memory};
+
+/// Tab stops are every `TAB_WIDTH` columns.
+const TAB_WIDTH: usize = 8;
+
+const FONT_WEIGHT: FontWeight = FontWeight::Regular;
+const GLYPH_HEIGHT: RasterHeight = RasterHeight::Size16;
+const GLYPH_WIDTH: usize = get_raster_width(FONT_WEIGHT, GLYPH_HEIGHT);
+/// Drawn for characters missing from the font
+const REPLACEMENT: char = '\u{FFFD}';
+
+/// Pixel surface that can draw glyphs in a grid of character cells.
+pub trait GlyphSurface {
+    /// Size of the surface in pixels, as `(width, height)`.
+    fn size(&self) -> (usize, usize);
+    /// Size of a glyph in pixels, as `(width, height)`.
+    fn glyph_size(&self) -> (usize, usize);
+    /// Draws `c` in the cell at `col`, `row`.
+    fn draw(&mut self, col: usize, row: usize, c: char);
+    /// Moves every row up by `rows`, clearing the rows uncovered at the bottom.
+    fn scroll_up(&mut self, rows: usize);
+}
+
+/// Shows the console on the bootloader's framebuffer.
+///
+/// The framebuffer is mapped again write-combining, or used through the bootloader's mapping if
+/// that fails. Must be called after the frame allocator is initialized.
+pub fn attach(fb: FrameBuffer) {
+    let info = fb.info();
+    let buffer = remap(fb.buffer()).unwrap_or_else(|| {
+        kprintln!("WARNING: failed to map the framebuffer write-combining");
+        fb.into_buffer()
+    });
+    let console = FramebufferConsole::new(Box::new(Framebuffer::new(buffer, info)));
+    super::attach_framebuffer(console);
+}
+
+/// Maps `buffer` into the framebuffer region.
+fn remap(buffer: &[u8]) -> Option<&'static mut [u8]> {
+    let phys = memory::PAGE_TABLE
+        .lock()
+        .as_ref()?
+        .translate_addr(VirtAddr::from_ptr(buffer.as_ptr()))?;
+    let virt = memory::map_framebuffer(phys, buffer.len() as u64).ok()?;
+    // SAFETY: the framebuffer was just mapped there, and nothing else uses the region
+    Some(unsafe { core::slice::from_raw_parts_mut(virt.as_mut_ptr(), buffer.len()) })
+}
+
+/// [`GlyphSurface`] drawing gray text on a black background into framebuffer memory.
+pub struct Framebuffer {
+    buffer: &'static mut [u8],
+    info: FrameBufferInfo,
+}
+
+impl Framebuffer {
+    pub const fn new(buffer: &'static mut [u8], info: FrameBufferInfo) -> Self {
+        Self { buffer, info }
+    }
+
+    fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
+        let bytes = self.info.bytes_per_pixel;
+        let offset = (y * self.info.stride + x) * bytes;
+        // The same value in every channel is gray whatever their order is
+        let color = [intensity, intensity, intensity, 0];
+        let len = bytes.min(color.len());
+        if let Some(pixel) = self.buffer.get_mut(offset..offset + len) {
+            pixel.copy_from_slice(&color[..len]);
+        }
+    }
+}
+
+impl GlyphSurface for Framebuffer {
+    fn size(&self) -> (usize, usize) {
+        (self.info.width, self.info.height)
+    }
+
+    fn glyph_size(&self) -> (usize, usize) {
+        (GLYPH_WIDTH, GLYPH_HEIGHT.val())
+    }
+
+    fn draw(&mut self, col: usize, row: usize, c: char) {
+        let Some(glyph) = get_raster(c, FONT_WEIGHT, GLYPH_HEIGHT)
+            .or_else(|| get_raster(REPLACEMENT, FONT_WEIGHT, GLYPH_HEIGHT))
+        else {
+            return;
+        };
+        let (x0, y0) = (col * GLYPH_WIDTH, row * GLYPH_HEIGHT.val());
+        for (y, line) in glyph.raster().iter().enumerate() {
+            for (x, &intensity) in line.iter().enumerate() {
+                self.write_pixel(x0 + x, y0 + y, intensity);
+            }
+        }
+    }
+
+    fn scroll_up(&mut self, rows: usize) {
+        let line = self.info.stride * self.info.bytes_per_pixel;
+        let shift = (rows * GLYPH_HEIGHT.val() * line).min(self.buffer.len());
+        self.buffer.copy_within(shift.., 0);
+        let len = self.buffer.len();
+        self.buffer[len - shift..].fill(0);
+    }
+}
+
+pub struct FramebufferConsole {
+    surface: Box<dyn GlyphSurface + Send>,
+    cols: usize,
+    rows: usize,
+    col: usize,
+    row: usize,
+    /// Characters on the current row, used to find a break when wrapping
+    line: Vec<char>,
+    /// Set by a carriage return, the row is cleared before anything else is written on it
+    overwrite: bool,
+}
+
+impl FramebufferConsole {
+    /// Creates a console filling `surface`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the surface is smaller than one glyph.
+    pub fn new(surface: Box<dyn GlyphSurface + Send>) -> Self {
+        let (width, height) = surface.size();
+        let (glyph_width, glyph_height) = surface.glyph_size();
+        let cols = width / glyph_width;
+        let rows = height / glyph_height;
+        assert!(cols > 0 && rows > 0, "framebuffer smaller than a glyph");
+
+        Self {
+            surface,
+            cols,
+            rows,
+            col: 0,
+            row: 0,
+            line: vec![' '; cols],
+            overwrite: false,
+        }
+    }
+
+    /// Size of the console in characters, as `(columns, rows)`.
+    pub const fn dimensions(&self) -> (usize, usize) {
+        (self.cols, self.rows)
+    }
+
+    /// Position of the cursor, as `(column, row)`.
+    pub const fn cursor(&self) -> (usize, usize) {
+        (self.col, self.row)
+    }
+
+    fn put_char(&mut self, c: char) {
+        match c {
+            '\n' => self.newline(),
+            '\r' => {
+                // Cleared lazily, so `\r\n` keeps the line
+                self.col = 0;
+                self.overwrite = true;
+            }
+            _ if self.overwrite => {
+                self.clear_row();
+                self.put_char(c);
+            }
+            '\t' => {
+                let stop = (self.col / TAB_WIDTH + 1) * TAB_WIDTH;
+                if stop > self.cols {
+                    self.newline();
+                } else {
+                    while self.col < stop {
+                        self.put_char(' ');
+                    }
+                }
+            }
+            c => {
+                if self.col == self.cols {
+                    self.wrap();
+                }
+                self.line[self.col] = c;
+                self.surface.draw(self.col, self.row, c);
+                self.col += 1;
+            }
+        }
+    }
+
+    /// Continues a full line on the next row, moving the last partial word down with it.
+    fn wrap(&mut self) {
+        let split = self
+            .line
+            .iter()
+            .rposition(|&c| c == ' ')
+            .map_or(0, |i| i + 1);
+        if split == 0 || split == self.cols {
+            // Nothing to break at, or the line already ends in a space
+            self.newline();
+            return;
+        }
+
+        // Erase the partial word and redraw it at the start of the next row
+        for col in split..self.cols {
+            self.surface.draw(col, self.row, ' ');
+        }
+        let len = self.cols - split;
+        self.line.copy_within(split.., 0);
+        self.line[len..].fill(' ');
+
+        self.next_row();
+        for col in 0..len {
+            self.surface.draw(col, self.row, self.line[col]);
+        }
+        self.col = len;
+    }
+
+    /// Erases the current row, as a carriage return followed by new text does
+    fn clear_row(&mut self) {
+        self.overwrite = false;
+        for col in 0..self.cols {
+            if self.line[col] != ' ' {
+                self.surface.draw(col, self.row, ' ');
+            }
+        }
+        self.line.fill(' ');
+    }
+
+    fn newline(&mut self) {
+        self.overwrite = false;
+        self.line.fill(' ');
+        self.next_row();
+        self.col = 0;
+    }
+
+    fn next_row(&mut self) {
+        if self.row + 1 == self.rows {
+            self.surface.scroll_up(1);
+        } else {
+            self.row += 1;
+        }
+    }
+}
+
+impl Write for FramebufferConsole {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            self.put_char(c);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, sync::Arc};
+
+    use bootloader_api::info::PixelFormat;
+    use spin::Mutex;
+
+    use super::*;
+
+    /// Character grid standing in for a framebuffer, shared with the test.
+    #[derive(Clone)]
+    struct MockSurface {
+        cols: usize,
+        rows: usize,
+        cells: Arc<Mutex<Vec<Vec<char>>>>,
+    }
+
+    impl MockSurface {
+        fn new(cols: usize, rows: usize) -> Self {
+            Self {
+                cols,
+                rows,
+                cells: Arc::new(Mutex::new(vec![vec![' '; cols]; rows])),
+            }
+        }
+
+        fn row(&self, row: usize) -> String {
+            self.cells.lock()[row].iter().collect()
+        }
+    }
+
+    impl GlyphSurface for MockSurface {
+        fn size(&self) -> (usize, usize) {
+            // 8x16 pixel glyphs, with a few spare pixels like a real screen
+            (self.cols * 8 + 3, self.rows * 16 + 5)
+        }
+
+        fn glyph_size(&self) -> (usize, usize) {
+            (8, 16)
+        }
+
+        fn draw(&mut self, col: usize, row: usize, c: char) {
+            self.cells.lock()[row][col] = c;
+        }
+
+        fn scroll_up(&mut self, rows: usize) {
+            let mut cells = self.cells.lock();
+            cells.drain(..rows);
+            cells.resize(self.rows, vec![' '; self.cols]);
+        }
+    }
+
+    fn console(cols: usize, rows: usize) -> (FramebufferConsole, MockSurface) {
+        let surface = MockSurface::new(cols, rows);
+        (FramebufferConsole::new(Box::new(surface.clone())), surface)
+    }
+
+    #[test]
+    fn wraps_at_the_last_space() {
+        let (mut console, surface) = console(10, 3);
+        assert_eq!(console.dimensions(), (10, 3));
+
+        write!(console, "hello wonderful world").unwrap();
+        assert_eq!(surface.row(0), "hello     ");
+        assert_eq!(surface.row(1), "wonderful ");
+        assert_eq!(surface.row(2), "world     ");
+        assert_eq!(console.cursor(), (5, 2));
+    }
+
+    #[test]
+    fn breaks_words_longer_than_a_line() {
+        let (mut console, surface) = console(4, 2);
+        write!(console, "abcdefg").unwrap();
+        assert_eq!(surface.row(0), "abcd");
+        assert_eq!(surface.row(1), "efg ");
+    }
+
+    #[test]
+    fn scrolls_past_the_last_row() {
+        let (mut console, surface) = console(5, 2);
+        write!(console, "one\r\ntwo\r\nthree").unwrap();
+        assert_eq!(surface.row(0), "two  ");
+        assert_eq!(surface.row(1), "three");
+        assert_eq!(console.cursor(), (5, 1));
+    }
+
+    #[test]
+    fn tabs_expand_to_the_next_stop() {
+        let (mut console, surface) = console(20, 2);
+        write!(console, "a\tb\tc").unwrap();
+        assert_eq!(surface.row(0), "a       b       c   ");
+    }
+
+    #[test]
+    fn carriage_return_overwrites_the_line() {
+        let (mut console, surface) = console(10, 2);
+        write!(console, "progress 10%\r").unwrap();
+        // Wrapped, so only the last row is overwritten
+        assert_eq!(surface.row(1), "10%       ");
+        write!(console, "5%").unwrap();
+        assert_eq!(surface.row(1), "5%        ");
+
+        // `\r\n` keeps the line
+        write!(console, "\r\n").unwrap();
+        assert_eq!(surface.row(0), "5%        ");
+    }
+
+    #[test]
+    fn framebuffer_draws_and_scrolls_pixels() {
+        let info = FrameBufferInfo {
+            byte_len: 4 * 2 * 16 * GLYPH_WIDTH,
+            width: GLYPH_WIDTH,
+            height: 2 * 16,
+            pixel_format: PixelFormat::Bgr,
+            bytes_per_pixel: 4,
+            stride: GLYPH_WIDTH,
+        };
+        let buffer = Box::leak(vec![0u8; info.byte_len].into_boxed_slice());
+        let mut fb = Framebuffer::new(buffer, info);
+        assert_eq!(fb.glyph_size(), (GLYPH_WIDTH, 16));
+
+        fb.draw(0, 1, '#');
+        let half = info.byte_len / 2;
+        assert!(fb.buffer[..half].iter().all(|&b| b == 0));
+        assert!(fb.buffer[half..].iter().any(|&b| b != 0));
+
+        fb.scroll_up(1);
+        assert!(fb.buffer[..half].iter().any(|&b| b != 0));
+        assert!(fb.buffer[half..].iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,302 @@
+//! Kernel console.
+//!
+//! Output always goes to [`COM1`], and can additionally be captured in memory by attaching a
+//! buffer with [`attach_buffer`], or shown on screen by attaching a framebuffer console with
+//! [`attach_framebuffer`].
+
+pub mod framebuffer;
+
+use alloc::string::String;
+use core::fmt::Write;
+
+use spin::{Mutex, MutexGuard};
+#[cfg(not(test))]
+use x86_64::instructions::interrupts;
+
+use self::framebuffer::FramebufferConsole;
+#[cfg(test)]
+use self::tests::interrupts;
+use crate::serial::{Serial, COM1};
+
+/// Capacity reserved for a captured buffer.
+///
+/// Output past this is dropped, since growing the buffer would allocate while the console is
+/// locked.
+const BUFFER_CAPACITY: usize = 64 * 1024;
+
+/// Size of the ring of recent output kept for panic reports.
+pub const RECENT_SIZE: usize = 2048;
+
+static BUFFER: Mutex<Option<BufferSink>> = Mutex::new(None);
+static FRAMEBUFFER: Mutex<Option<FramebufferConsole>> = Mutex::new(None);
+static RECENT: Mutex<RecentOutput> = Mutex::new(RecentOutput::new());
+
+#[macro_export]
+macro_rules! kprint {
+    ($($args:tt)*) => {
+        {
+            use ::core::fmt::Write;
+            let mut console = $crate::console::writer();
+            // Console write will never fail
+            let _ = write!(console, $($args)*);
+        }
+    };
+}
+
+/// Prints to a specific serial port instead of the console.
+///
+/// ```ignore
+/// if let Some(com2) = &*serial::COM2 {
+///     kprint_to!(com2, "{x}\r\n");
+/// }
+/// ```
+#[macro_export]
+macro_rules! kprint_to {
+    ($port:expr, $($args:tt)*) => {
+        $crate::serial::write_to($port, format_args!($($args)*))
+    };
+}
+
+/// Prints a line prefixed with the current [`Timestamp`](crate::time::Timestamp).
+#[macro_export]
+macro_rules! klog {
+    ($($args:tt)*) => {
+        $crate::kprintln!("{} {}", $crate::time::Timestamp::now(), format_args!($($args)*))
+    };
+}
+
+#[macro_export]
+macro_rules! kprintln {
+    ($($args:tt)*) => {
+        {
+            use ::core::fmt::Write;
+            let mut console = $crate::console::writer();
+            // Console write will never fail
+            // Use write! instead of writeln! to ensure a carriage return is written
+            let _ = write!(console, $($args)*);
+            let _ = console.write_str("\r\n");
+        }
+    };
+}
+
+/// In-memory console sink.
+///
+/// Carriage returns are dropped so the captured text only contains `\n` line endings.
+pub struct BufferSink {
+    buf: String,
+}
+
+impl Write for BufferSink {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars().filter(|&c| c != '\r') {
+            if self.buf.len() + c.len_utf8() > self.buf.capacity() {
+                break;
+            }
+            self.buf.push(c);
+        }
+        Ok(())
+    }
+}
+
+/// Ring of the last [`RECENT_SIZE`] bytes written to the console.
+///
+/// Always attached, it doesn't allocate. Carriage returns are dropped like in [`BufferSink`].
+struct RecentOutput {
+    buf: [u8; RECENT_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl RecentOutput {
+    const fn new() -> Self {
+        Self {
+            buf: [0; RECENT_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, s: &str) {
+        for &byte in s.as_bytes().iter().filter(|&&byte| byte != b'\r') {
+            self.buf[(self.start + self.len) % RECENT_SIZE] = byte;
+            if self.len == RECENT_SIZE {
+                self.start = (self.start + 1) % RECENT_SIZE;
+            } else {
+                self.len += 1;
+            }
+        }
+    }
+}
+
+/// Locked handle to every console sink.
+///
+/// Holding it keeps a single write from being interleaved with others, or with a buffer being
+/// attached or detached.
+///
+/// Interrupts are disabled while it's held, since the COM1 interrupt handler locks serial too.
+pub struct ConsoleWriter<'a> {
+    serial: MutexGuard<'a, Serial>,
+    /// `None` if the sink was skipped by [`panic_writer`]
+    buffer: Option<MutexGuard<'a, Option<BufferSink>>>,
+    framebuffer: Option<MutexGuard<'a, Option<FramebufferConsole>>>,
+    /// Declared last, so interrupts are only restored once every lock is released
+    _interrupts: InterruptsRestore,
+}
+
+/// Re-enables interrupts on drop if they were enabled before.
+struct InterruptsRestore(bool);
+
+impl Drop for InterruptsRestore {
+    fn drop(&mut self) {
+        if self.0 {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Write for ConsoleWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.serial.write_str(s)?;
+        if let Some(buffer) = self.buffer.as_deref_mut().and_then(Option::as_mut) {
+            buffer.write_str(s)?;
+        }
+        if let Some(framebuffer) = self.framebuffer.as_deref_mut().and_then(Option::as_mut) {
+            framebuffer.write_str(s)?;
+        }
+        // Only ever contended by `recent_output` in the panic handler
+        if let Some(mut recent) = RECENT.try_lock() {
+            recent.push(s);
+        }
+        Ok(())
+    }
+}
+
+/// Locks the console for writing.
+pub fn writer() -> ConsoleWriter<'static> {
+    let restore = InterruptsRestore(interrupts::are_enabled());
+    interrupts::disable();
+
+    // Always lock serial, then the buffer, then the framebuffer
+    let serial = COM1.lock();
+    let buffer = BUFFER.lock();
+    let framebuffer = FRAMEBUFFER.lock();
+    ConsoleWriter {
+        serial,
+        buffer: Some(buffer),
+        framebuffer: Some(framebuffer),
+        _interrupts: restore,
+    }
+}
+
+/// Locks the console for the panic handler, without waiting for any lock.
+///
+/// The code that panicked may hold any of the console locks. COM1 is forcibly unlocked if it's
+/// held, since the report must get out even if it garbles a line being written. The buffer and
+/// framebuffer are skipped if they're held.
+///
+/// Interrupts must already be disabled, they aren't restored on drop.
+pub fn panic_writer() -> ConsoleWriter<'static> {
+    let serial = COM1.try_lock().unwrap_or_else(|| {
+        // SAFETY: the holder either panicked or is on another CPU, whose output may be
+        // interleaved with the report but can't corrupt the port
+        unsafe { COM1.force_unlock() };
+        COM1.lock()
+    });
+    ConsoleWriter {
+        serial,
+        buffer: BUFFER.try_lock(),
+        framebuffer: FRAMEBUFFER.try_lock(),
+        _interrupts: InterruptsRestore(false),
+    }
+}
+
+/// Copies the most recent console output into `out` in order, returning its length.
+///
+/// Returns 0 instead of waiting if the output is being written, so it is safe to call from the
+/// panic handler. The oldest bytes may be cut in the middle of a UTF-8 character.
+pub fn recent_output(out: &mut [u8; RECENT_SIZE]) -> usize {
+    let Some(recent) = RECENT.try_lock() else {
+        return 0;
+    };
+    for (i, byte) in out.iter_mut().enumerate().take(recent.len) {
+        *byte = recent.buf[(recent.start + i) % RECENT_SIZE];
+    }
+    recent.len
+}
+
+/// Starts capturing console output, discarding any previously attached buffer.
+pub fn attach_buffer() {
+    // Allocate & free outside the lock, the allocator may print
+    let sink = BufferSink {
+        buf: String::with_capacity(BUFFER_CAPACITY),
+    };
+    let old = BUFFER.lock().replace(sink);
+    drop(old);
+}
+
+/// Stops capturing console output, returning everything written since [`attach_buffer`].
+pub fn detach_buffer() -> String {
+    let sink = BUFFER.lock().take();
+    sink.map_or_else(String::new, |sink| sink.buf)
+}
+
+/// Mirrors console output to a framebuffer console, replacing any previously attached one.
+pub fn attach_framebuffer(console: FramebufferConsole) {
+    let old = FRAMEBUFFER.lock().replace(console);
+    drop(old);
+}
+
+/// Stops mirroring console output to the framebuffer, returning the detached console.
+pub fn detach_framebuffer() -> Option<FramebufferConsole> {
+    FRAMEBUFFER.lock().take()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::tests::mock_com1;
+
+    /// The interrupt flag, which can't be changed on the host
+    pub mod interrupts {
+        pub fn are_enabled() -> bool {
+            false
+        }
+        pub fn disable() {}
+        pub fn enable() {}
+    }
+
+    /// Serializes the tests attaching the shared buffer
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn captures_output_while_attached() {
+        let _guard = LOCK.lock();
+        mock_com1();
+
+        crate::kprintln!("before");
+        attach_buffer();
+        crate::kprintln!("hello");
+        crate::kprint!("{}\r\n", "world");
+        assert_eq!(detach_buffer(), "hello\nworld\n");
+
+        crate::kprintln!("after");
+        assert_eq!(detach_buffer(), "");
+    }
+
+    #[test]
+    fn buffer_drops_output_past_capacity() {
+        let _guard = LOCK.lock();
+        mock_com1();
+
+        attach_buffer();
+        crate::kprint!("{}", "a".repeat(BUFFER_CAPACITY - 1));
+        // A character that doesn't fit is dropped whole, along with the rest of the write
+        crate::kprint!("\u{e9}b");
+        crate::kprint!("c");
+        crate::kprint!("d");
+
+        let captured = detach_buffer();
+        assert_eq!(captured.len(), BUFFER_CAPACITY);
+        assert_eq!(captured, "a".repeat(BUFFER_CAPACITY - 1) + "c");
+    }
+}
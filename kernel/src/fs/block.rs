@@ -0,0 +1,85 @@
+//! Block devices backing file systems.
+
+use alloc::{vec, vec::Vec};
+
+use spin::lock_api::RwLock;
+
+use crate::fs::vfs::{FSError, FSResult};
+
+/// Device read and written in fixed-size blocks
+pub trait BlockDevice {
+    /// Size of a block in bytes
+    fn block_size(&self) -> usize;
+
+    /// Reads block `idx` into `buf`, which is [`block_size`](Self::block_size) bytes long
+    fn read_block(&self, idx: u64, buf: &mut [u8]) -> FSResult<()>;
+
+    /// Writes `buf`, which is [`block_size`](Self::block_size) bytes long, to block `idx`
+    fn write_block(&self, idx: u64, buf: &[u8]) -> FSResult<()>;
+}
+
+/// Block device kept in memory
+#[derive(Debug)]
+pub struct RamDisk {
+    block_size: usize,
+    data: RwLock<Vec<u8>>,
+}
+
+impl RamDisk {
+    /// Creates a zeroed disk of `blocks` blocks
+    pub fn new(block_size: usize, blocks: usize) -> Self {
+        Self::from_bytes(block_size, vec![0; block_size * blocks])
+    }
+
+    /// Creates a disk holding the image `data`, padded with zeros to a whole block
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_size` is 0.
+    pub fn from_bytes(block_size: usize, mut data: Vec<u8>) -> Self {
+        assert!(block_size != 0, "block size must not be 0");
+        data.resize(data.len().next_multiple_of(block_size), 0);
+        Self {
+            block_size,
+            data: RwLock::new(data),
+        }
+    }
+
+    /// Number of blocks on the disk
+    pub fn blocks(&self) -> u64 {
+        (self.data.read().len() / self.block_size) as u64
+    }
+
+    /// Byte range of block `idx` for a buffer of `len` bytes
+    fn range(&self, idx: u64, len: usize, disk_len: usize) -> FSResult<core::ops::Range<usize>> {
+        if len != self.block_size {
+            return Err(FSError::InvalidArgument);
+        }
+        let start = usize::try_from(idx)
+            .ok()
+            .and_then(|idx| idx.checked_mul(self.block_size))
+            .filter(|&start| start < disk_len)
+            .ok_or(FSError::InvalidArgument)?;
+        Ok(start..start + self.block_size)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn read_block(&self, idx: u64, buf: &mut [u8]) -> FSResult<()> {
+        let data = self.data.read();
+        let range = self.range(idx, buf.len(), data.len())?;
+        buf.copy_from_slice(&data[range]);
+        Ok(())
+    }
+
+    fn write_block(&self, idx: u64, buf: &[u8]) -> FSResult<()> {
+        let mut data = self.data.write();
+        let range = self.range(idx, buf.len(), data.len())?;
+        data[range].copy_from_slice(buf);
+        Ok(())
+    }
+}
@@ -1,39 +1,78 @@
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
 use core::{
     fmt::{Debug, Formatter},
     iter::Peekable,
-    sync::atomic::{AtomicU64, Ordering},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
 };
 
-use hashbrown::HashMap;
 use spin::{
     lock_api::{RwLock, RwLockReadGuard, RwLockWriteGuard},
     Lazy,
 };
 
 use crate::{
+    collections::LruCache,
     fs::{
+        icache::{InodeId, SharedInode, INODE_CACHE},
         path::{Component, Path, PathBuf},
-        vfs,
+        resolve_link, vfs,
         vfs::{FSError, FSResult, FileSystem, Inode},
-        MOUNTS,
+        MAX_SYMLINKS, MOUNTS,
     },
-    time::TICKS,
 };
 
 const CACHE_SIZE: usize = 0x8000 / core::mem::size_of::<DEntry>();
 
 pub static DIR_CACHE: Lazy<DirectoryCache> = Lazy::new(DirectoryCache::new);
 
-type Entries = HashMap<PathBuf, (DEntry, AtomicU64)>;
+type Entries = LruCache<PathBuf, DEntry>;
 
 pub struct DirectoryCache {
     entries: RwLock<Entries>,
+    /// Lookups answered from the cache
+    hits: AtomicU64,
+    /// Lookups that had to resolve the path
+    misses: AtomicU64,
 }
+
+/// Counters of a [`DirectoryCache`], for tuning its capacity.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct CacheMetrics {
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries evicted to make room for others
+    pub evictions: u64,
+    /// Entries currently cached
+    pub len: usize,
+    pub capacity: usize,
+}
+
 impl DirectoryCache {
     pub fn new() -> Self {
+        Self::with_capacity(CACHE_SIZE)
+    }
+
+    /// Creates a cache holding up to `capacity` entries, not counting mount points.
+    pub fn with_capacity(capacity: usize) -> Self {
         Self {
-            entries: RwLock::new(HashMap::with_capacity(CACHE_SIZE)),
+            // Don't evict entries for root mount points
+            entries: RwLock::new(LruCache::with_pin(capacity, |path, _| {
+                MOUNTS.is_mount_path(path)
+            })),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns a snapshot of the cache counters
+    pub fn metrics(&self) -> CacheMetrics {
+        let entries = self.entries.read();
+        CacheMetrics {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: entries.evictions(),
+            len: entries.len(),
+            capacity: entries.capacity(),
         }
     }
 
@@ -56,16 +95,24 @@ impl DirectoryCache {
             let mut i = dentry.inode_mut();
             *i = inode;
         } else {
+            INODE_CACHE.evict(InodeId::new(&*dentry.fs(), dentry.inode().num));
             lock.remove(&*dentry.name());
         }
         Ok(())
     }
 
     fn get_opt(&self, path: &Path) -> Option<DEntry> {
-        self.entries.read().get(path).map(|entry| {
-            entry.1.store(TICKS.get(), Ordering::SeqCst);
-            entry.0.clone()
-        })
+        // Lookups share the lock, recency is only updated if no one else holds it
+        let entry = self.entries.read().peek(path).cloned();
+        if entry.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            if let Some(mut entries) = self.entries.try_write() {
+                let _ = entries.get(path);
+            }
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        entry
     }
 
     pub fn get<P: AsRef<Path>>(&self, path: P) -> FSResult<DEntry> {
@@ -78,48 +125,80 @@ impl DirectoryCache {
         }
 
         // Slow path, entry not cached
-        for parent in path.ancestors() {
-            if let Some(entry) = self.get_opt(parent) {
-                let remaining = path.strip_prefix(parent).unwrap().components();
-
-                let mut lock = self.entries.write();
-                return fill_path(&mut lock, parent, entry, remaining.peekable());
-            }
-        }
-
-        // Entry not found, that means there is no disk mounted at root
-        panic!("No disk mounted at root");
+        let mut lock = self.entries.write();
+        resolve(&mut lock, path, &mut 0)
     }
 
     pub fn delete(&self, path: &Path) {
         self.entries.write().remove(path);
     }
-    pub fn delete_inode(&self, fs: &dyn FileSystem, inode: &Inode) {
-        self.entries.write().retain(|_, entry| {
-            entry.0.fs().name() != fs.name() || entry.0.inode().num != inode.num
-        });
+    /// Deletes `path` and every cached entry below it
+    pub fn delete_all(&self, path: &Path) {
+        self.entries
+            .write()
+            .retain(|entry_path, _| !entry_path.starts_with(path));
     }
-    pub fn unmount(&self, fs: &dyn FileSystem) {
+    pub fn delete_inode(&self, fs: &dyn FileSystem, inode: &Inode) {
+        INODE_CACHE.evict(InodeId::new(fs, inode.num));
         self.entries
             .write()
-            .retain(|_, entry| entry.0.fs().name() != fs.name());
+            .retain(|_, entry| !entry.is_on(fs) || entry.inode().num != inode.num);
+    }
+    pub fn unmount(&self, fs: &dyn FileSystem) {
+        INODE_CACHE.unmount(fs);
+        self.entries.write().retain(|_, entry| !entry.is_on(fs));
+    }
+}
+
+/// Looks up `path` from its closest cached ancestor, filling the cache along the way
+///
+/// `links` counts the symbolic links followed so far.
+fn resolve(cache: &mut Entries, path: &Path, links: &mut usize) -> FSResult<DEntry> {
+    for parent in path.ancestors() {
+        if let Some(entry) = cache.get(parent).cloned() {
+            let remaining = path.strip_prefix(parent).unwrap().components();
+            return fill_path(cache, parent, entry, remaining.peekable(), links);
+        }
     }
+
+    // Entry not found, that means there is no disk mounted at root
+    Err(FSError::NoMount)
 }
 
 /// Fill the cache with the entries from `cached_parent` to path
+///
+/// Symbolic links are followed, except for the last component.
 fn fill_path<'a, C, P>(
     cache: &mut Entries,
     parent: P,
     pdentry: DEntry,
     mut comps: Peekable<C>,
+    links: &mut usize,
 ) -> FSResult<DEntry>
 where
     C: Iterator<Item = Component<'a>>,
     P: Into<PathBuf>,
 {
-    let Some(comp) = comps.next() else {
+    if comps.peek().is_none() {
         return Ok(pdentry);
-    };
+    }
+
+    // Continue from the link's target, since there are more components
+    if pdentry.inode().is_symlink() {
+        *links += 1;
+        if *links > MAX_SYMLINKS {
+            return Err(FSError::TooManyLinks);
+        }
+
+        let target = pdentry.inode().readlink()?;
+        let mut resolved = resolve_link(&parent.into(), &target);
+        for comp in comps {
+            resolved.push(comp);
+        }
+        return resolve(cache, &resolved, links);
+    }
+
+    let comp = comps.next().unwrap();
 
     let inode = pdentry.inode();
 
@@ -140,6 +219,12 @@ where
         let mut new_path = parent.into();
         new_path.push(path);
 
+        // Cross into the file system mounted here
+        if let Some(root) = MOUNTS.mount_root(&new_path) {
+            insert_entry(cache, root.clone());
+            return fill_path(cache, new_path, root, comps, links);
+        }
+
         // Insert the entry into the cache
         let entry = {
             let fs = pdentry.fs();
@@ -154,7 +239,7 @@ where
 
         insert_entry(cache, entry.clone());
 
-        return fill_path(cache, new_path, entry, comps);
+        return fill_path(cache, new_path, entry, comps, links);
     }
 
     // Entry not found
@@ -165,45 +250,22 @@ where
 ///
 /// Evicts the least recently used entry if the cache is full
 fn insert_entry(entries: &mut Entries, entry: DEntry) {
-    if entries.len() >= CACHE_SIZE {
-        evict_entry(entries);
-    }
-
     let name = entry.name().to_path_buf();
-
-    entries.insert(name, (entry, AtomicU64::new(TICKS.get())));
-}
-
-fn evict_entry(entries: &mut Entries) {
-    let mut lru = None;
-    let mut lru_time = u64::MAX;
-
-    for (path, entry) in entries.iter() {
-        if MOUNTS.is_mount_path(path) {
-            // Don't evict entries for root mount points
-            continue;
-        }
-
-        let last_access = entry.1.load(Ordering::SeqCst);
-        if last_access < lru_time {
-            lru = Some(path.clone());
-            lru_time = last_access;
-        }
-    }
-
-    if let Some(lru) = lru {
-        entries.remove(&lru);
-    }
+    entries.put(name, entry);
 }
 
 #[derive(Debug, Clone)]
-pub struct DEntry(Arc<RwLock<DEntryInner>>);
+pub struct DEntry {
+    inner: Arc<RwLock<DEntryInner>>,
+    /// Cached inode, shared with the dentries of every other link to it
+    inode: SharedInode,
+}
 
 struct DEntryInner {
     /// Cached path
     name: PathBuf,
-    /// Cached inode
-    inode: Inode,
+    /// Whether the cached inode has changes not yet written to the file system
+    dirty: AtomicBool,
     /// Filesystem key in the mount table
     fs: Arc<dyn vfs::FileSystem + Send + Sync>,
 }
@@ -217,31 +279,70 @@ impl DEntry {
         inode: Inode,
         fs: Arc<dyn vfs::FileSystem + Send + Sync>,
     ) -> Self {
-        Self(Arc::new(RwLock::new(DEntryInner {
-            name: name.into(),
+        let inode = INODE_CACHE.get_or_insert(InodeId::new(&*fs, inode.num), inode);
+        Self {
+            inner: Arc::new(RwLock::new(DEntryInner {
+                name: name.into(),
+                dirty: AtomicBool::new(false),
+                fs,
+            })),
             inode,
-            fs,
-        })))
+        }
     }
 
     pub fn reload(&self) -> FSResult<()> {
         DIR_CACHE.reload(self)
     }
 
-    pub fn name(&self) -> MappedReadGuard<Path> {
-        RwLockReadGuard::map(self.0.read(), |inner| &*inner.name)
+    pub fn name(&self) -> MappedReadGuard<'_, Path> {
+        RwLockReadGuard::map(self.inner.read(), |inner| &*inner.name)
+    }
+    pub fn inode(&self) -> RwLockReadGuard<'_, Inode> {
+        self.inode.read()
+    }
+    pub fn inode_mut(&self) -> RwLockWriteGuard<'_, Inode> {
+        self.inode.write()
     }
-    pub fn inode(&self) -> MappedReadGuard<Inode> {
-        RwLockReadGuard::map(self.0.read(), |inner| &inner.inode)
+    /// Returns a snapshot of the inode's metadata
+    pub fn metadata(&self) -> vfs::Metadata {
+        self.inode.read().metadata()
     }
-    pub fn inode_mut(&self) -> MappedWriteGuard<Inode> {
-        RwLockWriteGuard::map(self.0.write(), |inner| &mut inner.inode)
+    /// Appends the name and metadata of every entry in this directory to `out`
+    pub fn readdir_plus(&self, out: &mut Vec<(PathBuf, vfs::Metadata)>) -> FSResult<()> {
+        let ops = self.inode().ops();
+        ops.readdir_plus(self, out)
     }
-    pub fn fs(&self) -> MappedReadGuard<dyn vfs::FileSystem + Send + Sync> {
-        RwLockReadGuard::map(self.0.read(), |inner| &*inner.fs)
+    /// Marks the cached inode as modified, to be committed by [`sync`](Self::sync)
+    pub fn mark_dirty(&self) {
+        self.inner.read().dirty.store(true, Ordering::Release);
+    }
+    pub fn is_dirty(&self) -> bool {
+        self.inner.read().dirty.load(Ordering::Acquire)
+    }
+    /// Writes the cached inode to the file system if it was modified
+    pub fn sync(&self) -> FSResult<()> {
+        let inner = self.inner.read();
+        if !inner.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        let inode = self.inode.read();
+        let sb = inner.fs.superblock();
+        let res = sb.write().write_inode(&inode);
+        if res.is_err() {
+            inner.dirty.store(true, Ordering::Release);
+        }
+        res
+    }
+    pub fn fs(&self) -> MappedReadGuard<'_, dyn vfs::FileSystem + Send + Sync> {
+        RwLockReadGuard::map(self.inner.read(), |inner| &*inner.fs)
     }
     pub fn fs_arc(&self) -> Arc<dyn vfs::FileSystem + Send + Sync> {
-        self.0.read().fs.clone()
+        self.inner.read().fs.clone()
+    }
+    /// Whether the dentry belongs to `fs`
+    pub fn is_on(&self, fs: &dyn FileSystem) -> bool {
+        core::ptr::addr_eq(Arc::as_ptr(&self.inner.read().fs), fs)
     }
 }
 
@@ -249,8 +350,112 @@ impl Debug for DEntryInner {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("DEntry")
             .field("name", &self.name)
-            .field("inode", &self.inode)
+            .field("dirty", &self.dirty)
             .field("fs", &self.fs.name())
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs;
+
+    #[test]
+    fn evicts_the_least_recently_used_entry() {
+        let _guard = fs::tests::setup();
+        fs::mkdir("/lru").unwrap();
+        for name in ["/lru/a", "/lru/b", "/lru/c"] {
+            fs::write(name, b"").unwrap();
+        }
+
+        let cache = DirectoryCache::with_capacity(4);
+        cache.mount(fs::lookup("/").unwrap());
+        cache.get("/lru/a").unwrap();
+        cache.get("/lru/b").unwrap();
+        let metrics = cache.metrics();
+        assert_eq!((metrics.hits, metrics.misses), (0, 2));
+
+        // Repeated lookups of a cached path are all hits
+        for _ in 0..3 {
+            cache.get("/lru/a").unwrap();
+        }
+        let metrics = cache.metrics();
+        assert_eq!((metrics.hits, metrics.misses), (3, 2));
+        assert_eq!(metrics.len, 4);
+
+        // `/` is the least recently used, but it's a mount point
+        cache.get("/lru/c").unwrap();
+        let entries = cache.entries.read();
+        assert!(entries.peek(Path::new("/lru/b")).is_none());
+        for kept in ["/", "/lru", "/lru/a", "/lru/c"] {
+            assert!(
+                entries.peek(Path::new(kept)).is_some(),
+                "{kept} was evicted"
+            );
+        }
+        assert_eq!(entries.evictions(), 1);
+    }
+
+    #[test]
+    fn lookups_cross_into_a_mounted_file_system() {
+        let _guard = fs::tests::setup();
+        fs::mkdir("/mnt").unwrap();
+        fs::write("/mnt/hidden", b"").unwrap();
+        MOUNTS
+            .mount_fs(fs::mount::MountCtx {
+                fs: alloc::boxed::Box::new(fs::ramfs::FileSystem::new()),
+                dest: Some(fs::lookup("/mnt").unwrap()),
+                source: None,
+                device: None,
+                flags: fs::mount::MountFlags::empty(),
+            })
+            .unwrap();
+        fs::mkdir("/mnt/dir").unwrap();
+        fs::write("/mnt/dir/file", b"mounted").unwrap();
+        let root = fs::lookup("/").unwrap();
+        let mounted = MOUNTS.mount_root(Path::new("/mnt")).unwrap();
+
+        let cache = DirectoryCache::with_capacity(16);
+        cache.mount(root.clone());
+        let file = cache.get("/mnt/dir/file").unwrap();
+        assert!(file.is_on(&*mounted.fs()));
+        assert!(!file.is_on(&*root.fs()));
+        assert_eq!(file.inode().size(), 7);
+        // The mount point resolves to the mounted root, hiding what's below it
+        let mnt = cache.get("/mnt").unwrap();
+        assert_eq!(mnt.inode().num(), mounted.inode().num());
+        assert!(mnt.is_on(&*mounted.fs()));
+        assert_eq!(cache.get("/mnt/hidden").unwrap_err(), FSError::NoEntry);
+
+        MOUNTS.unmount("/mnt").unwrap();
+    }
+
+    #[test]
+    fn metadata_matches_what_was_written() {
+        let _guard = fs::tests::setup();
+        fs::mkdir("/metadata").unwrap();
+        fs::write("/metadata/file", [0xAB; 5000]).unwrap();
+        fs::link("/metadata/file", "/metadata/link").unwrap();
+
+        let dentry = fs::lookup("/metadata/file").unwrap();
+        let meta = dentry.metadata();
+        assert_eq!(meta.mode, vfs::Mode::REGULAR_FILE);
+        assert_eq!(meta.size, 5000);
+        assert_eq!(meta.nlink, 2);
+        assert_eq!(meta.block_size, 4096);
+        assert_eq!(meta.blocks, 2);
+        assert_eq!(meta.user_id, 0);
+
+        // An owned copy of the accessors, which stays the same once the lock is released
+        let inode = dentry.inode();
+        assert_eq!(meta.inode, inode.num());
+        assert_eq!(meta.permission, inode.permission());
+        assert_eq!(meta.group_id, inode.group_id());
+        assert_eq!(meta.creation_time, inode.creation_time());
+        assert_eq!(meta.last_modification_time, inode.last_modification_time());
+        assert_eq!(meta, inode.metadata());
+        drop(inode);
+        assert_eq!(fs::stat("/metadata/link").unwrap(), meta);
+    }
+}
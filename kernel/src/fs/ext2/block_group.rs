@@ -1,5 +1,12 @@
+use alloc::vec::Vec;
+
 use static_assertions::assert_eq_size;
 
+use super::Ext2Error;
+
+/// Size of a block group descriptor on disk
+pub const BLOCK_GROUP_SIZE: usize = 32;
+
 pub struct BlockGroup {
     pub block_usage_bitmap_block: u32,
     pub inode_usage_bitmap_block: u32,
@@ -10,4 +17,70 @@ pub struct BlockGroup {
     _unused: [u8; 14],
 }
 
-assert_eq_size!(BlockGroup, [u8; 32]);
+assert_eq_size!(BlockGroup, [u8; BLOCK_GROUP_SIZE]);
+
+impl BlockGroup {
+    /// Parses a block group descriptor from its little-endian on-disk form.
+    pub fn parse(bytes: &[u8; BLOCK_GROUP_SIZE]) -> Self {
+        let u16_at = |off: usize| u16::from_le_bytes([bytes[off], bytes[off + 1]]);
+        let u32_at = |off: usize| u32::from_le_bytes(bytes[off..off + 4].try_into().unwrap());
+
+        let mut unused = [0; 14];
+        unused.copy_from_slice(&bytes[18..]);
+        Self {
+            block_usage_bitmap_block: u32_at(0),
+            inode_usage_bitmap_block: u32_at(4),
+            inode_table_block: u32_at(8),
+            unallocated_blocks_count: u16_at(12),
+            unallocated_inodes_count: u16_at(14),
+            directories_count: u16_at(16),
+            _unused: unused,
+        }
+    }
+}
+
+/// Parses the first `count` descriptors of a block group descriptor table.
+///
+/// `count` is the number of block groups, `block_count.div_ceil(blocks_per_group)` from the
+/// superblock.
+pub fn parse_table(bytes: &[u8], count: u32) -> Result<Vec<BlockGroup>, Ext2Error> {
+    let len = count as usize * BLOCK_GROUP_SIZE;
+    let table = bytes.get(..len).ok_or(Ext2Error::TooShort)?;
+    Ok(table
+        .as_chunks::<BLOCK_GROUP_SIZE>()
+        .0
+        .iter()
+        .map(BlockGroup::parse)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(inode_table: u32, blocks: u16, inodes: u16) -> [u8; BLOCK_GROUP_SIZE] {
+        let mut bytes = [0; BLOCK_GROUP_SIZE];
+        bytes[8..12].copy_from_slice(&inode_table.to_le_bytes());
+        bytes[12..14].copy_from_slice(&blocks.to_le_bytes());
+        bytes[14..16].copy_from_slice(&inodes.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn parses_a_table() {
+        let mut bytes = Vec::new();
+        bytes.extend(descriptor(5, 100, 20));
+        bytes.extend(descriptor(0x1234_5678, 0xBEEF, 7));
+
+        let table = parse_table(&bytes, 2).unwrap();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table[0].inode_table_block, 5);
+        assert_eq!(table[0].unallocated_blocks_count, 100);
+        assert_eq!(table[0].unallocated_inodes_count, 20);
+        assert_eq!(table[1].inode_table_block, 0x1234_5678);
+        assert_eq!(table[1].unallocated_blocks_count, 0xBEEF);
+        assert_eq!(table[1].unallocated_inodes_count, 7);
+
+        assert!(matches!(parse_table(&bytes, 3), Err(Ext2Error::TooShort)));
+    }
+}
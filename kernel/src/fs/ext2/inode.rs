@@ -1,6 +1,11 @@
 use bitflags::bitflags;
 use static_assertions::assert_eq_size;
 
+use super::superblock::ReadOnlyFeatures;
+
+/// Size of an inode on disk in revision 0 file systems, and the part of it parsed in later ones
+pub const INODE_SIZE: usize = 128;
+
 pub struct Inode {
     pub tp_and_perm: TypeAndPermission,
     pub user_id: u16,
@@ -25,14 +30,76 @@ pub struct Inode {
     pub os_specific_value_2: [u32; 3],
 }
 
-assert_eq_size!(Inode, [u8; 128]);
+assert_eq_size!(Inode, [u8; INODE_SIZE]);
+
+impl Inode {
+    /// Parses an inode from its little-endian on-disk form.
+    pub fn parse(bytes: &[u8; INODE_SIZE]) -> Self {
+        let u16_at = |off: usize| u16::from_le_bytes([bytes[off], bytes[off + 1]]);
+        let u32_at = |off: usize| u32::from_le_bytes(bytes[off..off + 4].try_into().unwrap());
+
+        Self {
+            tp_and_perm: TypeAndPermission { value: u16_at(0) },
+            user_id: u16_at(2),
+            size_lo: u32_at(4),
+            last_access_time: u32_at(8),
+            creation_time: u32_at(12),
+            last_modification_time: u32_at(16),
+            deletion_time: u32_at(20),
+            group_id: u16_at(24),
+            hard_link_count: u16_at(26),
+            disk_sectors: u32_at(28),
+            flags: InodeFlags::from_bits_retain(u32_at(32)),
+            os_specific_value_1: u32_at(36),
+            direct_block_pointers: core::array::from_fn(|i| u32_at(40 + i * 4)),
+            singly_indirect_block_pointer: u32_at(88),
+            doubly_indirect_block_pointer: u32_at(92),
+            triply_indirect_block_pointer: u32_at(96),
+            generation_number: u32_at(100),
+            extended_attribute_block: u32_at(104),
+            size_hi: u32_at(108),
+            fragment_block_address: u32_at(112),
+            os_specific_value_2: core::array::from_fn(|i| u32_at(116 + i * 4)),
+        }
+    }
+
+    /// Size of the file in bytes
+    ///
+    /// `size_hi` only holds the upper 32 bits of the size for regular files on file systems with
+    /// [`ReadOnlyFeatures::USES_64_BIT_FILE_SIZE`], it's the directory ACL otherwise.
+    pub fn size(&self, features: &ReadOnlyFeatures) -> u64 {
+        let size_hi = if features.contains(ReadOnlyFeatures::USES_64_BIT_FILE_SIZE)
+            && self.tp_and_perm.file_type() == Type::REGULAR_FILE
+        {
+            self.size_hi
+        } else {
+            0
+        };
+        (u64::from(size_hi) << 32) | u64::from(self.size_lo)
+    }
+}
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct TypeAndPermission {
     value: u16,
 }
 
+impl TypeAndPermission {
+    /// Type of the inode, from the high nibble
+    ///
+    /// Types aren't independent bits, compare them with `==` rather than `contains`.
+    pub const fn file_type(self) -> Type {
+        Type::from_bits_retain(self.value & 0xF000)
+    }
+
+    /// Permissions of the inode, from the low 12 bits
+    pub const fn permissions(self) -> Permission {
+        Permission::from_bits_retain(self.value & 0x0FFF)
+    }
+}
+
 bitflags! {
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
     pub struct Type: u16 {
         const FIFO = 0x1000;
         const CHARACTER_DEVICE = 0x2000;
@@ -45,19 +112,20 @@ bitflags! {
 }
 
 bitflags! {
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
     pub struct Permission: u16 {
-        const OTHER_EXECUTE = 0x01;
-        const OTHER_WRITE = 0x02;
-        const OTHER_READ = 0x04;
-        const GROUP_EXECUTE = 0x10;
-        const GROUP_WRITE = 0x20;
-        const GROUP_READ = 0x40;
-        const USER_EXECUTE = 0x100;
-        const USER_WRITE = 0x200;
-        const USER_READ = 0x400;
-        const STICKY = 0x1000;
-        const SET_GROUP_ID = 0x2000;
-        const SET_USER_ID = 0x4000;
+        const OTHER_EXECUTE = 0o0001;
+        const OTHER_WRITE = 0o0002;
+        const OTHER_READ = 0o0004;
+        const GROUP_EXECUTE = 0o0010;
+        const GROUP_WRITE = 0o0020;
+        const GROUP_READ = 0o0040;
+        const USER_EXECUTE = 0o0100;
+        const USER_WRITE = 0o0200;
+        const USER_READ = 0o0400;
+        const STICKY = 0o1000;
+        const SET_GROUP_ID = 0o2000;
+        const SET_USER_ID = 0o4000;
     }
 }
 
@@ -76,3 +144,56 @@ bitflags! {
         const JOURNAL_FILE_DATA = 0x0004_0000;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inode(mode: u16, size_lo: u32, size_hi: u32) -> [u8; INODE_SIZE] {
+        let mut bytes = [0; INODE_SIZE];
+        bytes[0..2].copy_from_slice(&mode.to_le_bytes());
+        bytes[4..8].copy_from_slice(&size_lo.to_le_bytes());
+        bytes[26..28].copy_from_slice(&2_u16.to_le_bytes());
+        bytes[40..44].copy_from_slice(&7_u32.to_le_bytes());
+        bytes[84..88].copy_from_slice(&9_u32.to_le_bytes());
+        bytes[88..92].copy_from_slice(&12_u32.to_le_bytes());
+        bytes[108..112].copy_from_slice(&size_hi.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn decodes_a_regular_file() {
+        let inode = Inode::parse(&inode(0x8000 | 0o644, 0x1000, 1));
+        assert_eq!(inode.tp_and_perm.file_type(), Type::REGULAR_FILE);
+        assert_eq!(
+            inode.tp_and_perm.permissions(),
+            Permission::USER_READ
+                | Permission::USER_WRITE
+                | Permission::GROUP_READ
+                | Permission::OTHER_READ
+        );
+        assert_eq!(inode.hard_link_count, 2);
+        assert_eq!(inode.direct_block_pointers[0], 7);
+        assert_eq!(inode.direct_block_pointers[11], 9);
+        assert_eq!(inode.singly_indirect_block_pointer, 12);
+
+        assert_eq!(inode.size(&ReadOnlyFeatures::empty()), 0x1000);
+        assert_eq!(
+            inode.size(&ReadOnlyFeatures::USES_64_BIT_FILE_SIZE),
+            0x1_0000_1000
+        );
+    }
+
+    #[test]
+    fn decodes_a_directory() {
+        // The upper size field of a directory is its ACL
+        let inode = Inode::parse(&inode(0x4000 | 0o1755, 0x400, 5));
+        assert_eq!(inode.tp_and_perm.file_type(), Type::DIRECTORY);
+        assert!(inode.tp_and_perm.permissions().contains(Permission::STICKY));
+        assert!(!inode
+            .tp_and_perm
+            .permissions()
+            .contains(Permission::GROUP_WRITE));
+        assert_eq!(inode.size(&ReadOnlyFeatures::USES_64_BIT_FILE_SIZE), 0x400);
+    }
+}
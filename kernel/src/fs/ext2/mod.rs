@@ -1,5 +1,761 @@
 //! Ext2 File System implementation
+//!
+//! Read-only, every operation that would modify the file system fails with
+//! [`FSError::NotSupported`].
+
+use alloc::{
+    boxed::Box,
+    sync::Arc,
+    vec,
+    vec::{IntoIter, Vec},
+};
+use core::fmt::{Display, Formatter};
+
+use spin::lock_api::RwLock;
+
+use self::{
+    block_group::{parse_table, BlockGroup},
+    inode::{Type, INODE_SIZE},
+    superblock::{RequiredFeatures, SUPERBLOCK_SIZE},
+};
+use crate::fs::{
+    block::BlockDevice,
+    dentry::DEntry,
+    mount::MountType,
+    path::{Component, Path, PathBuf},
+    vfs,
+    vfs::{
+        file_iter::{FileIter, FileIterator},
+        FSError, FSResult,
+    },
+};
 
 mod block_group;
 mod inode;
 mod superblock;
+
+const FS_NAME: &str = "ext2";
+/// Byte offset of the superblock on the device
+const SUPERBLOCK_OFFSET: u64 = 1024;
+/// Inode number of the root directory
+const ROOT_INODE: u64 = 2;
+/// Symbolic links shorter than this are stored in the block pointers
+const FAST_SYMLINK_SIZE: u64 = 60;
+
+/// Error reading ext2 on-disk structures
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Ext2Error {
+    /// Superblock magic isn't `0xEF53`
+    BadMagic,
+    /// Buffer is smaller than the structure
+    TooShort,
+    /// Field holds a value the driver doesn't understand
+    InvalidField(&'static str),
+}
+
+impl Display for Ext2Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::BadMagic => f.write_str("Bad ext2 magic"),
+            Self::TooShort => f.write_str("Buffer too short"),
+            Self::InvalidField(field) => write!(f, "Invalid ext2 field: {field}"),
+        }
+    }
+}
+
+impl From<Ext2Error> for FSError {
+    fn from(_: Ext2Error) -> Self {
+        Self::InvalidData
+    }
+}
+
+/// Read-only ext2 file system on a [`BlockDevice`]
+pub struct FileSystem {
+    superblock: Arc<RwLock<SuperBlock>>,
+}
+
+impl FileSystem {
+    pub fn new() -> Self {
+        Self {
+            superblock: Arc::new(RwLock::new(SuperBlock { volume: None })),
+        }
+    }
+}
+
+impl vfs::FileSystem for FileSystem {
+    fn name(&self) -> &str {
+        FS_NAME
+    }
+
+    fn mount_type(&self) -> MountType {
+        MountType::BlockDevice
+    }
+
+    fn init_super(&mut self, dev: Option<Arc<dyn BlockDevice + Send + Sync>>) -> FSResult<()> {
+        let volume = Volume::open(dev.ok_or(FSError::NoDevice)?)?;
+        self.superblock.write().volume = Some(Arc::new(volume));
+        Ok(())
+    }
+
+    fn superblock(&self) -> Arc<RwLock<dyn vfs::SuperBlock + Send + Sync>> {
+        Arc::clone(&self.superblock) as Arc<RwLock<dyn vfs::SuperBlock + Send + Sync>>
+    }
+}
+
+/// Device and metadata of a mounted file system, shared by its inodes
+struct Volume {
+    device: Arc<dyn BlockDevice + Send + Sync>,
+    sb: superblock::SuperBlock,
+    groups: Vec<BlockGroup>,
+    /// Block size in bytes
+    block_size: u64,
+    /// Size of an inode table entry in bytes
+    inode_size: u64,
+}
+
+impl Volume {
+    fn open(device: Arc<dyn BlockDevice + Send + Sync>) -> FSResult<Self> {
+        let mut bytes = [0; SUPERBLOCK_SIZE];
+        read_bytes(&*device, SUPERBLOCK_OFFSET, &mut bytes, &mut Vec::new())?;
+        let sb = superblock::SuperBlock::parse(&bytes)?;
+
+        // Only directory entry types are understood, anything else changes the on-disk format
+        if sb.required_features.bits() & !RequiredFeatures::DIRECTORY_TYPE_FIELD.bits() != 0 {
+            return Err(FSError::NotSupported);
+        }
+
+        let inode_size = if sb.major_version >= 1 {
+            u64::from(sb.inode_size)
+        } else {
+            INODE_SIZE as u64
+        };
+        if inode_size < INODE_SIZE as u64 || sb.inodes_per_group == 0 {
+            return Err(FSError::InvalidData);
+        }
+
+        // The block group descriptor table starts in the block after the superblock
+        let block_size = u64::from(sb.block_size_bytes());
+        let count = sb.block_group_count();
+        let mut table = vec![0; count as usize * block_group::BLOCK_GROUP_SIZE];
+        read_bytes(
+            &*device,
+            (u64::from(sb.superblock_block_number) + 1) * block_size,
+            &mut table,
+            &mut Vec::new(),
+        )?;
+        let groups = parse_table(&table, count)?;
+
+        Ok(Self {
+            device,
+            sb,
+            groups,
+            block_size,
+            inode_size,
+        })
+    }
+
+    /// Reads the on-disk inode `num`, `None` if it's out of range
+    fn read_inode(&self, num: u64) -> FSResult<Option<inode::Inode>> {
+        if num == 0 || num > u64::from(self.sb.inode_count) {
+            return Ok(None);
+        }
+        let per_group = u64::from(self.sb.inodes_per_group);
+        let group = self
+            .groups
+            .get(((num - 1) / per_group) as usize)
+            .ok_or(FSError::InvalidData)?;
+        let offset = u64::from(group.inode_table_block) * self.block_size
+            + (num - 1) % per_group * self.inode_size;
+
+        let mut bytes = [0; INODE_SIZE];
+        read_bytes(&*self.device, offset, &mut bytes, &mut Vec::new())?;
+        Ok(Some(inode::Inode::parse(&bytes)))
+    }
+
+    /// Reads a little-endian block number at `idx` in the indirect block `block`.
+    ///
+    /// `level` is the number of indirect blocks below `block`, each level keeps its last block
+    /// in `cache`.
+    fn indirect(&self, cache: &mut ReadCache, level: usize, block: u32, idx: u64) -> FSResult<u32> {
+        if block == 0 {
+            return Ok(0);
+        }
+        let ReadCache { indirect, scratch } = cache;
+        let (cached, bytes) = &mut indirect[level];
+        if *cached != block {
+            // Left empty if the read fails
+            *cached = 0;
+            bytes.resize(self.block_size as usize, 0);
+            read_bytes(
+                &*self.device,
+                u64::from(block) * self.block_size,
+                bytes,
+                scratch,
+            )?;
+            *cached = block;
+        }
+        let pos = idx as usize * 4;
+        Ok(u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()))
+    }
+
+    /// Maps block `idx` of `inode` to a block on the device, 0 for a hole
+    fn block_of(&self, cache: &mut ReadCache, inode: &inode::Inode, idx: u64) -> FSResult<u32> {
+        let ptrs = self.block_size / 4;
+
+        let mut idx = idx;
+        if let Some(&block) = inode.direct_block_pointers.get(idx as usize) {
+            return Ok(block);
+        }
+        idx -= inode.direct_block_pointers.len() as u64;
+
+        if idx < ptrs {
+            return self.indirect(cache, 0, inode.singly_indirect_block_pointer, idx);
+        }
+        idx -= ptrs;
+
+        if idx < ptrs * ptrs {
+            let block = self.indirect(cache, 1, inode.doubly_indirect_block_pointer, idx / ptrs)?;
+            return self.indirect(cache, 0, block, idx % ptrs);
+        }
+        idx -= ptrs * ptrs;
+
+        if idx < ptrs * ptrs * ptrs {
+            let block = self.indirect(
+                cache,
+                2,
+                inode.triply_indirect_block_pointer,
+                idx / (ptrs * ptrs),
+            )?;
+            let block = self.indirect(cache, 1, block, idx / ptrs % ptrs)?;
+            return self.indirect(cache, 0, block, idx % ptrs);
+        }
+        Err(FSError::InvalidData)
+    }
+
+    /// Reads the data of `inode` at `offset` into `buf`, without checking the file size
+    fn read_data(&self, inode: &inode::Inode, offset: u64, buf: &mut [u8]) -> FSResult<()> {
+        let mut cache = ReadCache::default();
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let blk_off = pos % self.block_size;
+            let n = ((self.block_size - blk_off) as usize).min(buf.len() - done);
+
+            let out = &mut buf[done..done + n];
+            match self.block_of(&mut cache, inode, pos / self.block_size)? {
+                // Holes read as zeros
+                0 => out.fill(0),
+                block => {
+                    read_bytes(
+                        &*self.device,
+                        u64::from(block) * self.block_size + blk_off,
+                        out,
+                        &mut cache.scratch,
+                    )?;
+                }
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn to_vfs(self: &Arc<Self>, num: u64, raw: inode::Inode) -> vfs::Inode {
+        let file_type = raw.tp_and_perm.file_type();
+        let mode = match file_type {
+            Type::FIFO => vfs::Mode::FIFO,
+            Type::CHARACTER_DEVICE => vfs::Mode::CHARACTER_DEVICE,
+            Type::DIRECTORY => vfs::Mode::DIRECTORY,
+            Type::BLOCK_DEVICE => vfs::Mode::BLOCK_DEVICE,
+            Type::SYMBOLIC_LINK => vfs::Mode::SYMBOLIC_LINK,
+            Type::SOCKET => vfs::Mode::SOCKET,
+            _ => vfs::Mode::REGULAR_FILE,
+        };
+
+        vfs::Inode {
+            mode,
+            // Both use the octal mode bits, up to the sticky bit
+            permission: vfs::Permission::from_bits_truncate(raw.tp_and_perm.permissions().bits()),
+            user_id: raw.user_id,
+            group_id: raw.group_id,
+            num,
+            size: raw.size(&self.sb.readonly_features),
+            nlink: raw.hard_link_count,
+            blocks: u64::from(raw.disk_sectors) * 512 / self.block_size,
+            entry_count: None,
+            last_access_time: u64::from(raw.last_access_time),
+            creation_time: u64::from(raw.creation_time),
+            last_modification_time: u64::from(raw.last_modification_time),
+            block_size: self.block_size,
+            ops: &InodeOps,
+            private: Box::new(InodeData {
+                volume: Arc::clone(self),
+                raw,
+            }),
+        }
+    }
+}
+
+/// Buffers reused across the device reads of one file read
+#[derive(Default)]
+struct ReadCache {
+    /// Last indirect block read at each level, by [`Volume::indirect`], and its number.
+    ///
+    /// Consecutive blocks of a file share their indirect blocks, so each is only read once.
+    indirect: [(u32, Vec<u8>); 3],
+    /// Device block holding a partially read range, see [`read_bytes`]
+    scratch: Vec<u8>,
+}
+
+/// Reads `buf.len()` bytes at byte `offset` of `device`.
+///
+/// Whole device blocks are read straight into `buf`, partial ones through `scratch`.
+fn read_bytes(
+    device: &dyn BlockDevice,
+    offset: u64,
+    buf: &mut [u8],
+    scratch: &mut Vec<u8>,
+) -> FSResult<()> {
+    let block_size = device.block_size();
+
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = offset + done as u64;
+        let idx = pos / block_size as u64;
+        let blk_off = (pos % block_size as u64) as usize;
+        let n = (block_size - blk_off).min(buf.len() - done);
+
+        let out = &mut buf[done..done + n];
+        if n == block_size {
+            device.read_block(idx, out)?;
+        } else {
+            scratch.resize(block_size, 0);
+            device.read_block(idx, scratch)?;
+            out.copy_from_slice(&scratch[blk_off..blk_off + n]);
+        }
+        done += n;
+    }
+    Ok(())
+}
+
+struct SuperBlock {
+    /// `None` until the file system is initialized
+    volume: Option<Arc<Volume>>,
+}
+
+impl vfs::SuperBlock for SuperBlock {
+    fn root(&self) -> FSResult<vfs::Inode> {
+        self.get_inode(ROOT_INODE)?.ok_or(FSError::MissingInode)
+    }
+
+    fn create_inode(&mut self) -> FSResult<vfs::Inode> {
+        Err(FSError::NotSupported)
+    }
+
+    fn get_inode(&self, inode_n: u64) -> FSResult<Option<vfs::Inode>> {
+        let volume = self.volume.as_ref().ok_or(FSError::NoMount)?;
+        Ok(volume
+            .read_inode(inode_n)?
+            .map(|raw| volume.to_vfs(inode_n, raw)))
+    }
+
+    fn destroy_inode(&mut self, _inode_n: u64) -> FSResult<()> {
+        Err(FSError::NotSupported)
+    }
+
+    fn write_inode(&mut self, _inode: &vfs::Inode) -> FSResult<()> {
+        Err(FSError::NotSupported)
+    }
+}
+
+/// Private data of a vfs inode
+struct InodeData {
+    volume: Arc<Volume>,
+    raw: inode::Inode,
+}
+
+pub struct InodeOps;
+
+impl InodeOps {
+    fn data(inode: &vfs::Inode) -> FSResult<&InodeData> {
+        inode.private.downcast_ref().ok_or(FSError::WrongInode)
+    }
+}
+
+impl vfs::InodeOps for InodeOps {
+    fn create(&self, _dst: &mut vfs::Inode, _parent: &DEntry, _path: Component) -> FSResult<()> {
+        Err(FSError::NotSupported)
+    }
+
+    fn link(&self, _src: &mut vfs::Inode, _parent: &DEntry, _path: Component) -> FSResult<()> {
+        Err(FSError::NotSupported)
+    }
+
+    fn symlink(
+        &self,
+        _dst: &mut vfs::Inode,
+        _src: &Path,
+        _parent: &DEntry,
+        _path: Component,
+    ) -> FSResult<()> {
+        Err(FSError::NotSupported)
+    }
+
+    fn unlink(&self, _dst: &mut vfs::Inode, _parent: &DEntry, _path: Component) -> FSResult<()> {
+        Err(FSError::NotSupported)
+    }
+
+    fn rename(
+        &self,
+        _src: &mut vfs::Inode,
+        _src_p: &DEntry,
+        _src_path: Component,
+        _dst_p: &DEntry,
+        _path: Component,
+        _dst: Option<&mut vfs::Inode>,
+    ) -> FSResult<()> {
+        Err(FSError::NotSupported)
+    }
+
+    fn read(&self, inode: &vfs::Inode, offset: u64, buf: &mut [u8]) -> FSResult<usize> {
+        if inode.is_dir() {
+            return Err(FSError::IsDirectory);
+        }
+
+        // Short read at the end of the file
+        let Some(remaining) = inode.size.checked_sub(offset) else {
+            return Ok(0);
+        };
+        let len = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+
+        let data = Self::data(inode)?;
+        data.volume.read_data(&data.raw, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write(&self, _inode: &mut vfs::Inode, _offset: u64, _buf: &[u8]) -> FSResult<usize> {
+        Err(FSError::NotSupported)
+    }
+
+    fn truncate(&self, _inode: &mut vfs::Inode, _size: u64) -> FSResult<()> {
+        Err(FSError::NotSupported)
+    }
+
+    fn readlink(&self, inode: &vfs::Inode) -> FSResult<PathBuf> {
+        if !inode.is_symlink() {
+            return Err(FSError::NotSupported);
+        }
+
+        let data = Self::data(inode)?;
+        let raw = &data.raw;
+        let mut target = vec![0; usize::try_from(inode.size).map_err(|_| FSError::InvalidData)?];
+        if inode.size < FAST_SYMLINK_SIZE && raw.disk_sectors == 0 {
+            // Stored in place of the block pointers
+            let pointers = raw
+                .direct_block_pointers
+                .iter()
+                .chain([
+                    &raw.singly_indirect_block_pointer,
+                    &raw.doubly_indirect_block_pointer,
+                    &raw.triply_indirect_block_pointer,
+                ])
+                .flat_map(|ptr| ptr.to_le_bytes());
+            for (byte, ptr_byte) in target.iter_mut().zip(pointers) {
+                *byte = ptr_byte;
+            }
+        } else {
+            data.volume.read_data(raw, 0, &mut target)?;
+        }
+        PathBuf::from_bytes(&target)
+    }
+
+    fn mkdir(&self, _dst: &mut vfs::Inode, _parent: &DEntry, _path: Component) -> FSResult<()> {
+        Err(FSError::NotSupported)
+    }
+
+    fn list<'b>(&self, inode: &'b vfs::Inode) -> FSResult<FileIter<'b>> {
+        if !inode.is_dir() {
+            return Err(FSError::NotDirectory);
+        }
+
+        let data = Self::data(inode)?;
+        let mut bytes = vec![0; usize::try_from(inode.size).map_err(|_| FSError::InvalidData)?];
+        data.volume.read_data(&data.raw, 0, &mut bytes)?;
+
+        let type_field = data
+            .volume
+            .sb
+            .required_features
+            .contains(RequiredFeatures::DIRECTORY_TYPE_FIELD);
+        let entries = parse_dir_entries(&bytes, type_field)?;
+        Ok(FileIter::new(
+            inode,
+            Box::new(DirIterator(entries.into_iter())),
+        ))
+    }
+}
+
+/// Parses the entries of a directory, leaving out `.` and `..` and unused entries
+///
+/// Without the directory type feature the name length is 16 bits, with it the high byte is
+/// the entry's type.
+fn parse_dir_entries(bytes: &[u8], type_field: bool) -> FSResult<Vec<(PathBuf, u64)>> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= bytes.len() {
+        let entry = &bytes[pos..];
+        let inode = u32::from_le_bytes(entry[..4].try_into().unwrap());
+        let rec_len = usize::from(u16::from_le_bytes([entry[4], entry[5]]));
+        let name_len = if type_field {
+            usize::from(entry[6])
+        } else {
+            usize::from(u16::from_le_bytes([entry[6], entry[7]]))
+        };
+        if rec_len < 8 || rec_len > entry.len() || 8 + name_len > rec_len {
+            return Err(FSError::InvalidData);
+        }
+
+        let name = &entry[8..8 + name_len];
+        if inode != 0 && name != b"." && name != b".." {
+            entries.push((PathBuf::from_bytes(name)?, u64::from(inode)));
+        }
+        pos += rec_len;
+    }
+    Ok(entries)
+}
+
+struct DirIterator(IntoIter<(PathBuf, u64)>);
+
+impl Iterator for DirIterator {
+    type Item = (PathBuf, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+impl FileIterator for DirIterator {}
+
+#[cfg(test)]
+mod tests {
+    use spin::Mutex;
+
+    use super::*;
+    use crate::fs::{
+        self,
+        block::RamDisk,
+        mount::{MountCtx, MountFlags},
+        vfs::{FileSystem as _, InodeOps as _, SuperBlock as _},
+        MOUNTS,
+    };
+
+    const BLOCK: usize = 1024;
+    /// Block index of `sparse` mapped by its doubly indirect block
+    const DOUBLY_START: u64 = 12 + BLOCK as u64 / 4;
+
+    /// Disk counting the reads of each block
+    struct Counting {
+        disk: RamDisk,
+        reads: Mutex<Vec<u64>>,
+    }
+
+    impl Counting {
+        fn reads_of(&self, idx: u64) -> usize {
+            self.reads
+                .lock()
+                .iter()
+                .filter(|&&read| read == idx)
+                .count()
+        }
+    }
+
+    impl BlockDevice for Counting {
+        fn block_size(&self) -> usize {
+            self.disk.block_size()
+        }
+
+        fn read_block(&self, idx: u64, buf: &mut [u8]) -> FSResult<()> {
+            self.reads.lock().push(idx);
+            self.disk.read_block(idx, buf)
+        }
+
+        fn write_block(&self, idx: u64, buf: &[u8]) -> FSResult<()> {
+            self.disk.write_block(idx, buf)
+        }
+    }
+
+    /// Single group image with 1 KiB blocks, and in its root directory:
+    /// - `hello`, inode 12, holding `hello\n`
+    /// - `sparse`, inode 13, with holes in its direct blocks and two blocks mapped by each of
+    ///   its singly and doubly indirect blocks
+    fn image() -> Vec<u8> {
+        let mut img = vec![0; 64 * BLOCK];
+        let mut put = |off: usize, bytes: &[u8]| img[off..off + bytes.len()].copy_from_slice(bytes);
+
+        // Superblock
+        let sb = BLOCK;
+        put(sb, &16_u32.to_le_bytes());
+        put(sb + 4, &64_u32.to_le_bytes());
+        put(sb + 20, &1_u32.to_le_bytes());
+        put(sb + 32, &8192_u32.to_le_bytes());
+        put(sb + 40, &16_u32.to_le_bytes());
+        put(sb + 56, &superblock::EXT2_MAGIC.to_le_bytes());
+        put(sb + 58, &1_u16.to_le_bytes());
+        put(sb + 60, &1_u16.to_le_bytes());
+        put(sb + 76, &1_u32.to_le_bytes());
+        put(sb + 88, &128_u16.to_le_bytes());
+        put(
+            sb + 96,
+            &RequiredFeatures::DIRECTORY_TYPE_FIELD.bits().to_le_bytes(),
+        );
+
+        // Group descriptor, the inode table is in blocks 4 and 5
+        put(2 * BLOCK + 8, &4_u32.to_le_bytes());
+
+        let mut inode = |num: usize, mode: u16, size: usize, pointers: &[(usize, u32)]| {
+            let off = 4 * BLOCK + (num - 1) * INODE_SIZE;
+            put(off, &mode.to_le_bytes());
+            put(off + 4, &u32::try_from(size).unwrap().to_le_bytes());
+            put(off + 26, &1_u16.to_le_bytes());
+            for &(idx, block) in pointers {
+                put(off + 40 + idx * 4, &block.to_le_bytes());
+            }
+        };
+        inode(2, 0x4000 | 0o755, BLOCK, &[(0, 6)]);
+        inode(12, 0x8000 | 0o644, 6, &[(0, 7)]);
+        // Pointers 12 and 13 are the singly and doubly indirect blocks
+        inode(
+            13,
+            0x8000 | 0o644,
+            (DOUBLY_START as usize + 2) * BLOCK,
+            &[(12, 8), (13, 11)],
+        );
+
+        // Root directory
+        let mut pos = 6 * BLOCK;
+        for (inode, rec_len, name) in [
+            (2_u32, 12_u16, &b"."[..]),
+            (2, 12, b".."),
+            (12, 16, b"hello"),
+            (13, 984, b"sparse"),
+        ] {
+            put(pos, &inode.to_le_bytes());
+            put(pos + 4, &rec_len.to_le_bytes());
+            put(pos + 6, &[name.len() as u8]);
+            put(pos + 8, name);
+            pos += usize::from(rec_len);
+        }
+
+        put(7 * BLOCK, b"hello\n");
+
+        // Singly indirect block 8, doubly indirect block 11 pointing to block 12
+        put(8 * BLOCK, &9_u32.to_le_bytes());
+        put(8 * BLOCK + 4, &10_u32.to_le_bytes());
+        put(11 * BLOCK, &12_u32.to_le_bytes());
+        put(12 * BLOCK, &13_u32.to_le_bytes());
+        put(12 * BLOCK + 4, &14_u32.to_le_bytes());
+        for (block, byte) in [(9, 0xA1), (10, 0xA2), (13, 0xB1), (14, 0xB2)] {
+            put(block * BLOCK, &[byte; BLOCK]);
+        }
+        img
+    }
+
+    fn mount(device: Arc<dyn BlockDevice + Send + Sync>) -> SuperBlock {
+        let mut fs = FileSystem::new();
+        fs.init_super(Some(device)).unwrap();
+        let volume = fs.superblock.read().volume.clone();
+        SuperBlock { volume }
+    }
+
+    #[test]
+    fn lists_and_reads_the_root_directory() {
+        let sb = mount(Arc::new(RamDisk::from_bytes(512, image())));
+
+        let root = sb.root().unwrap();
+        assert!(root.is_dir());
+        let mut names: Vec<_> = InodeOps.list(&root).unwrap().collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                (PathBuf::from_bytes(b"hello").unwrap(), 12),
+                (PathBuf::from_bytes(b"sparse").unwrap(), 13),
+            ]
+        );
+
+        let hello = sb.get_inode(12).unwrap().unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(InodeOps.read(&hello, 0, &mut buf).unwrap(), 6);
+        assert_eq!(&buf[..6], b"hello\n");
+        assert_eq!(InodeOps.read(&hello, 6, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn reads_each_indirect_block_once() {
+        let device = Arc::new(Counting {
+            disk: RamDisk::from_bytes(BLOCK, image()),
+            reads: Mutex::new(Vec::new()),
+        });
+        let sb = mount(device.clone());
+        let sparse = sb.get_inode(13).unwrap().unwrap();
+
+        let mut data = vec![0xFF; usize::try_from(sparse.size).unwrap()];
+        assert_eq!(InodeOps.read(&sparse, 0, &mut data).unwrap(), data.len());
+
+        let blocks: Vec<_> = data.chunks(BLOCK).map(|block| block[0]).collect();
+        assert!(blocks[..12].iter().all(|&byte| byte == 0));
+        assert_eq!(blocks[12..14], [0xA1, 0xA2]);
+        assert!(blocks[14..DOUBLY_START as usize]
+            .iter()
+            .all(|&byte| byte == 0));
+        assert_eq!(blocks[DOUBLY_START as usize..], [0xB1, 0xB2]);
+        for block in [8, 11, 12] {
+            assert_eq!(device.reads_of(block), 1, "indirect block {block}");
+        }
+    }
+
+    #[test]
+    fn reads_across_a_block_boundary() {
+        let sb = mount(Arc::new(RamDisk::from_bytes(BLOCK, image())));
+        let sparse = sb.get_inode(13).unwrap().unwrap();
+
+        let mut buf = [0; 6];
+        let offset = 13 * BLOCK as u64 - 3;
+        assert_eq!(InodeOps.read(&sparse, offset, &mut buf).unwrap(), 6);
+        assert_eq!(buf, [0xA1, 0xA1, 0xA1, 0xA2, 0xA2, 0xA2]);
+    }
+
+    #[test]
+    fn mounts_over_a_ram_disk() {
+        let _guard = fs::tests::setup();
+        fs::mkdir("/ext2").unwrap();
+        let ctx = |device: Option<Arc<dyn BlockDevice + Send + Sync>>| MountCtx {
+            fs: Box::new(FileSystem::new()),
+            dest: Some(fs::lookup("/ext2").unwrap()),
+            source: None,
+            device,
+            flags: MountFlags::READ_ONLY,
+        };
+
+        assert_eq!(
+            MOUNTS.mount_fs(ctx(None)).unwrap_err().kind,
+            FSError::NoDevice
+        );
+        let blank = Arc::new(RamDisk::new(BLOCK, 64));
+        assert_eq!(
+            MOUNTS.mount_fs(ctx(Some(blank))).unwrap_err().kind,
+            FSError::InvalidData
+        );
+        assert!(!MOUNTS.is_mount_path(Path::new("/ext2")));
+
+        let disk = Arc::new(RamDisk::from_bytes(BLOCK, image()));
+        MOUNTS.mount_fs(ctx(Some(disk))).unwrap();
+        assert_eq!(fs::read("/ext2/hello").unwrap(), b"hello\n");
+        MOUNTS.unmount("/ext2").unwrap();
+        assert_eq!(
+            fs::lookup("/ext2/hello").unwrap_err().kind,
+            FSError::NoEntry
+        );
+    }
+}
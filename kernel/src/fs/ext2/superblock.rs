@@ -1,6 +1,15 @@
 use bitflags::bitflags;
 use static_assertions::assert_eq_size;
 
+use super::Ext2Error;
+
+/// Size of the superblock on disk
+pub const SUPERBLOCK_SIZE: usize = 1024;
+/// Value of [`SuperBlock::magic`]
+pub const EXT2_MAGIC: u16 = 0xEF53;
+/// Largest supported [`SuperBlock::block_size`], 64 KiB blocks
+const MAX_LOG_BLOCK_SIZE: u32 = 6;
+
 pub struct SuperBlock {
     pub inode_count: u32,
     pub block_count: u32,
@@ -49,7 +58,110 @@ pub struct SuperBlock {
     _unused2: [u8; 788],
 }
 
-assert_eq_size!(SuperBlock, [u8; 1024]);
+assert_eq_size!(SuperBlock, [u8; SUPERBLOCK_SIZE]);
+
+impl SuperBlock {
+    /// Parses a superblock from its little-endian on-disk form.
+    ///
+    /// The extended fields are only read if `major_version >= 1`, and are zeroed otherwise.
+    pub fn parse(bytes: &[u8]) -> Result<Self, Ext2Error> {
+        let bytes: &[u8; SUPERBLOCK_SIZE] = bytes
+            .get(..SUPERBLOCK_SIZE)
+            .and_then(|b| b.try_into().ok())
+            .ok_or(Ext2Error::TooShort)?;
+        let u16_at = |off: usize| u16::from_le_bytes([bytes[off], bytes[off + 1]]);
+        let u32_at = |off: usize| u32::from_le_bytes(bytes[off..off + 4].try_into().unwrap());
+        let u128_at = |off: usize| u128::from_le_bytes(bytes[off..off + 16].try_into().unwrap());
+
+        if u16_at(56) != EXT2_MAGIC {
+            return Err(Ext2Error::BadMagic);
+        }
+
+        let mut sb = Self {
+            inode_count: u32_at(0),
+            block_count: u32_at(4),
+            reserved_block_count: u32_at(8),
+            unallocated_block_count: u32_at(12),
+            unallocated_inode_count: u32_at(16),
+            superblock_block_number: u32_at(20),
+            block_size: u32_at(24),
+            fragment_size: u32_at(28),
+            blocks_per_group: u32_at(32),
+            fragments_per_group: u32_at(36),
+            inodes_per_group: u32_at(40),
+            last_mount_time: u32_at(44),
+            last_write_time: u32_at(48),
+            mount_count: u16_at(52),
+            max_mount_count: u16_at(54),
+            magic: u16_at(56),
+            state: FileSystemState::from_raw(u16_at(58))?,
+            errors: ErrorHandlingMethod::from_raw(u16_at(60))?,
+            minor_version: u16_at(62),
+            last_check_time: u32_at(64),
+            check_interval: u32_at(68),
+            creator_os: u32_at(72),
+            major_version: u32_at(76),
+            reserved_blocks_uid: u16_at(80),
+            reserved_blocks_gid: u16_at(82),
+
+            first_non_reserved_inode: 0,
+            inode_size: 0,
+            block_group_number: 0,
+            optional_features: OptFeatures::empty(),
+            required_features: RequiredFeatures::empty(),
+            readonly_features: ReadOnlyFeatures::empty(),
+            filesystem_id: 0,
+            volume_name: [0; 16],
+            path_to_last_mounted: [0; 64],
+            compression_algorithms: 0,
+            block_preallocations_for_files: 0,
+            block_preallocations_for_directories: 0,
+            _unused: 0,
+            journal_id: 0,
+            journal_inode: 0,
+            journal_device: 0,
+            orphan_inode_list_head: 0,
+            _unused2: [0; 788],
+        };
+        if sb.block_size > MAX_LOG_BLOCK_SIZE {
+            return Err(Ext2Error::InvalidField("block_size"));
+        }
+        if sb.blocks_per_group == 0 {
+            return Err(Ext2Error::InvalidField("blocks_per_group"));
+        }
+
+        if sb.major_version >= 1 {
+            sb.first_non_reserved_inode = u32_at(84);
+            sb.inode_size = u16_at(88);
+            sb.block_group_number = u16_at(90);
+            sb.optional_features = OptFeatures::from_bits_retain(u32_at(92));
+            sb.required_features = RequiredFeatures::from_bits_retain(u32_at(96));
+            sb.readonly_features = ReadOnlyFeatures::from_bits_retain(u32_at(100));
+            sb.filesystem_id = u128_at(104);
+            sb.volume_name.copy_from_slice(&bytes[120..136]);
+            sb.path_to_last_mounted.copy_from_slice(&bytes[136..200]);
+            sb.compression_algorithms = u32_at(200);
+            sb.block_preallocations_for_files = bytes[204];
+            sb.block_preallocations_for_directories = bytes[205];
+            sb.journal_id = u128_at(208);
+            sb.journal_inode = u32_at(224);
+            sb.journal_device = u32_at(228);
+            sb.orphan_inode_list_head = u32_at(232);
+        }
+
+        Ok(sb)
+    }
+
+    /// Size of a block in bytes
+    pub const fn block_size_bytes(&self) -> u32 {
+        1024 << self.block_size
+    }
+
+    /// Number of block groups, and of descriptors in the block group descriptor table
+    pub const fn block_group_count(&self) -> u32 {
+        self.block_count.div_ceil(self.blocks_per_group)
+    }
+}
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[repr(u16)]
@@ -58,6 +170,16 @@ pub enum FileSystemState {
     Error = 2,
 }
 
+impl FileSystemState {
+    const fn from_raw(raw: u16) -> Result<Self, Ext2Error> {
+        match raw {
+            1 => Ok(Self::Clean),
+            2 => Ok(Self::Error),
+            _ => Err(Ext2Error::InvalidField("state")),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[repr(u16)]
 pub enum ErrorHandlingMethod {
@@ -66,6 +188,17 @@ pub enum ErrorHandlingMethod {
     KernelPanic = 3,
 }
 
+impl ErrorHandlingMethod {
+    const fn from_raw(raw: u16) -> Result<Self, Ext2Error> {
+        match raw {
+            1 => Ok(Self::Ignore),
+            2 => Ok(Self::RemountAsReadOnly),
+            3 => Ok(Self::KernelPanic),
+            _ => Err(Ext2Error::InvalidField("errors")),
+        }
+    }
+}
+
 bitflags! {
     pub struct OptFeatures: u32 {
         /// Preallocate some number of blocks for files
@@ -106,3 +239,53 @@ bitflags! {
         const HAS_BINARY_TREES = 0x0004;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Superblock of revision `major`, with 4 KiB blocks in groups of 32 and 256 byte inodes
+    fn superblock(major: u32) -> [u8; SUPERBLOCK_SIZE] {
+        let mut bytes = [0; SUPERBLOCK_SIZE];
+        bytes[4..8].copy_from_slice(&100_u32.to_le_bytes());
+        bytes[24..28].copy_from_slice(&2_u32.to_le_bytes());
+        bytes[32..36].copy_from_slice(&32_u32.to_le_bytes());
+        bytes[56..58].copy_from_slice(&EXT2_MAGIC.to_le_bytes());
+        bytes[58..60].copy_from_slice(&1_u16.to_le_bytes());
+        bytes[60..62].copy_from_slice(&1_u16.to_le_bytes());
+        bytes[76..80].copy_from_slice(&major.to_le_bytes());
+        bytes[88..90].copy_from_slice(&256_u16.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn parses_the_geometry() {
+        let sb = SuperBlock::parse(&superblock(1)).unwrap();
+        assert_eq!(sb.block_size_bytes(), 4096);
+        assert_eq!(sb.block_group_count(), 4);
+        assert_eq!(sb.state, FileSystemState::Clean);
+        assert_eq!(sb.inode_size, 256);
+    }
+
+    #[test]
+    fn revision_0_has_no_extended_fields() {
+        let sb = SuperBlock::parse(&superblock(0)).unwrap();
+        assert_eq!(sb.major_version, 0);
+        assert_eq!(sb.inode_size, 0);
+    }
+
+    #[test]
+    fn rejects_bad_superblocks() {
+        let mut bytes = superblock(1);
+        assert!(matches!(
+            SuperBlock::parse(&bytes[..SUPERBLOCK_SIZE - 1]),
+            Err(Ext2Error::TooShort)
+        ));
+
+        bytes[56] = 0;
+        assert!(matches!(
+            SuperBlock::parse(&bytes),
+            Err(Ext2Error::BadMagic)
+        ));
+    }
+}
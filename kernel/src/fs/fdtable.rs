@@ -0,0 +1,157 @@
+use alloc::{sync::Arc, vec::Vec};
+
+use spin::Mutex;
+
+use crate::fs::{
+    file::File,
+    vfs::{FSError, FSResult},
+};
+
+/// Maximum number of open descriptors in a table
+pub const MAX_FDS: usize = 256;
+
+pub type Fd = usize;
+
+/// Open file shared by every descriptor duplicated from the same open
+pub type SharedFile = Arc<Mutex<File>>;
+
+/// Maps integer file descriptors to open files
+///
+/// New descriptors always get the lowest free number.
+#[derive(Debug, Default)]
+pub struct FdTable {
+    files: Vec<Option<SharedFile>>,
+}
+
+impl FdTable {
+    pub const fn new() -> Self {
+        Self { files: Vec::new() }
+    }
+
+    /// Adds `file` to the table, returning its descriptor
+    pub fn open(&mut self, file: File) -> FSResult<Fd> {
+        self.insert(Arc::new(Mutex::new(file)))
+    }
+
+    pub fn get(&self, fd: Fd) -> FSResult<&SharedFile> {
+        self.files
+            .get(fd)
+            .and_then(Option::as_ref)
+            .ok_or(FSError::BadDescriptor)
+    }
+
+    /// Closes `fd`
+    ///
+    /// The file itself is only closed, and its error reported, once no other descriptor refers
+    /// to it.
+    pub fn close(&mut self, fd: Fd) -> FSResult<()> {
+        let file = self
+            .files
+            .get_mut(fd)
+            .and_then(Option::take)
+            .ok_or(FSError::BadDescriptor)?;
+
+        // Drop trailing free slots
+        while matches!(self.files.last(), Some(None)) {
+            self.files.pop();
+        }
+
+        Arc::try_unwrap(file).map_or(Ok(()), |file| file.into_inner().close())
+    }
+
+    /// Duplicates `fd` onto the lowest free descriptor
+    pub fn dup(&mut self, fd: Fd) -> FSResult<Fd> {
+        let file = Arc::clone(self.get(fd)?);
+        self.insert(file)
+    }
+
+    /// Duplicates `fd` onto `newfd`, closing whatever `newfd` referred to
+    pub fn dup2(&mut self, fd: Fd, newfd: Fd) -> FSResult<Fd> {
+        let file = Arc::clone(self.get(fd)?);
+        if fd == newfd {
+            return Ok(newfd);
+        }
+        if newfd >= MAX_FDS {
+            return Err(FSError::BadDescriptor);
+        }
+
+        if newfd >= self.files.len() {
+            self.files.resize(newfd + 1, None);
+        }
+        let old = self.files[newfd].replace(file);
+
+        // Errors closing the replaced file are ignored, like POSIX `dup2`
+        if let Some(old) = old.and_then(|old| Arc::try_unwrap(old).ok()) {
+            let _ = old.into_inner().close();
+        }
+        Ok(newfd)
+    }
+
+    fn insert(&mut self, file: SharedFile) -> FSResult<Fd> {
+        if let Some(fd) = self.files.iter().position(Option::is_none) {
+            self.files[fd] = Some(file);
+            return Ok(fd);
+        }
+
+        if self.files.len() >= MAX_FDS {
+            return Err(FSError::TooManyFiles);
+        }
+        self.files.push(Some(file));
+        Ok(self.files.len() - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{self, file::OpenFlags, tests::setup};
+
+    fn open(table: &mut FdTable, path: &str) -> Fd {
+        let file = fs::open(path, OpenFlags::READ | OpenFlags::WRITE).unwrap();
+        table.open(file).unwrap()
+    }
+
+    #[test]
+    fn lowest_free_fd() {
+        let _guard = setup();
+        fs::mkdir("/fdtable_lowest").unwrap();
+        fs::write("/fdtable_lowest/file", b"").unwrap();
+        let path = "/fdtable_lowest/file";
+
+        let mut table = FdTable::new();
+        let fds: Vec<_> = (0..3).map(|_| open(&mut table, path)).collect();
+        assert_eq!(fds, [0, 1, 2]);
+
+        table.close(1).unwrap();
+        assert_eq!(table.get(1).unwrap_err(), FSError::BadDescriptor);
+        assert_eq!(table.close(1).unwrap_err(), FSError::BadDescriptor);
+        assert_eq!(open(&mut table, path), 1);
+
+        table.close(2).unwrap();
+        table.close(0).unwrap();
+        assert_eq!(open(&mut table, path), 0);
+        assert_eq!(open(&mut table, path), 2);
+    }
+
+    #[test]
+    fn dup_shares_the_file() {
+        let _guard = setup();
+        fs::mkdir("/fdtable_dup").unwrap();
+        fs::write("/fdtable_dup/file", b"").unwrap();
+
+        let mut table = FdTable::new();
+        let fd = open(&mut table, "/fdtable_dup/file");
+        let dup = table.dup(fd).unwrap();
+        assert_eq!(dup, 1);
+        assert!(Arc::ptr_eq(table.get(fd).unwrap(), table.get(dup).unwrap()));
+
+        // The cursor is shared too
+        table.get(fd).unwrap().lock().write(b"abc").unwrap();
+        assert_eq!(table.get(dup).unwrap().lock().position(), 3);
+
+        table.close(fd).unwrap();
+        table.get(dup).unwrap().lock().write(b"def").unwrap();
+        table.close(dup).unwrap();
+        assert_eq!(fs::read("/fdtable_dup/file").unwrap(), b"abcdef");
+    }
+}
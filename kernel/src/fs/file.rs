@@ -0,0 +1,170 @@
+use bitflags::bitflags;
+
+use crate::{
+    fs::{
+        dentry::DEntry,
+        icache::{InodeId, INODE_CACHE},
+        vfs::{FSError, FSResult},
+        MOUNTS,
+    },
+    kprintln,
+};
+
+bitflags! {
+    /// Access requested when opening a [`File`]
+    #[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+    pub struct OpenFlags: u8 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        /// Every write lands at the end of the file, regardless of the cursor
+        const APPEND = 1 << 2;
+    }
+}
+
+/// Position to [`seek`](File::seek) to
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SeekFrom {
+    /// Offset from the start of the file
+    Start(u64),
+    /// Offset from the end of the file
+    End(i64),
+    /// Offset from the cursor
+    Current(i64),
+}
+
+/// An open file
+///
+/// Writes update the cached inode and mark the dentry dirty, the inode is only committed to the
+/// file system on [`flush`](Self::flush), [`close`](Self::close), or drop.
+///
+/// The file system can't be unmounted while the file is open, and the inode isn't destroyed
+/// before the file is closed, even if its last link is removed.
+#[derive(Debug)]
+pub struct File {
+    dentry: DEntry,
+    pos: u64,
+    flags: OpenFlags,
+}
+
+impl File {
+    pub fn new(dentry: DEntry, flags: OpenFlags) -> Self {
+        MOUNTS.open_file(&*dentry.fs());
+        INODE_CACHE.open(InodeId::new(&*dentry.fs(), dentry.inode().num));
+        Self {
+            dentry,
+            pos: 0,
+            flags,
+        }
+    }
+
+    pub const fn dentry(&self) -> &DEntry {
+        &self.dentry
+    }
+
+    pub const fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    /// Current position of the cursor
+    pub const fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Reads into `buf` at the cursor, returning the number of bytes read
+    ///
+    /// Fails with [`FSError::BadDescriptor`] if the file wasn't opened for reading.
+    pub fn read(&mut self, buf: &mut [u8]) -> FSResult<usize> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(FSError::BadDescriptor);
+        }
+        let n = self.dentry.inode().read(self.pos, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    /// Writes `buf` at the cursor, or at the end of the file in append mode, returning the
+    /// number of bytes written
+    ///
+    /// Fails with [`FSError::BadDescriptor`] if the file wasn't opened for writing.
+    pub fn write(&mut self, buf: &[u8]) -> FSResult<usize> {
+        if !self.flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND) {
+            return Err(FSError::BadDescriptor);
+        }
+        let n = {
+            let mut inode = self.dentry.inode_mut();
+            if self.flags.contains(OpenFlags::APPEND) {
+                self.pos = inode.size;
+            }
+            inode.write(self.pos, buf)?
+        };
+        self.dentry.mark_dirty();
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    /// Truncates or extends the file to `len` bytes and commits the inode
+    ///
+    /// A cursor past the new end is moved back to it. Fails with [`FSError::BadDescriptor`] if
+    /// the file wasn't opened for writing.
+    pub fn set_len(&mut self, len: u64) -> FSResult<()> {
+        if !self.flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND) {
+            return Err(FSError::BadDescriptor);
+        }
+        self.dentry.inode_mut().truncate(len)?;
+        self.dentry.mark_dirty();
+        self.pos = self.pos.min(len);
+        self.dentry.sync()
+    }
+
+    /// Moves the cursor, returning its new position
+    ///
+    /// The cursor may be moved past the end of the file, but seeking before the start fails
+    /// with [`FSError::InvalidArgument`].
+    pub fn seek(&mut self, pos: SeekFrom) -> FSResult<u64> {
+        let new = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.dentry.inode().size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = new.ok_or(FSError::InvalidArgument)?;
+        Ok(self.pos)
+    }
+
+    /// Commits any pending inode changes to the file system
+    #[allow(clippy::needless_pass_by_ref_mut)]
+    pub fn flush(&mut self) -> FSResult<()> {
+        self.dentry.sync()
+    }
+
+    /// Flushes and closes the file
+    ///
+    /// Unlike dropping the file, this reports errors from the final flush.
+    pub fn close(mut self) -> FSResult<()> {
+        self.flush()
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            kprintln!(
+                "WARNING: failed to flush {} on drop: {e}",
+                &*self.dentry.name()
+            );
+        }
+
+        // Destroy the inode if it was unlinked while open
+        let fs = self.dentry.fs_arc();
+        let num = self.dentry.inode().num;
+        if INODE_CACHE.close(InodeId::new(&*fs, num)) {
+            let res = fs.superblock().write().destroy_inode(num);
+            if let Err(e) = res {
+                kprintln!(
+                    "WARNING: failed to destroy unlinked {} on close: {e}",
+                    &*self.dentry.name()
+                );
+            }
+        }
+        MOUNTS.close_file(&*fs);
+    }
+}
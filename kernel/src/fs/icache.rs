@@ -0,0 +1,227 @@
+use alloc::sync::{Arc, Weak};
+
+use hashbrown::HashMap;
+use spin::{lock_api::RwLock, Lazy, Mutex};
+
+use crate::fs::vfs::{FileSystem, Inode};
+
+pub static INODE_CACHE: Lazy<InodeCache> = Lazy::new(InodeCache::new);
+
+/// Inode shared by every dentry linking to it
+pub type SharedInode = Arc<RwLock<Inode>>;
+
+/// Identifies an inode across all mounted file systems
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct InodeId {
+    /// Address of the file system the inode belongs to
+    fs: usize,
+    /// Inode number within the file system
+    num: u64,
+}
+
+impl InodeId {
+    pub fn new(fs: &dyn FileSystem, num: u64) -> Self {
+        Self {
+            fs: fs_key(fs),
+            num,
+        }
+    }
+}
+
+/// Cache of the live inodes, so all hard links to an inode share the same state.
+///
+/// Only weak references are kept, an inode is dropped from memory once no dentry references it.
+///
+/// The cache also counts the open files of each inode, so an inode that lost its last link is
+/// only destroyed once its last file is closed. Both are decided under the cache lock, so
+/// exactly one of [`release_link`](Self::release_link) and [`close`](Self::close) reports it.
+pub struct InodeCache {
+    inodes: Mutex<HashMap<InodeId, CacheEntry>>,
+}
+
+struct CacheEntry {
+    inode: Weak<RwLock<Inode>>,
+    /// Number of open files
+    open: usize,
+    /// Whether the last link is gone, and the inode is waiting for its files to be closed
+    orphaned: bool,
+}
+
+impl InodeCache {
+    pub fn new() -> Self {
+        Self {
+            inodes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the cached inode `id` if it's still alive
+    pub fn get(&self, id: InodeId) -> Option<SharedInode> {
+        self.inodes
+            .lock()
+            .get(&id)
+            .and_then(|entry| entry.inode.upgrade())
+    }
+
+    /// Returns the cached inode `id`, caching `inode` if there is none.
+    ///
+    /// A cached inode takes precedence over `inode`, since it may have uncommitted changes.
+    pub fn get_or_insert(&self, id: InodeId, inode: Inode) -> SharedInode {
+        let mut inodes = self.inodes.lock();
+        if let Some(shared) = inodes.get(&id).and_then(|entry| entry.inode.upgrade()) {
+            return shared;
+        }
+
+        // Drop the entries of inodes that are no longer referenced
+        inodes.retain(|_, entry| entry.inode.strong_count() > 0);
+
+        let shared = Arc::new(RwLock::new(inode));
+        inodes.insert(
+            id,
+            CacheEntry {
+                inode: Arc::downgrade(&shared),
+                open: 0,
+                orphaned: false,
+            },
+        );
+        shared
+    }
+
+    /// Counts a file opened on the cached inode `id`
+    pub fn open(&self, id: InodeId) {
+        if let Some(entry) = self.inodes.lock().get_mut(&id) {
+            entry.open += 1;
+        }
+    }
+
+    /// Releases a file counted by [`open`](Self::open).
+    ///
+    /// Returns whether it was the last file of an inode without links, which the caller must
+    /// then destroy.
+    pub fn close(&self, id: InodeId) -> bool {
+        let mut inodes = self.inodes.lock();
+        let Some(entry) = inodes.get_mut(&id) else {
+            return false;
+        };
+        entry.open = entry.open.saturating_sub(1);
+        if entry.open != 0 || !entry.orphaned {
+            return false;
+        }
+        inodes.remove(&id);
+        true
+    }
+
+    /// Records that inode `id` now has `nlink` links, after one was removed.
+    ///
+    /// Returns whether the inode must be destroyed by the caller, which is when it has no links
+    /// and no open files. With open files, the last [`close`](Self::close) reports it instead.
+    pub fn release_link(&self, id: InodeId, nlink: u16) -> bool {
+        if nlink != 0 {
+            return false;
+        }
+
+        let mut inodes = self.inodes.lock();
+        match inodes.get_mut(&id) {
+            Some(entry) if entry.open != 0 => {
+                entry.orphaned = true;
+                false
+            }
+            _ => {
+                inodes.remove(&id);
+                true
+            }
+        }
+    }
+
+    /// Forgets inode `id`, for when it's destroyed and its number may be reused.
+    ///
+    /// Dentries still referencing the inode keep their copy.
+    pub fn evict(&self, id: InodeId) {
+        self.inodes.lock().remove(&id);
+    }
+
+    /// Forgets every inode of `fs`
+    pub fn unmount(&self, fs: &dyn FileSystem) {
+        let fs = fs_key(fs);
+        self.inodes.lock().retain(|id, _| id.fs != fs);
+    }
+}
+
+fn fs_key(fs: &dyn FileSystem) -> usize {
+    core::ptr::from_ref(fs).cast::<()>() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+
+    use crate::fs::{self, file::OpenFlags, tests::setup};
+
+    #[test]
+    fn hard_links_share_the_cached_inode() {
+        let _guard = setup();
+        fs::mkdir("/icache").unwrap();
+        fs::write("/icache/a", b"old").unwrap();
+        fs::link("/icache/a", "/icache/b").unwrap();
+
+        // Still open, so the write is only in the cached inode
+        let mut file = fs::open("/icache/a", OpenFlags::WRITE).unwrap();
+        file.write(b"new data").unwrap();
+        assert_eq!(fs::read("/icache/b").unwrap(), b"new data");
+        assert_eq!(fs::stat("/icache/b").unwrap().size, 8);
+
+        file.close().unwrap();
+        assert_eq!(fs::read("/icache/b").unwrap(), b"new data");
+    }
+
+    #[test]
+    fn inode_is_destroyed_after_the_last_link_and_file() {
+        let _guard = setup();
+        fs::mkdir("/icache_links").unwrap();
+        let sb = fs::lookup("/").unwrap().fs_arc().superblock();
+        let orders = [
+            [0, 1, 2, 3],
+            [3, 2, 1, 0],
+            [1, 3, 0, 2],
+            [2, 0, 3, 1],
+            [0, 2, 1, 3],
+        ];
+
+        for (i, order) in orders.into_iter().enumerate() {
+            let dir = format!("/icache_links/{i}");
+            let names = [0, 1, 2, 3].map(|n| format!("{dir}/{n}"));
+            fs::mkdir(dir.as_str()).unwrap();
+            fs::write(names[0].as_str(), b"data").unwrap();
+            for name in &names[1..] {
+                fs::link(names[0].as_str(), name.as_str()).unwrap();
+            }
+            let num = fs::stat(names[0].as_str()).unwrap().inode;
+
+            // Closed before, between or after the unlinks
+            let mut file = Some(fs::open(names[order[0]].as_str(), OpenFlags::READ).unwrap());
+            for (unlinked, &n) in order.iter().enumerate() {
+                if unlinked == i {
+                    drop(file.take());
+                }
+                // Ramfs never reuses inode numbers, so destroying the inode twice would fail here
+                // or on close with `MissingInode`
+                assert!(sb.read().get_inode(num).unwrap().is_some());
+                fs::unlink(names[n].as_str()).unwrap();
+
+                let left = 3 - unlinked;
+                if let Some(&next) = order.get(unlinked + 1) {
+                    assert_eq!(fs::stat(names[next].as_str()).unwrap().nlink as usize, left);
+                }
+            }
+
+            if let Some(mut file) = file {
+                // Unlinked but still open, the data is kept until the file is closed
+                assert!(sb.read().get_inode(num).unwrap().is_some());
+                let mut buf = [0; 4];
+                assert_eq!(file.read(&mut buf).unwrap(), 4);
+                assert_eq!(&buf, b"data");
+                file.close().unwrap();
+            }
+            assert!(sb.read().get_inode(num).unwrap().is_none());
+        }
+    }
+}
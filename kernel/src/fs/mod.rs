@@ -1,11 +1,27 @@
-use alloc::{sync::Arc, vec::Vec};
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 use spin::lock_api::RwLock;
 
-use crate::fs::{mount::MountType, path::PathBuf, vfs::FSResult};
+use crate::fs::{
+    dentry::DIR_CACHE,
+    file::{File, OpenFlags},
+    mount::{FsId, MountError, MountFlags, MountInfo, MountType},
+    path::{Component, Path, PathBuf},
+    vfs::{FSError, FSResult, FsErrorCtx},
+};
 
+pub mod block;
 pub mod dentry;
-// pub mod ext2;
+pub mod ext2;
+pub mod fdtable;
+pub mod file;
+pub mod icache;
 pub mod mount;
 pub mod path;
 pub mod ramfs;
@@ -13,54 +29,1252 @@ pub mod vfs;
 
 pub static MOUNTS: Mounts = Mounts::new();
 
+/// Maximum number of symbolic links followed while resolving a path
+const MAX_SYMLINKS: usize = 40;
+
+/// User id of the superuser, which bypasses permission checks
+pub const ROOT_UID: u16 = 0;
+
 pub struct Mounts {
     mounts: RwLock<Vec<Mount>>,
+    next_id: AtomicU64,
 }
 
 struct Mount {
+    id: FsId,
     fs: Arc<dyn vfs::FileSystem + Send + Sync>,
     dentry: dentry::DEntry,
+    source: Option<PathBuf>,
     tp: MountType,
+    flags: MountFlags,
+    /// Number of open files on the file system
+    open_files: AtomicUsize,
 }
 
 impl Mounts {
     pub const fn new() -> Self {
         Self {
             mounts: RwLock::new(Vec::new()),
+            next_id: AtomicU64::new(0),
         }
     }
 
-    pub fn mount_fs(&self, mut ctx: mount::MountCtx) -> FSResult<()> {
+    /// Initializes and mounts a file system.
+    ///
+    /// Nothing is added to the mount table if initialization fails, and the error names the
+    /// file system and source.
+    pub fn mount_fs(&self, mut ctx: mount::MountCtx) -> Result<(), MountError> {
+        let source = ctx.source.take();
+        let fs_name = ctx.fs.name().to_string();
+        let fail = |kind| MountError {
+            fs_name: fs_name.clone(),
+            source: source.clone(),
+            kind,
+        };
+
         let fs = match ctx.fs.mount_type() {
-            MountType::NoDevice => mount::mount_nodev(ctx.fs)?,
+            MountType::BlockDevice => {
+                let dev = ctx.device.take().ok_or(FSError::NoDevice).map_err(fail)?;
+                mount::mount_bdev(ctx.fs, dev).map_err(fail)?
+            }
+            MountType::NoDevice => mount::mount_nodev(ctx.fs).map_err(fail)?,
         };
 
-        let dentry = match ctx.dest.take() {
-            Some(dentry) => dentry,
-            None => dentry::DEntry::new(
-                PathBuf::from("/"),
-                fs.superblock().read().root()?,
-                Arc::clone(&fs),
-            ),
+        // The mount point gets its own dentry for the new file system's root
+        let path = match ctx.dest.take() {
+            Some(dest) if !dest.inode().is_dir() => return Err(fail(FSError::NotDirectory)),
+            Some(dest) => dest.name().to_path_buf(),
+            None => PathBuf::from("/"),
         };
+        let dentry = dentry::DEntry::new(
+            path,
+            fs.superblock().read().root().map_err(fail)?,
+            Arc::clone(&fs),
+        );
 
         // Add the mount to the mount table
         self.mounts.write().push(Mount {
+            id: FsId(self.next_id.fetch_add(1, Ordering::Relaxed)),
             tp: fs.mount_type(),
             fs: Arc::clone(&fs),
             dentry: dentry.clone(),
+            source,
+            flags: ctx.flags,
+            open_files: AtomicUsize::new(0),
         });
 
-        // Cache the root inode
+        // Cache the root inode, hiding whatever was cached below the mount point
+        let path = dentry.name().to_path_buf();
+        dentry::DIR_CACHE.delete_all(&path);
         dentry::DIR_CACHE.mount(dentry);
 
         Ok(())
     }
 
+    /// Unmounts the file system mounted at `path`.
+    ///
+    /// Fails with [`FSError::Busy`] if files are open on it or another file system is mounted
+    /// below it.
+    pub fn unmount<P: AsRef<Path>>(&self, path: P) -> FSResult<()> {
+        self._unmount(path.as_ref(), false)
+    }
+
+    /// Unmounts the file system mounted at `path`, even if it's busy.
+    ///
+    /// Open files keep referencing the file system until they're closed.
+    pub fn force_unmount<P: AsRef<Path>>(&self, path: P) -> FSResult<()> {
+        self._unmount(path.as_ref(), true)
+    }
+
+    fn _unmount(&self, path: &Path, force: bool) -> FSResult<()> {
+        let fs = {
+            let mut mounts = self.mounts.write();
+            let idx = mounts
+                .iter()
+                .position(|mount| &*mount.dentry.name() == path)
+                .ok_or(FSError::NoMount)?;
+
+            if !force {
+                let has_children = mounts.iter().any(|mount| {
+                    let name = mount.dentry.name();
+                    &*name != path && name.starts_with(path)
+                });
+                if has_children || mounts[idx].open_files.load(Ordering::Acquire) != 0 {
+                    return Err(FSError::Busy);
+                }
+            }
+
+            mounts.remove(idx).fs
+        };
+
+        // The mount table must be unlocked, the cache checks it for pinned entries
+        DIR_CACHE.unmount(&*fs);
+
+        Ok(())
+    }
+
+    /// Counts a file opened on `fs`, keeping it from being unmounted.
+    pub(super) fn open_file(&self, fs: &dyn vfs::FileSystem) {
+        if let Some(mount) = self.mounts.read().iter().find(|mount| mount.is(fs)) {
+            mount.open_files.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    /// Releases a file counted by [`open_file`](Self::open_file).
+    pub(super) fn close_file(&self, fs: &dyn vfs::FileSystem) {
+        if let Some(mount) = self.mounts.read().iter().find(|mount| mount.is(fs)) {
+            mount.open_files.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+
+    /// Returns the root dentry of the file system mounted at `path`
+    pub fn mount_root(&self, path: &Path) -> Option<dentry::DEntry> {
+        self.mounts
+            .read()
+            .iter()
+            .find(|mount| &*mount.dentry.name() == path)
+            .map(|mount| mount.dentry.clone())
+    }
+
     pub fn is_mount_path(&self, path: &path::Path) -> bool {
         self.mounts
             .read()
             .iter()
             .any(|mount| &*mount.dentry.name() == path)
     }
+
+    /// Returns a snapshot of the mount table.
+    pub fn list(&self) -> Vec<MountInfo> {
+        self.mounts
+            .read()
+            .iter()
+            .map(|mount| MountInfo {
+                id: mount.id,
+                path: mount.dentry.name().to_path_buf(),
+                source: mount.source.clone(),
+                fs_name: mount.fs.name().to_string(),
+                tp: mount.tp,
+                flags: mount.flags,
+            })
+            .collect()
+    }
+
+    /// Writes the mount table to `w`, one mount per line.
+    pub fn print<W: core::fmt::Write>(&self, w: &mut W) -> core::fmt::Result {
+        for info in self.list() {
+            writeln!(w, "{info}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Mount {
+    fn is(&self, fs: &dyn vfs::FileSystem) -> bool {
+        core::ptr::addr_eq(Arc::as_ptr(&self.fs), fs)
+    }
+}
+
+/// Looks up the directory entry at `path`.
+pub fn lookup<P: AsRef<Path>>(path: P) -> FSResult<dentry::DEntry, FsErrorCtx> {
+    let path = path.as_ref();
+    DIR_CACHE.get(path).map_err(|e| e.at(path))
+}
+
+/// Returns the metadata of the file at `path`, following symbolic links.
+///
+/// Same as [`stat`].
+pub fn metadata<P: AsRef<Path>>(path: P) -> FSResult<vfs::Metadata, FsErrorCtx> {
+    stat(path)
+}
+
+/// Returns the metadata of the file at `path`, following symbolic links to the final target.
+///
+/// A broken symbolic link fails with [`FSError::NoEntry`].
+pub fn stat<P: AsRef<Path>>(path: P) -> FSResult<vfs::Metadata, FsErrorCtx> {
+    follow(path.as_ref()).map(|dentry| dentry.metadata())
+}
+
+/// Returns the metadata of the file at `path` without following a final symbolic link.
+pub fn lstat<P: AsRef<Path>>(path: P) -> FSResult<vfs::Metadata, FsErrorCtx> {
+    lookup(path).map(|dentry| dentry.metadata())
+}
+
+/// Looks up `path`, following symbolic links until a non-link is reached.
+fn follow(path: &Path) -> FSResult<dentry::DEntry, FsErrorCtx> {
+    let mut path = path.to_path_buf();
+    for _ in 0..=MAX_SYMLINKS {
+        let dentry = lookup(&path)?;
+        let inode = dentry.inode();
+        if !inode.is_symlink() {
+            drop(inode);
+            return Ok(dentry);
+        }
+
+        let target = inode.readlink().map_err(|e| e.at(path.clone()))?;
+        path = resolve_link(&path, &target);
+    }
+    Err(FSError::TooManyLinks.at(path))
+}
+
+/// Resolves `target` of the symbolic link at `link` to an absolute path.
+fn resolve_link(link: &Path, target: &Path) -> PathBuf {
+    let joined = match link.parent() {
+        Some(parent) if target.is_relative() => parent.join(target),
+        _ => target.to_path_buf(),
+    };
+
+    // Lexically normalize, since directories have no `.` or `..` entries
+    joined.normalize()
+}
+
+/// Opens the file at `path`, following symbolic links.
+///
+/// Directories can't be opened for writing.
+pub fn open<P: AsRef<Path>>(path: P, flags: OpenFlags) -> FSResult<File, FsErrorCtx> {
+    let path = path.as_ref();
+    let dentry = follow(path)?;
+    if flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND) && dentry.inode().is_dir() {
+        return Err(FSError::IsDirectory.at(path));
+    }
+    Ok(File::new(dentry, flags))
+}
+
+/// Reads the entire contents of the file at `path`, following symbolic links.
+pub fn read<P: AsRef<Path>>(path: P) -> FSResult<Vec<u8>, FsErrorCtx> {
+    _read(path.as_ref())
+}
+
+fn _read(path: &Path) -> FSResult<Vec<u8>, FsErrorCtx> {
+    let dentry = follow(path)?;
+    let (size, block_size) = {
+        let inode = dentry.inode();
+        if inode.is_dir() {
+            return Err(FSError::IsDirectory.at(path));
+        }
+        (inode.size, inode.block_size)
+    };
+
+    let mut buf = vec![0; usize::try_from(size).map_err(|_| FSError::FileTooLarge.at(path))?];
+    let mut file = File::new(dentry, OpenFlags::READ);
+    let mut done = 0;
+    for chunk in buf.chunks_mut(usize::try_from(block_size).unwrap_or(usize::MAX).max(1)) {
+        let n = file.read(chunk).map_err(|e| e.at(path))?;
+        done += n;
+        if n < chunk.len() {
+            // File shrunk since its size was read
+            break;
+        }
+    }
+    buf.truncate(done);
+    file.close().map_err(|e| e.at(path))?;
+
+    Ok(buf)
+}
+
+/// Reads the entire contents of the file at `path` into a string.
+///
+/// Fails with [`FSError::InvalidData`] if the contents aren't valid UTF-8.
+pub fn read_to_string<P: AsRef<Path>>(path: P) -> FSResult<String, FsErrorCtx> {
+    let path = path.as_ref();
+    String::from_utf8(_read(path)?).map_err(|_| FSError::InvalidData.at(path))
+}
+
+/// Writes `data` as the entire contents of the file at `path`, creating it if it doesn't exist
+/// and truncating it otherwise.
+///
+/// The parent directory must exist.
+pub fn write<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, data: C) -> FSResult<(), FsErrorCtx> {
+    _write(path.as_ref(), data.as_ref())
+}
+
+fn _write(path: &Path, data: &[u8]) -> FSResult<(), FsErrorCtx> {
+    let dentry = match follow(path) {
+        Ok(dentry) => dentry,
+        Err(e) if e.kind == FSError::NoEntry => {
+            _create(path)?;
+            lookup(path)?
+        }
+        Err(e) => return Err(e),
+    };
+    {
+        let mut inode = dentry.inode_mut();
+        if inode.is_dir() {
+            return Err(FSError::IsDirectory.at(path));
+        }
+        inode.truncate(0).map_err(|e| e.at(path))?;
+    }
+    dentry.mark_dirty();
+
+    let mut file = File::new(dentry, OpenFlags::WRITE);
+    let mut done = 0;
+    while done < data.len() {
+        match file.write(&data[done..]).map_err(|e| e.at(path))? {
+            0 => return Err(FSError::FileTooLarge.at(path)),
+            n => done += n,
+        }
+    }
+    file.close().map_err(|e| e.at(path))
+}
+
+/// Creates an empty regular file at `path`.
+///
+/// The parent directory must exist.
+fn _create(path: &Path) -> FSResult<(), FsErrorCtx> {
+    let (parent, name) = split_parent(path)?;
+
+    let fs = parent.fs_arc();
+    let sb = fs.superblock();
+    let mut inode = sb.write().create_inode().map_err(|e| e.at(path))?;
+    if let Err(e) = inode.create(&parent, name) {
+        // Don't leak the unused inode
+        let _ = sb.write().destroy_inode(inode.num);
+        return Err(e.at(path));
+    }
+
+    // Commit the new inode and the parent
+    let i_parent = parent.inode();
+    let mut sb = sb.write();
+    sb.write_inode(&inode).map_err(|e| e.at(path))?;
+    sb.write_inode(&i_parent).map_err(|e| e.at(path))?;
+
+    Ok(())
+}
+
+/// Lists the name and inode number of every entry in the directory at `path`, sorted by name.
+///
+/// Names are compared byte by byte, and `.` and `..` are never included.
+pub fn read_dir_sorted<P: AsRef<Path>>(path: P) -> FSResult<Vec<(PathBuf, u64)>, FsErrorCtx> {
+    let path = path.as_ref();
+    let dentry = follow(path)?;
+    let inode = dentry.inode();
+    let mut entries: Vec<_> = inode
+        .list()
+        .map_err(|e| e.at(path))?
+        .filter(|(name, _)| !matches!(name.as_str(), "." | ".."))
+        .collect();
+    entries.sort_unstable_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
+    Ok(entries)
+}
+
+/// Creates a hard link `new` to the file at `existing`.
+///
+/// Both paths must be on the same file system.
+pub fn link<P: AsRef<Path>, Q: AsRef<Path>>(existing: P, new: Q) -> FSResult<(), FsErrorCtx> {
+    _link(existing.as_ref(), new.as_ref())
+}
+
+fn _link(existing: &Path, new: &Path) -> FSResult<(), FsErrorCtx> {
+    let src = lookup(existing)?;
+    let (parent, name) = split_parent(new)?;
+
+    let fs = parent.fs_arc();
+    if !core::ptr::addr_eq(Arc::as_ptr(&fs), Arc::as_ptr(&src.fs_arc())) {
+        return Err(FSError::NotSupported.at(new));
+    }
+
+    let mut inode = src.inode_mut();
+    if inode.is_dir() {
+        return Err(FSError::IsDirectory.at(existing));
+    }
+    inode.link(&parent, name).map_err(|e| e.at(new))?;
+
+    // Commit the linked inode and the parent
+    let i_parent = parent.inode();
+    let sb = fs.superblock();
+    let mut sb = sb.write();
+    sb.write_inode(&inode).map_err(|e| e.at(existing))?;
+    sb.write_inode(&i_parent).map_err(|e| e.at(new))?;
+
+    Ok(())
+}
+
+/// Creates a symbolic link at `link` pointing to `target`.
+///
+/// `target` is stored as is, it doesn't need to exist.
+pub fn symlink<P: AsRef<Path>, Q: AsRef<Path>>(target: P, link: Q) -> FSResult<(), FsErrorCtx> {
+    _symlink(target.as_ref(), link.as_ref())
+}
+
+fn _symlink(target: &Path, link: &Path) -> FSResult<(), FsErrorCtx> {
+    let (parent, name) = split_parent(link)?;
+
+    let fs = parent.fs_arc();
+    let sb = fs.superblock();
+    let mut inode = sb.write().create_inode().map_err(|e| e.at(link))?;
+    if let Err(e) = inode.symlink(target, &parent, name) {
+        // Don't leak the unused inode
+        let _ = sb.write().destroy_inode(inode.num);
+        return Err(e.at(link));
+    }
+
+    // Commit the new inode and the parent
+    let i_parent = parent.inode();
+    let mut sb = sb.write();
+    sb.write_inode(&inode).map_err(|e| e.at(link))?;
+    sb.write_inode(&i_parent).map_err(|e| e.at(link))?;
+
+    Ok(())
+}
+
+/// Creates a directory at `path`.
+///
+/// The parent directory must exist.
+pub fn mkdir<P: AsRef<Path>>(path: P) -> FSResult<(), FsErrorCtx> {
+    _mkdir(path.as_ref())
+}
+
+fn _mkdir(path: &Path) -> FSResult<(), FsErrorCtx> {
+    let (parent, name) = split_parent(path)?;
+
+    let fs = parent.fs_arc();
+    let sb = fs.superblock();
+    let mut inode = sb.write().create_inode().map_err(|e| e.at(path))?;
+    if let Err(e) = inode.mkdir(&parent, name) {
+        // Don't leak the unused inode
+        let _ = sb.write().destroy_inode(inode.num);
+        return Err(e.at(path));
+    }
+
+    // Commit the new inode and the parent
+    let i_parent = parent.inode();
+    let mut sb = sb.write();
+    sb.write_inode(&inode).map_err(|e| e.at(path))?;
+    sb.write_inode(&i_parent).map_err(|e| e.at(path))?;
+
+    Ok(())
+}
+
+/// Creates a directory at `path` along with any missing parent directories.
+///
+/// Succeeds if `path` is already a directory, and fails with [`FSError::NotDirectory`] if
+/// `path` or one of its ancestors exists but isn't a directory.
+pub fn create_dir_all<P: AsRef<Path>>(path: P) -> FSResult<(), FsErrorCtx> {
+    _create_dir_all(path.as_ref())
+}
+
+fn _create_dir_all(path: &Path) -> FSResult<(), FsErrorCtx> {
+    let mut current = PathBuf::new();
+    for comp in path.components() {
+        current.push(comp);
+        match lookup(&current) {
+            Ok(dentry) if dentry.inode().is_dir() => {}
+            Ok(_) => return Err(FSError::NotDirectory.at(current)),
+            Err(e) if e.kind == FSError::NoEntry => _mkdir(&current)?,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Removes the directory entry at `path`.
+///
+/// The file is destroyed once its last link is removed.
+pub fn unlink<P: AsRef<Path>>(path: P) -> FSResult<(), FsErrorCtx> {
+    _unlink(path.as_ref(), ROOT_UID)
+}
+
+/// Removes the directory entry at `path` on behalf of user `uid`.
+///
+/// See [`check_sticky`] for the permission checks.
+pub fn unlink_as<P: AsRef<Path>>(path: P, uid: u16) -> FSResult<(), FsErrorCtx> {
+    _unlink(path.as_ref(), uid)
+}
+
+fn _unlink(path: &Path, uid: u16) -> FSResult<(), FsErrorCtx> {
+    let dentry = lookup(path)?;
+    let (parent, name) = split_parent(path)?;
+    check_sticky(&parent, &dentry, uid).map_err(|e| e.at(path))?;
+
+    {
+        let mut inode = dentry.inode_mut();
+        if inode.is_dir() {
+            return Err(FSError::IsDirectory.at(path));
+        }
+        inode.unlink(&parent, name).map_err(|e| e.at(path))?;
+
+        // Commit the parent and the inode, destroying the inode once it lost its last link and
+        // isn't open anymore
+        let i_parent = parent.inode();
+        let fs = parent.fs_arc();
+        let sb = fs.superblock();
+        let mut sb = sb.write();
+        sb.write_inode(&i_parent).map_err(|e| e.at(path))?;
+        sb.write_inode(&inode).map_err(|e| e.at(path))?;
+        if icache::INODE_CACHE.release_link(icache::InodeId::new(&*fs, inode.num), inode.nlink) {
+            sb.destroy_inode(inode.num).map_err(|e| e.at(path))?;
+        }
+    }
+
+    DIR_CACHE.delete(path);
+
+    Ok(())
+}
+
+/// Checks that user `uid` may remove `entry` from `parent`.
+///
+/// In a directory with the sticky bit set, only the owner of the entry, the owner of the
+/// directory, or root may remove the entry.
+fn check_sticky(parent: &dentry::DEntry, entry: &dentry::DEntry, uid: u16) -> FSResult<()> {
+    let dir = parent.metadata();
+    if uid == ROOT_UID || !dir.permission.contains(vfs::Permission::STICKY) {
+        return Ok(());
+    }
+
+    if entry.metadata().user_id == uid || dir.user_id == uid {
+        Ok(())
+    } else {
+        Err(FSError::PermissionDenied)
+    }
+}
+
+/// Looks up the parent directory of `path`, returning it along with the final component.
+fn split_parent(path: &Path) -> FSResult<(dentry::DEntry, Component<'_>), FsErrorCtx> {
+    let name = path
+        .components()
+        .next_back()
+        .ok_or_else(|| FSError::BadPath.at(path))?;
+    let parent = lookup(path.parent().ok_or_else(|| FSError::BadPath.at(path))?)?;
+    Ok((parent, name))
+}
+
+/// Renames `from` to `to`.
+///
+/// If `to` already exists it is atomically replaced: a regular file may replace a regular file,
+/// and a directory may replace an empty directory.
+pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> FSResult<(), FsErrorCtx> {
+    _rename(from.as_ref(), to.as_ref(), ROOT_UID)
+}
+
+/// Renames `from` to `to` on behalf of user `uid`.
+///
+/// Both removing `from` from its directory and replacing `to` are subject to [`check_sticky`].
+pub fn rename_as<P: AsRef<Path>, Q: AsRef<Path>>(
+    from: P,
+    to: Q,
+    uid: u16,
+) -> FSResult<(), FsErrorCtx> {
+    _rename(from.as_ref(), to.as_ref(), uid)
+}
+
+fn _rename(from: &Path, to: &Path, uid: u16) -> FSResult<(), FsErrorCtx> {
+    if from.components().eq(to.components()) {
+        // Renaming onto itself does nothing, as long as the file exists
+        return lookup(from).map(drop);
+    }
+    if to.starts_with(from) {
+        // Can't move a directory into itself
+        return Err(FSError::BadPath.at(to));
+    }
+    if from.starts_with(to) {
+        // `to` is an ancestor of `from`, so it isn't empty
+        return Err(FSError::NotEmpty.at(to));
+    }
+
+    let src_name = from
+        .components()
+        .next_back()
+        .ok_or_else(|| FSError::BadPath.at(from))?;
+    let name = to
+        .components()
+        .next_back()
+        .ok_or_else(|| FSError::BadPath.at(to))?;
+    let src = lookup(from)?;
+    let src_p = lookup(from.parent().ok_or_else(|| FSError::BadPath.at(from))?)?;
+    let dst_p = lookup(to.parent().ok_or_else(|| FSError::BadPath.at(to))?)?;
+
+    let fs = src_p.fs_arc();
+    if !core::ptr::addr_eq(Arc::as_ptr(&fs), Arc::as_ptr(&dst_p.fs_arc())) {
+        return Err(FSError::CrossDevice.at(to));
+    }
+
+    check_sticky(&src_p, &src, uid).map_err(|e| e.at(from))?;
+    let dst = match lookup(to) {
+        Ok(dst) => {
+            check_sticky(&dst_p, &dst, uid).map_err(|e| e.at(to))?;
+            Some(dst)
+        }
+        Err(e) if e.kind == FSError::NoEntry => None,
+        Err(e) => return Err(e),
+    };
+
+    // Both names may be hard links to the same inode, which is left alone
+    let src_n = src.inode().num;
+    let dst_n = dst.as_ref().map(|dst| dst.inode().num);
+    if dst_n == Some(src_n) {
+        return Ok(());
+    }
+
+    {
+        // Lock both inodes in a consistent order to avoid deadlocking with a reverse rename.
+        // The replaced inode stays locked until it's committed or destroyed.
+        let (mut inode, mut old) = match &dst {
+            Some(dst) if dst_n < Some(src_n) => {
+                let old = dst.inode_mut();
+                (src.inode_mut(), Some(old))
+            }
+            Some(dst) => {
+                let inode = src.inode_mut();
+                (inode, Some(dst.inode_mut()))
+            }
+            None => (src.inode_mut(), None),
+        };
+        inode
+            .rename(&src_p, src_name, &dst_p, name, old.as_deref_mut())
+            .map_err(|e| e.at(to))?;
+
+        // Commit the renamed inode and both parents
+        let sb = fs.superblock();
+        let mut sb = sb.write();
+        sb.write_inode(&inode).map_err(|e| e.at(to))?;
+        sb.write_inode(&src_p.inode())
+            .map_err(|e| e.at(src_p.name().to_path_buf()))?;
+        sb.write_inode(&dst_p.inode())
+            .map_err(|e| e.at(dst_p.name().to_path_buf()))?;
+
+        // Commit the replaced inode, destroying it once it lost its last link and isn't open
+        // anymore
+        if let Some(old) = old {
+            sb.write_inode(&old).map_err(|e| e.at(to))?;
+            if icache::INODE_CACHE.release_link(icache::InodeId::new(&*fs, old.num), old.nlink) {
+                sb.destroy_inode(old.num).map_err(|e| e.at(to))?;
+            }
+        }
+    }
+
+    DIR_CACHE.delete_all(from);
+    DIR_CACHE.delete_all(to);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+
+    use spin::{Mutex, MutexGuard, Once};
+
+    use super::*;
+
+    static LOCK: Mutex<()> = Mutex::new(());
+    static ROOT: Once = Once::new();
+
+    /// Mounts a ramfs at `/` on first use, and serializes the tests using the global mount
+    /// table and caches.
+    ///
+    /// Tests should still work in their own directory, the file system is shared.
+    pub fn setup() -> MutexGuard<'static, ()> {
+        let guard = LOCK.lock();
+        ROOT.call_once(|| {
+            // Keep ramfs from reading the RTC
+            crate::time::set_wall_clock(0);
+            let ctx = mount::MountCtx {
+                fs: Box::new(ramfs::FileSystem::new()),
+                dest: None,
+                source: None,
+                device: None,
+                flags: MountFlags::empty(),
+            };
+            MOUNTS.mount_fs(ctx).unwrap();
+        });
+        guard
+    }
+
+    #[test]
+    fn link_shares_the_inode() {
+        let _guard = setup();
+        mkdir("/link").unwrap();
+        write("/link/a", b"shared").unwrap();
+
+        link("/link/a", "/link/b").unwrap();
+        assert_eq!(read("/link/b").unwrap(), b"shared");
+        assert_eq!(stat("/link/a").unwrap().nlink, 2);
+
+        assert_eq!(
+            link("/link/a", "/link/b").unwrap_err().kind,
+            FSError::Exists
+        );
+        assert_eq!(
+            link("/link", "/link/dir").unwrap_err().kind,
+            FSError::IsDirectory
+        );
+    }
+
+    #[test]
+    fn lookup_error_names_the_path() {
+        let _guard = setup();
+        mkdir("/lookup").unwrap();
+
+        let err = lookup("/lookup/missing").unwrap_err();
+        assert_eq!(err.kind, FSError::NoEntry);
+        assert_eq!(
+            err.path.as_deref().map(Path::as_str),
+            Some("/lookup/missing")
+        );
+        assert_eq!(
+            err.to_string(),
+            "/lookup/missing: No such file or directory"
+        );
+
+        // The whole path is reported, not just the missing component
+        let err = lookup("/lookup/missing/file").unwrap_err();
+        assert_eq!(err.kind, FSError::NoEntry);
+        assert_eq!(
+            err.path.as_deref().map(Path::as_str),
+            Some("/lookup/missing/file")
+        );
+    }
+
+    #[test]
+    fn close_commits_the_inode() {
+        let _guard = setup();
+        mkdir("/close").unwrap();
+        write("/close/file", b"").unwrap();
+        let num = stat("/close/file").unwrap().inode;
+        let sb = lookup("/").unwrap().fs_arc().superblock();
+        let stored_size = || sb.read().get_inode(num).unwrap().unwrap().size();
+
+        let mut file = open("/close/file", OpenFlags::WRITE).unwrap();
+        assert_eq!(file.write(b"committed").unwrap(), 9);
+        assert!(file.dentry().is_dirty());
+        assert_eq!(stored_size(), 0);
+
+        let dentry = file.dentry().clone();
+        file.close().unwrap();
+        assert!(!dentry.is_dirty());
+        assert_eq!(stored_size(), 9);
+
+        let mut buf = [0; 16];
+        let inode = sb.read().get_inode(num).unwrap().unwrap();
+        assert_eq!(inode.read(0, &mut buf).unwrap(), 9);
+        assert_eq!(&buf[..9], b"committed");
+    }
+
+    #[test]
+    fn sticky_directory_limits_unlink_to_owners() {
+        let _guard = setup();
+        mkdir("/sticky").unwrap();
+        let own = |path: &str, uid| lookup(path).unwrap().inode_mut().user_id = uid;
+        {
+            let dir = lookup("/sticky").unwrap();
+            let mut inode = dir.inode_mut();
+            inode.permission |= vfs::Permission::STICKY;
+            inode.user_id = 100;
+        }
+        for name in ["a", "b", "c", "d"] {
+            let path = alloc::format!("/sticky/{name}");
+            write(path.as_str(), b"").unwrap();
+            own(&path, 1);
+        }
+
+        assert_eq!(
+            unlink_as("/sticky/a", 2).unwrap_err().kind,
+            FSError::PermissionDenied
+        );
+        assert!(lookup("/sticky/a").is_ok());
+        unlink_as("/sticky/a", 1).unwrap();
+        // The directory's owner and root may remove anything
+        unlink_as("/sticky/b", 100).unwrap();
+        unlink_as("/sticky/c", ROOT_UID).unwrap();
+
+        // Without the sticky bit, anyone may
+        lookup("/sticky").unwrap().inode_mut().permission -= vfs::Permission::STICKY;
+        unlink_as("/sticky/d", 2).unwrap();
+    }
+
+    #[test]
+    fn create_dir_all_creates_every_missing_parent() {
+        let _guard = setup();
+        create_dir_all("/all/a/b/c").unwrap();
+        for path in ["/all", "/all/a", "/all/a/b", "/all/a/b/c"] {
+            assert!(lookup(path).unwrap().inode().is_dir(), "{path}");
+        }
+        // Existing directories are fine
+        create_dir_all("/all/a/b").unwrap();
+
+        write("/all/a/file", b"").unwrap();
+        let err = create_dir_all("/all/a/file/d").unwrap_err();
+        assert_eq!(err.kind, FSError::NotDirectory);
+        assert_eq!(err.path.as_deref().map(Path::as_str), Some("/all/a/file"));
+        assert_eq!(
+            create_dir_all("/all/a/file").unwrap_err().kind,
+            FSError::NotDirectory
+        );
+    }
+
+    #[test]
+    fn contents_round_trip() {
+        let _guard = setup();
+        mkdir("/contents").unwrap();
+
+        // Every byte value, across several blocks
+        let binary: Vec<u8> = (0..=u8::MAX).cycle().take(10_000).collect();
+        write("/contents/binary", &binary).unwrap();
+        assert_eq!(read("/contents/binary").unwrap(), binary);
+
+        let text = "h\u{e9}llo, w\u{f6}rld \u{1f980}\n".repeat(300);
+        write("/contents/text", &text).unwrap();
+        assert_eq!(read_to_string("/contents/text").unwrap(), text);
+
+        let err = read_to_string("/contents/binary").unwrap_err();
+        assert_eq!(err.kind, FSError::InvalidData);
+        assert_eq!(
+            err.path.as_deref().map(Path::as_str),
+            Some("/contents/binary")
+        );
+    }
+
+    #[test]
+    fn append_writes_land_at_the_end() {
+        let _guard = setup();
+        mkdir("/append").unwrap();
+        write("/append/log", b"one\n").unwrap();
+
+        let mut file = open("/append/log", OpenFlags::READ | OpenFlags::APPEND).unwrap();
+        // The cursor is ignored, every write goes after the current end
+        file.seek(file::SeekFrom::Start(0)).unwrap();
+        file.write(b"two\n").unwrap();
+        assert_eq!(file.position(), 8);
+
+        let mut other = open("/append/log", OpenFlags::WRITE).unwrap();
+        other.seek(file::SeekFrom::End(0)).unwrap();
+        other.write(b"three\n").unwrap();
+        drop(other);
+        // Data written by another file isn't overwritten
+        file.seek(file::SeekFrom::Start(1)).unwrap();
+        file.write(b"four\n").unwrap();
+        assert_eq!(file.position(), 19);
+        drop(file);
+        assert_eq!(read("/append/log").unwrap(), b"one\ntwo\nthree\nfour\n");
+
+        let mut file = open("/append/log", OpenFlags::READ).unwrap();
+        assert_eq!(file.seek(file::SeekFrom::End(-5)).unwrap(), 14);
+        let mut buf = [0; 8];
+        assert_eq!(file.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"four\n");
+        assert_eq!(file.seek(file::SeekFrom::End(3)).unwrap(), 22);
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+        assert_eq!(
+            file.seek(file::SeekFrom::End(-20)).unwrap_err(),
+            FSError::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn read_dir_sorted_orders_by_name() {
+        let _guard = setup();
+        mkdir("/sorted").unwrap();
+        for name in ["b", "a", "B", "ab", "_", "\u{e9}", "10", "9"] {
+            write(alloc::format!("/sorted/{name}").as_str(), b"").unwrap();
+        }
+        mkdir("/sorted/dir").unwrap();
+
+        let entries = read_dir_sorted("/sorted").unwrap();
+        let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        // Byte order: digits, upper case, `_`, lower case, then non-ASCII
+        assert_eq!(
+            names,
+            ["10", "9", "B", "_", "a", "ab", "b", "dir", "\u{e9}"]
+        );
+        for (name, inode) in &entries {
+            let path = alloc::format!("/sorted/{name}");
+            assert_eq!(*inode, lstat(path.as_str()).unwrap().inode);
+        }
+
+        assert_eq!(
+            read_dir_sorted("/sorted/a").unwrap_err().kind,
+            FSError::NotDirectory
+        );
+    }
+
+    #[test]
+    fn access_is_checked_against_the_matching_triad() {
+        use vfs::Permission as P;
+
+        let _guard = setup();
+        mkdir("/access").unwrap();
+        write("/access/file", b"").unwrap();
+        let dentry = lookup("/access/file").unwrap();
+        let set = |permission| {
+            let mut inode = dentry.inode_mut();
+            inode.permission = permission;
+            inode.user_id = 1;
+            inode.group_id = 10;
+        };
+
+        // Owner only
+        set(P::USER_READ | P::USER_WRITE | P::USER_EXECUTE);
+        let inode = dentry.inode();
+        assert!(inode.can_read(1, 10) && inode.can_write(1, 10) && inode.can_execute(1, 10));
+        for (uid, gid) in [(2, 10), (2, 20)] {
+            assert!(!inode.can_read(uid, gid));
+            assert!(!inode.can_write(uid, gid));
+            assert!(!inode.can_execute(uid, gid));
+        }
+        // Root is allowed whatever the bits
+        assert!(inode.can_read(ROOT_UID, 0) && inode.can_write(ROOT_UID, 0));
+        drop(inode);
+
+        // The owner's triad applies even when the others are more permissive
+        set(P::GROUP_READ | P::OTHER_READ | P::OTHER_EXECUTE);
+        let inode = dentry.inode();
+        assert!(!inode.can_read(1, 10));
+        assert!(inode.can_read(2, 10) && !inode.can_execute(2, 10));
+        assert!(inode.can_read(2, 20) && inode.can_execute(2, 20));
+        assert!(!inode.can_write(2, 20));
+        assert!(inode.can_execute(ROOT_UID, 0));
+    }
+
+    #[test]
+    fn set_len_clamps_the_cursor() {
+        let _guard = setup();
+        mkdir("/set_len").unwrap();
+        write("/set_len/file", [7; 1000]).unwrap();
+
+        let mut file = open("/set_len/file", OpenFlags::READ | OpenFlags::WRITE).unwrap();
+        file.seek(file::SeekFrom::Start(500)).unwrap();
+        file.set_len(100).unwrap();
+        assert_eq!(file.position(), 100);
+        let mut buf = [0; 1000];
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+
+        file.seek(file::SeekFrom::Start(0)).unwrap();
+        assert_eq!(file.read(&mut buf).unwrap(), 100);
+        assert_eq!(buf[..100], [7; 100]);
+        // A cursor before the new end stays put
+        file.seek(file::SeekFrom::Start(50)).unwrap();
+        file.set_len(80).unwrap();
+        assert_eq!(file.position(), 50);
+        drop(file);
+        assert_eq!(stat("/set_len/file").unwrap().size, 80);
+
+        let mut file = open("/set_len/file", OpenFlags::READ).unwrap();
+        assert_eq!(file.set_len(0), Err(FSError::BadDescriptor));
+    }
+
+    #[test]
+    fn symlink_is_followed() {
+        let _guard = setup();
+        mkdir("/symlink").unwrap();
+        write("/symlink/target", b"data").unwrap();
+
+        symlink("target", "/symlink/link").unwrap();
+        assert_eq!(read("/symlink/link").unwrap(), b"data");
+        assert_eq!(
+            lstat("/symlink/link").unwrap().mode,
+            vfs::Mode::SYMBOLIC_LINK
+        );
+        assert_eq!(
+            stat("/symlink/link").unwrap(),
+            stat("/symlink/target").unwrap()
+        );
+        assert_eq!(stat("/symlink/link").unwrap().mode, vfs::Mode::REGULAR_FILE);
+
+        // A broken link can still be inspected, but not followed
+        symlink("missing", "/symlink/broken").unwrap();
+        assert_eq!(
+            lstat("/symlink/broken").unwrap().mode,
+            vfs::Mode::SYMBOLIC_LINK
+        );
+        assert_eq!(stat("/symlink/broken").unwrap_err().kind, FSError::NoEntry);
+    }
+
+    #[test]
+    fn symlinks_inside_a_path_are_followed() {
+        let _guard = setup();
+        create_dir_all("/midlink/real/sub").unwrap();
+        write("/midlink/real/sub/file", b"inside").unwrap();
+
+        symlink("real", "/midlink/dir").unwrap();
+        symlink("/midlink/dir/sub", "/midlink/abs").unwrap();
+        assert_eq!(read("/midlink/dir/sub/file").unwrap(), b"inside");
+        assert_eq!(read("/midlink/abs/file").unwrap(), b"inside");
+        assert_eq!(
+            stat("/midlink/dir/sub/file").unwrap(),
+            stat("/midlink/real/sub/file").unwrap()
+        );
+
+        // A link to itself, and two links to each other, are never resolved
+        symlink("loop", "/midlink/loop").unwrap();
+        symlink("pong", "/midlink/ping").unwrap();
+        symlink("ping", "/midlink/pong").unwrap();
+        for path in ["/midlink/loop/file", "/midlink/ping/file"] {
+            assert_eq!(
+                lookup(path).unwrap_err().kind,
+                FSError::TooManyLinks,
+                "{path}"
+            );
+        }
+        for path in ["/midlink/loop", "/midlink/ping"] {
+            assert_eq!(
+                stat(path).unwrap_err().kind,
+                FSError::TooManyLinks,
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn symlink_too_long_is_not_linked() {
+        let _guard = setup();
+        mkdir("/symlink_long").unwrap();
+
+        // One byte more than a ramfs block
+        let target = "a".repeat(0x1001);
+        let err = symlink(target.as_str(), "/symlink_long/link").unwrap_err();
+        assert_eq!(err.kind, FSError::BadPath);
+        assert_eq!(
+            lookup("/symlink_long/link").unwrap_err().kind,
+            FSError::NoEntry
+        );
+        assert_eq!(read_dir_sorted("/symlink_long").unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn unlink_removes_the_file() {
+        let _guard = setup();
+        mkdir("/unlink").unwrap();
+        write("/unlink/file", b"gone").unwrap();
+
+        unlink("/unlink/file").unwrap();
+        assert_eq!(read("/unlink/file").unwrap_err().kind, FSError::NoEntry);
+        assert_eq!(unlink("/unlink").unwrap_err().kind, FSError::IsDirectory);
+    }
+
+    #[test]
+    fn unlink_keeps_other_links() {
+        let _guard = setup();
+        mkdir("/unlink_links").unwrap();
+        write("/unlink_links/a", b"kept").unwrap();
+        link("/unlink_links/a", "/unlink_links/b").unwrap();
+
+        // The second name, so matching on the inode alone would remove the first
+        unlink("/unlink_links/b").unwrap();
+        assert_eq!(read("/unlink_links/a").unwrap(), b"kept");
+        assert_eq!(stat("/unlink_links/a").unwrap().nlink, 1);
+        assert_eq!(
+            lookup("/unlink_links/b").unwrap_err().kind,
+            FSError::NoEntry
+        );
+        assert_eq!(read_dir_sorted("/unlink_links").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rename_replaces_the_destination() {
+        let _guard = setup();
+        mkdir("/rename").unwrap();
+        write("/rename/a", b"new").unwrap();
+        write("/rename/b", b"old").unwrap();
+        write("/rename/c", b"linked").unwrap();
+        link("/rename/c", "/rename/d").unwrap();
+        let old = stat("/rename/b").unwrap().inode;
+        let linked = stat("/rename/d").unwrap().inode;
+        let sb = lookup("/").unwrap().fs_arc().superblock();
+
+        // The replaced inode loses its last link and is destroyed
+        rename("/rename/a", "/rename/b").unwrap();
+        assert_eq!(read("/rename/b").unwrap(), b"new");
+        assert_eq!(lookup("/rename/a").unwrap_err().kind, FSError::NoEntry);
+        assert!(sb.read().get_inode(old).unwrap().is_none());
+
+        // Another link keeps it alive
+        rename("/rename/b", "/rename/d").unwrap();
+        assert_eq!(read("/rename/d").unwrap(), b"new");
+        assert_eq!(stat("/rename/c").unwrap().nlink, 1);
+        assert_eq!(sb.read().get_inode(linked).unwrap().unwrap().nlink, 1);
+        assert_eq!(read_dir_sorted("/rename").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rename_onto_itself_does_nothing() {
+        let _guard = setup();
+        mkdir("/rename_self").unwrap();
+        write("/rename_self/a", b"kept").unwrap();
+        link("/rename_self/a", "/rename_self/b").unwrap();
+
+        rename("/rename_self/a", "/rename_self/a").unwrap();
+        assert_eq!(read("/rename_self/a").unwrap(), b"kept");
+
+        // Two links to the same inode are both left in place
+        rename("/rename_self/a", "/rename_self/b").unwrap();
+        assert_eq!(read("/rename_self/a").unwrap(), b"kept");
+        assert_eq!(stat("/rename_self/b").unwrap().nlink, 2);
+
+        assert_eq!(
+            rename("/rename_self/missing", "/rename_self/missing")
+                .unwrap_err()
+                .kind,
+            FSError::NoEntry
+        );
+    }
+
+    #[test]
+    fn unmount_removes_the_mount() {
+        let _guard = setup();
+        mkdir("/unmount").unwrap();
+        MOUNTS
+            .mount_fs(mount::MountCtx {
+                fs: Box::new(ramfs::FileSystem::new()),
+                dest: Some(lookup("/unmount").unwrap()),
+                source: None,
+                device: None,
+                flags: MountFlags::empty(),
+            })
+            .unwrap();
+        assert!(MOUNTS.is_mount_path(Path::new("/unmount")));
+        write("/unmount/file", b"mounted").unwrap();
+
+        let file = open("/unmount/file", OpenFlags::READ).unwrap();
+        assert_eq!(MOUNTS.unmount("/unmount"), Err(FSError::Busy));
+        drop(file);
+
+        MOUNTS.unmount("/unmount").unwrap();
+        assert!(!MOUNTS.is_mount_path(Path::new("/unmount")));
+        assert_eq!(lookup("/unmount/file").unwrap_err().kind, FSError::NoEntry);
+        assert_eq!(MOUNTS.unmount("/unmount"), Err(FSError::NoMount));
+        assert_eq!(MOUNTS.unmount("/nowhere"), Err(FSError::NoMount));
+    }
+
+    #[test]
+    fn unmount_with_a_child_mount_is_busy() {
+        let _guard = setup();
+        mkdir("/nested").unwrap();
+        mkdir("/nested_sibling").unwrap();
+        let mount = |path: &str| {
+            MOUNTS
+                .mount_fs(mount::MountCtx {
+                    fs: Box::new(ramfs::FileSystem::new()),
+                    dest: Some(lookup(path).unwrap()),
+                    source: None,
+                    device: None,
+                    flags: MountFlags::empty(),
+                })
+                .unwrap();
+        };
+        mount("/nested");
+        mkdir("/nested/child").unwrap();
+        mount("/nested/child");
+        // Only a prefix of the name, not a child
+        mount("/nested_sibling");
+
+        assert_eq!(MOUNTS.unmount("/nested"), Err(FSError::Busy));
+        assert!(MOUNTS.is_mount_path(Path::new("/nested")));
+
+        MOUNTS.unmount("/nested/child").unwrap();
+        MOUNTS.unmount("/nested").unwrap();
+        assert!(!MOUNTS.is_mount_path(Path::new("/nested")));
+        MOUNTS.unmount("/nested_sibling").unwrap();
+    }
+
+    #[test]
+    fn failed_mount_leaves_no_entry() {
+        let _guard = setup();
+        mkdir("/badmount").unwrap();
+        let mounts = MOUNTS.list().len();
+        let mount = |device: Option<Arc<dyn block::BlockDevice + Send + Sync>>| {
+            MOUNTS.mount_fs(mount::MountCtx {
+                fs: Box::new(ext2::FileSystem::new()),
+                dest: Some(lookup("/badmount").unwrap()),
+                source: Some(PathBuf::from("/dev/blank")),
+                device,
+                flags: MountFlags::empty(),
+            })
+        };
+
+        // A blank disk has no ext2 superblock
+        let err = mount(Some(Arc::new(block::RamDisk::new(1024, 8)))).unwrap_err();
+        assert_eq!(
+            err,
+            MountError {
+                fs_name: "ext2".into(),
+                source: Some(PathBuf::from("/dev/blank")),
+                kind: FSError::InvalidData,
+            }
+        );
+        assert_eq!(mount(None).unwrap_err().kind, FSError::NoDevice);
+
+        assert_eq!(MOUNTS.list().len(), mounts);
+        assert!(!MOUNTS.is_mount_path(Path::new("/badmount")));
+        write("/badmount/file", b"still here").unwrap();
+        assert_eq!(read("/badmount/file").unwrap(), b"still here");
+    }
+
+    #[test]
+    fn list_reports_every_mount() {
+        let _guard = setup();
+        mkdir("/list_a").unwrap();
+        mkdir("/list_b").unwrap();
+        for (dest, source) in [("/list_a", None), ("/list_b", Some("/dev/ram1"))] {
+            MOUNTS
+                .mount_fs(mount::MountCtx {
+                    fs: Box::new(ramfs::FileSystem::new()),
+                    dest: Some(lookup(dest).unwrap()),
+                    source: source.map(PathBuf::from),
+                    device: None,
+                    flags: MountFlags::empty(),
+                })
+                .unwrap();
+        }
+
+        let mounts = MOUNTS.list();
+        let a = mounts
+            .iter()
+            .find(|m| m.path.as_str() == "/list_a")
+            .unwrap();
+        let b = mounts
+            .iter()
+            .find(|m| m.path.as_str() == "/list_b")
+            .unwrap();
+        assert_eq!(a.fs_name, "ramfs");
+        assert_eq!(b.fs_name, "ramfs");
+        assert_eq!(a.source, None);
+        assert_eq!(b.source.as_deref().map(Path::as_str), Some("/dev/ram1"));
+        assert_ne!(a.id, b.id);
+
+        MOUNTS.unmount("/list_a").unwrap();
+        MOUNTS.unmount("/list_b").unwrap();
+        assert!(!MOUNTS
+            .list()
+            .iter()
+            .any(|m| m.path.as_str().starts_with("/list_")));
+    }
 }
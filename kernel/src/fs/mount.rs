@@ -1,27 +1,119 @@
-use alloc::{boxed::Box, sync::Arc};
+use alloc::{boxed::Box, string::String, sync::Arc};
+use core::fmt::{Display, Formatter};
+
+use bitflags::bitflags;
 
 use crate::fs::{
+    block::BlockDevice,
     dentry::DEntry,
     path::PathBuf,
-    vfs::{FSResult, FileSystem},
+    vfs::{FSError, FSResult, FileSystem},
 };
 
 pub struct MountCtx {
     pub fs: Box<dyn FileSystem + Send + Sync>,
     pub dest: Option<DEntry>,
     pub source: Option<PathBuf>,
+    /// Device to mount, required by [`MountType::BlockDevice`] file systems
+    pub device: Option<Arc<dyn BlockDevice + Send + Sync>>,
+    pub flags: MountFlags,
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum MountType {
-    // BlockDevice,
+    /// Stored on a [`BlockDevice`]
+    BlockDevice,
+    /// Not backed by a device
     NoDevice,
 }
 
-// pub fn mount_bdev(fs: Box<dyn FileSystem>, )
+/// Identifier of a mounted filesystem, unique for the lifetime of the kernel.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct FsId(pub(super) u64);
+
+impl Display for FsId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+    pub struct MountFlags: u8 {
+        const READ_ONLY = 1 << 0;
+        const NO_EXEC = 1 << 1;
+        const NO_SUID = 1 << 2;
+    }
+}
+
+impl Display for MountFlags {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(if self.contains(Self::READ_ONLY) {
+            "ro"
+        } else {
+            "rw"
+        })?;
+        if self.contains(Self::NO_EXEC) {
+            f.write_str(",noexec")?;
+        }
+        if self.contains(Self::NO_SUID) {
+            f.write_str(",nosuid")?;
+        }
+        Ok(())
+    }
+}
+
+/// Snapshot of a single entry in the mount table.
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    pub id: FsId,
+    pub path: PathBuf,
+    pub source: Option<PathBuf>,
+    pub fs_name: String,
+    pub tp: MountType,
+    pub flags: MountFlags,
+}
+
+impl Display for MountInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let source = self.source.as_deref().map_or("none", |s| s.as_str());
+        write!(
+            f,
+            "{} {} {} {} {}",
+            self.id, source, self.path, self.fs_name, self.flags
+        )
+    }
+}
+
+/// Failure to mount a file system, returned by [`Mounts::mount_fs`](super::Mounts::mount_fs).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MountError {
+    pub fs_name: String,
+    pub source: Option<PathBuf>,
+    pub kind: FSError,
+}
+
+impl Display for MountError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "failed to mount {}", self.fs_name)?;
+        if let Some(source) = &self.source {
+            write!(f, " from {source}")?;
+        }
+        write!(f, ": {}", self.kind)
+    }
+}
+
+pub fn mount_bdev(
+    mut fs: Box<dyn FileSystem + Send + Sync>,
+    dev: Arc<dyn BlockDevice + Send + Sync>,
+) -> FSResult<Arc<dyn FileSystem + Send + Sync>> {
+    fs.init_super(Some(dev))?;
+    Ok(Arc::from(fs))
+}
 
 pub fn mount_nodev(
     mut fs: Box<dyn FileSystem + Send + Sync>,
 ) -> FSResult<Arc<dyn FileSystem + Send + Sync>> {
-    fs.init_super()?;
+    fs.init_super(None)?;
     Ok(Arc::from(fs))
 }
@@ -8,7 +8,7 @@ pub const fn has_physical_root(s: &[u8]) -> bool {
     !s.is_empty() && s[0] == SEPERATOR_BYTE
 }
 
-const unsafe fn parse_single_component(comp: &[u8]) -> Option<Component> {
+const unsafe fn parse_single_component(comp: &[u8]) -> Option<Component<'_>> {
     match comp {
         b"." | b"" => None, // CurDir are handled in include_cur_dir
         b".." => Some(Component::ParentDir),
@@ -94,7 +94,7 @@ impl<'a> Components<'a> {
     /// parse a component from the left, saying how many bytes to consume to
     /// remove the component.
     fn parse_next_component(&self) -> (usize, Option<Component<'a>>) {
-        debug_assert!(self.front == State::Body);
+        debug_assert_eq!(self.front, State::Body);
         let (extra, comp) = self
             .path
             .iter()
@@ -108,7 +108,7 @@ impl<'a> Components<'a> {
     /// parse a component from the right, saying how many bytes to consume to
     /// remove the component
     fn parse_next_component_back(&self) -> (usize, Option<Component<'a>>) {
-        debug_assert!(self.back == State::Body);
+        debug_assert_eq!(self.back, State::Body);
         let start = self.len_before_body();
         let (extra, comp) = self.path[start..]
             .iter()
@@ -153,11 +153,14 @@ impl<'a> Iterator for Components<'a> {
                 State::StartDir => {
                     self.front = State::Body;
                     if self.has_physical_root {
-                        debug_assert!(!self.path.is_empty());
+                        debug_assert!(!self.path.is_empty(), "the root is in the path");
                         self.path = &self.path[1..];
                         return Some(Component::RootDir);
                     } else if self.include_cur_dir() {
-                        debug_assert!(!self.path.is_empty());
+                        debug_assert!(
+                            !self.path.is_empty(),
+                            "the current directory is in the path"
+                        );
                         self.path = &self.path[1..];
                         return Some(Component::CurDir);
                     }
@@ -179,7 +182,7 @@ impl<'a> Iterator for Components<'a> {
     }
 }
 
-impl<'a> DoubleEndedIterator for Components<'a> {
+impl DoubleEndedIterator for Components<'_> {
     fn next_back(&mut self) -> Option<Self::Item> {
         while !self.finished() {
             match self.back {
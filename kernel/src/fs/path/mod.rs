@@ -16,30 +16,44 @@ pub struct Path {
 impl Path {
     pub fn new<S: AsRef<str> + ?Sized>(s: &S) -> &Self {
         // SAFETY: `Path` is the same size as `str`
-        unsafe { &*(s.as_ref() as *const str as *const Self) }
+        unsafe { &*(core::ptr::from_ref::<str>(s.as_ref()) as *const Self) }
     }
 
     fn from_mut(s: &mut str) -> &mut Self {
         // SAFETY: `Path` is the same size as `str`
-        unsafe { &mut *(s as *mut str as *mut Self) }
+        unsafe { &mut *(core::ptr::from_mut::<str>(s) as *mut Self) }
     }
 
     pub const fn as_str(&self) -> &str {
         &self.inner
     }
 
-    pub fn as_mut_str(&mut self) -> &mut str {
+    pub const fn as_mut_str(&mut self) -> &mut str {
         &mut self.inner
     }
 
+    /// Returns the UTF-8 bytes of the path, for storing names on disk.
+    pub const fn as_bytes(&self) -> &[u8] {
+        self.inner.as_bytes()
+    }
+
     pub fn to_path_buf(&self) -> PathBuf {
         PathBuf::from(self)
     }
 
-    pub const fn components(&self) -> Components {
+    pub const fn components(&self) -> Components<'_> {
         Components::new(self)
     }
 
+    /// Iterates over every separator-delimited segment of the path, including empty ones.
+    ///
+    /// Unlike [`components`](Self::components), no normalization is performed, so joining
+    /// the segments with [`SEPERATOR`] reconstructs the original path exactly.
+    /// For example, `a//b/` yields `["a", "", "b", ""]`.
+    pub fn raw_segments(&self) -> impl DoubleEndedIterator<Item = &str> {
+        self.inner.split(SEPERATOR)
+    }
+
     pub const fn has_root(&self) -> bool {
         self.components().has_root()
     }
@@ -63,7 +77,7 @@ impl Path {
         })
     }
 
-    pub const fn ancestors(&self) -> Ancestors {
+    pub const fn ancestors(&self) -> Ancestors<'_> {
         Ancestors::new(self)
     }
 
@@ -148,6 +162,65 @@ impl Path {
         buf
     }
 
+    /// Returns the path that leads from `base` to `self`, made of `..` and normal components
+    ///
+    /// Both paths should be [normalized](Self::normalize). Returns `.` if they're the same,
+    /// and `None` if only one of them is absolute.
+    #[must_use]
+    pub fn relative_to<P: AsRef<Self>>(&self, base: P) -> Option<PathBuf> {
+        self._relative_to(base.as_ref())
+    }
+
+    fn _relative_to(&self, base: &Self) -> Option<PathBuf> {
+        if self.is_absolute() != base.is_absolute() {
+            return None;
+        }
+
+        // Fast path, `base` is an ancestor
+        if let Some(rest) = iter_after(self.components(), base.components()) {
+            let rest = rest.as_path();
+            return Some(if rest.as_str().is_empty() {
+                PathBuf::from(".")
+            } else {
+                rest.to_path_buf()
+            });
+        }
+
+        // Go up from `base` to the common ancestor, then down to `self`
+        let mut comps = self.components().peekable();
+        let mut base_comps = base.components().peekable();
+        while comps.peek().is_some() && comps.peek() == base_comps.peek() {
+            comps.next();
+            base_comps.next();
+        }
+
+        let mut path: PathBuf = base_comps.map(|_| Component::ParentDir).collect();
+        path.extend(comps);
+        Some(path)
+    }
+
+    /// Lexically resolves `.` and `..` components, without touching the file system
+    ///
+    /// `..` removes the preceding component, and is dropped at the root of an absolute path.
+    /// Leading `..` components of a relative path are kept, since there is nothing to remove.
+    #[must_use]
+    pub fn normalize(&self) -> PathBuf {
+        let mut path = PathBuf::new();
+        for comp in self.components() {
+            match comp {
+                Component::CurDir => {}
+                Component::ParentDir if path.file_name().is_some() => {
+                    path.pop();
+                }
+                Component::ParentDir if path.is_absolute() => {}
+                Component::RootDir | Component::ParentDir | Component::Normal(_) => {
+                    path.push(comp);
+                }
+            }
+        }
+        path
+    }
+
     pub fn with_extension<S: AsRef<str>>(&self, extension: S) -> PathBuf {
         self._with_extension(extension.as_ref())
     }
@@ -258,7 +331,7 @@ fn rsplit_file_at_dot(file: &str) -> (Option<&str>, Option<&str>) {
         return (Some(file), None);
     }
 
-    let mut iter = file.rsplitn(2, |b| b == '.');
+    let mut iter = file.rsplitn(2, '.');
     let after = iter.next();
     let before = iter.next();
     if before == Some("") {
@@ -273,10 +346,11 @@ fn split_file_at_dot(file: &str) -> (&str, Option<&str>) {
         return (file, None);
     }
 
-    let i = match file[1..].chars().position(|b| b == '.') {
-        Some(i) => i + 1,
-        None => return (file, None),
+    // A leading dot is part of the prefix, search the bytes after it
+    let Some(i) = file.bytes().skip(1).position(|b| b == b'.') else {
+        return (file, None);
     };
+    let i = i + 1;
     let before = &file[..i];
     let after = &file[i + 1..];
     (before, Some(after))
@@ -284,3 +358,66 @@ fn split_file_at_dot(file: &str) -> (&str, Option<&str>) {
 
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub struct StripPrefixError(());
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    fn segments(path: &str) -> Vec<&str> {
+        Path::new(path).raw_segments().collect()
+    }
+
+    #[test]
+    fn raw_segments_keep_every_separator() {
+        assert_eq!(segments("/a"), ["", "a"]);
+        assert_eq!(segments("a//b"), ["a", "", "b"]);
+        assert_eq!(segments("a/"), ["a", ""]);
+        assert_eq!(segments(""), [""]);
+
+        let path = "/a//b/";
+        assert_eq!(segments(path).join("/"), path);
+        assert_eq!(
+            Path::new(path).raw_segments().rev().collect::<Vec<_>>(),
+            ["", "b", "", "a", ""]
+        );
+    }
+
+    #[test]
+    fn normalize_resolves_dots() {
+        let normalize = |path: &str| Path::new(path).normalize();
+        assert_eq!(normalize("/a/b/../c"), PathBuf::from("/a/c"));
+        assert_eq!(normalize("/../x"), PathBuf::from("/x"));
+        assert_eq!(normalize("a/./b"), PathBuf::from("a/b"));
+        // Nothing to remove in a relative path
+        assert_eq!(normalize("../a/../../b"), PathBuf::from("../../b"));
+        assert_eq!(normalize("a/.."), PathBuf::new());
+    }
+
+    #[test]
+    fn relative_to_sibling_ancestor_and_itself() {
+        let relative = |path: &str, base: &str| Path::new(path).relative_to(base);
+        assert_eq!(relative("/a/b", "/a/c"), Some(PathBuf::from("../b")));
+        assert_eq!(relative("/a/b/c", "/a"), Some(PathBuf::from("b/c")));
+        assert_eq!(relative("/a", "/a/b/c"), Some(PathBuf::from("../..")));
+        assert_eq!(relative("/a/b", "/a/b"), Some(PathBuf::from(".")));
+        assert_eq!(relative("a/b", "c"), Some(PathBuf::from("../a/b")));
+        assert_eq!(relative("/a", "a"), None);
+    }
+
+    #[test]
+    fn file_prefix_stops_at_the_first_dot() {
+        assert_eq!(split_file_at_dot(""), ("", None));
+        assert_eq!(split_file_at_dot("."), (".", None));
+        assert_eq!(split_file_at_dot(".a"), (".a", None));
+        assert_eq!(split_file_at_dot("a"), ("a", None));
+        assert_eq!(split_file_at_dot(".a.b.c"), (".a", Some("b.c")));
+
+        assert_eq!(Path::new("").file_prefix(), None);
+        assert_eq!(Path::new(".").file_prefix(), None);
+        assert_eq!(Path::new("/d/.a").file_prefix(), Some(".a"));
+        assert_eq!(Path::new("a").file_prefix(), Some("a"));
+        assert_eq!(Path::new("a.tar.gz").file_prefix(), Some("a"));
+    }
+}
@@ -5,7 +5,10 @@ use core::{
     ops::{Deref, DerefMut},
 };
 
-use crate::fs::path::{Path, SEPERATOR};
+use crate::fs::{
+    path::{Path, SEPERATOR},
+    vfs::{FSError, FSResult},
+};
 
 /// An owned, mutable path (akin to [`String`]).
 ///
@@ -48,6 +51,15 @@ impl PathBuf {
         }
     }
 
+    /// Creates a path from bytes read from disk.
+    ///
+    /// Paths are UTF-8, so invalid sequences fail with [`FSError::BadPath`].
+    pub fn from_bytes(bytes: &[u8]) -> FSResult<Self> {
+        core::str::from_utf8(bytes)
+            .map(Self::from)
+            .map_err(|_| FSError::BadPath)
+    }
+
     #[must_use]
     #[inline]
     pub fn as_path(&self) -> &Path {
@@ -59,7 +71,7 @@ impl PathBuf {
     }
     fn _push(&mut self, path: &Path) {
         // in general, a separator is needed if the rightmost byte is not a separator
-        let need_sep = self.inner.chars().last().map_or(false, |c| c == SEPERATOR);
+        let need_sep = self.inner.chars().last().is_some_and(|c| c != SEPERATOR);
 
         if path.is_absolute() {
             // absolute `path` replaces `self`
@@ -100,9 +112,8 @@ impl PathBuf {
     }
 
     fn _set_extension(&mut self, extension: &str) -> bool {
-        let file_stem = match self.file_stem() {
-            None => return false,
-            Some(f) => f,
+        let Some(file_stem) = self.file_stem() else {
+            return false;
         };
 
         // truncate until right after the file stem
@@ -123,7 +134,7 @@ impl PathBuf {
 
     #[must_use]
     #[inline]
-    pub fn as_mut_string(&mut self) -> &mut String {
+    pub const fn as_mut_string(&mut self) -> &mut String {
         &mut self.inner
     }
 
@@ -135,7 +146,7 @@ impl PathBuf {
 
     #[must_use]
     #[inline]
-    pub fn capacity(&self) -> usize {
+    pub const fn capacity(&self) -> usize {
         self.inner.capacity()
     }
 
@@ -213,6 +224,16 @@ impl<P: AsRef<Path>> Extend<P> for PathBuf {
     }
 }
 
+/// Joins the paths with separators, e.g. collecting [`Components`](super::Components) rebuilds
+/// the path without redundant or trailing separators.
+impl<P: AsRef<Path>> FromIterator<P> for PathBuf {
+    fn from_iter<T: IntoIterator<Item = P>>(iter: T) -> Self {
+        let mut buf = Self::new();
+        buf.extend(iter);
+        buf
+    }
+}
+
 impl From<&str> for PathBuf {
     fn from(s: &str) -> Self {
         Self {
@@ -240,3 +261,31 @@ impl Display for PathBuf {
         Display::fmt(self.as_str(), f)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collecting_components_rebuilds_the_path() {
+        let path: PathBuf = Path::new("/a/b/c").components().collect();
+        assert_eq!(path.as_str(), "/a/b/c");
+
+        // Redundant and trailing separators are dropped
+        let path: PathBuf = Path::new("/a//b/c/").components().collect();
+        assert_eq!(path.as_str(), "/a/b/c");
+        let path: PathBuf = Path::new("a/b/").components().collect();
+        assert_eq!(path.as_str(), "a/b");
+    }
+
+    #[test]
+    fn bytes_must_be_utf8() {
+        let path = PathBuf::from_bytes("/caf\u{e9}/a".as_bytes()).unwrap();
+        assert_eq!(path.as_str(), "/caf\u{e9}/a");
+        assert_eq!(path.as_bytes(), b"/caf\xc3\xa9/a");
+
+        // A lone continuation byte and a truncated sequence
+        assert_eq!(PathBuf::from_bytes(b"/a\x80"), Err(FSError::BadPath));
+        assert_eq!(PathBuf::from_bytes(b"/caf\xc3"), Err(FSError::BadPath));
+    }
+}
@@ -1,10 +1,12 @@
-use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use core::any::Any;
 
 use hashbrown::{hash_map::Entry, HashMap};
 use spin::lock_api::{RwLock, RwLockReadGuard};
 use static_assertions::assert_eq_size;
 
 use crate::fs::{
+    block::BlockDevice,
     dentry::DEntry,
     mount::MountType,
     path::{Component, Path, PathBuf},
@@ -15,6 +17,14 @@ use crate::fs::{
 const FS_NAME: &str = "ramfs";
 const BLOCK_SIZE: usize = 0x1000;
 const MAGIC: u64 = u64::from_be_bytes(*b"RAM_FS_M");
+/// Maximum size of a file, files live on the heap
+const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Blocks of an inode, keyed by block index.
+///
+/// Files may be sparse: a missing block is a hole and reads as zeros. Directories and symbolic
+/// links are always dense.
+type Blocks = BTreeMap<u64, Box<[u8; BLOCK_SIZE]>>;
 
 pub struct FileSystem {
     superblock: Arc<RwLock<SuperBlock>>,
@@ -41,12 +51,20 @@ impl vfs::FileSystem for FileSystem {
         MountType::NoDevice
     }
 
-    fn init_super(&mut self) -> FSResult<()> {
+    fn max_file_size(&self) -> u64 {
+        MAX_FILE_SIZE
+    }
+
+    fn init_super(&mut self, _dev: Option<Arc<dyn BlockDevice + Send + Sync>>) -> FSResult<()> {
         let mut superblock = self.superblock.write();
 
         let root = vfs::SuperBlock::create_inode(&mut *superblock)?;
         superblock.root = root.num;
-        superblock.inodes.get_mut(&root.num).unwrap().mode = vfs::Mode::DIRECTORY;
+        superblock
+            .inodes
+            .get_mut(&root.num)
+            .ok_or(vfs::FSError::MissingInode)?
+            .mode = vfs::Mode::DIRECTORY;
 
         Ok(())
     }
@@ -64,23 +82,25 @@ struct SuperBlock {
 
 impl vfs::SuperBlock for SuperBlock {
     fn root(&self) -> FSResult<vfs::Inode> {
-        Ok(vfs::Inode::from(self.inodes[&self.root].clone()))
+        self.inodes
+            .get(&self.root)
+            .map(|inode| vfs::Inode::from(inode.clone()))
+            .ok_or(vfs::FSError::MissingInode)
     }
 
     fn create_inode(&mut self) -> FSResult<vfs::Inode> {
-        let inode = Inode::default();
-
-        let key = self.count;
-        self.inodes.insert(key, inode);
-        let inode = self.inodes.get_mut(&key).unwrap();
-
-        inode.num = key;
-        inode.creation_time = crate::time::TICKS.get();
-        inode.last_access = inode.creation_time;
-        inode.last_modification = inode.creation_time;
+        let now = now();
+        let inode = Inode {
+            num: self.count,
+            creation_time: now,
+            last_access: now,
+            last_modification: now,
+            ..Inode::default()
+        };
 
+        self.inodes.insert(inode.num, inode.clone());
         self.count += 1;
-        Ok(vfs::Inode::from(self.inodes[&key].clone()))
+        Ok(vfs::Inode::from(inode))
     }
 
     fn get_inode(&self, inode_n: u64) -> FSResult<Option<vfs::Inode>> {
@@ -90,19 +110,34 @@ impl vfs::SuperBlock for SuperBlock {
             .map(|inode| vfs::Inode::from(inode.clone())))
     }
 
-    fn destroy_inode(&mut self, _inode_n: u64) -> FSResult<()> {
-        todo!()
+    /// Removes an unlinked inode. Its number is never reused.
+    fn destroy_inode(&mut self, inode_n: u64) -> FSResult<()> {
+        let inode = self
+            .inodes
+            .get(&inode_n)
+            .ok_or(vfs::FSError::MissingInode)?;
+        assert!(inode.nlink == 0, "destroying inode {inode_n} with links");
+
+        let inode = self.inodes.remove(&inode_n).unwrap();
+
+        // vfs inodes may still share the blocks, free them now
+        inode.blocks.write().clear();
+        Ok(())
     }
 
     fn write_inode(&mut self, inode: &vfs::Inode) -> FSResult<()> {
-        let r_inode = inode
+        let mut r_inode = inode
             .private
             .downcast_ref::<Inode>()
-            .ok_or(vfs::FSError::WrongInode)?;
+            .ok_or(vfs::FSError::WrongInode)?
+            .clone();
+
+        // Pick up any metadata changed through the vfs inode
+        r_inode.set_metadata(&inode.metadata());
 
         match self.inodes.entry(r_inode.num) {
             Entry::Occupied(mut e) => {
-                *e.get_mut() = r_inode.clone();
+                *e.get_mut() = r_inode;
                 Ok(())
             }
             Entry::Vacant(_) => Err(vfs::FSError::MissingInode),
@@ -122,13 +157,60 @@ struct Inode {
     size: u64,
     nlink: u16,
 
-    blocks: Arc<RwLock<Vec<Box<[u8; BLOCK_SIZE]>>>>,
+    blocks: Arc<RwLock<Blocks>>,
+    /// Number of entries, if this is a directory
+    entries: u64,
 
     last_access: u64,
     creation_time: u64,
     last_modification: u64,
 }
 
+impl Inode {
+    /// The ramfs inode of `inode`, with any metadata changed through the vfs inode.
+    ///
+    /// Operations rebuild the vfs inode from the ramfs inode, so changes made through the vfs
+    /// inode only, like the size after a write, must be picked up first.
+    fn synced(inode: &mut vfs::Inode) -> FSResult<&mut Self> {
+        let metadata = inode.metadata();
+        let i: &mut Self = inode
+            .private
+            .downcast_mut()
+            .ok_or(vfs::FSError::WrongInode)?;
+        i.set_metadata(&metadata);
+        Ok(i)
+    }
+
+    const fn set_metadata(&mut self, metadata: &vfs::Metadata) {
+        self.mode = metadata.mode;
+        self.permission = metadata.permission;
+        self.user_id = metadata.user_id;
+        self.group_id = metadata.group_id;
+        self.size = metadata.size;
+        self.nlink = metadata.nlink;
+        self.last_access = metadata.last_access_time;
+        self.creation_time = metadata.creation_time;
+        self.last_modification = metadata.last_modification_time;
+    }
+
+    fn metadata(&self) -> vfs::Metadata {
+        vfs::Metadata {
+            mode: self.mode,
+            permission: self.permission,
+            user_id: self.user_id,
+            group_id: self.group_id,
+            inode: self.num,
+            size: self.size,
+            nlink: self.nlink,
+            blocks: self.blocks.read().len() as u64,
+            block_size: BLOCK_SIZE as u64,
+            last_access_time: self.last_access,
+            creation_time: self.creation_time,
+            last_modification_time: self.last_modification,
+        }
+    }
+}
+
 impl From<Inode> for vfs::Inode {
     fn from(value: Inode) -> Self {
         let blocks = value.blocks.read().len() as u64;
@@ -141,9 +223,11 @@ impl From<Inode> for vfs::Inode {
             size: value.size,
             nlink: value.nlink,
             blocks,
+            entry_count: (value.mode == vfs::Mode::DIRECTORY).then_some(value.entries),
             last_access_time: value.last_access,
             creation_time: value.creation_time,
             last_modification_time: value.last_modification,
+            block_size: BLOCK_SIZE as u64,
             ops: &InodeOps,
             private: Box::new(value),
         }
@@ -153,13 +237,12 @@ impl From<Inode> for vfs::Inode {
 pub struct InodeOps;
 
 impl InodeOps {
-    fn append_dir_entry(inode: &Inode, entry: DirEntry) {
-        let mut blocks = inode.blocks.write();
+    fn append_dir_entry(blocks: &mut Blocks, entry: DirEntry) {
         let mut iter = blocks
-            .iter_mut()
+            .values_mut()
             .rev()
-            .flat_map(|block| block.chunks_exact_mut(core::mem::size_of::<DirEntry>()))
-            .map(|bytes| DirEntry::from_bytes_mut(bytes.try_into().unwrap()))
+            .flat_map(|block| block.as_chunks_mut::<DIR_ENTRY_SIZE>().0)
+            .map(DirEntry::from_bytes_mut)
             .filter(|dir_entry| dir_entry.inode == 0);
         if let Some(e) = iter.next() {
             *e = entry;
@@ -167,7 +250,7 @@ impl InodeOps {
             let mut block = Box::new([0u8; BLOCK_SIZE]);
             block[..core::mem::size_of::<DirEntry>()]
                 .copy_from_slice(&entry.to_bytes()[..core::mem::size_of::<DirEntry>()]);
-            blocks.push(block);
+            blocks.insert(blocks.len() as u64, block);
         }
     }
 
@@ -195,21 +278,14 @@ impl InodeOps {
             }
         }
 
-        let i_dst: &mut Inode = dst.private.downcast_mut().ok_or(vfs::FSError::WrongInode)?;
-        let i_parent: &mut Inode = i_vfs_parent
-            .private
-            .downcast_mut()
-            .ok_or(vfs::FSError::WrongInode)?;
+        let i_dst = Inode::synced(dst)?;
+        let i_parent = Inode::synced(i_vfs_parent)?;
 
         // Add file to parent directory
-        let mut entry = DirEntry {
-            inode: dst.num,
-            length: path.len() as u8,
-            name: [0; 247],
-        };
-        entry.name[..path.len()].copy_from_slice(path.as_bytes());
-
-        Self::append_dir_entry(i_parent, entry);
+        let entry = DirEntry::new(i_dst.num, path)?;
+        Self::append_dir_entry(&mut i_parent.blocks.write(), entry);
+        i_parent.entries += 1;
+        i_dst.nlink += 1;
 
         // Inherit permissions from parent
         if inherit_permissions {
@@ -217,7 +293,7 @@ impl InodeOps {
         }
 
         // Update inode times
-        let now = crate::time::TICKS.get();
+        let now = now();
         i_dst.last_modification = now;
         i_dst.last_access = now;
         i_parent.last_modification = now;
@@ -276,6 +352,13 @@ impl vfs::InodeOps for InodeOps {
         parent: &DEntry,
         path: Component,
     ) -> FSResult<()> {
+        let s_src = src.as_bytes();
+
+        // Check if path is too long, before the link is added to the parent
+        if s_src.len() > BLOCK_SIZE {
+            return Err(vfs::FSError::BadPath);
+        }
+
         let mut i_vfs_parent = parent.inode_mut();
 
         let (i_dst, i_parent) = {
@@ -284,21 +367,14 @@ impl vfs::InodeOps for InodeOps {
             // Set to symbolic link
             i_dst.mode = vfs::Mode::SYMBOLIC_LINK;
 
-            let s_src = src.as_str();
-
-            // Check if path is too long
-            if s_src.len() > BLOCK_SIZE {
-                return Err(vfs::FSError::BadPath);
-            }
-
             // Set size to length of path
             i_dst.size = s_src.len() as u64;
 
             // Write path to first block
             let mut block = Box::new([0u8; BLOCK_SIZE]);
-            block[..s_src.len()].copy_from_slice(s_src.as_bytes());
-            let mut blocks = i_dst.blocks.write();
-            blocks.push(block);
+            block[..s_src.len()].copy_from_slice(s_src);
+            // Released before the conversion below reads the blocks
+            i_dst.blocks.write().insert(0, block);
 
             (i_dst.clone().into(), i_parent.clone().into())
         };
@@ -310,18 +386,314 @@ impl vfs::InodeOps for InodeOps {
         Ok(())
     }
 
-    fn unlink(&self, _dst: &mut vfs::Inode, _parent: &DEntry) -> FSResult<()> {
-        Err(vfs::FSError::Unimplemented)
+    fn unlink(&self, dst: &mut vfs::Inode, parent: &DEntry, path: Component) -> FSResult<()> {
+        let Component::Normal(name) = path else {
+            return Err(vfs::FSError::BadPath);
+        };
+        let mut i_vfs_parent = parent.inode_mut();
+
+        if i_vfs_parent.mode != vfs::Mode::DIRECTORY {
+            return Err(vfs::FSError::NotDirectory);
+        }
+
+        let i_parent = Inode::synced(&mut i_vfs_parent)?;
+        let i_dst = Inode::synced(dst)?;
+
+        // Clear the entry, so the slot is reused by the next entry added. Other hard links to
+        // the inode may share the directory.
+        {
+            let mut entries = i_parent.blocks.write();
+            let entry = dir_entries_mut(&mut entries)
+                .find(|entry| entry.name() == name.as_bytes() && entry.inode == i_dst.num)
+                .ok_or(vfs::FSError::NoEntry)?;
+            entry.inode = 0;
+            entry.length = 0;
+            entry.name.fill(0);
+        }
+        i_parent.entries -= 1;
+
+        // The data is freed when the inode is destroyed, open files may still use it
+        i_dst.nlink = i_dst.nlink.saturating_sub(1);
+
+        // Update inode times
+        let now = now();
+        i_dst.last_modification = now;
+        i_parent.last_modification = now;
+        i_parent.last_access = now;
+
+        // Update vfs inodes
+        let (i_dst, i_parent) = (i_dst.clone().into(), i_parent.clone().into());
+        *dst = i_dst;
+        *i_vfs_parent = i_parent;
+
+        Ok(())
     }
 
     fn rename(
         &self,
-        _src: &mut vfs::Inode,
-        _src_p: &DEntry,
-        _dst_p: &DEntry,
-        _path: Component,
+        src: &mut vfs::Inode,
+        src_p: &DEntry,
+        src_path: Component,
+        dst_p: &DEntry,
+        path: Component,
+        dst: Option<&mut vfs::Inode>,
     ) -> FSResult<()> {
-        Err(vfs::FSError::Unimplemented)
+        let (Component::Normal(src_name), Component::Normal(name)) = (src_path, path) else {
+            return Err(vfs::FSError::BadPath);
+        };
+
+        // Lock both parents in a consistent order to avoid deadlocking with a reverse rename
+        let src_p_num = src_p.inode().num;
+        let dst_p_num = dst_p.inode().num;
+        let (mut i_vfs_src_p, mut i_vfs_dst_p) = match src_p_num.cmp(&dst_p_num) {
+            core::cmp::Ordering::Equal => (None, dst_p.inode_mut()),
+            core::cmp::Ordering::Less => {
+                let i_src_p = src_p.inode_mut();
+                (Some(i_src_p), dst_p.inode_mut())
+            }
+            core::cmp::Ordering::Greater => {
+                let i_dst_p = dst_p.inode_mut();
+                (Some(src_p.inode_mut()), i_dst_p)
+            }
+        };
+
+        if i_vfs_dst_p.mode != vfs::Mode::DIRECTORY {
+            return Err(vfs::FSError::NotDirectory);
+        }
+
+        let i_dst_p = Inode::synced(&mut i_vfs_dst_p)?;
+        let mut i_src_p = match &mut i_vfs_src_p {
+            Some(i) => Some(Inode::synced(i)?),
+            None => None,
+        };
+
+        // Both directories stay locked for the whole swap, so the destination name is never
+        // missing and the source name is never visible alongside it.
+        let dst_blocks = Arc::clone(&i_dst_p.blocks);
+        let src_blocks = i_src_p.as_ref().map(|i| Arc::clone(&i.blocks));
+        let mut dst_entries = dst_blocks.write();
+        let mut src_entries = src_blocks.as_ref().map(|blocks| blocks.write());
+
+        // The destination name must still lead to the inode the caller locked
+        let replaced = dir_entries(&dst_entries)
+            .find(|entry| entry.name() == name.as_bytes())
+            .map(|entry| entry.inode);
+        match (replaced, &dst) {
+            (Some(inode_n), Some(dst)) if inode_n == dst.num => {}
+            (Some(_), None) => return Err(vfs::FSError::Exists),
+            (None, None) => {}
+            _ => return Err(vfs::FSError::NoEntry),
+        }
+
+        if let Some(dst) = &dst {
+            match (src.is_dir(), dst.is_dir()) {
+                (true, true) if !dst.is_empty_dir()? => return Err(vfs::FSError::NotEmpty),
+                (false, true) => return Err(vfs::FSError::IsDirectory),
+                (true, false) => return Err(vfs::FSError::NotDirectory),
+                _ => {}
+            }
+        }
+
+        let new_entry = DirEntry::new(src.num, name)?;
+
+        // Remove the source name, other hard links to the inode may share its directory
+        {
+            let entries = src_entries.as_deref_mut().unwrap_or(&mut dst_entries);
+            let entry = dir_entries_mut(entries)
+                .find(|entry| entry.name() == src_name.as_bytes() && entry.inode == src.num)
+                .ok_or(vfs::FSError::NoEntry)?;
+            entry.inode = 0;
+            entry.length = 0;
+        }
+
+        // Point the destination name at the source inode
+        let existing =
+            dir_entries_mut(&mut dst_entries).find(|entry| entry.name() == name.as_bytes());
+        match existing {
+            Some(entry) => entry.inode = src.num,
+            None => Self::append_dir_entry(&mut dst_entries, new_entry),
+        }
+
+        // The replaced inode loses its link before the new entry is visible to anyone. Its data
+        // is freed when it's destroyed, open files may still use it.
+        if let Some(dst) = dst {
+            let i_dst = Inode::synced(dst)?;
+            i_dst.nlink = i_dst.nlink.saturating_sub(1);
+            i_dst.last_modification = now();
+            *dst = i_dst.clone().into();
+        }
+
+        drop(dst_entries);
+        drop(src_entries);
+
+        // Update entry counts, a replaced destination keeps its entry
+        if replaced.is_none() {
+            i_dst_p.entries += 1;
+        }
+        match &mut i_src_p {
+            Some(i_src_p) => i_src_p.entries -= 1,
+            None => i_dst_p.entries -= 1,
+        }
+
+        // Update inode times
+        let now = now();
+        i_dst_p.last_modification = now;
+        i_dst_p.last_access = now;
+        let i_dst_p = i_dst_p.clone();
+        let i_src_p = i_src_p.map(|i| {
+            i.last_modification = now;
+            i.last_access = now;
+            i.clone()
+        });
+
+        // Update vfs inodes
+        *i_vfs_dst_p = i_dst_p.into();
+        if let (Some(i_vfs_src_p), Some(i_src_p)) = (&mut i_vfs_src_p, i_src_p) {
+            **i_vfs_src_p = i_src_p.into();
+        }
+
+        Ok(())
+    }
+
+    fn read(&self, inode: &vfs::Inode, offset: u64, buf: &mut [u8]) -> FSResult<usize> {
+        let i: &Inode = inode
+            .private
+            .downcast_ref()
+            .ok_or(vfs::FSError::WrongInode)?;
+
+        if i.mode == vfs::Mode::DIRECTORY {
+            return Err(vfs::FSError::IsDirectory);
+        }
+
+        // Short read at the end of the file
+        let Some(remaining) = inode.size.checked_sub(offset) else {
+            return Ok(0);
+        };
+        let len = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+
+        let blocks = i.blocks.read();
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let blk_off = (pos % BLOCK_SIZE as u64) as usize;
+            let n = (BLOCK_SIZE - blk_off).min(len - done);
+
+            let out = &mut buf[done..done + n];
+            match blocks.get(&(pos / BLOCK_SIZE as u64)) {
+                Some(block) => out.copy_from_slice(&block[blk_off..blk_off + n]),
+                // Holes read as zeros
+                None => out.fill(0),
+            }
+            done += n;
+        }
+
+        Ok(len)
+    }
+
+    fn write(&self, inode: &mut vfs::Inode, offset: u64, buf: &[u8]) -> FSResult<usize> {
+        let i: &Inode = inode
+            .private
+            .downcast_ref()
+            .ok_or(vfs::FSError::WrongInode)?;
+
+        if i.mode == vfs::Mode::DIRECTORY {
+            return Err(vfs::FSError::IsDirectory);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if offset >= MAX_FILE_SIZE {
+            return Err(vfs::FSError::FileTooLarge);
+        }
+
+        // Writes crossing the size limit are short
+        let buf = &buf[..buf.len().min((MAX_FILE_SIZE - offset) as usize)];
+        let end = offset + buf.len() as u64;
+
+        // Only the blocks being written are allocated, anything skipped over stays a hole
+        let allocated = {
+            let mut blocks = i.blocks.write();
+            let mut done = 0;
+            while done < buf.len() {
+                let pos = offset + done as u64;
+                let blk_off = (pos % BLOCK_SIZE as u64) as usize;
+                let n = (BLOCK_SIZE - blk_off).min(buf.len() - done);
+
+                let block = blocks
+                    .entry(pos / BLOCK_SIZE as u64)
+                    .or_insert_with(|| Box::new([0u8; BLOCK_SIZE]));
+                block[blk_off..blk_off + n].copy_from_slice(&buf[done..done + n]);
+                done += n;
+            }
+            blocks.len() as u64
+        };
+
+        // Update vfs inode
+        let now = now();
+        inode.size = inode.size.max(end);
+        inode.blocks = allocated;
+        inode.last_modification_time = now;
+        inode.last_access_time = now;
+
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, inode: &mut vfs::Inode, size: u64) -> FSResult<()> {
+        let i: &Inode = inode
+            .private
+            .downcast_ref()
+            .ok_or(vfs::FSError::WrongInode)?;
+
+        if i.mode != vfs::Mode::REGULAR_FILE {
+            return Err(vfs::FSError::NotSupported);
+        }
+        if size > MAX_FILE_SIZE {
+            return Err(vfs::FSError::FileTooLarge);
+        }
+
+        // Growing leaves a hole, only shrinking touches the blocks
+        let allocated = {
+            let mut blocks = i.blocks.write();
+            if size < inode.size {
+                let kept = size.div_ceil(BLOCK_SIZE as u64);
+                blocks.split_off(&kept);
+
+                // Zero the tail of the last block, so growing again reads zeros
+                let blk_off = (size % BLOCK_SIZE as u64) as usize;
+                if blk_off != 0 {
+                    if let Some(block) = blocks.get_mut(&(kept - 1)) {
+                        block[blk_off..].fill(0);
+                    }
+                }
+            }
+            blocks.len() as u64
+        };
+
+        // Update vfs inode
+        inode.size = size;
+        inode.blocks = allocated;
+        inode.last_modification_time = now();
+
+        Ok(())
+    }
+
+    fn readlink(&self, inode: &vfs::Inode) -> FSResult<PathBuf> {
+        let i: &Inode = inode
+            .private
+            .downcast_ref()
+            .ok_or(vfs::FSError::WrongInode)?;
+
+        if i.mode != vfs::Mode::SYMBOLIC_LINK {
+            return Err(vfs::FSError::NotSupported);
+        }
+
+        // The target is stored in the first block
+        let blocks = i.blocks.read();
+        let block = blocks.get(&0).ok_or(vfs::FSError::BadPath)?;
+        let target = block.get(..i.size as usize).ok_or(vfs::FSError::BadPath)?;
+        PathBuf::from_bytes(target)
     }
 
     fn mkdir(&self, dst: &mut vfs::Inode, parent: &DEntry, path: Component) -> FSResult<()> {
@@ -356,6 +728,34 @@ impl vfs::InodeOps for InodeOps {
         let iter = DirIterator::new(i);
         Ok(vfs::file_iter::FileIter::new(inode, Box::new(iter)))
     }
+
+    fn readdir_plus(&self, dir: &DEntry, out: &mut Vec<(PathBuf, vfs::Metadata)>) -> FSResult<()> {
+        let inode = dir.inode();
+        let i: &Inode = inode
+            .private
+            .downcast_ref()
+            .ok_or(vfs::FSError::WrongInode)?;
+
+        if i.mode != vfs::Mode::DIRECTORY {
+            return Err(vfs::FSError::NotDirectory);
+        }
+
+        // Read the children straight from the superblock instead of building a vfs inode each
+        let fs = dir.fs_arc();
+        let sb = fs.superblock();
+        let sb = sb.read();
+        let sb: &SuperBlock = (&*sb as &dyn Any)
+            .downcast_ref()
+            .ok_or(vfs::FSError::WrongInode)?;
+
+        let blocks = i.blocks.read();
+        for entry in dir_entries(&blocks) {
+            let inode_n = entry.inode;
+            let child = sb.inodes.get(&inode_n).ok_or(vfs::FSError::MissingInode)?;
+            out.push((PathBuf::from_bytes(entry.name())?, child.metadata()));
+        }
+        Ok(())
+    }
 }
 
 const DIR_ENTRY_SIZE: usize = core::mem::size_of::<DirEntry>();
@@ -370,14 +770,32 @@ pub struct DirEntry {
 assert_eq_size!(DirEntry, [u8; 256]);
 
 impl DirEntry {
+    fn new(inode: u64, name: &str) -> FSResult<Self> {
+        let mut entry = Self {
+            inode,
+            length: name.len().try_into().map_err(|_| vfs::FSError::BadPath)?,
+            name: [0; 247],
+        };
+        entry
+            .name
+            .get_mut(..name.len())
+            .ok_or(vfs::FSError::BadPath)?
+            .copy_from_slice(name.as_bytes());
+        Ok(entry)
+    }
+
+    fn name(&self) -> &[u8] {
+        &self.name[..self.length as usize]
+    }
+
     const fn from_bytes(bytes: &[u8; DIR_ENTRY_SIZE]) -> &Self {
         // SAFETY: DirEntry is repr(C, packed) and has the same size as [u8; 256]
-        unsafe { &*(bytes as *const [u8; DIR_ENTRY_SIZE]).cast::<Self>() }
+        unsafe { &*core::ptr::from_ref(bytes).cast::<Self>() }
     }
 
     fn from_bytes_mut(bytes: &mut [u8; DIR_ENTRY_SIZE]) -> &mut Self {
         // SAFETY: DirEntry is repr(C, packed) and has the same size as [u8; 256]
-        unsafe { &mut *(bytes as *mut [u8; DIR_ENTRY_SIZE]).cast::<Self>() }
+        unsafe { &mut *core::ptr::from_mut(bytes).cast::<Self>() }
     }
 
     fn to_bytes(&self) -> [u8; DIR_ENTRY_SIZE] {
@@ -389,10 +807,33 @@ impl DirEntry {
     }
 }
 
+/// Seconds since the Unix epoch, for inode timestamps.
+fn now() -> u64 {
+    crate::time::unix_timestamp().unwrap_or_else(crate::rtc::now)
+}
+
+/// Iterates over the used entries of a locked directory.
+fn dir_entries(blocks: &Blocks) -> impl Iterator<Item = &DirEntry> {
+    blocks
+        .values()
+        .flat_map(|block| block.as_chunks::<DIR_ENTRY_SIZE>().0)
+        .map(DirEntry::from_bytes)
+        .filter(|entry| entry.inode != 0 && entry.length != 0)
+}
+
+/// Iterates mutably over the used entries of a locked directory.
+fn dir_entries_mut(blocks: &mut Blocks) -> impl Iterator<Item = &mut DirEntry> {
+    blocks
+        .values_mut()
+        .flat_map(|block| block.as_chunks_mut::<DIR_ENTRY_SIZE>().0)
+        .map(DirEntry::from_bytes_mut)
+        .filter(|entry| entry.inode != 0 && entry.length != 0)
+}
+
 struct DirIterator<'a> {
     inode: &'a Inode,
-    lock: RwLockReadGuard<'a, Vec<Box<[u8; BLOCK_SIZE]>>>,
-    blkidx: usize,
+    lock: RwLockReadGuard<'a, Blocks>,
+    blkidx: u64,
     entryidx: usize,
 }
 
@@ -412,9 +853,7 @@ impl Iterator for DirIterator<'_> {
     type Item = (PathBuf, u64);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let Some(blk) = self.lock.get(self.blkidx) else {
-            return None;
-        };
+        let blk = self.lock.get(&self.blkidx)?;
         // Safety: BLK_SIZE (4096) is a multiple of DIR_ENTRY_SIZE (256)
         let chunks: &[[u8; DIR_ENTRY_SIZE]] =
             unsafe { blk.as_chunks_unchecked::<DIR_ENTRY_SIZE>() };
@@ -430,11 +869,221 @@ impl Iterator for DirIterator<'_> {
         if entry.inode == 0 || entry.length == 0 {
             self.next()
         } else {
-            let path =
-                PathBuf::from(core::str::from_utf8(&entry.name[..entry.length as usize]).unwrap());
+            // Names are only ever written from `&str`
+            let path = PathBuf::from_bytes(entry.name()).expect("ramfs names are valid UTF-8");
             Some((path, entry.inode))
         }
     }
 }
 
 impl FileIterator for DirIterator<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, vec, vec::Vec};
+
+    use crate::fs::{
+        self,
+        file::OpenFlags,
+        tests::setup,
+        vfs::{self, FSError},
+    };
+
+    /// Names in the directory at `path`, in the order of their slots
+    fn list(path: &str) -> Vec<String> {
+        let dir = fs::lookup(path).unwrap();
+        let inode = dir.inode();
+        inode
+            .list()
+            .unwrap()
+            .map(|(name, _)| name.as_str().into())
+            .collect()
+    }
+
+    #[test]
+    fn unlinked_inode_is_destroyed() {
+        let _guard = setup();
+        fs::mkdir("/ramfs_destroy").unwrap();
+        fs::write("/ramfs_destroy/file", b"data").unwrap();
+        let num = fs::stat("/ramfs_destroy/file").unwrap().inode;
+        let sb = fs::lookup("/").unwrap().fs_arc().superblock();
+        assert!(sb.read().get_inode(num).unwrap().is_some());
+
+        // Dropping the last link destroys the inode
+        fs::unlink("/ramfs_destroy/file").unwrap();
+        assert!(sb.read().get_inode(num).unwrap().is_none());
+        assert_eq!(
+            sb.write().destroy_inode(num).unwrap_err(),
+            FSError::MissingInode
+        );
+
+        fs::write("/ramfs_destroy/new", b"").unwrap();
+        assert!(fs::stat("/ramfs_destroy/new").unwrap().inode > num);
+    }
+
+    #[test]
+    fn unlinked_slot_is_reused() {
+        let _guard = setup();
+        fs::mkdir("/ramfs_unlink").unwrap();
+        for path in ["/ramfs_unlink/a", "/ramfs_unlink/b", "/ramfs_unlink/c"] {
+            fs::write(path, b"").unwrap();
+        }
+
+        fs::unlink("/ramfs_unlink/b").unwrap();
+        assert_eq!(list("/ramfs_unlink"), ["a", "c"]);
+
+        // The next entry takes the freed slot instead of going after `c`
+        fs::write("/ramfs_unlink/d", b"").unwrap();
+        assert_eq!(list("/ramfs_unlink"), ["a", "d", "c"]);
+    }
+
+    #[test]
+    fn entry_count_follows_create_and_unlink() {
+        let _guard = setup();
+        fs::mkdir("/ramfs_count").unwrap();
+        let count = || fs::lookup("/ramfs_count").unwrap().inode().entry_count();
+        let is_empty = || fs::lookup("/ramfs_count").unwrap().inode().is_empty_dir();
+        assert_eq!(count(), Some(0));
+        assert_eq!(is_empty(), Ok(true));
+
+        fs::write("/ramfs_count/a", b"").unwrap();
+        fs::write("/ramfs_count/b", b"").unwrap();
+        assert_eq!(count(), Some(2));
+        assert_eq!(is_empty(), Ok(false));
+
+        fs::unlink("/ramfs_count/a").unwrap();
+        assert_eq!(count(), Some(1));
+        fs::unlink("/ramfs_count/b").unwrap();
+        assert_eq!(count(), Some(0));
+        assert_eq!(is_empty(), Ok(true));
+
+        // Files have no entries, and are never empty directories
+        fs::write("/ramfs_count/file", b"").unwrap();
+        let file = fs::lookup("/ramfs_count/file").unwrap();
+        assert_eq!(file.inode().entry_count(), None);
+        assert_eq!(file.inode().is_empty_dir(), Ok(false));
+    }
+
+    #[test]
+    fn readdir_plus_returns_each_entrys_metadata() {
+        let _guard = setup();
+        fs::mkdir("/ramfs_plus").unwrap();
+        fs::write("/ramfs_plus/empty", b"").unwrap();
+        fs::write("/ramfs_plus/small", b"hello").unwrap();
+        fs::write("/ramfs_plus/large", vec![1; 5000]).unwrap();
+        fs::mkdir("/ramfs_plus/dir").unwrap();
+
+        let mut entries = Vec::new();
+        fs::lookup("/ramfs_plus")
+            .unwrap()
+            .readdir_plus(&mut entries)
+            .unwrap();
+        let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["empty", "small", "large", "dir"]);
+
+        for (name, metadata) in &entries {
+            let path = alloc::format!("/ramfs_plus/{name}");
+            assert_eq!(*metadata, fs::stat(path.as_str()).unwrap(), "{path}");
+        }
+        let sizes: Vec<u64> = entries.iter().map(|(_, metadata)| metadata.size).collect();
+        assert_eq!(sizes[..3], [0, 5, 5000]);
+        assert_eq!(entries[2].1.blocks, 2);
+        assert_eq!(entries[3].1.mode, vfs::Mode::DIRECTORY);
+    }
+
+    #[test]
+    fn data_crosses_block_boundaries() {
+        let _guard = setup();
+        fs::mkdir("/ramfs_blocks").unwrap();
+        fs::write("/ramfs_blocks/file", b"").unwrap();
+        let data: Vec<u8> = (0..6000_u32).map(|i| (i % 251) as u8).collect();
+
+        let dentry = fs::lookup("/ramfs_blocks/file").unwrap();
+        let mut inode = dentry.inode_mut();
+        assert_eq!(inode.write(0, &data[..3000]).unwrap(), 3000);
+        // Ends in the second block
+        assert_eq!(inode.write(3000, &data[3000..]).unwrap(), 3000);
+        assert_eq!(inode.size(), 6000);
+        assert_eq!(inode.blocks(), 2);
+
+        // Across the end of the first block
+        let mut buf = [0; 200];
+        assert_eq!(inode.read(4000, &mut buf).unwrap(), 200);
+        assert_eq!(buf, data[4000..4200]);
+
+        // Reads past the end are short
+        assert_eq!(inode.read(5900, &mut buf).unwrap(), 100);
+        assert_eq!(buf[..100], data[5900..]);
+        assert_eq!(inode.read(6000, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn sparse_write_only_allocates_the_written_block() {
+        let _guard = setup();
+        fs::mkdir("/ramfs_sparse").unwrap();
+        fs::write("/ramfs_sparse/file", b"").unwrap();
+
+        let dentry = fs::lookup("/ramfs_sparse/file").unwrap();
+        let mut inode = dentry.inode_mut();
+        assert_eq!(inode.write(0x10_0000, b"x").unwrap(), 1);
+        assert_eq!(inode.size(), 0x10_0001);
+        assert_eq!(inode.blocks(), 1);
+
+        // The hole before the write reads as zeros
+        let mut buf = [0xFF; 16];
+        assert_eq!(inode.read(0x8_0000, &mut buf).unwrap(), 16);
+        assert_eq!(buf, [0; 16]);
+        assert_eq!(inode.read(0x10_0000, &mut buf).unwrap(), 1);
+        assert_eq!(buf[0], b'x');
+        assert_eq!(inode.blocks(), 1);
+    }
+
+    #[test]
+    fn writes_stop_at_the_size_limit() {
+        let _guard = setup();
+        fs::mkdir("/ramfs_limit").unwrap();
+        fs::write("/ramfs_limit/file", b"").unwrap();
+
+        let dentry = fs::lookup("/ramfs_limit/file").unwrap();
+        assert_eq!(dentry.fs().max_file_size(), super::MAX_FILE_SIZE);
+        let mut inode = dentry.inode_mut();
+        assert_eq!(inode.write(super::MAX_FILE_SIZE - 1, b"x").unwrap(), 1);
+        assert_eq!(inode.size(), super::MAX_FILE_SIZE);
+
+        // One byte more fails, a write crossing the limit is short
+        assert_eq!(
+            inode.write(super::MAX_FILE_SIZE, b"y").unwrap_err(),
+            FSError::FileTooLarge
+        );
+        assert_eq!(inode.write(super::MAX_FILE_SIZE - 1, b"yz").unwrap(), 1);
+        assert_eq!(inode.size(), super::MAX_FILE_SIZE);
+        assert_eq!(
+            inode.truncate(super::MAX_FILE_SIZE + 1).unwrap_err(),
+            FSError::FileTooLarge
+        );
+    }
+
+    #[test]
+    fn truncate_zeroes_the_cut_data() {
+        let _guard = setup();
+        fs::mkdir("/ramfs_truncate").unwrap();
+        fs::write("/ramfs_truncate/file", vec![0xAA; 5000]).unwrap();
+
+        let mut file = fs::open("/ramfs_truncate/file", OpenFlags::WRITE).unwrap();
+        file.set_len(100).unwrap();
+        assert_eq!(fs::read("/ramfs_truncate/file").unwrap(), [0xAA; 100]);
+
+        // Both the cut tail of the kept block and the dropped block read as zeros
+        file.set_len(6000).unwrap();
+        let data = fs::read("/ramfs_truncate/file").unwrap();
+        assert_eq!(data.len(), 6000);
+        assert_eq!(data[..100], [0xAA; 100]);
+        assert!(data[100..].iter().all(|&b| b == 0));
+
+        let dir = fs::lookup("/ramfs_truncate").unwrap();
+        assert_eq!(
+            dir.inode_mut().truncate(0).unwrap_err(),
+            FSError::NotSupported
+        );
+    }
+}
@@ -1,3 +1,7 @@
+use core::fmt::{Display, Formatter};
+
+use crate::fs::path::PathBuf;
+
 pub type FSResult<T, E = FSError> = Result<T, E>;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -14,10 +18,94 @@ pub enum FSError {
     WrongInode,
     /// Inode is not a directory
     NotDirectory,
+    /// Inode is a directory
+    IsDirectory,
+    /// Directory is not empty
+    NotEmpty,
+    /// Operation spans multiple file systems
+    CrossDevice,
+    /// Too many symbolic links were followed
+    TooManyLinks,
+    /// File descriptor is not open
+    BadDescriptor,
+    /// File descriptor table is full
+    TooManyFiles,
     /// File already exists
     Exists,
+    /// File system is in use
+    Busy,
+    /// Block device is missing
+    NoDevice,
+    /// Caller isn't allowed to perform the operation
+    PermissionDenied,
+    /// File would exceed the maximum file size
+    FileTooLarge,
+    /// Invalid argument, such as a seek before the start of a file
+    InvalidArgument,
+    /// Data isn't valid, such as non-UTF-8 text or a corrupted file system
+    InvalidData,
     /// Unimplemented
     Unimplemented,
     /// Not supported
     NotSupported,
 }
+
+impl FSError {
+    /// Attaches the offending path to the error.
+    pub fn at<P: Into<PathBuf>>(self, path: P) -> FsErrorCtx {
+        FsErrorCtx {
+            kind: self,
+            path: Some(path.into()),
+        }
+    }
+}
+
+impl Display for FSError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Self::BadPath => "Invalid path",
+            Self::NoEntry => "No such file or directory",
+            Self::NoMount => "No file system mounted",
+            Self::MissingInode => "Inode does not exist",
+            Self::WrongInode => "Inode belongs to a different file system",
+            Self::NotDirectory => "Not a directory",
+            Self::IsDirectory => "Is a directory",
+            Self::NotEmpty => "Directory not empty",
+            Self::CrossDevice => "Invalid cross-device link",
+            Self::TooManyLinks => "Too many levels of symbolic links",
+            Self::BadDescriptor => "Bad file descriptor",
+            Self::TooManyFiles => "Too many open files",
+            Self::Exists => "File exists",
+            Self::Busy => "Device or resource busy",
+            Self::NoDevice => "No such device",
+            Self::PermissionDenied => "Permission denied",
+            Self::FileTooLarge => "File too large",
+            Self::InvalidArgument => "Invalid argument",
+            Self::InvalidData => "Invalid data",
+            Self::Unimplemented => "Function not implemented",
+            Self::NotSupported => "Operation not supported",
+        })
+    }
+}
+
+/// [`FSError`] with the path that caused it, returned by the high-level `fs::` functions.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FsErrorCtx {
+    pub kind: FSError,
+    pub path: Option<PathBuf>,
+}
+
+impl From<FSError> for FsErrorCtx {
+    fn from(kind: FSError) -> Self {
+        Self { kind, path: None }
+    }
+}
+
+impl Display for FsErrorCtx {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        if let Some(path) = &self.path {
+            write!(f, "{path}: ")?;
+        }
+        Display::fmt(&self.kind, f)
+    }
+}
@@ -1,7 +1,7 @@
 mod error;
 pub mod file_iter;
 
-use alloc::{boxed::Box, sync::Arc};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
 use core::{
     any::Any,
     fmt::{Debug, Formatter},
@@ -12,9 +12,10 @@ use spin::lock_api::RwLock;
 
 pub use self::error::*;
 use crate::fs::{
+    block::BlockDevice,
     dentry::DEntry,
     mount::MountType,
-    path::{Component, Path},
+    path::{Component, Path, PathBuf},
     vfs::file_iter::FileIter,
 };
 
@@ -23,7 +24,17 @@ pub trait FileSystem {
 
     fn mount_type(&self) -> MountType;
 
-    fn init_super(&mut self) -> FSResult<()>;
+    /// Reads or sets up the superblock
+    ///
+    /// `dev` is the device being mounted, only given to [`MountType::BlockDevice`] file systems.
+    fn init_super(&mut self, dev: Option<Arc<dyn BlockDevice + Send + Sync>>) -> FSResult<()>;
+
+    /// Maximum size of a file in bytes
+    ///
+    /// Writes past it fail with [`FSError::FileTooLarge`].
+    fn max_file_size(&self) -> u64 {
+        u64::MAX
+    }
 
     /// Gets the superblock of the file system
     fn superblock(&self) -> Arc<RwLock<dyn SuperBlock + Send + Sync>>;
@@ -65,19 +76,65 @@ pub trait InodeOps {
         parent: &DEntry,
         path: Component,
     ) -> FSResult<()>;
-    /// Unlinks `dst` from `parent`
-    fn unlink(&self, dst: &mut Inode, parent: &DEntry) -> FSResult<()>;
-    /// Renames `src` to `dst` with `src_p` & `dst_p`
+    /// Unlinks `dst` from `parent`, where it's named `path`
+    ///
+    /// Other hard links to `dst` in `parent` are kept.
+    fn unlink(&self, dst: &mut Inode, parent: &DEntry, path: Component) -> FSResult<()>;
+    /// Renames `src` from `src_path` in `src_p` to `path` in `dst_p`
+    ///
+    /// If `path` already exists, `dst` must be its inode, locked by the caller and distinct from
+    /// `src`. It is atomically replaced and loses its link while both parents are locked. Users
+    /// must commit `dst` and destroy it once it has no links left.
     fn rename(
         &self,
         src: &mut Inode,
         src_p: &DEntry,
+        src_path: Component,
         dst_p: &DEntry,
         path: Component,
+        dst: Option<&mut Inode>,
     ) -> FSResult<()>;
 
+    /// Reads from `inode` at `offset` into `buf`, returning the number of bytes read
+    ///
+    /// Reads past the end of the file are short. Holes in sparse files read as zeros.
+    fn read(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> FSResult<usize>;
+    /// Writes `buf` to `inode` at `offset`, returning the number of bytes written
+    ///
+    /// Writing past the end of the file grows it, leaving a hole if `offset` is beyond the end.
+    /// Writes crossing [`FileSystem::max_file_size`] are short, and writes starting past it
+    /// fail with [`FSError::FileTooLarge`].
+    fn write(&self, inode: &mut Inode, offset: u64, buf: &[u8]) -> FSResult<usize>;
+    /// Sets the size of the regular file `inode` to `size`
+    ///
+    /// Shrinking discards everything past `size`, growing extends the file with zeros.
+    /// Directories and symbolic links fail with [`FSError::NotSupported`].
+    fn truncate(&self, inode: &mut Inode, size: u64) -> FSResult<()>;
+    /// Reads the target of the symbolic link `inode`
+    fn readlink(&self, inode: &Inode) -> FSResult<PathBuf>;
+
     fn mkdir(&self, dst: &mut Inode, parent: &DEntry, path: Component) -> FSResult<()>;
+    /// Lists the name and inode number of every entry in the directory `inode`
+    ///
+    /// Entries are in no particular order, which may change as the directory is modified.
+    /// Use [`fs::read_dir_sorted`](crate::fs::read_dir_sorted) for a stable listing.
     fn list<'b>(&self, inode: &'b Inode) -> FSResult<FileIter<'b>>;
+
+    /// Appends the name and metadata of every entry in `dir` to `out`
+    ///
+    /// The default implementation looks up each listed inode in the superblock.
+    fn readdir_plus(&self, dir: &DEntry, out: &mut Vec<(PathBuf, Metadata)>) -> FSResult<()> {
+        let inode = dir.inode();
+        let fs = dir.fs_arc();
+        let sb = fs.superblock();
+        let sb = sb.read();
+
+        for (name, inode_n) in self.list(&inode)? {
+            let child = sb.get_inode(inode_n)?.ok_or(FSError::MissingInode)?;
+            out.push((name, child.metadata()));
+        }
+        Ok(())
+    }
 }
 
 pub struct Inode {
@@ -100,6 +157,8 @@ pub struct Inode {
 
     /// The number of blocks used by the inode
     pub(super) blocks: u64,
+    /// The number of entries, for directories on file systems that track it
+    pub(super) entry_count: Option<u64>,
 
     /// The time the inode was last accessed
     pub(super) last_access_time: u64,
@@ -108,6 +167,9 @@ pub struct Inode {
     /// The time the inode was last modified
     pub(super) last_modification_time: u64,
 
+    /// The block size of the file system in bytes
+    pub(super) block_size: u64,
+
     /// Inode operations
     pub(super) ops: &'static (dyn InodeOps + Send + Sync),
 
@@ -115,6 +177,26 @@ pub struct Inode {
     pub(super) private: Box<dyn Any + Send + Sync>,
 }
 
+/// Metadata of an inode
+///
+/// `size` is the logical size of the file, while `blocks` only counts the blocks that are
+/// actually allocated, so a sparse file may use fewer than `size / block_size` blocks.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Metadata {
+    pub mode: Mode,
+    pub permission: Permission,
+    pub user_id: u16,
+    pub group_id: u16,
+    pub inode: u64,
+    pub size: u64,
+    pub nlink: u16,
+    pub blocks: u64,
+    pub block_size: u64,
+    pub last_access_time: u64,
+    pub creation_time: u64,
+    pub last_modification_time: u64,
+}
+
 bitflags! {
     #[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
     pub struct Mode: u8 {
@@ -155,6 +237,194 @@ impl Inode {
         self.mode.contains(Mode::DIRECTORY)
     }
 
+    /// The type of the inode
+    #[inline]
+    pub const fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// The permissions of the inode
+    #[inline]
+    pub const fn permission(&self) -> Permission {
+        self.permission
+    }
+
+    /// The user id of the owner
+    #[inline]
+    pub const fn user_id(&self) -> u16 {
+        self.user_id
+    }
+
+    /// The group id of the owner
+    #[inline]
+    pub const fn group_id(&self) -> u16 {
+        self.group_id
+    }
+
+    /// The number of the inode
+    #[inline]
+    pub const fn num(&self) -> u64 {
+        self.num
+    }
+
+    /// The size of the inode in bytes
+    #[inline]
+    pub const fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The number of hard links to the inode
+    #[inline]
+    pub const fn nlink(&self) -> u16 {
+        self.nlink
+    }
+
+    /// The number of blocks used by the inode
+    #[inline]
+    pub const fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    /// The block size of the file system in bytes
+    #[inline]
+    pub const fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /// The time the inode was last accessed
+    #[inline]
+    pub const fn last_access_time(&self) -> u64 {
+        self.last_access_time
+    }
+
+    /// The time the inode was created
+    #[inline]
+    pub const fn creation_time(&self) -> u64 {
+        self.creation_time
+    }
+
+    /// The time the inode was last modified
+    #[inline]
+    pub const fn last_modification_time(&self) -> u64 {
+        self.last_modification_time
+    }
+
+    /// Returns a snapshot of the inode's metadata
+    pub const fn metadata(&self) -> Metadata {
+        Metadata {
+            mode: self.mode,
+            permission: self.permission,
+            user_id: self.user_id,
+            group_id: self.group_id,
+            inode: self.num,
+            size: self.size,
+            nlink: self.nlink,
+            blocks: self.blocks,
+            block_size: self.block_size,
+            last_access_time: self.last_access_time,
+            creation_time: self.creation_time,
+            last_modification_time: self.last_modification_time,
+        }
+    }
+
+    #[inline]
+    pub const fn is_symlink(&self) -> bool {
+        self.mode.contains(Mode::SYMBOLIC_LINK)
+    }
+
+    /// Whether user `uid` in group `gid` may read the inode.
+    ///
+    /// See [`can_access`](Self::can_access).
+    #[inline]
+    pub const fn can_read(&self, uid: u16, gid: u16) -> bool {
+        self.can_access(
+            uid,
+            gid,
+            Permission::USER_READ,
+            Permission::GROUP_READ,
+            Permission::OTHER_READ,
+        )
+    }
+
+    /// Whether user `uid` in group `gid` may write the inode.
+    ///
+    /// See [`can_access`](Self::can_access).
+    #[inline]
+    pub const fn can_write(&self, uid: u16, gid: u16) -> bool {
+        self.can_access(
+            uid,
+            gid,
+            Permission::USER_WRITE,
+            Permission::GROUP_WRITE,
+            Permission::OTHER_WRITE,
+        )
+    }
+
+    /// Whether user `uid` in group `gid` may execute the inode, or search it if it's a
+    /// directory.
+    ///
+    /// See [`can_access`](Self::can_access).
+    #[inline]
+    pub const fn can_execute(&self, uid: u16, gid: u16) -> bool {
+        self.can_access(
+            uid,
+            gid,
+            Permission::USER_EXECUTE,
+            Permission::GROUP_EXECUTE,
+            Permission::OTHER_EXECUTE,
+        )
+    }
+
+    /// Checks the permission bit of the triad that applies to user `uid` in group `gid`.
+    ///
+    /// The owner gets the user triad and members of the inode's group the group triad, even if
+    /// the other triad is more permissive. Root is always allowed.
+    ///
+    /// The sticky bit doesn't grant or deny access to the inode itself, it only restricts who
+    /// may remove entries from a directory. That's checked when unlinking and renaming.
+    const fn can_access(
+        &self,
+        uid: u16,
+        gid: u16,
+        user: Permission,
+        group: Permission,
+        other: Permission,
+    ) -> bool {
+        if uid == crate::fs::ROOT_UID {
+            return true;
+        }
+
+        let bit = if uid == self.user_id {
+            user
+        } else if gid == self.group_id {
+            group
+        } else {
+            other
+        };
+        self.permission.contains(bit)
+    }
+
+    /// Returns the number of entries in the directory.
+    ///
+    /// `None` if the inode isn't a directory or the file system doesn't track it.
+    #[inline]
+    pub const fn entry_count(&self) -> Option<u64> {
+        self.entry_count
+    }
+
+    /// Returns whether the inode is a directory with no entries.
+    ///
+    /// Uses the cached entry count when available, otherwise lists the directory.
+    pub fn is_empty_dir(&self) -> FSResult<bool> {
+        if !self.is_dir() {
+            return Ok(false);
+        }
+        match self.entry_count {
+            Some(count) => Ok(count == 0),
+            None => Ok(self.list()?.next().is_none()),
+        }
+    }
+
     #[inline]
     pub fn create(&mut self, parent: &DEntry, path: Component) -> FSResult<()> {
         self.ops.create(self, parent, path)
@@ -171,13 +441,40 @@ impl Inode {
     }
 
     #[inline]
-    pub fn unlink(&mut self, parent: &DEntry) -> FSResult<()> {
-        self.ops.unlink(self, parent)
+    pub fn unlink(&mut self, parent: &DEntry, path: Component) -> FSResult<()> {
+        self.ops.unlink(self, parent, path)
+    }
+
+    #[inline]
+    pub fn rename(
+        &mut self,
+        src_p: &DEntry,
+        src_path: Component,
+        dst_p: &DEntry,
+        path: Component,
+        dst: Option<&mut Self>,
+    ) -> FSResult<()> {
+        self.ops.rename(self, src_p, src_path, dst_p, path, dst)
+    }
+
+    #[inline]
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> FSResult<usize> {
+        self.ops.read(self, offset, buf)
+    }
+
+    #[inline]
+    pub fn write(&mut self, offset: u64, buf: &[u8]) -> FSResult<usize> {
+        self.ops.write(self, offset, buf)
+    }
+
+    #[inline]
+    pub fn truncate(&mut self, size: u64) -> FSResult<()> {
+        self.ops.truncate(self, size)
     }
 
     #[inline]
-    pub fn rename(&mut self, src_p: &DEntry, dst_p: &DEntry, path: Component) -> FSResult<()> {
-        self.ops.rename(self, src_p, dst_p, path)
+    pub fn readlink(&self) -> FSResult<PathBuf> {
+        self.ops.readlink(self)
     }
 
     #[inline]
@@ -186,7 +483,7 @@ impl Inode {
     }
 
     #[inline]
-    pub fn list(&self) -> FSResult<FileIter> {
+    pub fn list(&self) -> FSResult<FileIter<'_>> {
         self.ops.list(self)
     }
 }
@@ -203,9 +500,11 @@ impl Debug for Inode {
             .field("size", &self.size)
             .field("nlink", &self.nlink)
             .field("blocks", &self.blocks)
+            .field("entry_count", &self.entry_count)
             .field("last_access_time", &self.last_access_time)
             .field("creation_time", &self.creation_time)
             .field("last_modification_time", &self.last_modification_time)
+            .field("block_size", &self.block_size)
             .finish()
     }
 }
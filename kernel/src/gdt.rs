@@ -0,0 +1,82 @@
+use spin::Lazy;
+use x86_64::{
+    instructions::{
+        segmentation::{Segment, CS, DS, ES, SS},
+        tables::load_tss,
+    },
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+        tss::TaskStateSegment,
+    },
+    VirtAddr,
+};
+
+/// Interrupt Stack Table entry the double fault handler runs on
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+/// Size of the double fault stack
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+struct Selectors {
+    code: SegmentSelector,
+    data: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[usize::from(DOUBLE_FAULT_IST_INDEX)] = {
+        static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+
+        // The stack grows down, so the CPU starts at its end
+        let start = VirtAddr::from_ptr(core::ptr::addr_of!(STACK));
+        start + IST_STACK_SIZE
+    };
+    tss
+});
+
+static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data = gdt.add_entry(Descriptor::kernel_data_segment());
+    let tss = gdt.add_entry(Descriptor::tss_segment(&TSS));
+    (gdt, Selectors { code, data, tss })
+});
+
+/// Replaces the bootloader's GDT with one holding the kernel's TSS.
+///
+/// Must run before the IDT is loaded, since the double fault handler uses the TSS's stack.
+pub fn init() {
+    let (gdt, selectors) = &*GDT;
+    gdt.load();
+    unsafe {
+        CS::set_reg(selectors.code);
+        // The bootloader's data selector may not be valid in the new GDT
+        SS::set_reg(selectors.data);
+        DS::set_reg(selectors.data);
+        ES::set_reg(selectors.data);
+        load_tss(selectors.tss);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn double_fault_stack_is_in_the_tss() {
+        let ist = TSS.interrupt_stack_table;
+        let top = ist[usize::from(DOUBLE_FAULT_IST_INDEX)];
+        assert!(!top.is_null());
+        assert_eq!(
+            ist.iter().filter(|entry| !entry.is_null()).count(),
+            1,
+            "only the double fault has its own stack"
+        );
+
+        let (_, selectors) = &*GDT;
+        assert_eq!(selectors.code.index(), 1);
+        assert_eq!(selectors.data.index(), 2);
+        assert_eq!(selectors.tss.index(), 3);
+    }
+}
@@ -0,0 +1,556 @@
+use alloc::vec::Vec;
+
+use crate::trap::IRQ0;
+
+#[cfg(test)]
+use self::tests::{read32, write32};
+
+/// Number of ISA IRQs, which may be remapped by interrupt source overrides
+const ISA_IRQS: usize = 16;
+
+/// Register index: ID
+const REG_ID: u32 = 0x00;
+/// Register index: version
+const REG_VER: u32 = 0x01;
+/// Redirection table base register
+const REG_TABLE: u32 = 0x10;
+
+/// Redirection entry bit set while the interrupt is masked
+const MASKED: u32 = 1 << 16;
+/// Redirection entry bit set for level triggered interrupts
+const LEVEL: u32 = 1 << 15;
+/// Redirection entry bit set for active low interrupts
+const ACTIVE_LOW: u32 = 1 << 13;
+/// Redirection entry bit set when the destination is a logical APIC ID
+const LOGICAL: u32 = 1 << 11;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+/// Configuration of a redirection table entry, unmasked
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Redirection {
+    pub vector: u8,
+    /// APIC ID, or logical APIC ID if `logical`, of the CPUs receiving the interrupt
+    pub dest: u8,
+    pub logical: bool,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+impl Redirection {
+    /// Low and high words of the redirection entry
+    pub const fn words(self) -> (u32, u32) {
+        let mut low = self.vector as u32;
+        if self.logical {
+            low |= LOGICAL;
+        }
+        if matches!(self.polarity, Polarity::ActiveLow) {
+            low |= ACTIVE_LOW;
+        }
+        if matches!(self.trigger, Trigger::Level) {
+            low |= LEVEL;
+        }
+        (low, (self.dest as u32) << 24)
+    }
+}
+
+/// Where an ISA IRQ is wired on the IOAPICs
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct IsaRoute {
+    gsi: u32,
+    polarity: Polarity,
+    trigger: Trigger,
+}
+
+impl IsaRoute {
+    /// Route of an IRQ without an override
+    const fn identity(irq: u8) -> Self {
+        Self {
+            gsi: irq as u32,
+            polarity: Polarity::ActiveHigh,
+            trigger: Trigger::Edge,
+        }
+    }
+}
+
+/// Routes ISA `irq` through the MADT's interrupt source overrides.
+///
+/// IRQs without an override are identity mapped, active high and edge triggered, like the
+/// ISA bus, unless another IRQ is overridden onto their GSI. Those have no route, so they
+/// can't reprogram the other IRQ's redirection entry.
+fn isa_route(overrides: &[acpi::InterruptSourceOverride], irq: u8) -> Option<IsaRoute> {
+    let Some(over) = overrides.iter().find(|over| over.isa_source == irq) else {
+        let taken = overrides
+            .iter()
+            .any(|over| over.global_system_interrupt == u32::from(irq));
+        return (!taken).then(|| IsaRoute::identity(irq));
+    };
+    Some(IsaRoute {
+        gsi: over.global_system_interrupt,
+        polarity: match over.polarity {
+            acpi::Polarity::ActiveLow => Polarity::ActiveLow,
+            acpi::Polarity::SameAsBus | acpi::Polarity::ActiveHigh => Polarity::ActiveHigh,
+        },
+        trigger: match over.trigger_mode {
+            acpi::TriggerMode::Level => Trigger::Level,
+            acpi::TriggerMode::SameAsBus | acpi::TriggerMode::Edge => Trigger::Edge,
+        },
+    })
+}
+
+/// Every I/O APIC of the machine, with the ISA IRQ routing from the MADT.
+pub struct IoApics {
+    /// Ordered by GSI base
+    chips: Vec<IoApic>,
+    isa_routes: [Option<IsaRoute>; ISA_IRQS],
+}
+
+impl IoApics {
+    pub fn new(mut chips: Vec<IoApic>, overrides: &[acpi::InterruptSourceOverride]) -> Self {
+        chips.sort_unstable_by_key(|chip| chip.gsi_base);
+        Self {
+            chips,
+            isa_routes: core::array::from_fn(|irq| {
+                #[allow(clippy::cast_possible_truncation)]
+                isa_route(overrides, irq as u8)
+            }),
+        }
+    }
+
+    pub fn chips(&mut self) -> &mut [IoApic] {
+        &mut self.chips
+    }
+
+    /// Masks every entry of every IOAPIC
+    pub fn disable_all(&mut self) {
+        for chip in &mut self.chips {
+            chip.disable_all();
+        }
+    }
+
+    /// The IOAPIC handling `gsi`, with the redirection entry of `gsi` on it
+    fn chip_for(&mut self, gsi: u32) -> Result<(&mut IoApic, u32), IoApicError> {
+        self.chips
+            .iter_mut()
+            .find_map(|chip| {
+                let entry = chip.entry_for(gsi)?;
+                Some((chip, entry))
+            })
+            .ok_or(IoApicError::Unhandled(gsi))
+    }
+
+    /// Enables `gsi` on vector `IRQ0 + gsi`, edge triggered and active high, routed to the
+    /// CPU with APIC ID `dest`.
+    ///
+    /// Ignores interrupt source overrides, see [`enable_isa`](Self::enable_isa).
+    pub fn enable(&mut self, gsi: u32, dest: u8) -> Result<(), IoApicError> {
+        let vector = u8::try_from(gsi)
+            .ok()
+            .and_then(|gsi| IRQ0.checked_add(gsi))
+            .ok_or(IoApicError::OutOfVectors(gsi))?;
+        let (chip, entry) = self.chip_for(gsi)?;
+        chip.redirect(
+            entry,
+            Redirection {
+                vector,
+                dest,
+                logical: false,
+                polarity: Polarity::ActiveHigh,
+                trigger: Trigger::Edge,
+            },
+        );
+        Ok(())
+    }
+
+    /// The GSI ISA `irq` is wired to, with its polarity and trigger mode, or `None` if its
+    /// GSI carries another IRQ.
+    ///
+    /// IRQs past the ISA range are assumed identity mapped.
+    pub fn gsi_for_isa_irq(&self, irq: u8) -> Option<(u32, Polarity, Trigger)> {
+        let route = match self.isa_routes.get(usize::from(irq)) {
+            Some(&route) => route?,
+            None => IsaRoute::identity(irq),
+        };
+        Some((route.gsi, route.polarity, route.trigger))
+    }
+
+    /// Enables ISA `irq` on vector `IRQ0 + irq`, routed to the CPU with APIC ID `dest`.
+    ///
+    /// Unlike [`enable`](Self::enable), follows the interrupt source overrides.
+    pub fn enable_isa(&mut self, irq: u8, dest: u8) -> Result<(), IoApicError> {
+        let (gsi, polarity, trigger) = self
+            .gsi_for_isa_irq(irq)
+            .ok_or(IoApicError::Unrouted(irq))?;
+        let (chip, entry) = self.chip_for(gsi)?;
+        chip.redirect(
+            entry,
+            Redirection {
+                vector: IRQ0 + irq,
+                dest,
+                logical: false,
+                polarity,
+                trigger,
+            },
+        );
+        Ok(())
+    }
+
+    /// Masks ISA `irq`, keeping the rest of its redirection entry.
+    pub fn mask(&mut self, irq: u8) -> Result<(), IoApicError> {
+        self.set_masked(irq, true)
+    }
+
+    /// Unmasks ISA `irq`, as it was configured before [`mask`](Self::mask).
+    pub fn unmask(&mut self, irq: u8) -> Result<(), IoApicError> {
+        self.set_masked(irq, false)
+    }
+
+    fn set_masked(&mut self, irq: u8, masked: bool) -> Result<(), IoApicError> {
+        let (gsi, _, _) = self
+            .gsi_for_isa_irq(irq)
+            .ok_or(IoApicError::Unrouted(irq))?;
+        let (chip, entry) = self.chip_for(gsi)?;
+        chip.set_masked(entry, masked);
+        Ok(())
+    }
+}
+
+/// Why an interrupt couldn't be routed.
+///
+/// Returned instead of logged, since callers may hold the console's serial port.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IoApicError {
+    /// No IOAPIC handles the GSI
+    Unhandled(u32),
+    /// The GSI is past the last interrupt vector
+    OutOfVectors(u32),
+    /// The ISA IRQ's GSI carries another IRQ
+    Unrouted(u8),
+}
+
+/// An I/O APIC, routing hardware interrupts to local APICs.
+pub struct IoApic {
+    /// Register select, followed by the data window at 0x10
+    regs: *mut u32,
+    /// First GSI handled by this IOAPIC
+    gsi_base: u32,
+    /// Number of redirection table entries
+    entries: u8,
+}
+
+unsafe impl Send for IoApic {}
+
+impl IoApic {
+    /// Creates the driver of the IOAPIC mapped at `regs`, handling GSIs from `gsi_base`.
+    ///
+    /// # Safety
+    /// `regs` must point to the IOAPIC's registers.
+    pub unsafe fn new(regs: *mut u32, gsi_base: u32) -> Self {
+        let mut chip = Self {
+            regs,
+            gsi_base,
+            entries: 0,
+        };
+        // The register holds the index of the last entry
+        chip.entries = (((chip.read_reg(REG_VER) >> 16) & 0xFF) + 1) as u8;
+        chip
+    }
+
+    pub fn id(&mut self) -> u8 {
+        unsafe { ((self.read_reg(REG_ID) >> 24) & 0xF) as u8 }
+    }
+
+    pub fn version(&mut self) -> u8 {
+        unsafe { (self.read_reg(REG_VER) & 0xFF) as u8 }
+    }
+
+    pub const fn gsi_base(&self) -> u32 {
+        self.gsi_base
+    }
+
+    /// Number of redirection table entries
+    pub const fn supported_interrupts(&self) -> u8 {
+        self.entries
+    }
+
+    /// Redirection entry of `gsi`, if it's in `[gsi_base, gsi_base + entries)`
+    pub fn entry_for(&self, gsi: u32) -> Option<u32> {
+        gsi.checked_sub(self.gsi_base)
+            .filter(|&entry| entry < u32::from(self.entries))
+    }
+
+    /// Masks every entry, edge triggered, active high and routed to no CPU
+    pub fn disable_all(&mut self) {
+        for entry in 0..u32::from(self.entries) {
+            unsafe { self.write_entry(entry, MASKED, 0) };
+        }
+    }
+
+    /// Programs and unmasks redirection table entry `entry`
+    pub fn redirect(&mut self, entry: u32, redirection: Redirection) {
+        let (low, high) = redirection.words();
+        unsafe { self.write_entry(entry, low, high) };
+    }
+
+    /// Sets the mask bit of redirection table entry `entry`, keeping the rest of it
+    pub fn set_masked(&mut self, entry: u32, masked: bool) {
+        let reg = REG_TABLE + 2 * entry;
+        unsafe {
+            let low = self.read_reg(reg);
+            let low = if masked { low | MASKED } else { low & !MASKED };
+            self.write_reg(reg, low);
+        }
+    }
+
+    unsafe fn write_entry(&mut self, entry: u32, low: u32, high: u32) {
+        // The destination first, since writing the low word may unmask the entry
+        self.write_reg(REG_TABLE + 2 * entry + 1, high);
+        self.write_reg(REG_TABLE + 2 * entry, low);
+    }
+
+    unsafe fn read_reg(&mut self, reg: u32) -> u32 {
+        write32(self.regs, reg);
+        read32(self.regs.add(4))
+    }
+
+    unsafe fn write_reg(&mut self, reg: u32, value: u32) {
+        write32(self.regs, reg);
+        write32(self.regs.add(4), value);
+    }
+}
+
+#[cfg(not(test))]
+unsafe fn read32(reg: *mut u32) -> u32 {
+    reg.read_volatile()
+}
+
+#[cfg(not(test))]
+unsafe fn write32(reg: *mut u32, value: u32) {
+    reg.write_volatile(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashMap, vec};
+
+    use super::*;
+
+    /// Offset of the data window from the register select
+    const WINDOW: usize = 0x10;
+
+    /// IOAPICs of the test thread, the real ones can't be accessed on the host.
+    ///
+    /// Each mocked IOAPIC is page aligned, so its address is that of its register select.
+    #[derive(Default)]
+    struct MockRegs {
+        /// Register select of each IOAPIC
+        selected: HashMap<usize, u32>,
+        /// Registers of each IOAPIC, by index
+        values: HashMap<(usize, u32), u32>,
+        /// Every MMIO write, in order, by offset from the IOAPIC's address
+        writes: Vec<(usize, u32)>,
+    }
+
+    std::thread_local! {
+        static REGS: RefCell<MockRegs> = RefCell::default();
+    }
+
+    fn split(reg: *mut u32) -> (usize, usize) {
+        let addr = reg.addr();
+        (addr & !0xFFF, addr & 0xFFF)
+    }
+
+    pub unsafe fn read32(reg: *mut u32) -> u32 {
+        let (chip, offset) = split(reg);
+        REGS.with_borrow(|mock| {
+            let selected = mock.selected.get(&chip).copied().unwrap_or_default();
+            match offset {
+                0 => selected,
+                WINDOW => mock
+                    .values
+                    .get(&(chip, selected))
+                    .copied()
+                    .unwrap_or_default(),
+                _ => panic!("read of offset {offset:#x}"),
+            }
+        })
+    }
+
+    pub unsafe fn write32(reg: *mut u32, value: u32) {
+        let (chip, offset) = split(reg);
+        REGS.with_borrow_mut(|mock| {
+            match offset {
+                0 => {
+                    mock.selected.insert(chip, value);
+                }
+                WINDOW => {
+                    let selected = mock.selected.get(&chip).copied().unwrap_or_default();
+                    mock.values.insert((chip, selected), value);
+                }
+                _ => panic!("write of offset {offset:#x}"),
+            }
+            mock.writes.push((reg.addr(), value));
+        });
+    }
+
+    fn set_reg(chip: usize, reg: u32, value: u32) {
+        REGS.with_borrow_mut(|mock| mock.values.insert((chip, reg), value));
+    }
+
+    fn reg(chip: usize, reg: u32) -> u32 {
+        REGS.with_borrow(|mock| mock.values.get(&(chip, reg)).copied().unwrap_or_default())
+    }
+
+    fn take_writes() -> Vec<(usize, u32)> {
+        REGS.with_borrow_mut(|mock| core::mem::take(&mut mock.writes))
+    }
+
+    /// A mocked IOAPIC at `addr` with `entries` redirection entries from `gsi_base`
+    fn chip(addr: usize, gsi_base: u32, entries: u32) -> IoApic {
+        set_reg(addr, REG_VER, ((entries - 1) << 16) | 0x20);
+        let chip = unsafe { IoApic::new(core::ptr::without_provenance_mut(addr), gsi_base) };
+        take_writes();
+        chip
+    }
+
+    fn isa_override(irq: u8, gsi: u32) -> acpi::InterruptSourceOverride {
+        acpi::InterruptSourceOverride {
+            isa_source: irq,
+            global_system_interrupt: gsi,
+            polarity: acpi::Polarity::ActiveLow,
+            trigger_mode: acpi::TriggerMode::Level,
+        }
+    }
+
+    #[test]
+    fn isa_irqs_follow_the_overrides() {
+        let apics = IoApics::new(Vec::new(), &[isa_override(0, 2)]);
+
+        assert_eq!(
+            apics.gsi_for_isa_irq(0),
+            Some((2, Polarity::ActiveLow, Trigger::Level))
+        );
+        // COM1 has no override
+        assert_eq!(
+            apics.gsi_for_isa_irq(4),
+            Some((4, Polarity::ActiveHigh, Trigger::Edge))
+        );
+        // GSI 2 carries IRQ 0, so IRQ 2 has nowhere to go
+        assert_eq!(apics.gsi_for_isa_irq(2), None);
+        // Past the ISA range
+        assert_eq!(
+            apics.gsi_for_isa_irq(20),
+            Some((20, Polarity::ActiveHigh, Trigger::Edge))
+        );
+    }
+
+    #[test]
+    fn swapped_irqs_keep_their_overrides() {
+        let overrides = vec![isa_override(3, 4), isa_override(4, 3)];
+        let apics = IoApics::new(Vec::new(), &overrides);
+
+        assert_eq!(apics.gsi_for_isa_irq(3).map(|(gsi, ..)| gsi), Some(4));
+        assert_eq!(apics.gsi_for_isa_irq(4).map(|(gsi, ..)| gsi), Some(3));
+    }
+
+    #[test]
+    fn mask_keeps_the_rest_of_the_entry() {
+        const CHIP: usize = 0x1000;
+        let mut chip = chip(CHIP, 0, 24);
+        let redirection = Redirection {
+            vector: 0x24,
+            dest: 3,
+            logical: true,
+            polarity: Polarity::ActiveLow,
+            trigger: Trigger::Level,
+        };
+        let (low, high) = redirection.words();
+        chip.redirect(5, redirection);
+
+        chip.set_masked(5, true);
+        assert_eq!(reg(CHIP, REG_TABLE + 10), low | MASKED);
+        assert_eq!(reg(CHIP, REG_TABLE + 11), high);
+
+        chip.set_masked(5, false);
+        assert_eq!(reg(CHIP, REG_TABLE + 10), low);
+        assert_eq!(reg(CHIP, REG_TABLE + 11), high);
+    }
+
+    #[test]
+    fn registers_go_through_the_select_and_window() {
+        const CHIP: usize = 0x2000;
+        let mut chip = chip(CHIP, 0, 4);
+        set_reg(CHIP, REG_ID, 7 << 24);
+
+        assert_eq!(chip.supported_interrupts(), 4);
+        assert_eq!(chip.id(), 7);
+        assert_eq!(chip.version(), 0x20);
+        assert_eq!(take_writes(), [(CHIP, REG_ID), (CHIP, REG_VER)]);
+
+        chip.disable_all();
+        let expected: Vec<_> = (0..4)
+            .flat_map(|entry| {
+                [
+                    (CHIP, REG_TABLE + 2 * entry + 1),
+                    (CHIP + WINDOW, 0),
+                    (CHIP, REG_TABLE + 2 * entry),
+                    (CHIP + WINDOW, MASKED),
+                ]
+            })
+            .collect();
+        assert_eq!(take_writes(), expected);
+    }
+
+    #[test]
+    fn enable_writes_the_destination_first() {
+        const CHIP: usize = 0x3000;
+        let mut apics = IoApics::new(vec![chip(CHIP, 0, 24)], &[]);
+
+        assert_eq!(apics.enable(4, 2), Ok(()));
+        assert_eq!(
+            take_writes(),
+            [
+                (CHIP, REG_TABLE + 9),
+                (CHIP + WINDOW, 2 << 24),
+                (CHIP, REG_TABLE + 8),
+                (CHIP + WINDOW, u32::from(IRQ0) + 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn gsis_go_to_the_chip_handling_them() {
+        const LOW: usize = 0x4000;
+        const HIGH: usize = 0x5000;
+        // Out of order, the chips are sorted by GSI base
+        let mut apics = IoApics::new(vec![chip(HIGH, 24, 8), chip(LOW, 0, 24)], &[]);
+
+        assert_eq!(apics.enable(23, 0), Ok(()));
+        assert_eq!(reg(LOW, REG_TABLE + 46), u32::from(IRQ0) + 23);
+        assert_eq!(apics.enable(25, 0), Ok(()));
+        assert_eq!(reg(HIGH, REG_TABLE + 2), u32::from(IRQ0) + 25);
+
+        assert_eq!(apics.enable(32, 0), Err(IoApicError::Unhandled(32)));
+        assert_eq!(apics.enable(300, 0), Err(IoApicError::OutOfVectors(300)));
+    }
+
+    #[test]
+    fn isa_irqs_without_a_route_fail() {
+        let mut apics = IoApics::new(vec![chip(0x6000, 0, 24)], &[isa_override(0, 2)]);
+
+        assert_eq!(apics.enable_isa(2, 0), Err(IoApicError::Unrouted(2)));
+        assert_eq!(apics.mask(2), Err(IoApicError::Unrouted(2)));
+        assert_eq!(apics.enable_isa(0, 0), Ok(()));
+        assert_eq!(apics.mask(0), Ok(()));
+    }
+}
@@ -0,0 +1,532 @@
+use spin::Mutex;
+use x86_64::instructions::{
+    interrupts,
+    port::{PortRead, PortWrite},
+};
+
+use crate::trap;
+
+/// IOAPIC line of the first PS/2 port
+pub const IRQ_KEYBOARD: u8 = 1;
+
+/// Size of the buffer holding key events until they're read.
+pub const EVENT_SIZE: usize = 64;
+
+const DATA_PORT: u16 = 0x60;
+/// Status register when read, command register when written
+const COMMAND_PORT: u16 = 0x64;
+
+/// Status bit set when the output buffer holds a byte for the CPU
+const OUTPUT_FULL: u8 = 1 << 0;
+/// Status bit set while the controller hasn't taken the last written byte
+const INPUT_FULL: u8 = 1 << 1;
+/// Status polls before the controller is considered unresponsive
+const RETRIES: usize = 100_000;
+/// Bytes read when flushing the output buffer before it's considered stuck
+const FLUSH_LIMIT: usize = 64;
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_PORT2: u8 = 0xA7;
+const CMD_SELF_TEST: u8 = 0xAA;
+const CMD_DISABLE_PORT1: u8 = 0xAD;
+const CMD_ENABLE_PORT1: u8 = 0xAE;
+
+/// Configuration bit enabling the first port's interrupt
+const CONFIG_PORT1_IRQ: u8 = 1 << 0;
+/// Configuration bit enabling the second port's interrupt
+const CONFIG_PORT2_IRQ: u8 = 1 << 1;
+/// Configuration bit translating the keyboard's scancodes to set 1
+const CONFIG_TRANSLATE: u8 = 1 << 6;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+
+/// Prefix of the extended scancodes
+const EXTENDED_PREFIX: u8 = 0xE0;
+/// Prefix of the Pause key sequence, which has no release
+const PAUSE_PREFIX: u8 = 0xE1;
+/// Bytes following [`PAUSE_PREFIX`] in the Pause key sequence
+const PAUSE_LEN: u8 = 5;
+/// Scancode bit set when a key is released
+const RELEASED: u8 = 0x80;
+
+static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard::new());
+
+/// A key being pressed or released
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub pressed: bool,
+}
+
+/// Physical key, named after its US layout legend
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum KeyCode {
+    Escape,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+    Key0,
+    Minus,
+    Equals,
+    Backspace,
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    Enter,
+    LeftCtrl,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    Quote,
+    Backtick,
+    LeftShift,
+    Backslash,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+    LeftAlt,
+    Space,
+    CapsLock,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    NumLock,
+    ScrollLock,
+    Keypad0,
+    Keypad1,
+    Keypad2,
+    Keypad3,
+    Keypad4,
+    Keypad5,
+    Keypad6,
+    Keypad7,
+    Keypad8,
+    Keypad9,
+    KeypadPeriod,
+    KeypadPlus,
+    KeypadMinus,
+    KeypadMultiply,
+    KeypadSlash,
+    KeypadEnter,
+    RightCtrl,
+    RightAlt,
+    LeftSuper,
+    RightSuper,
+    Menu,
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl KeyCode {
+    /// Decodes a scancode set 1 make code, without its release bit
+    ///
+    /// `extended` is whether it followed an `0xE0` prefix. Returns `None` for unknown codes.
+    #[allow(clippy::enum_glob_use, clippy::too_many_lines)]
+    pub const fn from_scancode(code: u8, extended: bool) -> Option<Self> {
+        use KeyCode::*;
+
+        if extended {
+            return match code {
+                0x1C => Some(KeypadEnter),
+                0x1D => Some(RightCtrl),
+                0x35 => Some(KeypadSlash),
+                0x38 => Some(RightAlt),
+                0x47 => Some(Home),
+                0x48 => Some(Up),
+                0x49 => Some(PageUp),
+                0x4B => Some(Left),
+                0x4D => Some(Right),
+                0x4F => Some(End),
+                0x50 => Some(Down),
+                0x51 => Some(PageDown),
+                0x52 => Some(Insert),
+                0x53 => Some(Delete),
+                0x5B => Some(LeftSuper),
+                0x5C => Some(RightSuper),
+                0x5D => Some(Menu),
+                // Includes the fake shifts sent around Print Screen
+                _ => None,
+            };
+        }
+
+        Some(match code {
+            0x01 => Escape,
+            0x02 => Key1,
+            0x03 => Key2,
+            0x04 => Key3,
+            0x05 => Key4,
+            0x06 => Key5,
+            0x07 => Key6,
+            0x08 => Key7,
+            0x09 => Key8,
+            0x0A => Key9,
+            0x0B => Key0,
+            0x0C => Minus,
+            0x0D => Equals,
+            0x0E => Backspace,
+            0x0F => Tab,
+            0x10 => Q,
+            0x11 => W,
+            0x12 => E,
+            0x13 => R,
+            0x14 => T,
+            0x15 => Y,
+            0x16 => U,
+            0x17 => I,
+            0x18 => O,
+            0x19 => P,
+            0x1A => LeftBracket,
+            0x1B => RightBracket,
+            0x1C => Enter,
+            0x1D => LeftCtrl,
+            0x1E => A,
+            0x1F => S,
+            0x20 => D,
+            0x21 => F,
+            0x22 => G,
+            0x23 => H,
+            0x24 => J,
+            0x25 => K,
+            0x26 => L,
+            0x27 => Semicolon,
+            0x28 => Quote,
+            0x29 => Backtick,
+            0x2A => LeftShift,
+            0x2B => Backslash,
+            0x2C => Z,
+            0x2D => X,
+            0x2E => C,
+            0x2F => V,
+            0x30 => B,
+            0x31 => N,
+            0x32 => M,
+            0x33 => Comma,
+            0x34 => Period,
+            0x35 => Slash,
+            0x36 => RightShift,
+            0x37 => KeypadMultiply,
+            0x38 => LeftAlt,
+            0x39 => Space,
+            0x3A => CapsLock,
+            0x3B => F1,
+            0x3C => F2,
+            0x3D => F3,
+            0x3E => F4,
+            0x3F => F5,
+            0x40 => F6,
+            0x41 => F7,
+            0x42 => F8,
+            0x43 => F9,
+            0x44 => F10,
+            0x45 => NumLock,
+            0x46 => ScrollLock,
+            0x47 => Keypad7,
+            0x48 => Keypad8,
+            0x49 => Keypad9,
+            0x4A => KeypadMinus,
+            0x4B => Keypad4,
+            0x4C => Keypad5,
+            0x4D => Keypad6,
+            0x4E => KeypadPlus,
+            0x4F => Keypad1,
+            0x50 => Keypad2,
+            0x51 => Keypad3,
+            0x52 => Keypad0,
+            0x53 => KeypadPeriod,
+            0x57 => F11,
+            0x58 => F12,
+            _ => return None,
+        })
+    }
+}
+
+/// Turns scancode set 1 bytes into key events, keeping track of multi-byte sequences.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    /// Whether the last byte was the extended prefix
+    extended: bool,
+    /// Bytes left to skip in the Pause key sequence
+    skip: u8,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self {
+            extended: false,
+            skip: 0,
+        }
+    }
+
+    /// Feeds the next byte, returning the event it completes, if any
+    pub const fn feed(&mut self, byte: u8) -> Option<KeyEvent> {
+        if self.skip != 0 {
+            self.skip -= 1;
+            return None;
+        }
+        match byte {
+            EXTENDED_PREFIX => {
+                self.extended = true;
+                None
+            }
+            PAUSE_PREFIX => {
+                self.skip = PAUSE_LEN;
+                None
+            }
+            _ => {
+                let extended = self.extended;
+                self.extended = false;
+                match KeyCode::from_scancode(byte & !RELEASED, extended) {
+                    Some(code) => Some(KeyEvent {
+                        code,
+                        pressed: byte & RELEASED == 0,
+                    }),
+                    None => None,
+                }
+            }
+        }
+    }
+}
+
+/// Ring of key events. Events received while it's full are dropped.
+struct EventBuffer {
+    buf: [Option<KeyEvent>; EVENT_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl EventBuffer {
+    const fn new() -> Self {
+        Self {
+            buf: [None; EVENT_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    const fn push(&mut self, event: KeyEvent) {
+        if self.len == EVENT_SIZE {
+            return;
+        }
+        self.buf[(self.start + self.len) % EVENT_SIZE] = Some(event);
+        self.len += 1;
+    }
+
+    const fn pop_front(&mut self) -> Option<KeyEvent> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.buf[self.start].take();
+        self.start = (self.start + 1) % EVENT_SIZE;
+        self.len -= 1;
+        event
+    }
+}
+
+struct Keyboard {
+    decoder: Decoder,
+    events: EventBuffer,
+}
+
+impl Keyboard {
+    const fn new() -> Self {
+        Self {
+            decoder: Decoder::new(),
+            events: EventBuffer::new(),
+        }
+    }
+}
+
+/// Initializes the PS/2 controller and enables the keyboard interrupt.
+///
+/// The controller translates the keyboard's scancodes to set 1. Fails if the controller
+/// doesn't respond or fails its self test.
+pub fn init() -> Result<(), KeyboardError> {
+    unsafe {
+        // Disable both ports while configuring, so they can't send anything
+        write_command(CMD_DISABLE_PORT1)?;
+        write_command(CMD_DISABLE_PORT2)?;
+
+        flush_output()?;
+
+        write_command(CMD_READ_CONFIG)?;
+        let config = read_data()? & !(CONFIG_PORT1_IRQ | CONFIG_PORT2_IRQ);
+        write_command(CMD_WRITE_CONFIG)?;
+        write_data(config)?;
+
+        write_command(CMD_SELF_TEST)?;
+        if read_data()? != SELF_TEST_PASSED {
+            return Err(KeyboardError);
+        }
+
+        // The self test may reset the controller, so the configuration is written again
+        write_command(CMD_WRITE_CONFIG)?;
+        write_data(config | CONFIG_PORT1_IRQ | CONFIG_TRANSLATE)?;
+        write_command(CMD_ENABLE_PORT1)?;
+    }
+
+    trap::register_handler(trap::IRQ0 + IRQ_KEYBOARD, handle_interrupt)
+        .map_err(|_| KeyboardError)?;
+
+    // Enable interrupts on IOAPIC, routed to this CPU
+    let dest = crate::apic::apic_id();
+    crate::apic::IOAPIC
+        .lock()
+        .enable_isa(IRQ_KEYBOARD, dest)
+        .map_err(|_| KeyboardError)
+}
+
+/// Takes the oldest buffered key event
+pub fn read_event() -> Option<KeyEvent> {
+    // The interrupt handler locks the keyboard too
+    interrupts::without_interrupts(|| KEYBOARD.lock().events.pop_front())
+}
+
+/// Decodes the received scancodes into the event buffer
+fn handle_interrupt(_: u8) {
+    let mut keyboard = KEYBOARD.lock();
+    while unsafe { u8::read_from_port(COMMAND_PORT) } & OUTPUT_FULL != 0 {
+        let byte = unsafe { u8::read_from_port(DATA_PORT) };
+        if let Some(event) = keyboard.decoder.feed(byte) {
+            keyboard.events.push(event);
+        }
+    }
+}
+
+/// Discards the bytes left in the output buffer.
+///
+/// Fails if the buffer stays full, as a missing controller may read as all ones.
+unsafe fn flush_output() -> Result<(), KeyboardError> {
+    for _ in 0..FLUSH_LIMIT {
+        if u8::read_from_port(COMMAND_PORT) & OUTPUT_FULL == 0 {
+            return Ok(());
+        }
+        u8::read_from_port(DATA_PORT);
+    }
+    Err(KeyboardError)
+}
+
+/// Waits until the controller can take a byte
+fn wait_input_empty() -> Result<(), KeyboardError> {
+    for _ in 0..RETRIES {
+        if unsafe { u8::read_from_port(COMMAND_PORT) } & INPUT_FULL == 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(KeyboardError)
+}
+
+unsafe fn write_command(command: u8) -> Result<(), KeyboardError> {
+    wait_input_empty()?;
+    u8::write_to_port(COMMAND_PORT, command);
+    Ok(())
+}
+
+unsafe fn write_data(data: u8) -> Result<(), KeyboardError> {
+    wait_input_empty()?;
+    u8::write_to_port(DATA_PORT, data);
+    Ok(())
+}
+
+unsafe fn read_data() -> Result<u8, KeyboardError> {
+    for _ in 0..RETRIES {
+        if u8::read_from_port(COMMAND_PORT) & OUTPUT_FULL != 0 {
+            return Ok(u8::read_from_port(DATA_PORT));
+        }
+        core::hint::spin_loop();
+    }
+    Err(KeyboardError)
+}
+
+#[derive(Debug)]
+pub struct KeyboardError;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scancodes_map_to_key_codes() {
+        assert_eq!(KeyCode::from_scancode(0x01, false), Some(KeyCode::Escape));
+        assert_eq!(KeyCode::from_scancode(0x1E, false), Some(KeyCode::A));
+        assert_eq!(KeyCode::from_scancode(0x39, false), Some(KeyCode::Space));
+        assert_eq!(KeyCode::from_scancode(0x58, false), Some(KeyCode::F12));
+        assert_eq!(KeyCode::from_scancode(0x54, false), None);
+
+        // The same code means another key after the extended prefix
+        assert_eq!(KeyCode::from_scancode(0x48, false), Some(KeyCode::Keypad8));
+        assert_eq!(KeyCode::from_scancode(0x48, true), Some(KeyCode::Up));
+        assert_eq!(KeyCode::from_scancode(0x2A, true), None);
+    }
+
+    #[test]
+    fn decoder_reports_presses_and_releases() {
+        let mut decoder = Decoder::new();
+        let event = |code, pressed| Some(KeyEvent { code, pressed });
+
+        assert_eq!(decoder.feed(0x1E), event(KeyCode::A, true));
+        assert_eq!(decoder.feed(0x9E), event(KeyCode::A, false));
+
+        assert_eq!(decoder.feed(EXTENDED_PREFIX), None);
+        assert_eq!(decoder.feed(0x48), event(KeyCode::Up, true));
+        assert_eq!(decoder.feed(EXTENDED_PREFIX), None);
+        assert_eq!(decoder.feed(0xC8), event(KeyCode::Up, false));
+        // The prefix only applies to the next byte
+        assert_eq!(decoder.feed(0x48), event(KeyCode::Keypad8, true));
+
+        // Pause is E1 1D 45 E1 9D C5 and produces no event
+        for byte in [0xE1, 0x1D, 0x45, 0xE1, 0x9D, 0xC5] {
+            assert_eq!(decoder.feed(byte), None);
+        }
+        assert_eq!(decoder.feed(0x01), event(KeyCode::Escape, true));
+    }
+}
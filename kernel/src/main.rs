@@ -1,25 +1,32 @@
-#![feature(allocator_api, abi_x86_interrupt, asm_const, never_type)]
+#![feature(abi_x86_interrupt)]
 #![feature(slice_ptr_get)]
-#![feature(slice_as_chunks)]
 #![warn(clippy::pedantic, clippy::nursery)]
 #![allow(
     dead_code,
     clippy::module_name_repetitions,
     clippy::cast_possible_truncation,
-    clippy::significant_drop_tightening
+    clippy::significant_drop_tightening,
+    clippy::used_underscore_items
 )]
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
 extern crate alloc;
 
 mod acpi;
 mod apic;
+mod collections;
+mod console;
 mod fs;
+mod gdt;
+mod ioapic;
+mod keyboard;
 mod memory;
 mod mp;
 mod panic;
 mod pit;
+mod qemu;
+mod rtc;
 mod serial;
 mod time;
 mod trap;
@@ -33,6 +40,7 @@ const BOOT_CONFIG: bootloader_api::BootloaderConfig = {
     ));
     config
 };
+#[cfg(not(test))]
 bootloader_api::entry_point!(kmain, config = &BOOT_CONFIG);
 
 /// The entry point for the kernel.
@@ -41,17 +49,50 @@ bootloader_api::entry_point!(kmain, config = &BOOT_CONFIG);
 ///
 /// Panics if the kernel crashes.
 pub fn kmain(info: &'static mut bootloader_api::BootInfo) -> ! {
+    // UEFI firmware doesn't put the RSDP where it's searched for on BIOS
+    if let Some(rsdp) = info.rsdp_addr.into_option() {
+        acpi::RDSP_ADDRESS.call_once(|| rsdp as usize);
+    }
+    gdt::init();
+    trap::init_cpu();
     trap::init_idt();
     memory::init();
     memory::init_frame_allocator(&info.memory_regions);
+    let framebuffer =
+        core::mem::replace(&mut info.framebuffer, bootloader_api::info::Optional::None);
+    if let Some(framebuffer) = framebuffer.into_option() {
+        console::framebuffer::attach(framebuffer);
+    }
 
     apic::LAPIC.lock().attach();
     apic::IOAPIC.lock().disable_all();
-    serial::COM1.lock().enable_interrupts();
+    // Logged once the port is unlocked, since the console prints to COM1
+    let com1 = serial::COM1.lock().enable_interrupts(trap::IRQ_COM1);
+    if let Err(e) = com1 {
+        kprintln!("WARNING: failed to enable COM1 interrupts: {e:?}");
+    }
+    if let Some(com2) = &*serial::COM2 {
+        let result = com2.lock().enable_interrupts(trap::IRQ_COM2);
+        if let Err(e) = result {
+            kprintln!("WARNING: failed to enable COM2 interrupts: {e:?}");
+        }
+    }
+    if let Err(e) = keyboard::init() {
+        kprintln!("WARNING: failed to initialize the keyboard: {e:?}");
+    }
     time::start_timer();
+    time::set_wall_clock(rtc::now());
     x86_64::instructions::interrupts::enable();
 
     kprintln!("Hello, world!");
+    match mp::detect() {
+        Ok(aps) => kprintln!("Found {aps} application processors"),
+        Err(e) => kprintln!("WARNING: failed to detect CPUs: {e:?}"),
+    }
+    acpi::init_power();
+    // Nothing reads the ACPI tables past this point
+    let reclaimed = unsafe { memory::reclaim_acpi_regions() };
+    kprintln!("Reclaimed {} KiB of ACPI memory", reclaimed * 4);
     kprintln!(
         "Physical memory offset: {:x}",
         info.physical_memory_offset.into_option().unwrap()
@@ -69,7 +110,7 @@ pub fn kmain(info: &'static mut bootloader_api::BootInfo) -> ! {
         );
     }
 
-    kprintln!("kmain address: {:x}", kmain as usize);
+    kprintln!("kmain address: {:p}", kmain as *const ());
 
     kprintln!(
         "ALLOCATOR MEM RANGE: {:x} - {:x}",
@@ -101,6 +142,8 @@ pub fn kmain(info: &'static mut bootloader_api::BootInfo) -> ! {
     //     fs: Box::new(fs),
     //     dest: None,
     //     source: None,
+    //     device: None,
+    //     flags: fs::mount::MountFlags::empty(),
     // };
     // fs::MOUNTS.mount_fs(ctx).unwrap();
     //
@@ -132,6 +175,21 @@ pub fn kmain(info: &'static mut bootloader_api::BootInfo) -> ! {
     //     }
     // }
 
+    if let Err(e) = memory::protect_kernel_image(info) {
+        kprintln!("WARNING: failed to protect the kernel image: {e:?}");
+    }
+
+    kprintln!("Heap: {}", memory::ALLOCATOR.stats());
+    {
+        let fr_alloc = memory::FRAME_ALLOCATOR.lock();
+        let frames = fr_alloc.as_ref().unwrap();
+        kprintln!(
+            "Memory: {} MiB free of {} MiB",
+            frames.free_frames() * 4096 / (1024 * 1024),
+            frames.total_frames() * 4096 / (1024 * 1024)
+        );
+    }
+
     kprintln!("No Crash!");
     loop {
         x86_64::instructions::interrupts::enable_and_hlt();
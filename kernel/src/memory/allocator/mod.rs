@@ -3,6 +3,7 @@ pub mod page;
 use alloc::boxed::Box;
 use core::{
     alloc::{AllocError, Allocator, GlobalAlloc, Layout},
+    fmt::{Display, Formatter},
     ptr::NonNull,
 };
 
@@ -13,8 +14,37 @@ use x86_64::{
 };
 
 pub use self::page::FullPageAllocator;
+#[cfg(not(test))]
 use crate::memory::PAGE_ALLOCATOR;
 
+/// Allocator the heap gets its pages from, for buckets and large allocations.
+///
+/// Host tests use the system allocator instead, the page allocator needs the kernel's page
+/// tables.
+#[cfg(not(test))]
+type PageSource = FullPageAllocator;
+#[cfg(test)]
+type PageSource = std::alloc::System;
+
+#[cfg(not(test))]
+static PAGE_SOURCE: &PageSource = &PAGE_ALLOCATOR;
+#[cfg(test)]
+static PAGE_SOURCE: &PageSource = &std::alloc::System;
+
+/// Layout of a bucket's page
+const PAGE_LAYOUT: Layout = match Layout::from_size_align(4096, 4096) {
+    Ok(layout) => layout,
+    Err(_) => panic!("invalid page layout"),
+};
+
+/// Layout of the pages backing a large allocation.
+///
+/// Always whole pages, so a cached page fits any allocation of up to a page.
+fn large_layout(layout: Layout) -> Layout {
+    Layout::from_size_align(layout.size().next_multiple_of(4096), 4096)
+        .expect("large allocations fit in the address space")
+}
+
 /// Default kernel allocator.
 ///
 /// This is the global allocator used by the kernel.
@@ -23,7 +53,104 @@ use crate::memory::PAGE_ALLOCATOR;
 /// Returns kernel-only memory with flags `PRESENT | WRITABLE`.
 #[derive(Debug)]
 pub struct KAllocator {
-    buckets: Mutex<Buckets>,
+    heap: Mutex<Heap>,
+}
+
+#[derive(Debug)]
+struct Heap {
+    buckets: Buckets,
+    page_cache: PageCache,
+    /// Kept up to date by every allocation
+    stats: AllocStats,
+}
+
+impl Heap {
+    fn stats(&self) -> AllocStats {
+        let cached_pages = self.page_cache.len;
+        AllocStats {
+            cached_pages,
+            pages: self.buckets.pages() + self.stats.large_pages + cached_pages,
+            ..self.stats
+        }
+    }
+}
+
+/// Maximum number of free pages kept in the [`PageCache`].
+pub const PAGE_CACHE_SIZE: usize = 16;
+
+/// Free single pages kept for reuse by allocations too large for a bucket that still fit in a
+/// page, so they don't round-trip through the frame allocator.
+#[derive(Debug)]
+struct PageCache {
+    pages: [VirtAddr; PAGE_CACHE_SIZE],
+    len: usize,
+}
+
+impl PageCache {
+    fn pop(&mut self) -> Option<VirtAddr> {
+        self.len = self.len.checked_sub(1)?;
+        Some(self.pages[self.len])
+    }
+
+    /// Caches `page`, returning `false` if the cache is full.
+    const fn push(&mut self, page: VirtAddr) -> bool {
+        if self.len == PAGE_CACHE_SIZE {
+            return false;
+        }
+        self.pages[self.len] = page;
+        self.len += 1;
+        true
+    }
+}
+
+/// Heap usage of a [`KAllocator`].
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct AllocStats {
+    /// Bytes currently allocated, as requested by the layouts
+    pub live_bytes: usize,
+    /// Blocks currently allocated in each size class of [`BUCKET_SIZES`]
+    pub blocks: [usize; BUCKET_SIZES.len()],
+    /// Pages currently allocated directly from the page allocator
+    pub large_pages: usize,
+    /// Free pages held in the page cache
+    pub cached_pages: usize,
+    /// 4 KiB pages owned by the heap: bucket pages, large allocations and cached pages
+    pub pages: usize,
+}
+
+impl Display for AllocStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{} bytes live in {} pages ({} large, {} cached), blocks:",
+            self.live_bytes, self.pages, self.large_pages, self.cached_pages
+        )?;
+        for (size, count) in BUCKET_SIZES.iter().zip(self.blocks) {
+            write!(f, " {size}B={count}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Block sizes of the buckets in [`Buckets`], in ascending order.
+pub const BUCKET_SIZES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Byte free bucket blocks are filled with in debug builds.
+///
+/// It's checked when a block is reallocated, catching writes through dangling pointers.
+const POISON: u8 = 0xDE;
+
+/// Selects the bucket for an allocation of `size` bytes aligned to `align`.
+///
+/// Blocks are aligned to their size, so the smallest bucket whose block size is at least
+/// `max(size, align)` satisfies both. A small allocation with a large alignment, e.g. 8 bytes
+/// aligned to 64, therefore lands in the 64-byte bucket.
+///
+/// Returns `None` if the allocation is too large for any bucket and must be served by the page
+/// allocator.
+pub fn bucket_index(size: usize, align: usize) -> Option<usize> {
+    let max = size.max(align);
+    BUCKET_SIZES.iter().position(|&block| max <= block)
 }
 
 #[derive(Debug)]
@@ -39,19 +166,38 @@ struct Buckets(
     Option<Bucket<1, 2048>>, // 2048 bytes
 );
 
+impl Buckets {
+    /// Number of pages owned by all buckets.
+    fn pages(&self) -> usize {
+        [
+            self.0.as_ref().map_or(0, Bucket::pages),
+            self.1.as_ref().map_or(0, Bucket::pages),
+            self.2.as_ref().map_or(0, Bucket::pages),
+            self.3.as_ref().map_or(0, Bucket::pages),
+            self.4.as_ref().map_or(0, Bucket::pages),
+            self.5.as_ref().map_or(0, Bucket::pages),
+            self.6.as_ref().map_or(0, Bucket::pages),
+            self.7.as_ref().map_or(0, Bucket::pages),
+            self.8.as_ref().map_or(0, Bucket::pages),
+        ]
+        .iter()
+        .sum()
+    }
+}
+
 #[derive(Debug)]
 struct Bucket<const SIZE: usize, const BLOCK: u64> {
     page: Page,
     bitmap: [u8; SIZE],
     // TODO: Change this to not use FullPageAllocator
-    next: Option<Box<Bucket<SIZE, BLOCK>, &'static FullPageAllocator>>,
+    next: Option<Box<Self, &'static PageSource>>,
 }
 
 impl<const SIZE: usize, const BLOCK: u64> Drop for Bucket<SIZE, BLOCK> {
     fn drop(&mut self) {
         let ptr = unsafe { NonNull::new_unchecked(self.page.start_address().as_mut_ptr()) };
         unsafe {
-            PAGE_ALLOCATOR.deallocate(ptr, Layout::new::<u8>());
+            PAGE_SOURCE.deallocate(ptr, PAGE_LAYOUT);
         }
     }
 }
@@ -59,11 +205,34 @@ impl<const SIZE: usize, const BLOCK: u64> Drop for Bucket<SIZE, BLOCK> {
 impl KAllocator {
     pub const fn new() -> Self {
         Self {
-            buckets: Mutex::new(Buckets(
-                None, None, None, None, None, None, None, None, None,
-            )),
+            heap: Mutex::new(Heap {
+                buckets: Buckets(None, None, None, None, None, None, None, None, None),
+                page_cache: PageCache {
+                    pages: [VirtAddr::zero(); PAGE_CACHE_SIZE],
+                    len: 0,
+                },
+                stats: AllocStats {
+                    live_bytes: 0,
+                    blocks: [0; BUCKET_SIZES.len()],
+                    large_pages: 0,
+                    cached_pages: 0,
+                    pages: 0,
+                },
+            }),
         }
     }
+
+    /// Returns the current heap usage.
+    pub fn stats(&self) -> AllocStats {
+        self.heap.lock().stats()
+    }
+
+    /// Same as [`stats`](Self::stats), but returns `None` instead of waiting for the heap lock.
+    ///
+    /// Safe to call from the panic handler, which may have interrupted an allocation.
+    pub fn try_stats(&self) -> Option<AllocStats> {
+        self.heap.try_lock().map(|heap| heap.stats())
+    }
 }
 
 macro_rules! allocate {
@@ -82,7 +251,12 @@ macro_rules! allocate {
 macro_rules! deallocate {
     ($buckets:ident, $idx:tt, $addr:ident) => {
         if let Some(bucket) = &mut $buckets.$idx {
-            bucket.free_block($addr)
+            bucket.free_block($addr);
+
+            // Release the head bucket's page once it's empty, promoting the next one if any
+            if bucket.is_empty() {
+                $buckets.$idx = bucket.next.take().map(|next| *next);
+            }
         } else {
             panic!("invalid free")
         }
@@ -93,79 +267,222 @@ unsafe impl Allocator for KAllocator {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
         let size = layout.size();
         let align = layout.align();
-        let max = size.max(align);
-
-        let mut buckets = self.buckets.lock();
-
-        let addr = match max {
-            0..=8 => allocate!(buckets, 0),
-            9..=16 => allocate!(buckets, 1),
-            17..=32 => allocate!(buckets, 2),
-            33..=64 => allocate!(buckets, 3),
-            65..=128 => allocate!(buckets, 4),
-            129..=256 => allocate!(buckets, 5),
-            257..=512 => allocate!(buckets, 6),
-            513..=1024 => allocate!(buckets, 7),
-            1025..=2048 => allocate!(buckets, 8),
-            _ => {
-                // Fall back to page allocator
+
+        crate::trap::assert_not_in_interrupt("kernel heap");
+        let mut heap = self.heap.lock();
+        let Heap {
+            buckets,
+            page_cache,
+            stats,
+        } = &mut *heap;
+
+        let idx = bucket_index(size, align);
+        let addr = match idx {
+            Some(0) => allocate!(buckets, 0),
+            Some(1) => allocate!(buckets, 1),
+            Some(2) => allocate!(buckets, 2),
+            Some(3) => allocate!(buckets, 3),
+            Some(4) => allocate!(buckets, 4),
+            Some(5) => allocate!(buckets, 5),
+            Some(6) => allocate!(buckets, 6),
+            Some(7) => allocate!(buckets, 7),
+            Some(8) => allocate!(buckets, 8),
+            Some(_) => unreachable!("bucket index out of range"),
+            None => {
+                // Fall back to page allocator, reusing a cached page if one is enough
                 assert!(align as u64 <= Size4KiB::SIZE, "invalid alignment");
-                return PAGE_ALLOCATOR.allocate(layout);
+                let cached = if size as u64 <= Size4KiB::SIZE {
+                    page_cache.pop()
+                } else {
+                    None
+                };
+                let ptr = match cached {
+                    Some(page) => NonNull::slice_from_raw_parts(
+                        NonNull::new(page.as_mut_ptr()).ok_or(AllocError)?,
+                        size,
+                    ),
+                    None => PAGE_SOURCE.allocate(large_layout(layout))?,
+                };
+                stats.live_bytes += size;
+                stats.large_pages += size.div_ceil(4096);
+                return Ok(ptr);
             }
         };
 
+        if let Some(idx) = idx {
+            stats.blocks[idx] += 1;
+        }
+        stats.live_bytes += size;
+
         Ok(NonNull::slice_from_raw_parts(
             NonNull::new(addr.as_mut_ptr()).ok_or(AllocError)?,
             size,
         ))
     }
 
+    /// Bucket blocks and cached pages are reused without being cleared, so only the returned
+    /// block is zeroed, not the whole bucket page.
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.allocate(layout)?;
+        unsafe { ptr.as_mut_ptr().write_bytes(0, layout.size()) };
+        Ok(ptr)
+    }
+
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
         let size = layout.size();
         let align = layout.align();
-        let max = size.max(align);
 
         let addr = VirtAddr::from_ptr(ptr.as_ptr());
 
-        let mut buckets = self.buckets.lock();
-
-        match max {
-            0..=8 => deallocate!(buckets, 0, addr),
-            9..=16 => deallocate!(buckets, 1, addr),
-            17..=32 => deallocate!(buckets, 2, addr),
-            33..=64 => deallocate!(buckets, 3, addr),
-            65..=128 => deallocate!(buckets, 4, addr),
-            129..=256 => deallocate!(buckets, 5, addr),
-            257..=512 => deallocate!(buckets, 6, addr),
-            513..=1024 => deallocate!(buckets, 7, addr),
-            1025..=2048 => deallocate!(buckets, 8, addr),
-            _ => {
-                // Fall back to page allocator
+        crate::trap::assert_not_in_interrupt("kernel heap");
+        let mut heap = self.heap.lock();
+        let Heap {
+            buckets,
+            page_cache,
+            stats,
+        } = &mut *heap;
+
+        stats.live_bytes -= size;
+        let idx = bucket_index(size, align);
+        match idx {
+            Some(0) => deallocate!(buckets, 0, addr),
+            Some(1) => deallocate!(buckets, 1, addr),
+            Some(2) => deallocate!(buckets, 2, addr),
+            Some(3) => deallocate!(buckets, 3, addr),
+            Some(4) => deallocate!(buckets, 4, addr),
+            Some(5) => deallocate!(buckets, 5, addr),
+            Some(6) => deallocate!(buckets, 6, addr),
+            Some(7) => deallocate!(buckets, 7, addr),
+            Some(8) => deallocate!(buckets, 8, addr),
+            Some(_) => unreachable!("bucket index out of range"),
+            None => {
+                // Fall back to page allocator, keeping single pages for reuse
                 assert!(align as u64 <= Size4KiB::SIZE, "invalid alignment");
-                PAGE_ALLOCATOR.deallocate(ptr, layout);
+                if size as u64 > Size4KiB::SIZE || !page_cache.push(addr) {
+                    PAGE_SOURCE.deallocate(ptr, large_layout(layout));
+                }
+                stats.large_pages -= size.div_ceil(4096);
             }
         }
+        if let Some(idx) = idx {
+            stats.blocks[idx] -= 1;
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        match (
+            bucket_index(old_layout.size(), old_layout.align()),
+            bucket_index(new_layout.size(), new_layout.align()),
+        ) {
+            // Same size class, the block is already big enough
+            (Some(old), Some(new)) if old == new => {
+                self.resized_in_place(old_layout, new_layout, false);
+                Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+            }
+            (None, None) => {
+                let new_ptr =
+                    PAGE_SOURCE.grow(ptr, large_layout(old_layout), large_layout(new_layout))?;
+                self.resized_in_place(old_layout, new_layout, true);
+                Ok(new_ptr)
+            }
+            _ => self.move_allocation(ptr, old_layout, new_layout),
+        }
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        match (
+            bucket_index(old_layout.size(), old_layout.align()),
+            bucket_index(new_layout.size(), new_layout.align()),
+        ) {
+            // Same size class, keep the block
+            (Some(old), Some(new)) if old == new => {
+                self.resized_in_place(old_layout, new_layout, false);
+                Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+            }
+            (None, None) => {
+                let new_ptr =
+                    PAGE_SOURCE.shrink(ptr, large_layout(old_layout), large_layout(new_layout))?;
+                self.resized_in_place(old_layout, new_layout, true);
+                Ok(new_ptr)
+            }
+            _ => self.move_allocation(ptr, old_layout, new_layout),
+        }
+    }
+}
+
+impl KAllocator {
+    /// Updates the statistics for an allocation resized without moving to another size class.
+    fn resized_in_place(&self, old_layout: Layout, new_layout: Layout, large: bool) {
+        let stats = &mut self.heap.lock().stats;
+        stats.live_bytes = stats.live_bytes - old_layout.size() + new_layout.size();
+        if large {
+            stats.large_pages = stats.large_pages - old_layout.size().div_ceil(4096)
+                + new_layout.size().div_ceil(4096);
+        }
+    }
+
+    /// Moves an allocation to a new block when it changes size class.
+    unsafe fn move_allocation(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let new_ptr = self.allocate(new_layout)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.as_mut_ptr(),
+                old_layout.size().min(new_layout.size()),
+            );
+            self.deallocate(ptr, old_layout);
+        }
+        Ok(new_ptr)
     }
 }
 
 unsafe impl GlobalAlloc for KAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         self.allocate(layout)
-            .map(core::ptr::NonNull::as_mut_ptr)
-            .unwrap_or(core::ptr::null_mut())
+            .map_or(core::ptr::null_mut(), core::ptr::NonNull::as_mut_ptr)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         self.deallocate(NonNull::new_unchecked(ptr), layout);
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let ptr = NonNull::new_unchecked(ptr);
+        let res = if new_size >= layout.size() {
+            self.grow(ptr, layout, new_layout)
+        } else {
+            self.shrink(ptr, layout, new_layout)
+        };
+        res.map_or(core::ptr::null_mut(), NonNull::as_mut_ptr)
+    }
 }
 
 impl<const SIZE: usize, const BLOCK: u64> Bucket<SIZE, BLOCK> {
     fn new() -> Result<Self, AllocError> {
-        let layout = Layout::new::<u8>();
-        let page_ptr = PAGE_ALLOCATOR.allocate(layout)?.as_mut_ptr();
+        let page_ptr = PAGE_SOURCE.allocate(PAGE_LAYOUT)?.as_mut_ptr();
         let page = Page::containing_address(VirtAddr::from_ptr(page_ptr));
 
+        // Every block starts out free
+        if cfg!(debug_assertions) {
+            unsafe { core::ptr::write_bytes(page_ptr, POISON, Size4KiB::SIZE as usize) };
+        }
+
         Ok(Self {
             page,
             bitmap: [0; SIZE],
@@ -173,6 +490,12 @@ impl<const SIZE: usize, const BLOCK: u64> Bucket<SIZE, BLOCK> {
         })
     }
 
+    /// Number of pages owned by this bucket and the ones chained after it.
+    fn pages(&self) -> usize {
+        // Chained buckets are boxed in a page of their own
+        1 + self.next.as_ref().map_or(0, |next| 1 + next.pages())
+    }
+
     fn is_empty(&self) -> bool {
         self.bitmap.iter().all(|byte| *byte == 0)
     }
@@ -198,32 +521,263 @@ impl<const SIZE: usize, const BLOCK: u64> Bucket<SIZE, BLOCK> {
         }
 
         if let Some(offset) = offset {
-            return Ok(self.page.start_address() + offset * BLOCK);
+            let addr = self.page.start_address() + offset * BLOCK;
+            if cfg!(debug_assertions) {
+                Self::check_poison(addr);
+            }
+            return Ok(addr);
         }
 
         if let Some(next) = &mut self.next {
             next.allocate_block()
         } else {
-            let mut next = Box::new_in(Self::new()?, &PAGE_ALLOCATOR);
+            let mut next = Box::new_in(Self::new()?, PAGE_SOURCE);
             let addr = next.allocate_block()?;
             self.next = Some(next);
             Ok(addr)
         }
     }
 
+    /// Frees the block at `addr`.
+    ///
+    /// In debug builds, panics if the block is already free or `addr` isn't the start of a block
+    /// owned by this bucket chain, and fills the block with [`POISON`].
     fn free_block(&mut self, addr: VirtAddr) {
         if addr.align_down(Size4KiB::SIZE) == self.page.start_address() {
-            let offset = (addr - self.page.start_address()) / BLOCK;
+            let offset = addr - self.page.start_address();
+            debug_assert!(
+                offset.is_multiple_of(BLOCK),
+                "invalid free of {addr:?}: not the start of a {BLOCK} byte block"
+            );
+
+            let offset = offset / BLOCK;
             let byte = offset as usize / 8;
             let bit = 7 - (offset as usize % 8);
 
+            debug_assert!(
+                self.bitmap[byte] & (1 << bit) != 0,
+                "double free of {addr:?}"
+            );
             self.bitmap[byte] &= !(1 << bit);
+
+            if cfg!(debug_assertions) {
+                unsafe { core::ptr::write_bytes(addr.as_mut_ptr::<u8>(), POISON, BLOCK as usize) };
+            }
         } else if let Some(next) = &mut self.next {
             next.free_block(addr);
 
             if next.is_empty() {
                 self.next = next.next.take();
             }
+        } else if cfg!(debug_assertions) {
+            panic!("invalid free of {addr:?}: not owned by the {BLOCK} byte bucket");
+        }
+    }
+
+    /// Panics if the free block at `addr` was written to since it was poisoned.
+    fn check_poison(addr: VirtAddr) {
+        // SAFETY: the block is in a page owned by the bucket, and free
+        let block = unsafe { core::slice::from_raw_parts(addr.as_ptr::<u8>(), BLOCK as usize) };
+        if let Some(i) = block.iter().position(|&byte| byte != POISON) {
+            panic!(
+                "heap corruption: free {BLOCK} byte block {addr:?} was written to at offset {i} \
+                 ({:#04x})",
+                block[i]
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    const SMALL: Layout = Layout::new::<[u8; 16]>();
+
+    #[test]
+    fn bucket_index_boundaries() {
+        for (i, &block) in BUCKET_SIZES.iter().enumerate() {
+            assert_eq!(bucket_index(block, 1), Some(i), "size {block}");
+            let next = (i + 1 < BUCKET_SIZES.len()).then_some(i + 1);
+            assert_eq!(bucket_index(block + 1, 1), next, "size {}", block + 1);
+        }
+        assert_eq!(bucket_index(0, 1), Some(0));
+        assert_eq!(bucket_index(1, 1), Some(0));
+    }
+
+    #[test]
+    fn bucket_index_alignment_dominated() {
+        assert_eq!(bucket_index(8, 256), Some(5));
+        assert_eq!(bucket_index(8, 64), Some(3));
+        assert_eq!(bucket_index(300, 256), Some(6));
+        assert_eq!(bucket_index(8, 2048), Some(8));
+    }
+
+    #[test]
+    fn bucket_index_large_fallback() {
+        assert_eq!(bucket_index(2049, 8), None);
+        assert_eq!(bucket_index(8, 4096), None);
+        assert_eq!(bucket_index(4096, 4096), None);
+    }
+
+    #[test]
+    fn live_bytes_return_to_zero() {
+        let heap = KAllocator::new();
+        let layouts: Vec<_> = [1, 8, 24, 100, 700, 2048]
+            .into_iter()
+            .map(|size| Layout::from_size_align(size, 8).unwrap())
+            .collect();
+
+        let ptrs: Vec<_> = layouts.iter().map(|&l| heap.allocate(l).unwrap()).collect();
+        assert_eq!(heap.stats().live_bytes, 1 + 8 + 24 + 100 + 700 + 2048);
+
+        // Moves to another size class and back
+        let grown = Layout::from_size_align(300, 8).unwrap();
+        let ptr = unsafe { heap.grow(ptrs[2].as_non_null_ptr(), layouts[2], grown) }.unwrap();
+        let ptr = unsafe { heap.shrink(ptr.as_non_null_ptr(), grown, layouts[2]) }.unwrap();
+
+        for (i, (p, &layout)) in ptrs.iter().zip(&layouts).enumerate() {
+            let p = if i == 2 { ptr } else { *p };
+            unsafe { heap.deallocate(p.as_non_null_ptr(), layout) };
+        }
+        let stats = heap.stats();
+        assert_eq!(stats.live_bytes, 0);
+        assert_eq!(stats.blocks, [0; BUCKET_SIZES.len()]);
+    }
+
+    #[test]
+    fn resizing_moves_only_across_size_classes() {
+        let heap = KAllocator::new();
+        let layout = |size| Layout::from_size_align(size, 8).unwrap();
+
+        let ptr = heap.allocate(layout(10)).unwrap();
+        unsafe { ptr.as_mut_ptr().copy_from(b"resizable!".as_ptr(), 10) };
+
+        // 10 and 16 bytes share a size class, so does 9
+        let grown = unsafe { heap.grow(ptr.as_non_null_ptr(), layout(10), layout(16)) }.unwrap();
+        assert_eq!(grown.as_mut_ptr(), ptr.as_mut_ptr());
+        assert_eq!(grown.len(), 16);
+        let shrunk =
+            unsafe { heap.shrink(grown.as_non_null_ptr(), layout(16), layout(9)) }.unwrap();
+        assert_eq!(shrunk.as_mut_ptr(), ptr.as_mut_ptr());
+
+        // Another size class moves the block, keeping its contents
+        let moved = unsafe { heap.grow(shrunk.as_non_null_ptr(), layout(9), layout(100)) }.unwrap();
+        assert_ne!(moved.as_mut_ptr(), ptr.as_mut_ptr());
+        assert_eq!(unsafe { &moved.as_ref()[..9] }, b"resizable");
+        let back = unsafe { heap.shrink(moved.as_non_null_ptr(), layout(100), layout(9)) }.unwrap();
+        assert_ne!(back.as_mut_ptr(), moved.as_mut_ptr());
+        assert_eq!(unsafe { &back.as_ref()[..9] }, b"resizable");
+
+        unsafe { heap.deallocate(back.as_non_null_ptr(), layout(9)) };
+        assert_eq!(heap.stats().live_bytes, 0);
+    }
+
+    #[test]
+    fn reused_block_is_zeroed() {
+        let heap = KAllocator::new();
+        // Keeps the bucket from being released after the free
+        let _kept = heap.allocate(SMALL).unwrap();
+
+        let ptr = heap.allocate(SMALL).unwrap();
+        unsafe {
+            ptr.as_mut_ptr().write_bytes(0xAB, SMALL.size());
+            heap.deallocate(ptr.as_non_null_ptr(), SMALL);
+        }
+
+        let zeroed = heap.allocate_zeroed(SMALL).unwrap();
+        assert_eq!(zeroed.as_mut_ptr(), ptr.as_mut_ptr());
+        assert_eq!(unsafe { zeroed.as_ref() }, [0; 16]);
+    }
+
+    #[test]
+    fn empty_head_bucket_is_released() {
+        let heap = KAllocator::new();
+
+        let ptrs: Vec<_> = (0..1000).map(|_| heap.allocate(SMALL).unwrap()).collect();
+        assert!(heap.stats().pages > 1);
+        for ptr in ptrs {
+            unsafe { heap.deallocate(ptr.as_non_null_ptr(), SMALL) };
+        }
+
+        let stats = heap.stats();
+        assert_eq!(stats.blocks[1], 0);
+        assert_eq!(stats.live_bytes, 0);
+        assert_eq!(stats.pages, 0);
+    }
+
+    #[test]
+    fn emptied_head_promotes_the_next_bucket() {
+        let heap = KAllocator::new();
+
+        let ptrs: Vec<_> = (0..1000).map(|_| heap.allocate(SMALL).unwrap()).collect();
+        let pages = heap.stats().pages;
+        // The head bucket holds the first blocks, freeing them leaves the chained ones
+        let per_page = 4096 / SMALL.size();
+        for &ptr in &ptrs[..per_page] {
+            unsafe { heap.deallocate(ptr.as_non_null_ptr(), SMALL) };
+        }
+        assert!(heap.stats().pages < pages);
+
+        // The promoted buckets still own their blocks
+        for &ptr in &ptrs[per_page..] {
+            unsafe { ptr.as_mut_ptr().write_bytes(0xAB, SMALL.size()) };
+        }
+        for &ptr in &ptrs[per_page..] {
+            unsafe { heap.deallocate(ptr.as_non_null_ptr(), SMALL) };
+        }
+        assert_eq!(heap.stats().pages, 0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic = "double free"]
+    fn double_free_panics() {
+        let heap = KAllocator::new();
+        // Keeps the bucket from being released after the first free
+        let _kept = heap.allocate(SMALL).unwrap();
+
+        let ptr = heap.allocate(SMALL).unwrap();
+        unsafe {
+            heap.deallocate(ptr.as_non_null_ptr(), SMALL);
+            heap.deallocate(ptr.as_non_null_ptr(), SMALL);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic = "heap corruption"]
+    fn write_after_free_panics() {
+        let heap = KAllocator::new();
+        // Keeps the bucket from being released after the free
+        let _kept = heap.allocate(SMALL).unwrap();
+
+        let ptr = heap.allocate(SMALL).unwrap();
+        unsafe {
+            heap.deallocate(ptr.as_non_null_ptr(), SMALL);
+            ptr.as_mut_ptr().add(3).write(0);
+        }
+        let _ = heap.allocate(SMALL);
+    }
+
+    #[test]
+    fn page_cache_reuses_freed_pages() {
+        let heap = KAllocator::new();
+        let layout = Layout::from_size_align(3 * 1024, 8).unwrap();
+
+        let first = heap.allocate(layout).unwrap();
+        unsafe { heap.deallocate(first.as_non_null_ptr(), layout) };
+        let warm = heap.stats();
+        assert_eq!(warm.cached_pages, 1);
+
+        for _ in 0..100 {
+            let ptr = heap.allocate(layout).unwrap();
+            assert_eq!(ptr.as_mut_ptr(), first.as_mut_ptr());
+            assert_eq!(heap.stats().cached_pages, 0);
+            unsafe { heap.deallocate(ptr.as_non_null_ptr(), layout) };
+            assert_eq!(heap.stats(), warm);
         }
     }
 }
@@ -29,8 +29,8 @@ type FPAGuard<'a> = lock_api::MappedMutexGuard<'a, spin::Mutex<()>, FPAInner>;
 
 struct FPAInner {
     entries: [Entry; ENTRIES_LEN],
-    prev: Option<NonNull<FPAInner>>,
-    next: Option<NonNull<FPAInner>>,
+    prev: Option<NonNull<Self>>,
+    next: Option<NonNull<Self>>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -54,7 +54,7 @@ impl FullPageAllocator {
     }
 
     /// Retrieve the inner [`FPAInner`] struct or initialize it if it doesn't exist.
-    fn init_or_get(&self) -> Result<FPAGuard, AllocError> {
+    fn init_or_get(&self) -> Result<FPAGuard<'_>, AllocError> {
         let mut inner = self.inner.lock();
         if inner.is_none() {
             add_entry_page(&mut inner)?;
@@ -92,6 +92,17 @@ unsafe impl Allocator for FullPageAllocator {
         ))
     }
 
+    /// Freshly mapped frames can't be assumed to be zero: the firmware leaves data behind in
+    /// usable memory and [`free_kpage`] only scrubs freed pages in debug builds.
+    /// So every mapped page is zeroed, including the slack after `layout.size()`, which keeps
+    /// pages grown in place clean too.
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.allocate(layout)?;
+        let num_pages = layout.size().div_ceil(4096);
+        unsafe { ptr.as_mut_ptr().write_bytes(0, num_pages * 4096) };
+        Ok(ptr)
+    }
+
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
         let size = layout.size();
         let num_pages = size.div_ceil(4096);
@@ -101,38 +112,107 @@ unsafe impl Allocator for FullPageAllocator {
             end: Page::containing_address(VirtAddr::from_ptr(ptr.as_ptr()) + 0x1000 * num_pages),
         };
 
-        let mut fr_alloc = FRAME_ALLOCATOR.lock();
-        let alloc = fr_alloc.as_mut().unwrap();
+        {
+            let mut fr_alloc = FRAME_ALLOCATOR.lock();
+            let alloc = fr_alloc.as_mut().unwrap();
+
+            for page in pages {
+                unsafe { free_kpage(alloc, page.start_address()) };
+            }
+        }
+
+        // Give back the range, so it can be reused and allocations before it can grow in place.
+        // If the free list can't grow, the range is leaked rather than failing the free.
+        if let Ok(mut inner) = self.init_or_get() {
+            let _ = unsafe { inner.dealloc_pages(pages.start.start_address(), num_pages as u64) };
+        }
+    }
+
+    /// Grows the allocation in place if the pages right after it are free,
+    /// otherwise falls back to allocating, copying and deallocating.
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let old_pages = old_layout.size().div_ceil(4096) as u64;
+        let new_pages = new_layout.size().div_ceil(4096) as u64;
+
+        if new_pages > old_pages {
+            let tail = VirtAddr::from_ptr(ptr.as_ptr()) + old_pages * 0x1000;
+            let extra = new_pages - old_pages;
+
+            let in_place = self.init_or_get()?.try_alloc_pages(tail, extra);
+
+            if !in_place {
+                let new_ptr = self.allocate(new_layout)?;
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        ptr.as_ptr(),
+                        new_ptr.as_mut_ptr(),
+                        old_layout.size(),
+                    );
+                    self.deallocate(ptr, old_layout);
+                }
+                return Ok(new_ptr);
+            }
 
-        for page in pages {
-            unsafe { free_kpage(alloc, page.start_address()) };
+            // Allocate the new tail pages
+            let mut fr_alloc = FRAME_ALLOCATOR.lock();
+            let alloc = fr_alloc.as_mut().unwrap();
+
+            for i in 0..extra {
+                if let Err(e) = unsafe { alloc_kpage(alloc, tail + i * 0x1000) } {
+                    // Unmap the pages mapped so far and give back the reserved tail
+                    for mapped in 0..i {
+                        unsafe { free_kpage(alloc, tail + mapped * 0x1000) };
+                    }
+                    drop(fr_alloc);
+                    unsafe { self.init_or_get()?.dealloc_pages(tail, extra) }?;
+                    return Err(e.into());
+                }
+            }
         }
+
+        Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()))
     }
 
-    // unsafe fn grow(
-    //     &self,
-    //     ptr: NonNull<u8>,
-    //     old_layout: Layout,
-    //     new_layout: Layout,
-    // ) -> Result<NonNull<[u8]>, AllocError> {
-    //     todo!()
-    // }
-    //
-    // unsafe fn shrink(
-    //     &self,
-    //     ptr: NonNull<u8>,
-    //     old_layout: Layout,
-    //     new_layout: Layout,
-    // ) -> Result<NonNull<[u8]>, AllocError> {
-    //     todo!()
-    // }
+    /// Shrinks the allocation in place, returning the trailing pages to the allocator.
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let old_pages = old_layout.size().div_ceil(4096) as u64;
+        let new_pages = new_layout.size().div_ceil(4096) as u64;
+
+        if new_pages < old_pages {
+            let tail = VirtAddr::from_ptr(ptr.as_ptr()) + new_pages * 0x1000;
+            let extra = old_pages - new_pages;
+
+            // Free the trailing pages
+            {
+                let mut fr_alloc = FRAME_ALLOCATOR.lock();
+                let alloc = fr_alloc.as_mut().unwrap();
+
+                for i in 0..extra {
+                    unsafe { free_kpage(alloc, tail + i * 0x1000) };
+                }
+            }
+
+            unsafe { self.init_or_get()?.dealloc_pages(tail, extra) }?;
+        }
+
+        Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+    }
 }
 
 unsafe impl GlobalAlloc for FullPageAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         self.allocate(layout)
-            .map(core::ptr::NonNull::as_mut_ptr)
-            .unwrap_or(core::ptr::null_mut())
+            .map_or(core::ptr::null_mut(), core::ptr::NonNull::as_mut_ptr)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
@@ -156,6 +236,31 @@ impl FPAInner {
             .and_then(|next| unsafe { next.as_ref().find_free_pages(req_pages) })
     }
 
+    /// Checks whether the `req_pages` pages starting at `start` are free.
+    fn is_free(&self, start: VirtAddr, req_pages: u64) -> bool {
+        for entry in &self.entries {
+            let Entry::Usable { start: s, pages } = *entry else {
+                return false;
+            };
+            if s == start {
+                return pages >= req_pages;
+            }
+        }
+
+        // Not found in this entry page, search next
+        self.next
+            .is_some_and(|next| unsafe { next.as_ref().is_free(start, req_pages) })
+    }
+
+    /// Allocates the `pages` pages starting at `start` if they're all free.
+    fn try_alloc_pages(&mut self, start: VirtAddr, pages: u64) -> bool {
+        let free = self.is_free(start, pages);
+        if free {
+            self.alloc_pages(start, pages);
+        }
+        free
+    }
+
     fn insert_entry(&mut self, idx: usize, entry: Entry) -> Result<(), AllocError> {
         // If last entry is usable, move it to next page
         if let Entry::Usable { .. } = self.entries[ENTRIES_LEN - 1] {
@@ -313,11 +418,12 @@ impl FPAInner {
 
             // Check if we can add directly to this entry
             if s == start + pages * 0x1000 {
-                // Add to start of entry
+                // Add to start of entry, which may now follow the previous one
                 *entry = Entry::Usable {
                     start,
                     pages: p + pages,
                 };
+                self.squash_entries();
                 return Ok(());
             }
 
@@ -342,14 +448,14 @@ impl FPAInner {
 
     /// Append a new entry page to the linked list.
     fn add_entry_page(&mut self) -> Result<&mut Self, AllocError> {
-        let mut inner = Some(NonNull::from(&*self));
+        let mut inner = Some(NonNull::from(&mut *self));
         add_entry_page(&mut inner)?;
         Ok(unsafe { self.next.unwrap().as_mut() })
     }
 
     /// Removes last entry page from linked list.
     fn remove_entry_page(&mut self) {
-        let mut inner = Some(NonNull::from(&*self));
+        let mut inner = Some(NonNull::from(&mut *self));
         remove_entry_page(&mut inner);
     }
 }
@@ -431,7 +537,7 @@ fn remove_entry_page(fpa_inner: &mut Option<NonNull<FPAInner>>) {
                 unsafe {
                     free_kpage(
                         alloc.as_mut().unwrap(),
-                        VirtAddr::from_ptr(last as *const _),
+                        VirtAddr::from_ptr(core::ptr::from_ref(last)),
                     );
                 };
             }
@@ -445,3 +551,68 @@ fn remove_entry_page(fpa_inner: &mut Option<NonNull<FPAInner>>) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+
+    use super::*;
+
+    const BASE: VirtAddr = VirtAddr::new_truncate(0x4000_0000);
+
+    /// Free list of 16 pages at [`BASE`], small enough to never need a second entry page
+    fn inner() -> Box<FPAInner> {
+        let mut inner = Box::new(FPAInner {
+            entries: [Entry::Empty; ENTRIES_LEN],
+            prev: None,
+            next: None,
+        });
+        inner.entries[0] = Entry::Usable {
+            start: BASE,
+            pages: 16,
+        };
+        inner
+    }
+
+    fn alloc(inner: &mut FPAInner, pages: u64) -> VirtAddr {
+        let addr = inner.find_free_pages(pages).unwrap();
+        inner.alloc_pages(addr, pages);
+        addr
+    }
+
+    #[test]
+    fn grows_in_place_next_to_a_free_range() {
+        let mut inner = inner();
+        let a = alloc(&mut inner, 2);
+
+        assert!(inner.try_alloc_pages(a + 0x2000u64, 3));
+        // The grown pages aren't handed out again
+        assert_eq!(alloc(&mut inner, 1), a + 0x5000u64);
+        assert!(!inner.try_alloc_pages(a + 0x6000u64, 11));
+    }
+
+    #[test]
+    fn boxed_in_allocation_grows_once_its_neighbour_is_freed() {
+        let mut inner = inner();
+        let a = alloc(&mut inner, 1);
+        let b = alloc(&mut inner, 1);
+        let c = alloc(&mut inner, 1);
+        assert!(!inner.try_alloc_pages(a + 0x1000u64, 1));
+
+        // Freed ranges merge with their neighbours, wherever they are in the free list
+        unsafe { inner.dealloc_pages(b, 1) }.unwrap();
+        assert!(inner.try_alloc_pages(a + 0x1000u64, 1));
+        unsafe { inner.dealloc_pages(a, 2) }.unwrap();
+        unsafe { inner.dealloc_pages(c, 1) }.unwrap();
+        assert!(matches!(
+            inner.entries[..2],
+            [
+                Entry::Usable {
+                    start: BASE,
+                    pages: 16
+                },
+                Entry::Empty
+            ]
+        ));
+    }
+}
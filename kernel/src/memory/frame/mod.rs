@@ -6,21 +6,53 @@ use bootloader_api::info::{MemoryRegion, MemoryRegionKind, MemoryRegions};
 use x86_64::{
     structures::paging::{
         FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame,
-        Size4KiB,
+        Size4KiB, Translate,
     },
     PhysAddr,
 };
 
-use crate::memory::{frame::boot::BootFrameAllocator, layout::BITMAP_FRAME_ALLOCATOR_START};
+use crate::memory::{
+    frame::boot::BootFrameAllocator,
+    layout::{BITMAP_FRAME_ALLOCATOR_END, BITMAP_FRAME_ALLOCATOR_START, PHYSICAL_MEM_START},
+};
+
+/// E820 type of ACPI reclaimable memory
+const E820_ACPI_RECLAIMABLE: u32 = 3;
+/// UEFI memory type of ACPI reclaimable memory (`EfiACPIReclaimMemory`)
+const UEFI_ACPI_RECLAIMABLE: u32 = 9;
+
+/// Maximum number of regions that can be added with [`BitmapFrameAllocator::add_region`]
+pub const MAX_HOTPLUG_REGIONS: usize = 8;
+
+const EMPTY_REGION: MemoryRegion = MemoryRegion {
+    start: 0,
+    end: 0,
+    kind: MemoryRegionKind::Usable,
+};
 
 /// Bitmap frame allocator.
 ///
 /// This allocator uses a bitmap to keep track of which frames are free.
 /// The bitmap is stored in the first N frames, where N is the number of frames required to store the bitmap for the
 /// entire physical memory space.
+///
+/// The bitmap also covers ACPI reclaimable memory, after all usable memory. Those frames start out used
+/// and are only freed by [`reclaim_acpi`](Self::reclaim_acpi) once the ACPI tables are no longer needed.
+///
+/// Memory added after boot with [`add_region`](Self::add_region) comes last.
 pub struct BitmapFrameAllocator {
     regions: &'static MemoryRegions,
+    /// Regions added after boot, the first `hotplug_len` are valid
+    hotplug: [MemoryRegion; MAX_HOTPLUG_REGIONS],
+    hotplug_len: usize,
     bitmap: &'static mut [u64],
+    /// Word to start searching for a free frame from.
+    /// Every word before it is full.
+    next_search: usize,
+    /// Number of frames available for allocation, used or not
+    total: u64,
+    /// Number of free frames
+    free: u64,
 }
 
 unsafe impl Send for BitmapFrameAllocator {}
@@ -40,24 +72,32 @@ impl BitmapFrameAllocator {
         pt: &mut OffsetPageTable<'static>,
         alloc: BootFrameAllocator,
     ) -> Self {
-        let bitmap = Self::allocate_bitmap(regions, pt, alloc);
-        Self { regions, bitmap }
+        let (bitmap, bitmap_frames) = Self::allocate_bitmap(regions, pt, alloc);
+        let total = region_frames(usable_regions(regions));
+        Self {
+            regions,
+            hotplug: [EMPTY_REGION; MAX_HOTPLUG_REGIONS],
+            hotplug_len: 0,
+            bitmap,
+            next_search: 0,
+            total,
+            free: total - bitmap_frames,
+        }
     }
 
     /// Calculate the required size of the bitmap in bytes.
     fn required_bitmap_size(regions: &MemoryRegions) -> u64 {
-        let total_bytes =
-            usable_regions(regions).fold(0, |acc, region| acc + region.end - region.start);
-
-        total_bytes.div_ceil(4096).div_ceil(8)
+        region_frames(managed_regions(regions)).div_ceil(8)
     }
 
     /// Allocate required space for the bitmap in the first usable frame.
+    ///
+    /// Returns the bitmap and the number of frames used by the boot frame allocator to build it.
     fn allocate_bitmap(
         regions: &MemoryRegions,
         pt: &mut OffsetPageTable<'static>,
         mut alloc: BootFrameAllocator,
-    ) -> &'static mut [u64] {
+    ) -> (&'static mut [u64], u64) {
         let bitmap_size = Self::required_bitmap_size(regions);
         let bitmap_frames = bitmap_size.div_ceil(4096);
 
@@ -98,46 +138,317 @@ impl BitmapFrameAllocator {
             )
         };
 
-        // Zero out the bitmap
-        slice.fill(0);
+        let used = alloc.used() as u64;
+        Self::init_bitmap(slice, regions, used);
+
+        (slice, used)
+    }
+
+    /// Clears `bitmap`, then marks the first `used` frames and the ACPI reclaimable frames used.
+    fn init_bitmap(bitmap: &mut [u64], regions: &MemoryRegions, used: u64) {
+        bitmap.fill(0);
 
         // Mark the bitmap frames as used
-        for i in 0..alloc.used() {
-            Self::mark_frame_used(slice, i as u64);
+        for i in 0..used {
+            Self::mark_frame_used(bitmap, i);
+        }
+
+        // ACPI tables may still be in use
+        let usable = region_frames(usable_regions(regions));
+        for frame in usable..region_frames(managed_regions(regions)) {
+            Self::mark_frame_used(bitmap, frame);
         }
+    }
 
-        slice
+    /// Every region tracked by the bitmap, in frame number order.
+    fn all_regions(&self) -> impl Iterator<Item = &MemoryRegion> {
+        managed_regions(self.regions).chain(&self.hotplug[..self.hotplug_len])
+    }
+
+    /// Adds hot-plugged memory to the allocator, growing the bitmap to cover it.
+    ///
+    /// The bitmap grows in place and is never relocated. Its virtual area is large enough for
+    /// 32 PiB of memory, past that this fails with [`AddRegionError::OutOfMemory`]. The new bitmap
+    /// pages are backed by the first frames of `region`, and the region is mapped into the direct
+    /// physical memory map if the bootloader didn't map it. Page tables for either mapping are
+    /// allocated from the memory already managed. If adding the region fails, the direct map
+    /// entries and page tables are kept.
+    ///
+    /// Only whole frames within `region` are used.
+    pub fn add_region(
+        &mut self,
+        region: MemoryRegion,
+        pt: &mut OffsetPageTable<'static>,
+    ) -> Result<(), AddRegionError> {
+        if region.kind != MemoryRegionKind::Usable {
+            return Err(AddRegionError::Unusable);
+        }
+        if self.hotplug_len == MAX_HOTPLUG_REGIONS {
+            return Err(AddRegionError::TooManyRegions);
+        }
+
+        let start = region.start.next_multiple_of(4096);
+        let end = region.end & !0xFFF;
+        if start >= end {
+            return Err(AddRegionError::Unusable);
+        }
+        if self
+            .all_regions()
+            .any(|other| start < other.end && other.start < end)
+        {
+            return Err(AddRegionError::Overlaps);
+        }
+
+        let first = region_frames(self.all_regions());
+        let frames = (end - start) / 4096;
+
+        let old_len = self.bitmap.len();
+        let old_pages = size_of_val(self.bitmap) as u64 / 4096;
+        let new_pages = ((first + frames).div_ceil(64) * 8)
+            .div_ceil(4096)
+            .max(old_pages);
+        let grow = new_pages - old_pages;
+        if grow >= frames {
+            // Too small to even hold its own part of the bitmap
+            return Err(AddRegionError::Unusable);
+        }
+        if BITMAP_FRAME_ALLOCATOR_START + (new_pages * 4096 - 1) > BITMAP_FRAME_ALLOCATOR_END {
+            return Err(AddRegionError::OutOfMemory);
+        }
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+        // Page tables may be allocated from the region later, so it must be in the direct map
+        for frame in (start..end).step_by(4096) {
+            let virt = PHYSICAL_MEM_START + frame;
+            if pt.translate_addr(virt).is_some() {
+                continue;
+            }
+            let page = Page::<Size4KiB>::containing_address(virt);
+            let frame = PhysFrame::containing_address(PhysAddr::new(frame));
+            unsafe { pt.map_to(page, frame, flags, &mut *self) }
+                .map_err(|_| AddRegionError::OutOfMemory)?
+                .flush();
+        }
+
+        let bitmap_page =
+            |i: u64| Page::<Size4KiB>::containing_address(BITMAP_FRAME_ALLOCATOR_START + i * 4096);
+        for i in 0..grow {
+            let frame = PhysFrame::containing_address(PhysAddr::new(start + i * 4096));
+            let res = unsafe { pt.map_to(bitmap_page(old_pages + i), frame, flags, &mut *self) };
+            let Ok(flush) = res else {
+                for page in (0..i).map(|j| bitmap_page(old_pages + j)) {
+                    if let Ok((_, flush)) = pt.unmap(page) {
+                        flush.flush();
+                    }
+                }
+                return Err(AddRegionError::OutOfMemory);
+            };
+            flush.flush();
+        }
+
+        if grow != 0 {
+            let len = new_pages as usize * (4096 / size_of::<u64>());
+            self.bitmap = unsafe {
+                core::slice::from_raw_parts_mut(BITMAP_FRAME_ALLOCATOR_START.as_mut_ptr(), len)
+            };
+            self.bitmap[old_len..].fill(0);
+        }
+        // The old bitmap may end partway through the new frames
+        for frame in first..(old_len as u64 * 64).min(first + frames) {
+            Self::mark_frame_free(self.bitmap, frame);
+        }
+        for frame in first..first + grow {
+            Self::mark_frame_used(self.bitmap, frame);
+        }
+
+        self.hotplug[self.hotplug_len] = MemoryRegion {
+            start,
+            end,
+            kind: MemoryRegionKind::Usable,
+        };
+        self.hotplug_len += 1;
+        self.total += frames;
+        self.free += frames - grow;
+        Ok(())
+    }
+
+    /// Marks the frames covering `len` bytes at `start` as used, so they're never handed out.
+    ///
+    /// Frames outside of the managed regions are ignored, they're never handed out anyway.
+    /// Nothing is reserved if any frame in the range is already used.
+    pub fn reserve_range(&mut self, start: PhysAddr, len: u64) -> Result<(), FrameInUse> {
+        if len == 0 {
+            return Ok(());
+        }
+        let first = PhysFrame::<Size4KiB>::containing_address(start);
+        let last = PhysFrame::<Size4KiB>::containing_address(start + (len - 1));
+
+        for frame in PhysFrame::range_inclusive(first, last) {
+            let idx = self.address_to_frame(frame.start_address());
+            if idx.is_some_and(|idx| Self::is_frame_used(self.bitmap, idx)) {
+                return Err(FrameInUse(frame));
+            }
+        }
+
+        for frame in PhysFrame::range_inclusive(first, last) {
+            if let Some(idx) = self.address_to_frame(frame.start_address()) {
+                Self::mark_frame_used(self.bitmap, idx);
+                self.free -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of frames managed by the allocator.
+    ///
+    /// Includes ACPI reclaimable memory only once it's reclaimed.
+    pub const fn total_frames(&self) -> u64 {
+        self.total
+    }
+
+    /// Number of frames available for allocation.
+    pub const fn free_frames(&self) -> u64 {
+        self.free
+    }
+
+    /// Number of allocated frames.
+    pub const fn used_frames(&self) -> u64 {
+        self.total - self.free
+    }
+
+    /// Frees the ACPI reclaimable frames, returning how many were freed.
+    ///
+    /// # Safety
+    ///
+    /// The ACPI tables must not be accessed afterward.
+    pub unsafe fn reclaim_acpi(&mut self) -> u64 {
+        let usable = region_frames(usable_regions(self.regions));
+        let total = region_frames(managed_regions(self.regions));
+        for frame in usable..total {
+            Self::mark_frame_free(self.bitmap, frame);
+        }
+
+        self.next_search = self.next_search.min((usable / 64) as usize);
+        self.total += total - usable;
+        self.free += total - usable;
+        total - usable
     }
 
     #[inline]
-    fn mark_frame_used(bitmap: &mut [u64], frame: u64) {
+    const fn mark_frame_used(bitmap: &mut [u64], frame: u64) {
         let word = frame / 64;
         let bit = 63 - (frame % 64);
         bitmap[word as usize] |= 1 << bit;
     }
 
     #[inline]
-    fn mark_frame_free(bitmap: &mut [u64], frame: u64) {
+    const fn mark_frame_free(bitmap: &mut [u64], frame: u64) {
         let word = frame / 64;
         let bit = 63 - (frame % 64);
         bitmap[word as usize] &= !(1 << bit);
     }
 
+    #[inline]
+    const fn is_frame_used(bitmap: &[u64], frame: u64) -> bool {
+        let word = frame / 64;
+        let bit = 63 - (frame % 64);
+        bitmap[word as usize] & (1 << bit) != 0
+    }
+
+    /// Allocates `count` physically contiguous frames, returning the first one.
+    ///
+    /// The frames are always within a single memory region, since two regions may not be
+    /// adjacent in physical memory.
+    pub fn allocate_contiguous(&mut self, count: u64) -> Option<PhysFrame<Size4KiB>> {
+        if count == 0 {
+            return None;
+        }
+
+        let mut start = None;
+        let mut region_first = 0;
+        for region in self.all_regions() {
+            let frames = (region.end - region.start) / 4096;
+
+            // Runs restart at every region boundary
+            let mut run = 0;
+            for frame in region_first..region_first + frames {
+                if Self::is_frame_used(self.bitmap, frame) {
+                    run = 0;
+                    continue;
+                }
+                run += 1;
+                if run == count {
+                    start = Some(frame + 1 - count);
+                    break;
+                }
+            }
+
+            if start.is_some() {
+                break;
+            }
+            region_first += frames;
+        }
+
+        let start = start?;
+        let addr = self.frame_to_address(start)?;
+        for frame in start..start + count {
+            Self::mark_frame_used(self.bitmap, frame);
+        }
+        self.free -= count;
+
+        Some(PhysFrame::from_start_address(addr).expect("All frame address are page aligned"))
+    }
+
+    /// Frees `count` contiguous frames allocated by [`allocate_contiguous`](Self::allocate_contiguous).
+    ///
+    /// # Safety
+    ///
+    /// The frames must no longer be in use.
+    pub unsafe fn deallocate_contiguous(&mut self, first: PhysFrame<Size4KiB>, count: u64) {
+        let start = self
+            .address_to_frame(first.start_address())
+            .expect("frame should be located in regions");
+
+        for frame in start..start + count {
+            Self::mark_frame_free(self.bitmap, frame);
+        }
+        self.free += count;
+
+        self.next_search = self.next_search.min((start / 64) as usize);
+    }
+
     /// Find the first free frame in the bitmap.
+    ///
+    /// Starts scanning at the search cursor, skipping the full words before it, and wraps around
+    /// to the start of the bitmap if nothing is free after the cursor.
+    ///
+    /// The bitmap is longer than the number of frames, the bits past the last frame are never
+    /// free frames.
     fn first_free_frame(&self) -> Option<u64> {
-        for (i, word) in self.bitmap.iter().enumerate() {
-            if *word != 0 {
+        let frames = region_frames(self.all_regions());
+        for (i, word) in self.search_order() {
+            if *word != u64::MAX {
                 // Found a word with an empty frame
                 let bit: u64 = u64::from(word.leading_ones());
-                return Some(i as u64 * 64 + bit);
+                let frame = i as u64 * 64 + bit;
+                if frame < frames {
+                    return Some(frame);
+                }
             }
         }
         None
     }
 
+    /// Bitmap words with their indices, from the search cursor to the end then from the start
+    fn search_order(&self) -> impl Iterator<Item = (usize, &u64)> {
+        let (before, after) = self.bitmap.split_at(self.next_search);
+        (self.next_search..).zip(after).chain((0..).zip(before))
+    }
+
     /// Convert a frame number to a physical address.
     fn frame_to_address(&self, mut frame: u64) -> Option<PhysAddr> {
-        for region in usable_regions(self.regions) {
+        for region in self.all_regions() {
             let frames = (region.end - region.start) / 4096;
             if frame < frames {
                 return Some(PhysAddr::new(region.start + frame * 4096));
@@ -150,27 +461,46 @@ impl BitmapFrameAllocator {
     /// Convert a physical address to a frame number.
     fn address_to_frame(&self, addr: PhysAddr) -> Option<u64> {
         let mut frame = 0;
-        for region in usable_regions(self.regions) {
+        for region in self.all_regions() {
             if addr.as_u64() >= region.start && addr.as_u64() < region.end {
                 return Some(frame + (addr.as_u64() - region.start) / 4096);
             }
-            frame += (region.start - region.end) / 4096;
+            frame += (region.end - region.start) / 4096;
         }
         None
     }
 }
 
+/// Error adding a region with [`BitmapFrameAllocator::add_region`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AddRegionError {
+    /// Region isn't usable memory, or is too small to hold its part of the bitmap
+    Unusable,
+    /// Region overlaps memory the allocator already manages
+    Overlaps,
+    /// [`MAX_HOTPLUG_REGIONS`] regions were already added
+    TooManyRegions,
+    /// No frames are left for page tables, or the bitmap's virtual area is full
+    OutOfMemory,
+}
+
+/// Error reserving a frame that is already used
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FrameInUse(pub PhysFrame<Size4KiB>);
+
 unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
         // Find first free frame
         let frame = self.first_free_frame()?;
 
-        // Mark frame as used
-        Self::mark_frame_used(self.bitmap, frame);
-
         // Calculate frame start address
         let addr = self.frame_to_address(frame)?;
 
+        // Mark frame as used
+        Self::mark_frame_used(self.bitmap, frame);
+        self.next_search = (frame / 64) as usize;
+        self.free -= 1;
+
         Some(PhysFrame::from_start_address(addr).expect("All frame address are page aligned"))
     }
 }
@@ -182,6 +512,10 @@ impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
             .expect("frame should be located in regions");
 
         Self::mark_frame_free(self.bitmap, frame);
+        self.free += 1;
+
+        // Move the cursor back so the freed frame is reused promptly
+        self.next_search = self.next_search.min((frame / 64) as usize);
     }
 }
 
@@ -191,6 +525,27 @@ fn usable_regions(regions: &MemoryRegions) -> impl Iterator<Item = &MemoryRegion
         .filter(|region| region.kind == MemoryRegionKind::Usable)
 }
 
+/// Regions tracked by the bitmap: usable regions followed by ACPI reclaimable regions.
+fn managed_regions(regions: &MemoryRegions) -> impl Iterator<Item = &MemoryRegion> {
+    usable_regions(regions).chain(regions.iter().filter(|region| is_acpi_reclaimable(region)))
+}
+
+/// Whether the firmware marked `region` as ACPI reclaimable.
+///
+/// ACPI NVS memory is never reclaimable, it must be preserved across sleep states.
+const fn is_acpi_reclaimable(region: &MemoryRegion) -> bool {
+    matches!(
+        region.kind,
+        MemoryRegionKind::UnknownBios(E820_ACPI_RECLAIMABLE)
+            | MemoryRegionKind::UnknownUefi(UEFI_ACPI_RECLAIMABLE)
+    )
+}
+
+/// Total number of frames in `regions`.
+fn region_frames<'a>(regions: impl Iterator<Item = &'a MemoryRegion>) -> u64 {
+    regions.fold(0, |acc, region| acc + (region.end - region.start) / 4096)
+}
+
 /// Check if a range of frames is contiguous.
 const fn is_contiguous(start: PhysFrame, end: PhysFrame, pages: u64) -> bool {
     let start = start.start_address().as_u64();
@@ -199,3 +554,301 @@ const fn is_contiguous(start: PhysFrame, end: PhysFrame, pages: u64) -> bool {
 
     end - start == pages
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{boxed::Box, vec, vec::Vec};
+
+    use super::*;
+
+    const ACPI: MemoryRegionKind = MemoryRegionKind::UnknownBios(E820_ACPI_RECLAIMABLE);
+
+    impl BitmapFrameAllocator {
+        /// Allocator over `regions`, with its bitmap on the heap instead of in the regions
+        fn with_regions(regions: &[MemoryRegion]) -> Self {
+            let regions: &'static mut [MemoryRegion] = Box::leak(regions.into());
+            let regions: &'static MemoryRegions = Box::leak(Box::new(regions.into()));
+            let words = Self::required_bitmap_size(regions).div_ceil(4096) * 512;
+            let bitmap = Box::leak(vec![0; words as usize].into_boxed_slice());
+            Self::init_bitmap(bitmap, regions, 0);
+
+            let total = region_frames(usable_regions(regions));
+            Self {
+                regions,
+                hotplug: [EMPTY_REGION; MAX_HOTPLUG_REGIONS],
+                hotplug_len: 0,
+                bitmap,
+                next_search: 0,
+                total,
+                free: total,
+            }
+        }
+    }
+
+    const fn region(start: u64, end: u64, kind: MemoryRegionKind) -> MemoryRegion {
+        MemoryRegion { start, end, kind }
+    }
+
+    /// Allocates frames until the allocator runs out, returning their addresses
+    fn drain(alloc: &mut BitmapFrameAllocator) -> Vec<u64> {
+        core::iter::from_fn(|| alloc.allocate_frame())
+            .map(|frame| frame.start_address().as_u64())
+            .collect()
+    }
+
+    #[test]
+    fn reclaim_acpi_frees_acpi_frames() {
+        let mut alloc = BitmapFrameAllocator::with_regions(&[
+            region(0x1000, 0x9000, MemoryRegionKind::Usable),
+            region(0x9000, 0x10000, MemoryRegionKind::Bootloader),
+            region(0x10000, 0x13000, ACPI),
+        ]);
+        assert_eq!(alloc.free_frames(), 8);
+        assert_eq!(drain(&mut alloc).len(), 8);
+
+        assert_eq!(unsafe { alloc.reclaim_acpi() }, 3);
+        assert_eq!(alloc.free_frames(), 3);
+        assert_eq!(alloc.total_frames(), 11);
+        assert_eq!(drain(&mut alloc), [0x10000, 0x11000, 0x12000]);
+    }
+
+    #[test]
+    fn exhaustion_stops_at_the_last_frame() {
+        let mut alloc =
+            BitmapFrameAllocator::with_regions(&[region(0x1000, 0x4000, MemoryRegionKind::Usable)]);
+        assert_eq!(drain(&mut alloc), [0x1000, 0x2000, 0x3000]);
+        assert_eq!(alloc.free_frames(), 0);
+        assert_eq!(alloc.allocate_frame(), None);
+        assert_eq!(alloc.allocate_contiguous(1), None);
+        assert_eq!(alloc.free_frames(), 0);
+
+        unsafe { alloc.deallocate_frame(PhysFrame::containing_address(PhysAddr::new(0x2000))) };
+        assert_eq!(drain(&mut alloc), [0x2000]);
+    }
+
+    #[test]
+    fn free_frames_tracks_allocations() {
+        let mut alloc = BitmapFrameAllocator::with_regions(&[
+            region(0x1000, 0x9000, MemoryRegionKind::Usable),
+            region(0x10000, 0x20000, MemoryRegionKind::Usable),
+        ]);
+        assert_eq!(alloc.free_frames(), 24);
+
+        let frames: Vec<_> = (0..10).map(|_| alloc.allocate_frame().unwrap()).collect();
+        assert_eq!(alloc.free_frames(), 14);
+        assert_eq!(alloc.used_frames(), 10);
+        let run = alloc.allocate_contiguous(4).unwrap();
+        assert_eq!(alloc.free_frames(), 10);
+
+        for frame in frames {
+            unsafe { alloc.deallocate_frame(frame) };
+        }
+        unsafe { alloc.deallocate_contiguous(run, 4) };
+        assert_eq!(alloc.free_frames(), 24);
+        assert_eq!(alloc.used_frames(), 0);
+        assert_eq!(alloc.total_frames(), 24);
+    }
+
+    #[test]
+    fn contiguous_runs_stay_within_a_region() {
+        let mut alloc = BitmapFrameAllocator::with_regions(&[
+            region(0x1000, 0x3000, MemoryRegionKind::Usable),
+            region(0x10000, 0x14000, MemoryRegionKind::Usable),
+        ]);
+
+        // The bitmap bits of both regions are adjacent, but the frames aren't
+        let run = alloc.allocate_contiguous(3).unwrap();
+        assert_eq!(run.start_address().as_u64(), 0x10000);
+        assert_eq!(alloc.free_frames(), 3);
+        assert_eq!(
+            alloc
+                .allocate_contiguous(2)
+                .unwrap()
+                .start_address()
+                .as_u64(),
+            0x1000
+        );
+        assert_eq!(alloc.allocate_contiguous(2), None);
+
+        unsafe { alloc.deallocate_contiguous(run, 3) };
+        assert_eq!(alloc.free_frames(), 4);
+        assert_eq!(alloc.allocate_contiguous(4), Some(run));
+        assert_eq!(alloc.free_frames(), 0);
+    }
+
+    #[test]
+    fn reserved_frames_are_never_allocated() {
+        let mut alloc =
+            BitmapFrameAllocator::with_regions(&[region(0x1000, 0x9000, MemoryRegionKind::Usable)]);
+
+        // Partial frames at either end are reserved whole
+        alloc.reserve_range(PhysAddr::new(0x3800), 0x1000).unwrap();
+        assert_eq!(alloc.free_frames(), 6);
+        // The frame past the end of the region is ignored
+        alloc.reserve_range(PhysAddr::new(0x8000), 0x2000).unwrap();
+        assert_eq!(alloc.free_frames(), 5);
+
+        assert_eq!(
+            alloc.reserve_range(PhysAddr::new(0x2000), 0x2000),
+            Err(FrameInUse(PhysFrame::containing_address(PhysAddr::new(
+                0x3000
+            ))))
+        );
+        // Nothing was reserved by the failed call
+        assert_eq!(alloc.free_frames(), 5);
+
+        assert_eq!(drain(&mut alloc), [0x1000, 0x2000, 0x5000, 0x6000, 0x7000]);
+    }
+
+    #[test]
+    fn added_region_is_allocated_from() {
+        let _guard = crate::memory::tests::test_table();
+        let mut alloc =
+            BitmapFrameAllocator::with_regions(&[region(0x1000, 0x3000, MemoryRegionKind::Usable)]);
+        assert_eq!(drain(&mut alloc).len(), 2);
+
+        // Already in the direct map, and covered by the bitmap's first page, so nothing is
+        // mapped and no frames go to the bitmap
+        let (start, end) = (0x10_0000, 0x10_4000);
+        for addr in (start..end).step_by(4096) {
+            crate::memory::tests::map(Page::containing_address(PHYSICAL_MEM_START + addr));
+        }
+        let mut pt = crate::memory::PAGE_TABLE.lock();
+        let pt = pt.as_mut().unwrap();
+        alloc
+            .add_region(region(start, end, MemoryRegionKind::Usable), pt)
+            .unwrap();
+        assert_eq!(alloc.total_frames(), 6);
+        assert_eq!(alloc.free_frames(), 4);
+        assert_eq!(
+            drain(&mut alloc),
+            [0x10_0000, 0x10_1000, 0x10_2000, 0x10_3000]
+        );
+
+        assert_eq!(
+            alloc.add_region(region(0x10_2000, 0x20_0000, MemoryRegionKind::Usable), pt),
+            Err(AddRegionError::Overlaps)
+        );
+        assert_eq!(
+            alloc.add_region(region(0x20_0000, 0x20_0fff, MemoryRegionKind::Usable), pt),
+            Err(AddRegionError::Unusable)
+        );
+    }
+
+    #[test]
+    fn frame_numbers_round_trip_across_regions() {
+        let alloc = BitmapFrameAllocator::with_regions(&[
+            region(0x1000, 0x4000, MemoryRegionKind::Usable),
+            region(0x4000, 0x10000, MemoryRegionKind::Bootloader),
+            region(0x10000, 0x12000, MemoryRegionKind::Usable),
+            region(0x20000, 0x21000, ACPI),
+        ]);
+
+        for (addr, frame) in [
+            (0x1000, 0),
+            (0x3000, 2),
+            (0x10000, 3),
+            (0x11000, 4),
+            (0x20000, 5),
+        ] {
+            let addr = PhysAddr::new(addr);
+            assert_eq!(alloc.address_to_frame(addr), Some(frame));
+            assert_eq!(alloc.frame_to_address(frame), Some(addr));
+        }
+        assert_eq!(alloc.address_to_frame(PhysAddr::new(0x4000)), None);
+        assert_eq!(alloc.frame_to_address(6), None);
+    }
+
+    #[test]
+    fn search_resumes_at_the_cursor() {
+        let mut alloc = BitmapFrameAllocator::with_regions(&[region(
+            0x10_0000,
+            0x10_0000 + 20_000 * 4096,
+            MemoryRegionKind::Usable,
+        )]);
+
+        for frame in 0..10_000 {
+            let addr = alloc.allocate_frame().unwrap().start_address();
+            assert_eq!(addr.as_u64(), 0x10_0000 + frame as u64 * 4096);
+            // Every word before the cursor is full, so they're never scanned again
+            assert_eq!(alloc.next_search, frame / 64);
+            assert!(alloc.bitmap[..alloc.next_search]
+                .iter()
+                .all(|&w| w == u64::MAX));
+        }
+
+        let freed = PhysFrame::containing_address(PhysAddr::new(0x10_0000 + 100 * 4096));
+        unsafe { alloc.deallocate_frame(freed) };
+        assert_eq!(alloc.next_search, 1);
+        assert_eq!(alloc.allocate_frame(), Some(freed));
+    }
+
+    #[test]
+    fn no_frame_is_handed_out_twice() {
+        let mut alloc = BitmapFrameAllocator::with_regions(&[region(
+            0x10_0000,
+            0x10_0000 + 300 * 4096,
+            MemoryRegionKind::Usable,
+        )]);
+
+        let held: Vec<u64> = (0..200)
+            .map(|_| alloc.allocate_frame().unwrap().start_address().as_u64())
+            .collect();
+        // Free every third frame, moving the cursor back, then take everything left
+        for &addr in held.iter().step_by(3) {
+            unsafe { alloc.deallocate_frame(PhysFrame::containing_address(PhysAddr::new(addr))) };
+        }
+        let mut all: Vec<u64> = held
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i % 3 != 0)
+            .map(|(_, &addr)| addr)
+            .collect();
+        all.extend(drain(&mut alloc));
+
+        let len = all.len();
+        all.sort_unstable();
+        all.dedup();
+        assert_eq!(all.len(), len, "a frame was handed out twice");
+        assert_eq!(len, 300);
+    }
+
+    #[test]
+    fn search_wraps_around() {
+        let mut alloc = BitmapFrameAllocator::with_regions(&[region(
+            0x10_0000,
+            0x10_0000 + 256 * 4096,
+            MemoryRegionKind::Usable,
+        )]);
+        assert_eq!(drain(&mut alloc).len(), 256);
+
+        let low = PhysFrame::containing_address(PhysAddr::new(0x10_0000 + 5 * 4096));
+        unsafe { alloc.deallocate_frame(low) };
+        // Nothing is free from the last word on, so the search has to wrap to find the frame
+        alloc.next_search = 3;
+        assert_eq!(alloc.allocate_frame(), Some(low));
+        assert_eq!(alloc.allocate_frame(), None);
+    }
+
+    #[test]
+    fn search_examines_few_words() {
+        let mut alloc = BitmapFrameAllocator::with_regions(&[region(
+            0x10_0000,
+            0x10_0000 + 20_000 * 4096,
+            MemoryRegionKind::Usable,
+        )]);
+
+        let mut examined = 0;
+        for _ in 0..10_000 {
+            examined += alloc
+                .search_order()
+                .position(|(_, &word)| word != u64::MAX)
+                .unwrap()
+                + 1;
+            alloc.allocate_frame().unwrap();
+        }
+        // One word per allocation, plus the full word the cursor is left on each time a word
+        // fills up. Rescanning from zero would examine about 780 000.
+        assert_eq!(examined, 10_000 + 10_000 / 64);
+    }
+}
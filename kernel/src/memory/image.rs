@@ -0,0 +1,242 @@
+//! Kernel image protection.
+//!
+//! The bootloader leaves a copy of the kernel ELF file in memory, so the program headers can be
+//! used to find where each loadable segment was mapped and what permissions it should have.
+
+use alloc::vec::Vec;
+
+use bootloader_api::BootInfo;
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{mapper::FlagUpdateError, page::PageRangeInclusive, Page, PageTableFlags},
+    VirtAddr,
+};
+
+use crate::memory::{protect, PHYSICAL_MEM_START};
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+
+const PT_LOAD: u32 = 1;
+
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+/// ELF64 file header, up to the program header table fields.
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    tp: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+}
+
+/// ELF64 program header.
+#[repr(C)]
+struct ProgramHeader {
+    tp: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+/// Enforces W^X on the loaded kernel image.
+///
+/// Every `PT_LOAD` segment is remapped according to its ELF flags:
+/// `.text` becomes read-only & executable, `.rodata` read-only & no-execute,
+/// and `.data`/`.bss` writable & no-execute.
+///
+/// Pages shared by two segments receive the union of both segments' permissions.
+///
+/// # Panics
+///
+/// Panics if the bootloader did not leave a valid ELF file at `kernel_addr`.
+pub fn protect_kernel_image(info: &BootInfo) -> Result<(), FlagUpdateError> {
+    let elf = PHYSICAL_MEM_START + info.kernel_addr;
+
+    // SAFETY: The bootloader keeps the kernel ELF file mapped in the direct physical map.
+    let header = unsafe { elf.as_ptr::<ElfHeader>().read_unaligned() };
+    assert!(
+        header.ident[..4] == ELF_MAGIC,
+        "kernel image is not an ELF file"
+    );
+
+    // Make sure the CPU honors the flags we're about to set
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+
+    let phdrs = (0..u64::from(header.phnum)).map(|i| {
+        let ptr = elf + header.phoff + i * u64::from(header.phentsize);
+        // SAFETY: `ptr` is within the program header table of the ELF file.
+        unsafe { ptr.as_ptr::<ProgramHeader>().read_unaligned() }
+    });
+    for (pages, flags) in segment_protections(phdrs, info.kernel_image_offset) {
+        unsafe { protect(pages, flags) }?;
+    }
+
+    Ok(())
+}
+
+/// Pages of the `PT_LOAD` segments in `phdrs` loaded at `offset`, with the flags each should
+/// have.
+///
+/// Pages shared by two segments are listed again with the union of both segments' permissions,
+/// after the first segment's pages.
+fn segment_protections(
+    phdrs: impl IntoIterator<Item = ProgramHeader>,
+    offset: u64,
+) -> Vec<(PageRangeInclusive, PageTableFlags)> {
+    let mut protections = Vec::new();
+    let mut prev: Option<(Page, PageTableFlags)> = None;
+    for phdr in phdrs {
+        if phdr.tp != PT_LOAD || phdr.memsz == 0 {
+            continue;
+        }
+
+        let start = VirtAddr::new(offset + phdr.vaddr);
+        let mut pages = PageRangeInclusive {
+            start: Page::containing_address(start),
+            end: Page::containing_address(start + (phdr.memsz - 1)),
+        };
+        let flags = segment_flags(phdr.flags);
+
+        // Merge the first page with the previous segment if they share it
+        if let Some((last, last_flags)) = prev {
+            if last == pages.start {
+                protections.push((page_range(last), merge_flags(last_flags, flags)));
+                if pages.start == pages.end {
+                    prev = Some((last, merge_flags(last_flags, flags)));
+                    continue;
+                }
+                pages.start += 1;
+            }
+        }
+
+        protections.push((pages, flags));
+        prev = Some((pages.end, flags));
+    }
+
+    protections
+}
+
+/// Converts ELF segment flags to page table flags.
+fn segment_flags(elf_flags: u32) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT;
+    if elf_flags & PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if elf_flags & PF_X == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// Combines the flags of two segments sharing a page into the least restrictive set.
+fn merge_flags(a: PageTableFlags, b: PageTableFlags) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT | ((a | b) & PageTableFlags::WRITABLE);
+    if a.contains(PageTableFlags::NO_EXECUTE) && b.contains(PageTableFlags::NO_EXECUTE) {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+const fn page_range(page: Page) -> PageRangeInclusive {
+    PageRangeInclusive {
+        start: page,
+        end: page,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::structures::paging::{mapper::TranslateResult, Mapper, Translate};
+
+    use super::*;
+    use crate::memory::{
+        set_access,
+        tests::{map, test_table},
+        PAGE_TABLE,
+    };
+
+    const PF_R: u32 = 1 << 2;
+    const OFFSET: u64 = 0x5000_0000;
+
+    fn segment(tp: u32, vaddr: u64, memsz: u64, flags: u32) -> ProgramHeader {
+        ProgramHeader {
+            tp,
+            flags,
+            offset: 0,
+            vaddr,
+            paddr: 0,
+            filesz: memsz,
+            memsz,
+            align: 0x1000,
+        }
+    }
+
+    fn flags(addr: u64) -> PageTableFlags {
+        let pt = PAGE_TABLE.lock();
+        match pt.as_ref().unwrap().translate(VirtAddr::new(OFFSET + addr)) {
+            TranslateResult::Mapped { flags, .. } => flags,
+            _ => panic!("{addr:#x} isn't mapped"),
+        }
+    }
+
+    #[test]
+    fn segments_get_their_own_flags() {
+        let _guard = test_table();
+        let pages = Page::range(
+            Page::containing_address(VirtAddr::new(OFFSET + 0x1000)),
+            Page::containing_address(VirtAddr::new(OFFSET + 0x6000)),
+        );
+        // Mapped writable and global by the bootloader
+        for page in pages {
+            map(page);
+            let mut pt = PAGE_TABLE.lock();
+            let pt = pt.as_mut().unwrap();
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::GLOBAL;
+            unsafe { pt.update_flags(page, flags) }.unwrap().ignore();
+        }
+
+        // .text, then .rodata sharing its last page, a non-loaded segment, then .data
+        let phdrs = [
+            segment(PT_LOAD, 0x1000, 0x1800, PF_R | PF_X),
+            segment(PT_LOAD, 0x2800, 0x800, PF_R),
+            segment(0x6474_e551, 0, 0, PF_R | PF_W),
+            segment(PT_LOAD, 0x4000, 0x2000, PF_R | PF_W),
+        ];
+        for (pages, flags) in segment_protections(phdrs, OFFSET) {
+            let mut pt = PAGE_TABLE.lock();
+            for page in pages {
+                unsafe { set_access(pt.as_mut().unwrap(), page, flags) }
+                    .unwrap()
+                    .ignore();
+            }
+        }
+
+        let present = PageTableFlags::PRESENT | PageTableFlags::GLOBAL;
+        let data = present | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        assert_eq!(flags(0x1000), present);
+        // Shared by .text and .rodata, so it stays executable
+        assert_eq!(flags(0x2000), present);
+        assert_eq!(flags(0x4000), data);
+        assert_eq!(flags(0x5000), data);
+        // Between the segments, left alone
+        assert_eq!(flags(0x3000), present | PageTableFlags::WRITABLE);
+    }
+}
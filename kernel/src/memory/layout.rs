@@ -51,4 +51,6 @@ memory_layout! {
     BITMAP_FRAME_ALLOCATOR = UNUSED_HOLE1_END.as_u64() + 1 => s_lit!(1, TiB);
     /// Allocator (31 TiB)
     ALLOCATOR = BITMAP_FRAME_ALLOCATOR_END.as_u64() + 1 => s_lit!(31, TiB);
+    /// Framebuffer (1 GiB)
+    FRAMEBUFFER = ALLOCATOR_END.as_u64() + 1 => s_lit!(1, GiB);
 }
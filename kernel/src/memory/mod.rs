@@ -1,30 +1,44 @@
 pub mod allocator;
 pub mod frame;
+mod image;
 pub mod layout;
+pub mod pat;
+mod phys;
 
-use core::alloc::AllocError;
+use core::{
+    alloc::AllocError,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use bootloader_api::info::MemoryRegions;
+use bootloader_api::info::{MemoryRegion, MemoryRegions};
 use spin::Mutex;
 use x86_64::{
     structures::paging::{
-        mapper::CleanUp, page::PageRangeInclusive, FrameAllocator, FrameDeallocator, Mapper,
-        OffsetPageTable, Page, PageTable, PageTableFlags, Size4KiB,
+        mapper::{CleanUp, FlagUpdateError, MapToError, MapperFlush, TranslateResult},
+        page::PageRangeInclusive,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size4KiB, Translate,
     },
-    VirtAddr,
+    PhysAddr, VirtAddr,
 };
 
-pub use self::layout::PHYSICAL_MEM_START;
-use crate::memory::frame::BitmapFrameAllocator;
+pub use self::phys::{phys_read, phys_read_slice, phys_write};
+pub use self::{
+    image::protect_kernel_image,
+    layout::{FRAMEBUFFER_END, FRAMEBUFFER_START, PHYSICAL_MEM_START},
+};
+use crate::memory::frame::{AddRegionError, BitmapFrameAllocator};
 
 pub static PAGE_TABLE: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
 pub static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
 
-#[global_allocator]
+#[cfg_attr(not(test), global_allocator)]
 pub static ALLOCATOR: allocator::KAllocator = allocator::KAllocator::new();
 
 pub static PAGE_ALLOCATOR: allocator::FullPageAllocator = allocator::FullPageAllocator::new();
 
+static ACPI_RECLAIMED: AtomicBool = AtomicBool::new(false);
+
 fn active_level_4_table() -> &'static mut PageTable {
     let (level_4_table, _) = x86_64::registers::control::Cr3::read();
 
@@ -48,12 +62,183 @@ pub fn init() {
 /// Initialize the [`BitmapFrameAllocator`] with the given memory regions.
 pub fn init_frame_allocator(memory_regions: &'static MemoryRegions) {
     init();
+    pat::init();
+    phys::init(memory_regions);
     let mut ptable = PAGE_TABLE.lock();
     let pt = ptable.as_mut().unwrap();
     let frame_alloc = BitmapFrameAllocator::new(memory_regions, pt);
     *FRAME_ALLOCATOR.lock() = Some(frame_alloc);
 }
 
+/// Returns the free and total number of frames, or `None` if the frame allocator isn't
+/// initialized or is locked.
+///
+/// Doesn't block, so it is safe to call from the panic handler.
+pub fn try_frame_stats() -> Option<(u64, u64)> {
+    let fr_alloc = FRAME_ALLOCATOR.try_lock()?;
+    let frames = fr_alloc.as_ref()?;
+    Some((frames.free_frames(), frames.total_frames()))
+}
+
+/// Adds hot-plugged memory to the frame allocator.
+///
+/// See [`BitmapFrameAllocator::add_region`].
+///
+/// # Panics
+///
+/// Panics if the frame allocator is not initialized.
+pub fn add_memory_region(region: MemoryRegion) -> Result<(), AddRegionError> {
+    let mut fr_alloc = FRAME_ALLOCATOR.lock();
+    let mut ptable = PAGE_TABLE.lock();
+    fr_alloc
+        .as_mut()
+        .unwrap()
+        .add_region(region, ptable.as_mut().unwrap())
+}
+
+/// Returns ACPI reclaimable memory to the frame allocator, returning the number of frames freed.
+///
+/// Only the first call reclaims anything. [`acpi::get_acpi`] panics afterward, since the tables
+/// may have been overwritten.
+///
+/// # Safety
+///
+/// Must be called after all ACPI table access, and no references into the tables may remain.
+///
+/// # Panics
+///
+/// Panics if the frame allocator is not initialized.
+///
+/// [`acpi::get_acpi`]: crate::acpi::get_acpi
+pub unsafe fn reclaim_acpi_regions() -> u64 {
+    if ACPI_RECLAIMED.swap(true, Ordering::AcqRel) {
+        return 0;
+    }
+
+    let mut fr_alloc = FRAME_ALLOCATOR.lock();
+    unsafe { fr_alloc.as_mut().unwrap().reclaim_acpi() }
+}
+
+/// Whether the ACPI reclaimable memory was given to the frame allocator.
+pub fn acpi_reclaimed() -> bool {
+    ACPI_RECLAIMED.load(Ordering::Acquire)
+}
+
+/// Map the framebuffer at `phys` into the framebuffer region.
+///
+/// The framebuffer is mapped write-combining, or write-through if the PAT isn't available.
+///
+/// # Panics
+///
+/// Panics if the framebuffer doesn't fit in the framebuffer region.
+pub fn map_framebuffer(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let offset = phys.as_u64() % Size4KiB::SIZE;
+    let pages = (offset + size).div_ceil(Size4KiB::SIZE);
+    assert!(
+        pages * Size4KiB::SIZE <= FRAMEBUFFER_END - FRAMEBUFFER_START + 1,
+        "framebuffer too large"
+    );
+
+    let flags = pat::write_combining_flags(pat::write_combining());
+    let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let mut fr_alloc = FRAME_ALLOCATOR.lock();
+    let alloc = fr_alloc.as_mut().unwrap();
+    let mut page_table = PAGE_TABLE.lock();
+    let pt = page_table.as_mut().unwrap();
+
+    let start_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let start_page = Page::<Size4KiB>::containing_address(FRAMEBUFFER_START);
+    for i in 0..pages {
+        let page = start_page + i;
+        let frame = start_frame + i;
+        unsafe {
+            pt.map_to_with_table_flags(page, frame, flags, table_flags, alloc)?
+                .flush();
+        }
+    }
+
+    Ok(FRAMEBUFFER_START + offset)
+}
+
+/// Whether every page in `pages` is mapped, huge pages included.
+///
+/// # Panics
+///
+/// Panics if the page table is not initialized.
+pub fn is_range_mapped(pages: PageRangeInclusive) -> bool {
+    let page_table = PAGE_TABLE.lock();
+    let pt = page_table.as_ref().unwrap();
+
+    pages
+        .into_iter()
+        .all(|page| pt.translate_addr(page.start_address()).is_some())
+}
+
+/// Flags set by [`protect`], the others are kept
+const ACCESS_FLAGS: PageTableFlags = PageTableFlags::WRITABLE.union(PageTableFlags::NO_EXECUTE);
+
+/// Change the access flags ([`WRITABLE`](PageTableFlags::WRITABLE) and
+/// [`NO_EXECUTE`](PageTableFlags::NO_EXECUTE)) of every page in `pages` to those in `flags`.
+///
+/// Other flags, like [`GLOBAL`](PageTableFlags::GLOBAL), are kept.
+///
+/// # Safety
+///
+/// Removing permissions from a page that is still in use in that way will fault.
+pub unsafe fn protect(
+    pages: PageRangeInclusive,
+    flags: PageTableFlags,
+) -> Result<(), FlagUpdateError> {
+    let mut page_table = PAGE_TABLE.lock();
+    let pt = page_table.as_mut().unwrap();
+
+    for page in pages {
+        unsafe { set_access(pt, page, flags) }?.flush();
+    }
+
+    Ok(())
+}
+
+/// Changes the access flags of `page` like [`protect`], without flushing it.
+///
+/// # Safety
+///
+/// Same as [`protect`].
+unsafe fn set_access(
+    pt: &mut OffsetPageTable,
+    page: Page,
+    flags: PageTableFlags,
+) -> Result<MapperFlush<Size4KiB>, FlagUpdateError> {
+    let TranslateResult::Mapped { flags: old, .. } = pt.translate(page.start_address()) else {
+        return Err(FlagUpdateError::PageNotMapped);
+    };
+    let flags = (old - ACCESS_FLAGS) | (flags & ACCESS_FLAGS);
+    unsafe { pt.update_flags(page, flags) }
+}
+
+/// Error mapping a kernel page
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum KPageError {
+    /// The page is already mapped to a frame
+    AlreadyMapped(PhysFrame<Size4KiB>),
+    /// No frame is left for the page or its page tables
+    OutOfFrames,
+    /// The page is part of a huge page
+    HugePage,
+    /// The page table isn't initialized
+    NoPageTable,
+}
+
+impl From<KPageError> for AllocError {
+    fn from(_: KPageError) -> Self {
+        Self
+    }
+}
+
+/// Flags of kernel pages
+const KPAGE_FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
 /// Allocate a single kernel page.
 ///
 /// Very simple, to be used for allocators only.
@@ -63,26 +248,55 @@ pub fn init_frame_allocator(memory_regions: &'static MemoryRegions) {
 ///
 /// Page table & unmanaged memory allocations are inherently unsafe.
 unsafe fn alloc_kpage(
-    alloc: &mut impl FrameAllocator<Size4KiB>,
+    alloc: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
     virt_addr: VirtAddr,
-) -> Result<(), AllocError> {
-    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-    let frame = alloc.allocate_frame().ok_or(AllocError)?;
+) -> Result<(), KPageError> {
     let page: Page<Size4KiB> = Page::containing_address(virt_addr);
 
+    let mut page_table = PAGE_TABLE.lock();
+    let pt = page_table.as_mut().ok_or(KPageError::NoPageTable)?;
+
+    // Check before taking a frame, the page table stays locked so this can't race
+    if let Ok(frame) = pt.translate_page(page) {
+        return Err(KPageError::AlreadyMapped(frame));
+    }
+
+    let frame = alloc.allocate_frame().ok_or(KPageError::OutOfFrames)?;
+
     if cfg!(feature = "verbose") {
         crate::kprintln!("DEBUG: Allocating {:?} for {:?}", frame, page);
     }
 
-    PAGE_TABLE
-        .lock()
-        .as_mut()
-        .ok_or(AllocError)?
-        .map_to_with_table_flags(page, frame, flags, flags, alloc)
-        .unwrap()
-        .flush();
+    match pt.map_to_with_table_flags(page, frame, KPAGE_FLAGS, KPAGE_FLAGS, alloc) {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(e) => {
+            // Don't leak the frame
+            alloc.deallocate_frame(frame);
+            Err(match e {
+                MapToError::FrameAllocationFailed => KPageError::OutOfFrames,
+                MapToError::ParentEntryHugePage => KPageError::HugePage,
+                MapToError::PageAlreadyMapped(frame) => KPageError::AlreadyMapped(frame),
+            })
+        }
+    }
+}
 
-    Ok(())
+/// Like [`alloc_kpage`], but a page that's already mapped is left as is.
+///
+/// # Safety
+///
+/// Same as [`alloc_kpage`].
+unsafe fn alloc_kpage_if_unmapped(
+    alloc: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    virt_addr: VirtAddr,
+) -> Result<(), KPageError> {
+    match alloc_kpage(alloc, virt_addr) {
+        Err(KPageError::AlreadyMapped(_)) => Ok(()),
+        res => res,
+    }
 }
 
 unsafe fn free_kpage(alloc: &mut impl FrameDeallocator<Size4KiB>, virt_addr: VirtAddr) {
@@ -113,3 +327,101 @@ unsafe fn free_kpage(alloc: &mut impl FrameDeallocator<Size4KiB>, virt_addr: Vir
         alloc,
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+
+    use spin::MutexGuard;
+
+    use super::*;
+
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    /// Hands out up to `.0` frames on the heap, whose addresses are used as physical addresses
+    /// by the table from [`test_table`]
+    struct HeapFrames(usize);
+
+    unsafe impl FrameAllocator<Size4KiB> for HeapFrames {
+        fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+            self.0 = self.0.checked_sub(1)?;
+            let table: *mut PageTable = Box::leak(Box::new(PageTable::new()));
+            PhysFrame::from_start_address(PhysAddr::new(table as u64)).ok()
+        }
+    }
+
+    impl FrameDeallocator<Size4KiB> for HeapFrames {
+        unsafe fn deallocate_frame(&mut self, _frame: PhysFrame<Size4KiB>) {}
+    }
+
+    /// Replaces [`PAGE_TABLE`] with an empty table on the heap, and serializes the tests using
+    /// it.
+    ///
+    /// Nothing can be flushed from the TLB on the host, so mappings are made with [`map`].
+    pub fn test_table() -> MutexGuard<'static, ()> {
+        let guard = LOCK.lock();
+        let l4 = Box::leak(Box::new(PageTable::new()));
+        *PAGE_TABLE.lock() = Some(unsafe { OffsetPageTable::new(l4, VirtAddr::zero()) });
+        guard
+    }
+
+    /// Maps `page` to a new frame in the test table
+    pub fn map(page: Page<Size4KiB>) -> PhysFrame<Size4KiB> {
+        let mut frames = HeapFrames(4);
+        let frame = frames.allocate_frame().unwrap();
+        let mut pt = PAGE_TABLE.lock();
+        let pt = pt.as_mut().unwrap();
+        unsafe { pt.map_to_with_table_flags(page, frame, KPAGE_FLAGS, KPAGE_FLAGS, &mut frames) }
+            .unwrap()
+            .ignore();
+        frame
+    }
+
+    #[test]
+    fn alloc_kpage_reports_mapped_pages() {
+        let _guard = test_table();
+        let page = Page::containing_address(VirtAddr::new(0x4000_0000));
+        let frame = map(page);
+
+        let res = unsafe { alloc_kpage(&mut HeapFrames(4), page.start_address()) };
+        assert_eq!(res, Err(KPageError::AlreadyMapped(frame)));
+
+        let res = unsafe { alloc_kpage(&mut HeapFrames(0), (page + 1).start_address()) };
+        assert_eq!(res, Err(KPageError::OutOfFrames));
+    }
+
+    #[test]
+    fn alloc_kpage_if_unmapped_keeps_mapped_pages() {
+        let _guard = test_table();
+        let page = Page::containing_address(VirtAddr::new(0x4800_0000));
+        let frame = map(page);
+
+        let res = unsafe { alloc_kpage_if_unmapped(&mut HeapFrames(0), page.start_address()) };
+        assert_eq!(res, Ok(()));
+        let pt = PAGE_TABLE.lock();
+        assert_eq!(pt.as_ref().unwrap().translate_page(page).ok(), Some(frame));
+        drop(pt);
+
+        // Other errors are still reported
+        let res =
+            unsafe { alloc_kpage_if_unmapped(&mut HeapFrames(0), (page + 1).start_address()) };
+        assert_eq!(res, Err(KPageError::OutOfFrames));
+    }
+
+    #[test]
+    fn range_with_a_hole_is_not_mapped() {
+        let _guard = test_table();
+        let start = Page::containing_address(VirtAddr::new(0x5000_0000));
+        for page in [start, start + 1, start + 3] {
+            map(page);
+        }
+
+        assert!(is_range_mapped(Page::range_inclusive(start, start + 1)));
+        assert!(is_range_mapped(Page::range_inclusive(start + 3, start + 3)));
+        assert!(!is_range_mapped(Page::range_inclusive(start, start + 3)));
+        assert!(!is_range_mapped(Page::range_inclusive(
+            start + 2,
+            start + 2
+        )));
+    }
+}
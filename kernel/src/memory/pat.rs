@@ -0,0 +1,164 @@
+//! Page Attribute Table configuration.
+//!
+//! The PAT is reprogrammed so that index 1 (`PWT` set, `PCD` clear) selects write-combining,
+//! like Linux does. The PAT bit of 4KiB entries is bit 7, which the page table code treats as
+//! [`PageTableFlags::HUGE_PAGE`], so only indices 0-3 are used. Nothing maps memory
+//! write-through before the PAT is programmed, so no existing mapping changes type.
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use raw_cpuid::CpuId;
+use x86_64::{
+    instructions::{interrupts, tlb},
+    registers::{
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        model_specific::Msr,
+    },
+    structures::paging::PageTableFlags,
+};
+
+const IA32_PAT: u32 = 0x277;
+
+/// PAT index used for write-combining mappings.
+const WC_INDEX: usize = 1;
+
+static WRITE_COMBINING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum MemoryType {
+    Uncacheable = 0x00,
+    WriteCombining = 0x01,
+    WriteThrough = 0x04,
+    WriteProtected = 0x05,
+    WriteBack = 0x06,
+    UncachedMinus = 0x07,
+}
+
+/// Layout programmed into `IA32_PAT`.
+const PAT_ENTRIES: [MemoryType; 8] = [
+    MemoryType::WriteBack,
+    MemoryType::WriteCombining,
+    MemoryType::UncachedMinus,
+    MemoryType::Uncacheable,
+    MemoryType::WriteBack,
+    MemoryType::WriteThrough,
+    MemoryType::UncachedMinus,
+    MemoryType::Uncacheable,
+];
+
+/// Compute the `IA32_PAT` MSR value for the given entries.
+pub const fn pat_value(entries: [MemoryType; 8]) -> u64 {
+    let mut value = 0;
+    let mut i = 0;
+    while i < entries.len() {
+        value |= (entries[i] as u64) << (i * 8);
+        i += 1;
+    }
+    value
+}
+
+/// Program the PAT with a write-combining entry, if the CPU supports it.
+///
+/// The PAT is per CPU and must be the same on all of them, so every AP must run this too once
+/// it's brought up, before using a write-combining mapping.
+pub fn init() {
+    let supported = CpuId::new()
+        .get_feature_info()
+        .is_some_and(|info| info.has_pat());
+    if !supported {
+        crate::kprintln!("WARNING: PAT not supported, framebuffer will be write-through");
+        return;
+    }
+
+    interrupts::without_interrupts(|| unsafe { write_pat(pat_value(PAT_ENTRIES)) });
+    WRITE_COMBINING.store(true, Ordering::Relaxed);
+}
+
+/// Writes `value` to `IA32_PAT` following the SDM's sequence for changing memory types.
+///
+/// Caching is disabled and the caches and TLB are flushed around the write, so no line or
+/// translation of the old memory type is left behind.
+///
+/// # Safety
+///
+/// Interrupts must be disabled, and `value` must not change the type of memory in use in a way
+/// that breaks it.
+unsafe fn write_pat(value: u64) {
+    let cr0 = Cr0::read();
+    let cr4 = Cr4::read();
+    unsafe {
+        Cr0::write((cr0 | Cr0Flags::CACHE_DISABLE) - Cr0Flags::NOT_WRITE_THROUGH);
+        asm!("wbinvd", options(nostack, preserves_flags));
+        // Clearing PGE flushes the whole TLB, global pages included
+        if cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+            Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+        } else {
+            tlb::flush_all();
+        }
+
+        Msr::new(IA32_PAT).write(value);
+
+        asm!("wbinvd", options(nostack, preserves_flags));
+        tlb::flush_all();
+        Cr0::write(cr0);
+        Cr4::write(cr4);
+    }
+}
+
+/// Returns whether write-combining mappings are available.
+pub fn write_combining() -> bool {
+    WRITE_COMBINING.load(Ordering::Relaxed)
+}
+
+/// Select the page table flags for a 4KiB write-combining mapping.
+///
+/// Falls back to write-through if the PAT couldn't be configured.
+pub const fn write_combining_flags(pat_ok: bool) -> PageTableFlags {
+    let flags = PageTableFlags::PRESENT
+        .union(PageTableFlags::WRITABLE)
+        .union(PageTableFlags::NO_EXECUTE);
+    if pat_ok {
+        flags.union(pat_index_flags(WC_INDEX))
+    } else {
+        flags.union(PageTableFlags::WRITE_THROUGH)
+    }
+}
+
+/// Convert a PAT index from 0 to 3 into the `PCD` & `PWT` bits of a page table entry.
+const fn pat_index_flags(index: usize) -> PageTableFlags {
+    assert!(index < 4, "the PAT bit isn't supported");
+    let mut flags = PageTableFlags::empty();
+    if index & 0b010 != 0 {
+        flags = flags.union(PageTableFlags::NO_CACHE);
+    }
+    if index & 0b001 != 0 {
+        flags = flags.union(PageTableFlags::WRITE_THROUGH);
+    }
+    flags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_combining_avoids_the_pat_bit() {
+        let flags = write_combining_flags(true);
+        assert!(!flags.contains(PageTableFlags::HUGE_PAGE));
+        assert!(flags.contains(PageTableFlags::WRITE_THROUGH));
+        assert!(!flags.contains(PageTableFlags::NO_CACHE));
+
+        // PWT alone selects write-combining instead of the default write-through
+        let entry = (pat_value(PAT_ENTRIES) >> (WC_INDEX * 8)) & 0xFF;
+        assert_eq!(entry, MemoryType::WriteCombining as u64);
+    }
+
+    #[test]
+    fn default_mappings_stay_write_back() {
+        assert_eq!(pat_value(PAT_ENTRIES) & 0xFF, MemoryType::WriteBack as u64);
+    }
+}
@@ -0,0 +1,158 @@
+use core::{
+    mem::{align_of, size_of},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bootloader_api::info::MemoryRegions;
+use x86_64::{PhysAddr, VirtAddr};
+
+#[cfg(test)]
+use self::tests::direct_map_start;
+use crate::memory::layout::{PHYSICAL_MEM_END, PHYSICAL_MEM_START};
+
+/// End of the physical memory mapped by the bootloader at [`PHYSICAL_MEM_START`]
+static MAPPED_END: AtomicU64 = AtomicU64::new(0);
+
+/// Records how much physical memory the bootloader mapped, from the memory map it was given.
+pub(super) fn init(regions: &MemoryRegions) {
+    let end = regions.iter().map(|region| region.end).max().unwrap_or(0);
+    let end = end.min(PHYSICAL_MEM_END - PHYSICAL_MEM_START + 1);
+    MAPPED_END.store(end, Ordering::Relaxed);
+}
+
+/// Translates `len` bytes at `phys` to their address in the direct map.
+///
+/// # Panics
+///
+/// Panics if the range isn't entirely within the direct map.
+fn direct_map(phys: PhysAddr, len: usize) -> VirtAddr {
+    let end = MAPPED_END.load(Ordering::Relaxed);
+    let range_end = phys.as_u64().checked_add(len as u64);
+    let in_range = range_end.is_some_and(|range_end| range_end <= end);
+    assert!(
+        in_range,
+        "physical range {:#x}..{:#x} is outside of the direct map (0..{end:#x})",
+        phys.as_u64(),
+        phys.as_u64().saturating_add(len as u64),
+    );
+    direct_map_start() + phys.as_u64()
+}
+
+/// Where the direct map starts
+#[cfg(not(test))]
+const fn direct_map_start() -> VirtAddr {
+    PHYSICAL_MEM_START
+}
+
+/// Reads a `T` from physical memory at `phys` with a volatile load.
+///
+/// # Safety
+///
+/// The memory at `phys` must hold a valid `T`, and reading it must not have side effects the
+/// caller doesn't expect, like some MMIO registers do.
+///
+/// # Panics
+///
+/// Panics if `phys` isn't aligned for `T` or isn't in the direct map.
+pub unsafe fn phys_read<T: Copy>(phys: PhysAddr) -> T {
+    assert!(
+        phys.is_aligned(align_of::<T>() as u64),
+        "unaligned physical read at {phys:?}"
+    );
+    let virt = direct_map(phys, size_of::<T>());
+    unsafe { virt.as_ptr::<T>().read_volatile() }
+}
+
+/// Writes `val` to physical memory at `phys` with a volatile store.
+///
+/// # Safety
+///
+/// The memory at `phys` must not be in use by anything expecting a different value, such as a
+/// kernel data structure or an allocated frame.
+///
+/// # Panics
+///
+/// Panics if `phys` isn't aligned for `T` or isn't in the direct map.
+pub unsafe fn phys_write<T: Copy>(phys: PhysAddr, val: T) {
+    assert!(
+        phys.is_aligned(align_of::<T>() as u64),
+        "unaligned physical write at {phys:?}"
+    );
+    let virt = direct_map(phys, size_of::<T>());
+    unsafe { virt.as_mut_ptr::<T>().write_volatile(val) }
+}
+
+/// Fills `buf` from physical memory starting at `phys`, one volatile byte load at a time.
+///
+/// # Safety
+///
+/// Same as [`phys_read`].
+///
+/// # Panics
+///
+/// Panics if the range isn't in the direct map.
+pub unsafe fn phys_read_slice(phys: PhysAddr, buf: &mut [u8]) {
+    let ptr = direct_map(phys, buf.len()).as_ptr::<u8>();
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = unsafe { ptr.add(i).read_volatile() };
+    }
+}
+
+/// Copies `buf` to physical memory starting at `phys`, one volatile byte store at a time.
+///
+/// # Safety
+///
+/// Same as [`phys_write`].
+///
+/// # Panics
+///
+/// Panics if the range isn't in the direct map.
+pub unsafe fn phys_write_slice(phys: PhysAddr, buf: &[u8]) {
+    let ptr = direct_map(phys, buf.len()).as_mut_ptr::<u8>();
+    for (i, byte) in buf.iter().enumerate() {
+        unsafe { ptr.add(i).write_volatile(*byte) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{boxed::Box, vec};
+
+    use spin::Lazy;
+
+    use super::*;
+
+    /// Physical memory of the tests, the real direct map isn't mapped on the host
+    static MEMORY: Lazy<usize> =
+        Lazy::new(|| Box::leak(vec![0u64; 512].into_boxed_slice()).as_ptr() as usize);
+
+    pub fn direct_map_start() -> VirtAddr {
+        VirtAddr::new(*MEMORY as u64)
+    }
+
+    #[test]
+    fn values_round_trip_through_physical_memory() {
+        MAPPED_END.store(4096, Ordering::Relaxed);
+        let phys = PhysAddr::new(0x100);
+
+        unsafe {
+            phys_write(phys, 0xDEAD_BEEF_u32);
+            assert_eq!(phys_read::<u32>(phys), 0xDEAD_BEEF);
+            // Little endian, like the hardware
+            let mut bytes = [0; 4];
+            phys_read_slice(phys, &mut bytes);
+            assert_eq!(bytes, [0xEF, 0xBE, 0xAD, 0xDE]);
+
+            phys_write_slice(phys + 2u64, &[0x12, 0x34]);
+            assert_eq!(phys_read::<u32>(phys), 0x3412_BEEF);
+        }
+    }
+
+    #[test]
+    #[should_panic = "outside of the direct map"]
+    fn reads_past_the_direct_map_panic() {
+        MAPPED_END.store(4096, Ordering::Relaxed);
+        let mut buf = [0; 8];
+        unsafe { phys_read_slice(PhysAddr::new(4092), &mut buf) };
+    }
+}
@@ -1 +1,126 @@
+//! Multiprocessor support.
+//!
+//! Every CPU is tracked in a table indexed by its local APIC ID. The application processors
+//! (APs) are found in the ACPI MADT by [`detect`], and mark themselves online with
+//! [`register_current`] once running.
+//!
+//! There is no real-mode trampoline for the APs to start in yet, so they stay parked in their
+//! wait-for-SIPI state. [`start_ap`] is the INIT-SIPI-SIPI sequence that will wake them. Once
+//! running, an AP must program its PAT with [`pat::init`](crate::memory::pat::init) like the
+//! BSP did.
 
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use acpi::AcpiResult;
+use raw_cpuid::CpuId;
+
+use crate::{apic, kprintln, time::tsc, trap::MAX_CPUS};
+
+/// Time an AP is given to reset after the INIT IPI
+const INIT_DELAY_NS: u64 = 10_000_000;
+/// Time an AP is given to start after a startup IPI
+const STARTUP_DELAY_NS: u64 = 200_000;
+
+static CPUS: [Cpu; MAX_CPUS] = [const { Cpu::new() }; MAX_CPUS];
+
+/// Number of usable APs found by [`detect`]
+static AP_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Entry of a CPU in the table
+struct Cpu {
+    /// Whether the CPU exists and is enabled
+    present: AtomicBool,
+    /// Whether the CPU is running kernel code
+    online: AtomicBool,
+}
+
+impl Cpu {
+    const fn new() -> Self {
+        Self {
+            present: AtomicBool::new(false),
+            online: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Fills the CPU table from the MADT, returning the number of usable APs.
+///
+/// The calling CPU is registered as the bootstrap processor. Disabled APs, and APs with an APIC
+/// ID past [`MAX_CPUS`], are skipped.
+pub fn detect() -> AcpiResult<usize> {
+    let cpus = crate::acpi::cpus()?;
+
+    register_current();
+
+    let mut count = 0;
+    for ap in cpus.iter().filter(|cpu| !cpu.is_bsp && cpu.enabled) {
+        let Some(cpu) = usize::try_from(ap.local_apic_id)
+            .ok()
+            .and_then(|id| CPUS.get(id))
+        else {
+            kprintln!(
+                "WARNING: skipping CPU with APIC ID {}, past the {MAX_CPUS} CPU limit",
+                ap.local_apic_id
+            );
+            continue;
+        };
+        cpu.present.store(true, Ordering::Release);
+        count += 1;
+    }
+
+    AP_COUNT.store(count, Ordering::Release);
+    Ok(count)
+}
+
+/// Number of usable APs found by [`detect`]
+pub fn ap_count() -> usize {
+    AP_COUNT.load(Ordering::Acquire)
+}
+
+/// Whether the CPU with APIC ID `apic_id` exists and is enabled
+pub fn is_present(apic_id: u8) -> bool {
+    CPUS.get(usize::from(apic_id))
+        .is_some_and(|cpu| cpu.present.load(Ordering::Acquire))
+}
+
+/// Whether the CPU with APIC ID `apic_id` is running kernel code
+pub fn is_online(apic_id: u8) -> bool {
+    CPUS.get(usize::from(apic_id))
+        .is_some_and(|cpu| cpu.online.load(Ordering::Acquire))
+}
+
+/// Number of CPUs running kernel code, including the bootstrap processor
+pub fn online_count() -> usize {
+    CPUS.iter()
+        .filter(|cpu| cpu.online.load(Ordering::Acquire))
+        .count()
+}
+
+/// Marks the calling CPU as present and online.
+pub fn register_current() {
+    let apic_id = CpuId::new()
+        .get_feature_info()
+        .map_or(0, |info| info.initial_local_apic_id());
+    if let Some(cpu) = CPUS.get(usize::from(apic_id)) {
+        cpu.present.store(true, Ordering::Release);
+        cpu.online.store(true, Ordering::Release);
+    }
+}
+
+/// Wakes the AP with APIC ID `apic_id` with the INIT-SIPI-SIPI sequence, starting it in real
+/// mode at physical address `page << 12`.
+///
+/// # Safety
+///
+/// `page` must hold a trampoline that brings the AP into the kernel, and the AP must be parked
+/// in its wait-for-SIPI state.
+pub unsafe fn start_ap(apic_id: u8, page: u8) {
+    apic::send_init_ipi(apic_id);
+    tsc::busy_wait_ns(INIT_DELAY_NS);
+
+    // The second SIPI is only needed if the first is lost, but is harmless otherwise
+    for _ in 0..2 {
+        apic::send_startup_ipi(apic_id, page);
+        tsc::busy_wait_ns(STARTUP_DELAY_NS);
+    }
+}
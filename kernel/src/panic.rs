@@ -1,15 +1,46 @@
+use core::fmt::Display;
+
 use x86_64::instructions::{hlt, interrupts};
 
-use crate::kprintln;
+use crate::{memory::allocator::AllocStats, trap::FaultContext};
+
+/// Number of recent console lines included in a panic report
+const REPORT_LINES: usize = 16;
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+    use core::fmt::Write;
+
+    use crate::{
+        console::{self, RECENT_SIZE},
+        memory,
+    };
+
     // Disable interrupts
     interrupts::disable();
 
-    // Print panic message
-    kprintln!("KERNEL PANIC:");
-    kprintln!("{}", info);
+    // Gather everything before printing, since printing adds to the recent output. None of it
+    // waits for a lock or allocates, the heap may be corrupted or locked by the code that
+    // panicked.
+    let mut recent = [0; RECENT_SIZE];
+    let len = console::recent_output(&mut recent);
+    let report = PanicReport {
+        message: info,
+        fault: crate::trap::take_fault_context(),
+        heap: memory::ALLOCATOR.try_stats(),
+        frames: memory::try_frame_stats(),
+        recent: &recent[..len],
+    };
+
+    // The console may be locked by the code that panicked too, so don't wait for it. Console
+    // write will never fail
+    let _ = write!(console::panic_writer(), "{report}");
+
+    // Report the failure to the runner, instead of waiting to be inspected
+    if cfg!(feature = "qemu-exit") {
+        crate::qemu::exit_qemu(crate::qemu::ExitCode::Failed);
+    }
 
     // Halts forever
     loop {
@@ -17,6 +48,61 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     }
 }
 
+/// Everything printed when the kernel panics, in order.
+///
+/// Sections that aren't available are left out.
+pub struct PanicReport<'a, M: Display> {
+    pub message: M,
+    /// Context of the exception that caused the panic
+    pub fault: Option<FaultContext>,
+    pub heap: Option<AllocStats>,
+    /// Free and total frames
+    pub frames: Option<(u64, u64)>,
+    /// Recent console output, of which the last [`REPORT_LINES`] lines are printed
+    pub recent: &'a [u8],
+}
+
+impl<M: Display> Display for PanicReport<'_, M> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "KERNEL PANIC:\r\n{}\r\n", self.message)?;
+
+        if let Some(fault) = &self.fault {
+            write!(f, "--- fault ---\r\n{fault}\r\n")?;
+        }
+
+        if self.heap.is_some() || self.frames.is_some() {
+            f.write_str("--- memory ---\r\n")?;
+            if let Some(heap) = &self.heap {
+                write!(f, "Heap: {heap}\r\n")?;
+            }
+            if let Some((free, total)) = self.frames {
+                write!(f, "Frames: {free} free of {total}\r\n")?;
+            }
+        }
+
+        if !self.recent.is_empty() {
+            f.write_str("--- recent output ---\r\n")?;
+            let recent = self.recent.strip_suffix(b"\n").unwrap_or(self.recent);
+            // Start after the newline ending the line before the last REPORT_LINES
+            let start = recent
+                .iter()
+                .enumerate()
+                .rev()
+                .filter(|&(_, &byte)| byte == b'\n')
+                .nth(REPORT_LINES - 1)
+                .map_or(0, |(i, _)| i + 1);
+            for line in recent[start..].split(|&byte| byte == b'\n') {
+                for chunk in line.utf8_chunks() {
+                    f.write_str(chunk.valid())?;
+                }
+                f.write_str("\r\n")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
 pub fn halt_and_never_return() -> ! {
     // Disable interrupts
     interrupts::disable();
@@ -26,3 +112,80 @@ pub fn halt_and_never_return() -> ! {
         hlt();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{
+        format,
+        string::{String, ToString},
+        vec::Vec,
+    };
+    use core::fmt::Write;
+
+    use super::*;
+
+    #[test]
+    fn report_sections_are_in_order() {
+        let fault = FaultContext {
+            vector: 0x0e,
+            rip: 0x1000,
+            rsp: 0x2000,
+            rflags: 0x2,
+            cr2: Some(0xdead),
+            error_code: Some(2),
+        };
+        let recent: Vec<u8> = (0..20)
+            .flat_map(|i| format!("line {i}\n").into_bytes())
+            .collect();
+        let report = PanicReport {
+            message: "oops",
+            fault: Some(fault),
+            heap: Some(AllocStats::default()),
+            frames: Some((3, 10)),
+            recent: &recent,
+        }
+        .to_string();
+
+        let sections = [
+            "KERNEL PANIC:\r\noops\r\n",
+            "--- fault ---\r\n",
+            "--- memory ---\r\nHeap: ",
+            "Frames: 3 free of 10\r\n",
+            "--- recent output ---\r\n",
+        ];
+        let positions: Vec<usize> = sections
+            .iter()
+            .map(|section| {
+                report
+                    .find(section)
+                    .unwrap_or_else(|| panic!("{section:?}"))
+            })
+            .collect();
+        assert!(positions.is_sorted(), "{report}");
+        assert!(report.contains(&format!("{fault}\r\n")));
+
+        // Only the last lines of the recent output
+        assert!(!report.contains("line 3\r\n"));
+        let mut last = String::new();
+        for i in 4..20 {
+            write!(last, "line {i}\r\n").unwrap();
+        }
+        assert!(report.ends_with(&last), "{report}");
+    }
+
+    #[test]
+    fn missing_sections_are_left_out() {
+        let report = PanicReport {
+            message: "oops",
+            fault: None,
+            heap: None,
+            frames: Some((3, 10)),
+            recent: &[],
+        }
+        .to_string();
+        assert_eq!(
+            report,
+            "KERNEL PANIC:\r\noops\r\n--- memory ---\r\nFrames: 3 free of 10\r\n"
+        );
+    }
+}
@@ -1,63 +1,175 @@
 use core::num::TryFromIntError;
 
 use spin::Mutex;
-use x86_64::instructions::port::{Port, PortWriteOnly};
+#[cfg(not(test))]
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+#[cfg(test)]
+use self::tests::{inb, outb};
 
 const TIMER_FREQUENCY: u32 = 1_193_182;
 
+/// Rough upper bound, in TSC cycles, on how long to wait for a calibration countdown.
+///
+/// Calibration waits are 10ms, which this comfortably exceeds on any realistic CPU.
+pub const CALIBRATION_TIMEOUT: u64 = 1 << 30;
+
 pub static PIT0: ProgrammableIntervalTimer = ProgrammableIntervalTimer::new(Channel::Channel0);
 pub static PIT1: ProgrammableIntervalTimer = ProgrammableIntervalTimer::new(Channel::Channel1);
 pub static PIT2: ProgrammableIntervalTimer = ProgrammableIntervalTimer::new(Channel::Channel2);
 
 pub struct ProgrammableIntervalTimer(Mutex<Pit>);
 
+/// Mode/command register, write only
+const COMMAND_PORT: u16 = 0x43;
+/// Port holding channel 2's gate and output bits, shared with the PC speaker
+const CONTROL_PORT: u16 = 0x61;
+/// Control bit driving channel 2's gate
+const GATE2: u8 = 1 << 0;
+/// Control bit reflecting channel 2's output
+const OUTPUT2: u8 = 1 << 5;
+
 struct Pit {
-    ch: Port<u8>,
-    cmd: PortWriteOnly<u8>,
+    channel: Channel,
 }
 
 impl ProgrammableIntervalTimer {
     const fn new(ch: Channel) -> Self {
-        Self(Mutex::new(Pit {
-            ch: Port::new(ch.port()),
-            cmd: PortWriteOnly::new(0x43),
-        }))
-    }
-
-    fn set_cmd(
-        cmd: &mut PortWriteOnly<u8>,
-        channel: Channel,
-        access_mode: AccessMode,
-        operating_mode: OperatingMode,
-    ) {
+        Self(Mutex::new(Pit { channel: ch }))
+    }
+
+    fn set_cmd(channel: Channel, access_mode: AccessMode, operating_mode: OperatingMode) {
         let mut val = channel as u8;
         val |= (access_mode as u8) << 4;
         val |= (operating_mode as u8) << 1;
         unsafe {
-            cmd.write(val);
+            outb(COMMAND_PORT, val);
         }
     }
 
     pub fn start_timer(&self, mode: OperatingMode, freq: u32) -> Result<(), TryFromIntError> {
-        let mut pit = self.0.lock();
         let divisor: u16 = (TIMER_FREQUENCY / freq).try_into()?;
+        self.start_countdown(mode, divisor);
+        Ok(())
+    }
 
-        Self::set_cmd(&mut pit.cmd, Channel::Channel0, AccessMode::LoHiByte, mode);
+    fn start_countdown(&self, mode: OperatingMode, divisor: u16) {
+        let pit = self.0.lock();
+        Self::set_cmd(pit.channel, AccessMode::LoHiByte, mode);
         unsafe {
-            pit.ch.write((divisor & 0xff) as u8);
-            pit.ch.write((divisor >> 8) as u8);
+            outb(pit.channel.port(), (divisor & 0xff) as u8);
+            outb(pit.channel.port(), (divisor >> 8) as u8);
         }
+    }
+
+    /// Busy-waits for `micros` microseconds, counting down once from the matching divisor.
+    ///
+    /// Fails if the duration doesn't fit a 16-bit divisor, about 54ms, or if the countdown
+    /// doesn't finish within [`CALIBRATION_TIMEOUT`].
+    pub fn sleep_us(&self, micros: u32) -> Result<(), SleepError> {
+        let divisor = divisor_for_us(micros)?;
+        self.start_countdown(OperatingMode::InterruptOnTerminalCount, divisor);
+        self.wait_for_zero(CALIBRATION_TIMEOUT)?;
         Ok(())
     }
 
     pub fn get_count(&self) -> u16 {
-        let mut pit = self.0.lock();
+        let pit = self.0.lock();
         unsafe {
-            let lo = pit.ch.read();
-            let hi = pit.ch.read();
+            let lo = inb(pit.channel.port());
+            let hi = inb(pit.channel.port());
             (u16::from(hi) << 8) | u16::from(lo)
         }
     }
+
+    /// Enables or disables counting, by driving the gate input.
+    ///
+    /// Only channel 2's gate is controllable, the others are wired high and this does nothing
+    /// for them.
+    pub fn set_gate(&self, enabled: bool) {
+        let pit = self.0.lock();
+        if pit.channel != Channel::Channel2 {
+            return;
+        }
+        unsafe {
+            let control = inb(CONTROL_PORT);
+            outb(CONTROL_PORT, with_gate(control, enabled));
+        }
+    }
+
+    /// Whether the channel's output is high.
+    ///
+    /// Only channel 2's output is readable, this is always `false` for the others.
+    pub fn output_high(&self) -> bool {
+        let pit = self.0.lock();
+        pit.channel == Channel::Channel2 && unsafe { inb(CONTROL_PORT) } & OUTPUT2 != 0
+    }
+
+    /// Spins until the counter reaches 0, giving up after `timeout` TSC cycles.
+    ///
+    /// A timeout usually means the PIT is absent or disabled.
+    pub fn wait_for_zero(&self, timeout: u64) -> Result<(), PitTimeout> {
+        let start = unsafe { x86::time::rdtsc() };
+        while self.get_count() != 0 {
+            if unsafe { x86::time::rdtsc() }.wrapping_sub(start) > timeout {
+                return Err(PitTimeout);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the PIT register at `port`
+#[cfg(not(test))]
+unsafe fn inb(port: u16) -> u8 {
+    u8::read_from_port(port)
+}
+
+/// Writes `value` to the PIT register at `port`
+#[cfg(not(test))]
+unsafe fn outb(port: u16, value: u8) {
+    u8::write_to_port(port, value);
+}
+
+/// Control port value with channel 2's gate bit set to `enabled`, other bits unchanged
+const fn with_gate(control: u8, enabled: bool) -> u8 {
+    if enabled {
+        control | GATE2
+    } else {
+        control & !GATE2
+    }
+}
+
+/// Divisor counting down for `micros` microseconds, rounded to the nearest PIT tick.
+///
+/// Durations shorter than a tick count down for one tick.
+pub const fn divisor_for_us(micros: u32) -> Result<u16, SleepError> {
+    let ticks = (micros as u64 * TIMER_FREQUENCY as u64 + 500_000) / 1_000_000;
+    if ticks > u16::MAX as u64 {
+        return Err(SleepError::TooLong);
+    }
+    if ticks == 0 {
+        return Ok(1);
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    Ok(ticks as u16)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PitTimeout;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SleepError {
+    /// The duration doesn't fit a 16-bit divisor
+    TooLong,
+    /// The countdown didn't finish, see [`ProgrammableIntervalTimer::wait_for_zero`]
+    Timeout,
+}
+
+impl From<PitTimeout> for SleepError {
+    fn from(_: PitTimeout) -> Self {
+        Self::Timeout
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -94,3 +206,100 @@ pub enum OperatingMode {
     SoftwareTriggeredStrobe = 4,
     HardwareTriggeredStrobe = 5,
 }
+
+#[cfg(test)]
+pub mod tests {
+    use std::{cell::RefCell, collections::HashMap, vec::Vec};
+
+    use super::*;
+
+    /// PIT registers of the test thread, the real ones can't be accessed on the host
+    #[derive(Default)]
+    struct MockPorts {
+        /// Every write, in order
+        writes: Vec<(u16, u8)>,
+        /// Values read from each port, the last one is repeated once the others are read
+        reads: HashMap<u16, Vec<u8>>,
+    }
+
+    std::thread_local! {
+        static PORTS: RefCell<MockPorts> = RefCell::default();
+    }
+
+    pub unsafe fn inb(port: u16) -> u8 {
+        PORTS.with_borrow_mut(|ports| match ports.reads.get_mut(&port) {
+            Some(values) if values.len() > 1 => values.remove(0),
+            Some(values) => values.first().copied().unwrap_or(0),
+            None => 0,
+        })
+    }
+
+    pub unsafe fn outb(port: u16, value: u8) {
+        PORTS.with_borrow_mut(|ports| ports.writes.push((port, value)));
+    }
+
+    /// Makes reads of `port` return `values` in order, then the last one forever
+    pub fn set_reads(port: u16, values: &[u8]) {
+        PORTS.with_borrow_mut(|ports| ports.reads.insert(port, values.to_vec()));
+    }
+
+    pub fn take_writes() -> Vec<(u16, u8)> {
+        PORTS.with_borrow_mut(|ports| core::mem::take(&mut ports.writes))
+    }
+
+    #[test]
+    fn gate_changes_only_its_control_bit() {
+        assert_eq!(with_gate(0b1010_0000, true), 0b1010_0001);
+        assert_eq!(with_gate(0b1010_0011, false), 0b1010_0010);
+
+        let pit = ProgrammableIntervalTimer::new(Channel::Channel2);
+        // Speaker data bit set, gate low
+        set_reads(CONTROL_PORT, &[0b0000_0010, 0b0000_0011]);
+        pit.set_gate(true);
+        pit.set_gate(false);
+        assert_eq!(
+            take_writes(),
+            [(CONTROL_PORT, 0b0000_0011), (CONTROL_PORT, 0b0000_0010)]
+        );
+
+        set_reads(CONTROL_PORT, &[OUTPUT2, 0]);
+        assert!(pit.output_high());
+        assert!(!pit.output_high());
+
+        // The other channels' gates and outputs aren't wired to the control port
+        set_reads(CONTROL_PORT, &[OUTPUT2]);
+        let pit = ProgrammableIntervalTimer::new(Channel::Channel0);
+        pit.set_gate(false);
+        assert_eq!(take_writes(), []);
+        assert!(!pit.output_high());
+    }
+
+    #[test]
+    fn divisors_round_to_the_nearest_tick() {
+        assert_eq!(divisor_for_us(0), Ok(1));
+        assert_eq!(divisor_for_us(1), Ok(1));
+        assert_eq!(divisor_for_us(100), Ok(119));
+        assert_eq!(divisor_for_us(1_000), Ok(1193));
+        assert_eq!(divisor_for_us(10_000), Ok(11932));
+        // The longest countdown a 16-bit divisor allows
+        assert_eq!(divisor_for_us(54_924), Ok(65534));
+        assert_eq!(divisor_for_us(54_925), Err(SleepError::TooLong));
+        assert_eq!(divisor_for_us(u32::MAX), Err(SleepError::TooLong));
+    }
+
+    #[test]
+    fn sleep_counts_down_from_the_divisor() {
+        let pit = ProgrammableIntervalTimer::new(Channel::Channel2);
+        set_reads(Channel::Channel2.port(), &[0]);
+        assert_eq!(pit.sleep_us(10_000), Ok(()));
+        let divisor: Vec<_> = take_writes()
+            .into_iter()
+            .filter(|&(port, _)| port == Channel::Channel2.port())
+            .collect();
+        // 11932, low byte first
+        assert_eq!(divisor, [(0x42, 0x9c), (0x42, 0x2e)]);
+
+        assert_eq!(pit.sleep_us(60_000), Err(SleepError::TooLong));
+        assert_eq!(take_writes(), []);
+    }
+}
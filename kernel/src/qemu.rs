@@ -0,0 +1,86 @@
+//! Exiting QEMU through its `isa-debug-exit` device.
+//!
+//! QEMU must be started with `-device isa-debug-exit,iobase=0xf4,iosize=0x04`. It then exits
+//! with status `(code << 1) | 1` when `code` is written to the port, which the runner maps back.
+
+#[cfg(not(test))]
+use x86_64::instructions::port::PortWrite;
+
+#[cfg(test)]
+use self::tests::outl;
+
+/// I/O port of the `isa-debug-exit` device
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Code written to the debug exit device.
+///
+/// Neither maps to status 0 or 1, so they can't be confused with QEMU's own exit statuses.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u32)]
+pub enum ExitCode {
+    /// QEMU exits with status 33
+    Success = 0x10,
+    /// QEMU exits with status 35
+    Failed = 0x11,
+}
+
+impl ExitCode {
+    /// Exit status of QEMU after writing this code
+    pub const fn status(self) -> i32 {
+        ((self as i32) << 1) | 1
+    }
+}
+
+/// Exits QEMU with `code`.
+///
+/// Halts if QEMU wasn't started with the debug exit device.
+pub fn exit_qemu(code: ExitCode) -> ! {
+    request_exit(code);
+    crate::panic::halt_and_never_return();
+}
+
+/// Writes `code` to the debug exit device, returning only if there is none.
+fn request_exit(code: ExitCode) {
+    // SAFETY: The port is only used by the debug exit device, writing it stops the machine.
+    unsafe {
+        outl(DEBUG_EXIT_PORT, code as u32);
+    }
+}
+
+/// Writes `value` to the I/O port `port`
+#[cfg(not(test))]
+unsafe fn outl(port: u16, value: u32) {
+    u32::write_to_port(port, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+
+    std::thread_local! {
+        /// Last write of the test thread to an I/O port
+        static WRITTEN: Cell<Option<(u16, u32)>> = const { Cell::new(None) };
+    }
+
+    pub unsafe fn outl(port: u16, value: u32) {
+        WRITTEN.set(Some((port, value)));
+    }
+
+    /// Status QEMU exits with once `value` is written to the debug exit device
+    fn qemu_status(value: u32) -> i32 {
+        i32::try_from((value << 1) | 1).unwrap()
+    }
+
+    #[test]
+    fn codes_map_to_the_statuses_qemu_exits_with() {
+        for (code, status) in [(ExitCode::Success, 33), (ExitCode::Failed, 35)] {
+            request_exit(code);
+            let (port, value) = WRITTEN.take().unwrap();
+            assert_eq!(port, 0xf4);
+            assert_eq!(qemu_status(value), status);
+            assert_eq!(code.status(), status);
+        }
+    }
+}
@@ -0,0 +1,226 @@
+#[cfg(not(test))]
+use x86_64::instructions::{
+    interrupts,
+    port::{PortRead, PortWrite},
+};
+
+#[cfg(test)]
+use self::tests::read_register;
+
+/// CMOS register select port
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+/// Status A bit set while the RTC updates its registers
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Status B bit set when hours are in 24-hour format
+const HOUR_24: u8 = 1 << 1;
+/// Status B bit set when values are binary instead of BCD
+const BINARY: u8 = 1 << 2;
+/// Hours bit set for PM times in 12-hour format
+const HOUR_PM: u8 = 1 << 7;
+
+/// Calendar date and time, in UTC
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since the Unix epoch, saturating at 0 for earlier dates
+    #[allow(clippy::cast_sign_loss)]
+    pub const fn unix_timestamp(self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        let secs =
+            days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        if secs < 0 {
+            0
+        } else {
+            secs as u64
+        }
+    }
+}
+
+/// Days from 1970-01-01 to the given date of the proleptic Gregorian calendar.
+///
+/// Howard Hinnant's `days_from_civil`.
+const fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    // Year of the era, 0 to 399
+    let yoe = year - era * 400;
+    // Day of the year, counting from March 1st
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    // Day of the era, 0 to 146096
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Decodes a two digit BCD value
+pub const fn bcd_to_binary(bcd: u8) -> u8 {
+    (bcd >> 4) * 10 + (bcd & 0x0F)
+}
+
+/// Seconds since the Unix epoch, according to the RTC
+pub fn now() -> u64 {
+    read().unix_timestamp()
+}
+
+/// Reads the current date and time from the RTC.
+///
+/// The RTC is assumed to keep UTC, in the 21st century.
+pub fn read() -> DateTime {
+    // Registers read during an update may be inconsistent, so read until two reads agree
+    let mut last = read_raw();
+    loop {
+        let raw = read_raw();
+        if raw == last {
+            break;
+        }
+        last = raw;
+    }
+    let [second, minute, hour, day, month, year] = last;
+
+    let status_b = read_register(REG_STATUS_B);
+    let decode = |value: u8| {
+        if status_b & BINARY == 0 {
+            bcd_to_binary(value)
+        } else {
+            value
+        }
+    };
+
+    let mut hour_24 = decode(hour & !HOUR_PM);
+    if status_b & HOUR_24 == 0 {
+        // 12 AM is midnight and 12 PM is noon
+        hour_24 %= 12;
+        if hour & HOUR_PM != 0 {
+            hour_24 += 12;
+        }
+    }
+
+    DateTime {
+        year: 2000 + u16::from(decode(year)),
+        month: decode(month),
+        day: decode(day),
+        hour: hour_24,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+/// Reads the time registers once no update is in progress
+fn read_raw() -> [u8; 6] {
+    while read_register(REG_STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    [
+        REG_SECONDS,
+        REG_MINUTES,
+        REG_HOURS,
+        REG_DAY,
+        REG_MONTH,
+        REG_YEAR,
+    ]
+    .map(read_register)
+}
+
+#[cfg(not(test))]
+fn read_register(reg: u8) -> u8 {
+    // The register select and read must not be split by another CMOS access
+    interrupts::without_interrupts(|| unsafe {
+        u8::write_to_port(CMOS_ADDRESS, reg);
+        u8::read_from_port(CMOS_DATA)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+
+    std::thread_local! {
+        /// CMOS registers of the mocked RTC, up to status B
+        static REGISTERS: Cell<[u8; 0x0C]> = const { Cell::new([0; 0x0C]) };
+    }
+
+    pub fn read_register(reg: u8) -> u8 {
+        REGISTERS.get()[usize::from(reg)]
+    }
+
+    /// Sets the mocked time registers and status B
+    fn set_registers(status_b: u8, [second, minute, hour, day, month, year]: [u8; 6]) {
+        let mut registers = [0; 0x0C];
+        registers[usize::from(REG_SECONDS)] = second;
+        registers[usize::from(REG_MINUTES)] = minute;
+        registers[usize::from(REG_HOURS)] = hour;
+        registers[usize::from(REG_DAY)] = day;
+        registers[usize::from(REG_MONTH)] = month;
+        registers[usize::from(REG_YEAR)] = year;
+        registers[usize::from(REG_STATUS_B)] = status_b;
+        REGISTERS.set(registers);
+    }
+
+    const fn date(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    }
+
+    #[test]
+    fn bcd_values_are_decoded() {
+        assert_eq!(bcd_to_binary(0x00), 0);
+        assert_eq!(bcd_to_binary(0x09), 9);
+        assert_eq!(bcd_to_binary(0x10), 10);
+        assert_eq!(bcd_to_binary(0x59), 59);
+        assert_eq!(bcd_to_binary(0x99), 99);
+    }
+
+    #[test]
+    fn dates_convert_to_unix_time() {
+        assert_eq!(date(1970, 1, 1, 0, 0, 0).unix_timestamp(), 0);
+        assert_eq!(date(2000, 2, 29, 0, 0, 0).unix_timestamp(), 951_782_400);
+        assert_eq!(date(2024, 1, 1, 13, 5, 9).unix_timestamp(), 1_704_114_309);
+        assert_eq!(date(2038, 1, 19, 3, 14, 8).unix_timestamp(), 1 << 31);
+        // Dates before the epoch saturate
+        assert_eq!(date(1969, 12, 31, 23, 59, 59).unix_timestamp(), 0);
+    }
+
+    #[test]
+    fn registers_are_decoded_by_status_b() {
+        // 2024-03-15 21:30:45 in BCD, with 12-hour PM hours
+        set_registers(0, [0x45, 0x30, HOUR_PM | 0x09, 0x15, 0x03, 0x24]);
+        assert_eq!(read(), date(2024, 3, 15, 21, 30, 45));
+
+        // 12 AM is midnight
+        set_registers(0, [0x00, 0x00, 0x12, 0x01, 0x01, 0x25]);
+        assert_eq!(read(), date(2025, 1, 1, 0, 0, 0));
+
+        // The same time in binary and 24-hour format
+        set_registers(BINARY | HOUR_24, [45, 30, 21, 15, 3, 24]);
+        assert_eq!(read(), date(2024, 3, 15, 21, 30, 45));
+        assert_eq!(now(), 1_710_538_245);
+    }
+}
@@ -1,8 +1,15 @@
-use core::fmt::Write;
+use core::fmt::{Arguments, Write};
 
 use spin::{Lazy, Mutex};
+use x86_64::instructions::interrupts;
+#[cfg(not(test))]
 use x86_64::instructions::port::{PortRead, PortWrite};
 
+use crate::ioapic::IoApicError;
+
+#[cfg(test)]
+use self::tests::{inb, outb};
+
 pub static COM1: Lazy<Mutex<Serial>> = Lazy::new(|| {
     let Ok(serial) = Serial::com1() else {
         crate::panic::halt_and_never_return();
@@ -10,120 +17,330 @@ pub static COM1: Lazy<Mutex<Serial>> = Lazy::new(|| {
     Mutex::new(serial)
 });
 
-#[macro_export]
-macro_rules! kprint {
-    ($($args:tt)*) => {
-        {
-            use ::core::fmt::Write;
-            let mut serial = $crate::serial::COM1.lock();
-            // Serial write will never fail
-            let _ = write!(*serial, $($args)*);
+/// Second serial port, `None` if the machine doesn't have one
+pub static COM2: Lazy<Option<Mutex<Serial>>> = Lazy::new(|| Serial::com2().ok().map(Mutex::new));
+
+/// Baud rate of the UART clock, every baud rate divides it
+const MAX_BAUD: u32 = 115_200;
+
+/// Line parameters of a serial port, 115200 baud 8N1 by default.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SerialConfig {
+    pub baud: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// Parity bit always set
+    Mark,
+    /// Parity bit always clear
+    Space,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StopBits {
+    One,
+    /// Two stop bits, or one and a half with five data bits
+    Two,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self {
+            baud: MAX_BAUD,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
         }
-    };
+    }
 }
 
-#[macro_export]
-macro_rules! kprintln {
-    ($($args:tt)*) => {
-        {
-            use ::core::fmt::Write;
-            let mut serial = $crate::serial::COM1.lock();
-            // Serial write will never fail
-            // Use write! instead of writeln! to ensure a carriage return is written
-            let _ = write!(*serial, $($args)*);
-            serial.write_byte(b'\r');
-            serial.write_byte(b'\n');
+impl SerialConfig {
+    /// Baud rate divisor, failing if the baud rate doesn't divide the UART clock
+    pub const fn divisor(self) -> Result<u16, SerialError> {
+        if self.baud == 0 || self.baud > MAX_BAUD || !MAX_BAUD.is_multiple_of(self.baud) {
+            return Err(SerialError);
         }
-    };
+        let divisor = MAX_BAUD / self.baud;
+        if divisor > u16::MAX as u32 {
+            return Err(SerialError);
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        Ok(divisor as u16)
+    }
+
+    /// Value of the line control register, with DLAB clear
+    pub const fn line_control(self) -> u8 {
+        let data = match self.data_bits {
+            DataBits::Five => 0b00,
+            DataBits::Six => 0b01,
+            DataBits::Seven => 0b10,
+            DataBits::Eight => 0b11,
+        };
+        let stop = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => 1 << 2,
+        };
+        let parity = match self.parity {
+            Parity::None => 0b000,
+            Parity::Odd => 0b001,
+            Parity::Even => 0b011,
+            Parity::Mark => 0b101,
+            Parity::Space => 0b111,
+        };
+        data | stop | parity << 3
+    }
 }
 
+/// Size of the buffer holding received input until it's read.
+pub const INPUT_SIZE: usize = 256;
+
 pub struct Serial {
     port: u16,
+    input: InputBuffer,
+    /// Whether received input is echoed back
+    echo: bool,
+    /// Whether the last received byte was `\r`, so a `\n` following it ends the same line
+    after_cr: bool,
+}
+
+/// Ring of received bytes, with line endings stored as `\n`.
+///
+/// Bytes received while it's full are dropped.
+struct InputBuffer {
+    buf: [u8; INPUT_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl InputBuffer {
+    const fn new() -> Self {
+        Self {
+            buf: [0; INPUT_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    const fn get(&self, i: usize) -> u8 {
+        self.buf[(self.start + i) % INPUT_SIZE]
+    }
+
+    /// Appends `byte`, returning whether there was room for it
+    const fn push(&mut self, byte: u8) -> bool {
+        if self.len == INPUT_SIZE {
+            return false;
+        }
+        self.buf[(self.start + self.len) % INPUT_SIZE] = byte;
+        self.len += 1;
+        true
+    }
+
+    const fn pop_front(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.buf[self.start];
+        self.start = (self.start + 1) % INPUT_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    /// Removes the last byte of the line being typed, returning whether there was one
+    const fn erase(&mut self) -> bool {
+        if self.len == 0 || self.get(self.len - 1) == b'\n' {
+            return false;
+        }
+        self.len -= 1;
+        true
+    }
 }
 
 impl Serial {
     const COM1: u16 = 0x3F8;
+    const COM2: u16 = 0x2F8;
+    /// Line status bit set when the transmit holding register is empty
+    const TRANSMIT_EMPTY: u8 = 1 << 5;
+    /// Line status polls before a byte is written regardless, so a stuck UART can't hang the
+    /// console
+    const TRANSMIT_RETRIES: usize = 100_000;
 
     pub fn com1() -> Result<Self, SerialError> {
         unsafe { Self::new(Self::COM1) }
     }
 
+    pub fn com2() -> Result<Self, SerialError> {
+        unsafe { Self::new(Self::COM2) }
+    }
+
     pub unsafe fn new(port: u16) -> Result<Self, SerialError> {
-        Self::init_serial(port)?;
-        Ok(Self { port })
+        Self::with_config(port, SerialConfig::default())
     }
 
-    fn init_serial(port: u16) -> Result<(), SerialError> {
+    /// Initializes the serial port at `port` with the line parameters in `config`
+    ///
+    /// Fails if the baud rate isn't supported or the port fails its loopback test.
+    pub unsafe fn with_config(port: u16, config: SerialConfig) -> Result<Self, SerialError> {
+        Self::init_serial(port, config)?;
+        Ok(Self {
+            port,
+            input: InputBuffer::new(),
+            echo: true,
+            after_cr: false,
+        })
+    }
+
+    fn init_serial(port: u16, config: SerialConfig) -> Result<(), SerialError> {
+        let [divisor_lo, divisor_hi] = config.divisor()?.to_le_bytes();
         unsafe {
-            u8::write_to_port(port + 1, 0x00); // Disable all interrupts
-            u8::write_to_port(port + 3, 0x80); // Enable DLAB (set baud rate divisor)
-            u8::write_to_port(port, 0x03); // Set divisor to 3 (lo byte) 38400 baud
-            u8::write_to_port(port + 1, 0x00); //                  (hi byte)
-            u8::write_to_port(port + 3, 0x03); // 8 bits, no parity, one stop bit
-            u8::write_to_port(port + 2, 0xC7); // Enable FIFO, clear them, with 14-byte threshold
-            u8::write_to_port(port + 4, 0x0B); // IRQs enabled, RTS/DSR set
-            u8::write_to_port(port + 4, 0x1E); // Set in loopback mode, test the serial chip
-            u8::write_to_port(port, 0xAE); // Test serial chip (send byte 0xAE and check if serial returns same byte)
+            outb(port + 1, 0x00); // Disable all interrupts
+            outb(port + 3, 0x80); // Enable DLAB (set baud rate divisor)
+            outb(port, divisor_lo); // Set divisor (lo byte)
+            outb(port + 1, divisor_hi); //          (hi byte)
+            outb(port + 3, config.line_control()); // Data, parity & stop bits
+            outb(port + 2, 0xC7); // Enable FIFO, clear them, with 14-byte threshold
+            outb(port + 4, 0x0B); // IRQs enabled, RTS/DSR set
+            outb(port + 4, 0x1E); // Set in loopback mode, test the serial chip
+            outb(port, 0xAE); // Test serial chip (send byte 0xAE and check if serial returns same byte)
 
             // Check if serial is faulty (i.e: not same byte as sent)
-            if u8::read_from_port(port) != 0xAE {
+            if inb(port) != 0xAE {
                 return Err(SerialError);
             }
 
             // If serial is not faulty set it in normal operation mode
             // (not-loopback with IRQs enabled and OUT#1 and OUT#2 bits enabled)
-            u8::write_to_port(port + 4, 0x0F);
+            outb(port + 4, 0x0F);
             Ok(())
         }
     }
 
-    pub fn enable_interrupts(&mut self) {
+    /// Enables the received data interrupt, routed to `irq` on the IOAPIC
+    pub fn enable_interrupts(&mut self, irq: u8) -> Result<(), IoApicError> {
         unsafe {
-            u8::write_to_port(self.port + 1, 0x01);
+            outb(self.port + 1, 0x01);
 
             // Acknowledge any pending interrupts
-            u8::read_from_port(self.port + 2);
-            u8::read_from_port(self.port);
+            inb(self.port + 2);
+            inb(self.port);
         }
 
-        // Enable interrupts on IOAPIC
-        let mut ioapic = crate::apic::IOAPIC.lock();
-        ioapic.enable(crate::trap::IRQ_COM1, 0);
+        // Enable interrupts on IOAPIC, routed to this CPU
+        let dest = crate::apic::apic_id();
+        crate::apic::IOAPIC.lock().enable_isa(irq, dest)
     }
 
+    /// Writes `byte` once the transmitter is ready, waiting a bounded amount of time
     pub fn write_byte(&mut self, byte: u8) {
+        for _ in 0..Self::TRANSMIT_RETRIES {
+            if self.transmit_empty() {
+                break;
+            }
+            core::hint::spin_loop();
+        }
         unsafe {
-            u8::write_to_port(self.port, byte);
+            outb(self.port, byte);
         }
     }
 
+    /// Writes `byte` if the transmitter is ready, returning whether it was written
+    pub fn try_write_byte(&mut self, byte: u8) -> bool {
+        if !self.transmit_empty() {
+            return false;
+        }
+        unsafe {
+            outb(self.port, byte);
+        }
+        true
+    }
+
+    pub fn transmit_empty(&mut self) -> bool {
+        unsafe { inb(self.port + 5) & Self::TRANSMIT_EMPTY != 0 }
+    }
+
     pub fn data_available(&mut self) -> bool {
-        unsafe { u8::read_from_port(self.port + 5) & 1 == 1 }
+        unsafe { inb(self.port + 5) & 1 == 1 }
     }
 
     pub fn read_byte(&mut self) -> Option<u8> {
         if self.data_available() {
-            Some(unsafe { u8::read_from_port(self.port) })
+            Some(unsafe { inb(self.port) })
         } else {
             None
         }
     }
 
+    /// Sets whether received input is echoed back, on by default
+    pub const fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    /// Takes the oldest buffered input byte
+    ///
+    /// Line endings read as `\n`, and erased bytes are already removed.
+    pub const fn try_read_byte(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    /// Takes the oldest completed line from the input, returning its length
+    ///
+    /// The line ending isn't included. If the line doesn't fit in `buf`, the rest of it is
+    /// discarded. Returns `None` until a whole line was received.
+    pub fn read_line(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let end = (0..self.input.len).find(|&i| self.input.get(i) == b'\n')?;
+        for i in 0..end {
+            let byte = self.input.pop_front()?;
+            if let Some(out) = buf.get_mut(i) {
+                *out = byte;
+            }
+        }
+
+        // Drop the line ending
+        self.input.pop_front();
+        Some(end.min(buf.len()))
+    }
+
+    /// Moves received bytes into the input buffer
     pub fn handle_interrupt(&mut self) {
         while let Some(byte) = self.read_byte() {
+            let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
             match byte {
+                // Second half of a CR LF
+                b'\n' if after_cr => {}
                 // Backspace
                 0x7f => {
-                    self.write_byte(b'\x08');
-                    self.write_byte(b' ');
-                    self.write_byte(b'\x08');
+                    if self.input.erase() && self.echo {
+                        self.write_byte(b'\x08');
+                        self.write_byte(b' ');
+                        self.write_byte(b'\x08');
+                    }
                 }
                 // New line
                 b'\r' | b'\n' => {
-                    self.write_byte(b'\r');
-                    self.write_byte(b'\n');
+                    if self.input.push(b'\n') && self.echo {
+                        self.write_byte(b'\r');
+                        self.write_byte(b'\n');
+                    }
+                }
+                b => {
+                    if self.input.push(b) && self.echo {
+                        self.write_byte(b);
+                    }
                 }
-                b => self.write_byte(b),
             }
         }
     }
@@ -138,6 +355,28 @@ impl Write for Serial {
     }
 }
 
+/// Writes `args` to `serial`, with interrupts disabled since its handler locks it too.
+///
+/// Used by [`kprint_to`](crate::kprint_to).
+pub fn write_to(serial: &Mutex<Serial>, args: Arguments) {
+    interrupts::without_interrupts(|| {
+        // Serial write will never fail
+        let _ = serial.lock().write_fmt(args);
+    });
+}
+
+/// Reads the UART register at `port`
+#[cfg(not(test))]
+unsafe fn inb(port: u16) -> u8 {
+    u8::read_from_port(port)
+}
+
+/// Writes `value` to the UART register at `port`
+#[cfg(not(test))]
+unsafe fn outb(port: u16, value: u8) {
+    u8::write_to_port(port, value);
+}
+
 pub struct SerialError;
 
 impl core::fmt::Debug for SerialError {
@@ -145,3 +384,232 @@ impl core::fmt::Debug for SerialError {
         f.write_str("SerialError")
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use std::{
+        cell::RefCell,
+        collections::{HashMap, VecDeque},
+        vec::Vec,
+    };
+
+    use super::*;
+
+    /// UART registers of the test thread, the real ones can't be accessed on the host
+    #[derive(Default)]
+    struct MockPorts {
+        /// Every write, in order
+        writes: Vec<(u16, u8)>,
+        /// Values read from each port, the last one is repeated once the others are read
+        reads: HashMap<u16, Vec<u8>>,
+        /// Bytes waiting to be read from each data port
+        received: HashMap<u16, VecDeque<u8>>,
+    }
+
+    std::thread_local! {
+        static PORTS: RefCell<MockPorts> = RefCell::default();
+    }
+
+    pub unsafe fn inb(port: u16) -> u8 {
+        PORTS.with_borrow_mut(|ports| {
+            if let Some(byte) = ports.received.get_mut(&port).and_then(VecDeque::pop_front) {
+                return byte;
+            }
+            let value = match ports.reads.get_mut(&port) {
+                Some(values) if values.len() > 1 => values.remove(0),
+                Some(values) => values.first().copied().unwrap_or(0),
+                None => 0,
+            };
+            // The line status has data ready while received bytes are left
+            let data_ready = port
+                .checked_sub(5)
+                .and_then(|data| ports.received.get(&data))
+                .is_some_and(|received| !received.is_empty());
+            value | u8::from(data_ready)
+        })
+    }
+
+    pub unsafe fn outb(port: u16, value: u8) {
+        PORTS.with_borrow_mut(|ports| ports.writes.push((port, value)));
+    }
+
+    /// Makes reads of `port` return `values` in order, then the last one forever
+    fn set_reads(port: u16, values: &[u8]) {
+        PORTS.with_borrow_mut(|ports| ports.reads.insert(port, values.to_vec()));
+    }
+
+    /// Queues `bytes` as received by the UART at `port`
+    fn receive(port: u16, bytes: &[u8]) {
+        PORTS.with_borrow_mut(|ports| ports.received.entry(port).or_default().extend(bytes));
+    }
+
+    fn take_writes() -> Vec<(u16, u8)> {
+        PORTS.with_borrow_mut(|ports| core::mem::take(&mut ports.writes))
+    }
+
+    /// Lets [`COM1`] pass its loopback test and write without waiting, for tests on this thread
+    /// writing to the console
+    pub fn mock_com1() {
+        set_reads(Serial::COM1, &[0xAE]);
+        set_reads(Serial::COM1 + 5, &[Serial::TRANSMIT_EMPTY]);
+    }
+
+    /// A COM1 that passed its loopback test, with no writes recorded
+    fn serial() -> Serial {
+        set_reads(Serial::COM1, &[0xAE]);
+        let serial = Serial::com1().unwrap();
+        set_reads(Serial::COM1 + 5, &[Serial::TRANSMIT_EMPTY]);
+        take_writes();
+        serial
+    }
+
+    fn read_line(serial: &mut Serial) -> Option<Vec<u8>> {
+        let mut buf = [0; INPUT_SIZE];
+        let len = serial.read_line(&mut buf)?;
+        Some(buf[..len].to_vec())
+    }
+
+    #[test]
+    fn write_waits_for_the_transmitter() {
+        let mut serial = serial();
+        let status = Serial::COM1 + 5;
+
+        set_reads(status, &[0, 0, Serial::TRANSMIT_EMPTY]);
+        assert!(!serial.try_write_byte(b'a'));
+        assert_eq!(take_writes(), []);
+
+        serial.write_byte(b'b');
+        assert_eq!(take_writes(), [(Serial::COM1, b'b')]);
+        assert!(serial.try_write_byte(b'c'));
+        assert_eq!(take_writes(), [(Serial::COM1, b'c')]);
+    }
+
+    #[test]
+    fn write_gives_up_waiting_on_a_stuck_transmitter() {
+        let mut serial = serial();
+        set_reads(Serial::COM1 + 5, &[0]);
+
+        serial.write_byte(b'a');
+        assert_eq!(take_writes(), [(Serial::COM1, b'a')]);
+    }
+
+    #[test]
+    fn crlf_ends_a_single_line() {
+        let mut serial = serial();
+
+        receive(Serial::COM1, b"ab\r\ncd\ref\n\r");
+        serial.handle_interrupt();
+        // The LF of a CR LF split across interrupts is skipped too
+        receive(Serial::COM1, b"\ngh\n\n");
+        serial.handle_interrupt();
+
+        for line in [&b"ab"[..], b"cd", b"ef", b"", b"gh", b""] {
+            assert_eq!(read_line(&mut serial).as_deref(), Some(line));
+        }
+        assert_eq!(read_line(&mut serial), None);
+
+        let echoed: Vec<_> = take_writes().into_iter().map(|(_, byte)| byte).collect();
+        assert_eq!(echoed, b"ab\r\ncd\r\nef\r\n\r\ngh\r\n\r\n");
+    }
+
+    #[test]
+    fn backspace_erases_the_line() {
+        let mut serial = serial();
+        serial.set_echo(false);
+
+        receive(Serial::COM1, b"ab\x7fc\r\x7f\x7fd\n");
+        serial.handle_interrupt();
+
+        assert_eq!(read_line(&mut serial).as_deref(), Some(&b"ac"[..]));
+        // Backspace stops at the start of the line
+        assert_eq!(read_line(&mut serial).as_deref(), Some(&b"d"[..]));
+        assert_eq!(take_writes(), []);
+    }
+
+    #[test]
+    fn divisor_and_line_control_follow_the_config() {
+        let config = |baud, data_bits, parity, stop_bits| SerialConfig {
+            baud,
+            data_bits,
+            parity,
+            stop_bits,
+        };
+
+        let default = SerialConfig::default();
+        assert_eq!(default.divisor().unwrap(), 1);
+        assert_eq!(default.line_control(), 0x03);
+
+        let cfg = config(38_400, DataBits::Seven, Parity::Even, StopBits::One);
+        assert_eq!(cfg.divisor().unwrap(), 3);
+        assert_eq!(cfg.line_control(), 0b0001_1010);
+
+        let cfg = config(9600, DataBits::Five, Parity::Odd, StopBits::Two);
+        assert_eq!(cfg.divisor().unwrap(), 12);
+        assert_eq!(cfg.line_control(), 0b0000_1100);
+
+        let cfg = config(50, DataBits::Eight, Parity::Space, StopBits::One);
+        assert_eq!(cfg.divisor().unwrap(), 2304);
+        assert_eq!(cfg.line_control(), 0b0011_1011);
+
+        for baud in [0, 7, 115_201, 230_400] {
+            assert!(config(baud, DataBits::Eight, Parity::None, StopBits::One)
+                .divisor()
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn init_programs_the_divisor_and_line() {
+        let port = Serial::COM1;
+        set_reads(port, &[0xAE]);
+        let config = SerialConfig {
+            baud: 9600,
+            data_bits: DataBits::Seven,
+            parity: Parity::Even,
+            stop_bits: StopBits::One,
+        };
+        unsafe { Serial::with_config(port, config) }.unwrap();
+
+        let writes = take_writes();
+        let dlab = writes.iter().position(|&w| w == (port + 3, 0x80)).unwrap();
+        assert_eq!(
+            writes[dlab + 1..dlab + 4],
+            [(port, 12), (port + 1, 0), (port + 3, config.line_control())]
+        );
+
+        // Fails the loopback test
+        set_reads(port, &[0]);
+        assert!(unsafe { Serial::with_config(port, config) }.is_err());
+        // Rejected before touching the port
+        take_writes();
+        let config = SerialConfig { baud: 7, ..config };
+        assert!(unsafe { Serial::with_config(port, config) }.is_err());
+        assert_eq!(take_writes(), []);
+    }
+
+    #[test]
+    fn com2_is_initialized_at_its_own_port() {
+        let port = Serial::COM2;
+        set_reads(port, &[0xAE]);
+        set_reads(port + 5, &[Serial::TRANSMIT_EMPTY]);
+        let mut com2 = Serial::com2().unwrap();
+        assert_eq!(
+            take_writes(),
+            [
+                (0x2F9, 0x00),
+                (0x2FB, 0x80),
+                (0x2F8, 0x01),
+                (0x2F9, 0x00),
+                (0x2FB, 0x03),
+                (0x2FA, 0xC7),
+                (0x2FC, 0x0B),
+                (0x2FC, 0x1E),
+                (0x2F8, 0xAE),
+                (0x2FC, 0x0F),
+            ]
+        );
+
+        com2.write_str("hi").unwrap();
+        assert_eq!(take_writes(), [(0x2F8, b'h'), (0x2F8, b'i')]);
+    }
+}
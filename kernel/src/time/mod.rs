@@ -0,0 +1,320 @@
+use core::{
+    fmt::{Display, Formatter},
+    ops::Sub,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use spin::Once;
+use x86::apic::xapic::ApicRegister;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+use crate::{
+    apic::{CPU_FREQ, LAPIC},
+    kprintln,
+    pit::PIT0,
+};
+
+pub mod tsc;
+
+/// Ticks per second.
+pub const TICK_FREQ: u32 = 1000;
+
+/// Number of ticks since the system booted.
+pub static TICKS: Ticks = Ticks::new();
+
+/// Whether the periodic timer interrupt is advancing [`TICKS`]
+static TICKING: AtomicBool = AtomicBool::new(false);
+
+/// Unix time in seconds and the tick count when the wall clock was set
+static WALL_CLOCK: Once<(u64, u64)> = Once::new();
+
+#[derive(Debug)]
+pub struct Ticks(AtomicU64);
+
+impl Ticks {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Converts a number of ticks to nanoseconds, saturating at `u64::MAX`.
+pub const fn ticks_to_ns(ticks: u64) -> u64 {
+    let freq = TICK_FREQ as u64;
+    let ns = (ticks as u128) * 1_000_000_000 / (freq as u128);
+    if ns > u64::MAX as u128 {
+        u64::MAX
+    } else {
+        ns as u64
+    }
+}
+
+/// Milliseconds since the system booted.
+pub fn uptime_ms() -> u64 {
+    uptime_ns() / 1_000_000
+}
+
+/// Nanoseconds since the system booted, in steps of one tick.
+pub fn uptime_ns() -> u64 {
+    ticks_to_ns(TICKS.get())
+}
+
+/// A point in time measured by [`TICKS`], only meaningful compared with another `Instant`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(TICKS.get())
+    }
+
+    /// Ticks since boot at this instant
+    pub const fn ticks(self) -> u64 {
+        self.0
+    }
+
+    /// Time passed since this instant
+    pub fn elapsed(self) -> Duration {
+        Self::now().duration_since(self)
+    }
+
+    /// Time passed from `earlier` to this instant, zero if `earlier` is later.
+    pub const fn duration_since(self, earlier: Self) -> Duration {
+        Duration::from_nanos(ticks_to_ns(self.0.saturating_sub(earlier.0)))
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Self) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// Whether the periodic timer interrupt is advancing [`TICKS`]
+pub fn is_ticking() -> bool {
+    TICKING.load(Ordering::Relaxed)
+}
+
+/// Records whether the periodic timer interrupt is advancing [`TICKS`]
+pub fn set_ticking(ticking: bool) {
+    TICKING.store(ticking, Ordering::Relaxed);
+}
+
+/// Waits for at least `ms` milliseconds, halting between ticks.
+///
+/// Fails like [`sleep_until`].
+pub fn sleep_ms(ms: u64) -> Result<(), TimerStopped> {
+    let freq = u64::from(TICK_FREQ);
+    // Round up, so the sleep is never shorter than asked
+    let ticks = ms.saturating_mul(freq).div_ceil(1000);
+    sleep_until(TICKS.get().saturating_add(ticks))
+}
+
+/// Waits until [`TICKS`] reaches `tick`, returning immediately if it already has.
+///
+/// Interrupts are enabled while waiting, since the timer interrupt advances [`TICKS`], and
+/// disabled again afterwards if they were disabled. Fails instead of waiting forever if the
+/// periodic timer isn't running.
+pub fn sleep_until(tick: u64) -> Result<(), TimerStopped> {
+    if TICKS.get() >= tick {
+        return Ok(());
+    }
+    if !is_ticking() {
+        return Err(TimerStopped);
+    }
+    let enabled = interrupts::are_enabled();
+    wait_for_tick(&TICKS, tick, interrupts::enable_and_hlt);
+    if !enabled {
+        interrupts::disable();
+    }
+    Ok(())
+}
+
+/// Calls `wait` until `ticks` reaches `tick`
+fn wait_for_tick(ticks: &Ticks, tick: u64, mut wait: impl FnMut()) {
+    while ticks.get() < tick {
+        wait();
+    }
+}
+
+/// [`TICKS`] isn't advancing, so a sleep would never end
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TimerStopped;
+
+/// Sets the wall clock to `unix_secs` seconds since the Unix epoch.
+///
+/// Only the first call has an effect, the clock then advances with [`TICKS`].
+pub fn set_wall_clock(unix_secs: u64) {
+    WALL_CLOCK.call_once(|| (unix_secs, TICKS.get()));
+}
+
+/// Returns the seconds since the Unix epoch, or `None` if the wall clock isn't set yet.
+pub fn unix_timestamp() -> Option<u64> {
+    Timestamp::now().unix.map(|(secs, _)| secs)
+}
+
+/// Point in time for log lines.
+///
+/// Displays as `[HH:MM:SS.mmm]` wall-clock time (UTC), or as `[ticks]` before the wall clock
+/// is set.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Timestamp {
+    /// Ticks since boot
+    pub ticks: u64,
+    /// Seconds since the Unix epoch and milliseconds into that second
+    pub unix: Option<(u64, u32)>,
+}
+
+impl Timestamp {
+    pub fn now() -> Self {
+        let ticks = TICKS.get();
+        let unix = WALL_CLOCK.get().map(|&(secs, at)| {
+            let elapsed = ticks.saturating_sub(at);
+            let freq = u64::from(TICK_FREQ);
+            let millis = (elapsed % freq) * 1000 / freq;
+            (secs + elapsed / freq, millis as u32)
+        });
+        Self { ticks, unix }
+    }
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self.unix {
+            Some((secs, millis)) => {
+                let secs = secs % (24 * 60 * 60);
+                write!(
+                    f,
+                    "[{:02}:{:02}:{:02}.{millis:03}]",
+                    secs / 3600,
+                    secs / 60 % 60,
+                    secs % 60
+                )
+            }
+            None => write!(f, "[{}]", self.ticks),
+        }
+    }
+}
+
+pub fn start_timer() {
+    without_interrupts(|| {
+        let mut lapic = LAPIC.lock();
+
+        // Tell APIC timer to use divider 16
+        lapic.write(ApicRegister::XAPIC_TIMER_DIV_CONF, 0x3);
+
+        // Set APIC init counter to -1
+        lapic.write(ApicRegister::XAPIC_TIMER_INIT_COUNT, 0xffff_ffff);
+
+        // Sleep for 10ms
+        if PIT0.sleep_us(10_000).is_err() {
+            kprintln!("WARNING: PIT calibration timed out, calibrating APIC timer against TSC");
+
+            // Resolve the CPU frequency first, since it may also need to calibrate
+            let cycles_per_10ms = *CPU_FREQ / 100;
+
+            lapic.write(ApicRegister::XAPIC_TIMER_INIT_COUNT, 0xffff_ffff);
+            tsc::busy_wait_cycles(cycles_per_10ms);
+        }
+
+        // Stop APIC timer
+        lapic.write(ApicRegister::XAPIC_LVT_TIMER, 0x10000);
+
+        let ticks_per_10ms = 0xFFFF_FFFF - lapic.read(ApicRegister::XAPIC_TIMER_CURRENT_COUNT);
+        let ticks_per_s = ticks_per_10ms * 100;
+        crate::apic::set_timer_frequency(u64::from(ticks_per_s));
+
+        // Start timer as periodic on IRQ 0, divider 16, with the number of ticks to achieve TICK_FREQ
+        lapic.write(ApicRegister::XAPIC_LVT_TIMER, 0x20 | 0x20000);
+        lapic.write(ApicRegister::XAPIC_TIMER_DIV_CONF, 0x3);
+        lapic.write(
+            ApicRegister::XAPIC_TIMER_INIT_COUNT,
+            ticks_per_s / TICK_FREQ,
+        );
+        set_ticking(true);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_ends_at_the_target_tick() {
+        let ticks = Ticks::new();
+        let mut waits = 0;
+        wait_for_tick(&ticks, 5, || {
+            waits += 1;
+            ticks.inc();
+        });
+        assert_eq!(waits, 5);
+        assert_eq!(ticks.get(), 5);
+
+        // A tick already past doesn't wait
+        wait_for_tick(&ticks, 3, || panic!("waited for a past tick"));
+    }
+
+    #[test]
+    fn sleep_fails_without_the_timer() {
+        assert!(!is_ticking());
+        assert_eq!(sleep_until(TICKS.get() + 1), Err(TimerStopped));
+        assert_eq!(sleep_until(TICKS.get()), Ok(()));
+        assert_eq!(sleep_ms(0), Ok(()));
+    }
+
+    #[test]
+    fn uptime_and_instants_follow_the_ticks() {
+        assert_eq!(ticks_to_ns(0), 0);
+        assert_eq!(ticks_to_ns(1), 1_000_000);
+        assert_eq!(ticks_to_ns(1500), 1_500_000_000);
+        assert_eq!(ticks_to_ns(u64::MAX), u64::MAX);
+
+        // Only this test advances TICKS
+        let start = Instant::now();
+        let uptime = uptime_ms();
+        for _ in 0..250 {
+            TICKS.inc();
+        }
+        let end = Instant::now();
+        assert_eq!(end.ticks() - start.ticks(), 250);
+        assert_eq!(uptime_ms() - uptime, 250);
+        assert_eq!(uptime_ns(), ticks_to_ns(TICKS.get()));
+
+        assert_eq!((end - start).as_millis(), 250);
+        assert_eq!(start.elapsed(), Duration::from_millis(250));
+        // Subtracting a later instant saturates to zero
+        assert_eq!(start - end, Duration::ZERO);
+    }
+
+    #[test]
+    fn timestamp_shows_the_wall_clock_once_set() {
+        let before = Timestamp {
+            ticks: 1234,
+            unix: None,
+        };
+        assert_eq!(before.to_string(), "[1234]");
+
+        // 2024-01-01 13:05:09 UTC
+        let at = |millis| Timestamp {
+            ticks: 1234,
+            unix: Some((1_704_114_309, millis)),
+        };
+        assert_eq!(at(7).to_string(), "[13:05:09.007]");
+        assert_eq!(at(999).to_string(), "[13:05:09.999]");
+        let midnight = Timestamp {
+            ticks: 0,
+            unix: Some((86_400, 0)),
+        };
+        assert_eq!(midnight.to_string(), "[00:00:00.000]");
+    }
+}
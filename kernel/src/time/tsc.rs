@@ -0,0 +1,134 @@
+//! Busy waiting on the time stamp counter.
+//!
+//! Usable before the timer is running, such as while calibrating it.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(test)]
+use self::tests::rdtsc;
+
+/// TSC frequency assumed before [`CPU_FREQ`](crate::apic::CPU_FREQ) is known.
+///
+/// Deliberately high, so early waits last at least as long as requested.
+const ROUGH_FREQ: u64 = 5_000_000_000;
+
+/// TSC frequency in Hz, 0 until [`set_frequency`] is called
+static FREQ: AtomicU64 = AtomicU64::new(0);
+
+/// Records the measured TSC frequency, used by [`busy_wait_ns`] from then on.
+pub fn set_frequency(hz: u64) {
+    FREQ.store(hz, Ordering::Relaxed);
+}
+
+/// Returns the TSC frequency in Hz, or a rough overestimate if it isn't known yet.
+pub fn frequency() -> u64 {
+    match FREQ.load(Ordering::Relaxed) {
+        0 => ROUGH_FREQ,
+        hz => hz,
+    }
+}
+
+/// Converts `ns` nanoseconds to TSC cycles at `freq` Hz, rounding up.
+pub const fn ns_to_cycles(ns: u64, freq: u64) -> u64 {
+    let cycles = (ns as u128 * freq as u128).div_ceil(1_000_000_000);
+    if cycles > u64::MAX as u128 {
+        u64::MAX
+    } else {
+        cycles as u64
+    }
+}
+
+/// Converts `cycles` TSC cycles at `freq` Hz to nanoseconds, rounding down.
+pub const fn cycles_to_ns(cycles: u64, freq: u64) -> u64 {
+    let ns = cycles as u128 * 1_000_000_000 / freq as u128;
+    if ns > u64::MAX as u128 {
+        u64::MAX
+    } else {
+        ns as u64
+    }
+}
+
+/// Spins until the TSC advanced by at least `cycles`.
+pub fn busy_wait_cycles(cycles: u64) {
+    let start = rdtsc();
+    // Wrapping, in case the counter overflows during the wait
+    while rdtsc().wrapping_sub(start) < cycles {
+        core::hint::spin_loop();
+    }
+}
+
+/// Spins for at least `ns` nanoseconds.
+pub fn busy_wait_ns(ns: u64) {
+    busy_wait_cycles(ns_to_cycles(ns, frequency()));
+}
+
+/// Reads the time stamp counter
+#[cfg(not(test))]
+fn rdtsc() -> u64 {
+    unsafe { x86::time::rdtsc() }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+
+    std::thread_local! {
+        /// Time stamp counter of the test thread
+        static TSC: Cell<u64> = const { Cell::new(0) };
+        /// Cycles the counter advances by on each read
+        static STEP: Cell<u64> = const { Cell::new(1) };
+        /// Number of reads of the counter
+        static READS: Cell<u64> = const { Cell::new(0) };
+    }
+
+    pub fn rdtsc() -> u64 {
+        READS.set(READS.get() + 1);
+        let tsc = TSC.get();
+        TSC.set(tsc.wrapping_add(STEP.get()));
+        tsc
+    }
+
+    /// Starts the counter at `tsc`, advancing by `step` on each read
+    fn mock_tsc(tsc: u64, step: u64) {
+        TSC.set(tsc);
+        STEP.set(step);
+        READS.set(0);
+    }
+
+    #[test]
+    fn conversions_round_the_wait_up() {
+        const GHZ_3: u64 = 3_000_000_000;
+        assert_eq!(ns_to_cycles(1_000, GHZ_3), 3_000);
+        assert_eq!(cycles_to_ns(3_000, GHZ_3), 1_000);
+        // A partial cycle is a whole one, a partial nanosecond is dropped
+        assert_eq!(ns_to_cycles(1, 1_500_000_000), 2);
+        assert_eq!(cycles_to_ns(2, 1_500_000_000), 1);
+        assert_eq!(cycles_to_ns(1, GHZ_3), 0);
+
+        assert_eq!(ns_to_cycles(u64::MAX, GHZ_3), u64::MAX);
+        assert_eq!(cycles_to_ns(u64::MAX, 1), u64::MAX);
+        assert_eq!(
+            cycles_to_ns(ns_to_cycles(123_456_789, GHZ_3), GHZ_3),
+            123_456_789
+        );
+    }
+
+    #[test]
+    fn busy_wait_reads_until_enough_cycles_passed() {
+        mock_tsc(1_000, 10);
+        busy_wait_cycles(95);
+        // The start, then 10 reads to get 100 cycles past it
+        assert_eq!(READS.get(), 11);
+
+        // The counter wraps during the wait
+        mock_tsc(u64::MAX - 15, 10);
+        busy_wait_cycles(30);
+        assert_eq!(READS.get(), 4);
+
+        mock_tsc(0, 10);
+        busy_wait_cycles(0);
+        assert_eq!(READS.get(), 2);
+    }
+}
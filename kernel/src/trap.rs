@@ -1,59 +1,511 @@
-use lazy_static::lazy_static;
-use x86::apic::ApicControl;
+use core::{
+    fmt::{Display, Formatter},
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use spin::{Lazy, Mutex};
 use x86_64::{
+    registers::{control::Cr2, model_specific::GsBase},
     set_general_handler,
     structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
 };
 
-use crate::kprintln;
+use crate::{gdt, kprintln, serial, serial::Serial};
 
 pub const IRQ0: u8 = 0x20;
 pub const IRQ_COM1: u8 = 4;
+pub const IRQ_COM2: u8 = 3;
+
+/// Number of times each vector was raised, on all CPUs
+static INTERRUPT_COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// Handlers registered at runtime, called by [`general_handler`]. Null if unregistered.
+static HANDLERS: [AtomicPtr<()>; 256] = [const { AtomicPtr::new(ptr::null_mut()) }; 256];
+
+/// Maximum number of CPUs with a fault context slot
+pub const MAX_CPUS: usize = 16;
+
+/// Interrupt handler nesting depth of each CPU, indexed by CPU index.
+/// Only tracked in debug builds.
+static HANDLER_DEPTH: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+/// The last unhandled fault of each CPU, indexed by CPU index
+static FAULT_CONTEXTS: [Mutex<Option<FaultContext>>; MAX_CPUS] =
+    [const { Mutex::new(None) }; MAX_CPUS];
+
+/// CPU state at the time of an exception
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FaultContext {
+    pub vector: u8,
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+    /// Faulting address, for page faults
+    pub cr2: Option<u64>,
+    pub error_code: Option<u64>,
+}
+
+impl FaultContext {
+    pub fn new(frame: &InterruptStackFrame, vector: u8, error_code: Option<u64>) -> Self {
+        Self {
+            vector,
+            rip: frame.instruction_pointer.as_u64(),
+            rsp: frame.stack_pointer.as_u64(),
+            rflags: frame.cpu_flags,
+            cr2: None,
+            error_code,
+        }
+    }
+
+    /// Stores the context in the current CPU's slot
+    pub fn record(self) {
+        *FAULT_CONTEXTS[cpu_slot()].lock() = Some(self);
+    }
+}
+
+impl Display for FaultContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "vector={:#04x} RIP={:#018x} RSP={:#018x} RFLAGS={:#010x}",
+            self.vector, self.rip, self.rsp, self.rflags
+        )?;
+        if let Some(cr2) = self.cr2 {
+            write!(f, " CR2={cr2:#018x}")?;
+        }
+        if let Some(error_code) = self.error_code {
+            write!(f, " ERR={error_code:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Takes the current CPU's last fault context, if any.
+///
+/// Doesn't block, so it is safe to call from the panic handler.
+pub fn take_fault_context() -> Option<FaultContext> {
+    FAULT_CONTEXTS[cpu_slot()].try_lock()?.take()
+}
+
+/// Per-CPU data, pointed to by the GS base once [`init_cpu`] ran on the CPU
+struct PerCpu {
+    /// Dense index of the CPU, in bring-up order
+    index: usize,
+}
+
+static PER_CPU: [PerCpu; MAX_CPUS] = {
+    let mut cpus = [const { PerCpu { index: 0 } }; MAX_CPUS];
+    let mut i = 0;
+    while i < MAX_CPUS {
+        cpus[i].index = i;
+        i += 1;
+    }
+    cpus
+};
+
+/// Index of the next CPU brought up
+static NEXT_CPU: AtomicUsize = AtomicUsize::new(0);
+
+/// Gives the calling CPU the next CPU index, and points its GS base at its [`PerCpu`].
+///
+/// Must run once on every CPU, before it takes any interrupt. The bootstrap processor runs it
+/// first, so it gets index 0, which is also what CPUs are treated as before calling this.
+///
+/// # Panics
+///
+/// Panics if more than [`MAX_CPUS`] CPUs are brought up.
+pub fn init_cpu() {
+    let index = NEXT_CPU.fetch_add(1, Ordering::Relaxed);
+    assert!(index < MAX_CPUS, "more than {MAX_CPUS} CPUs brought up");
+    GsBase::write(VirtAddr::from_ptr(core::ptr::addr_of!(PER_CPU[index])));
+}
+
+/// Index of the current CPU, in `0..MAX_CPUS`
+#[cfg(not(test))]
+fn cpu_slot() -> usize {
+    let cpu = GsBase::read();
+    if cpu.is_null() {
+        return 0;
+    }
+    // SAFETY: A non-null GS base was set by init_cpu to point into PER_CPU
+    unsafe { (*cpu.as_ptr::<PerCpu>()).index }
+}
+
+#[cfg(test)]
+std::thread_local! {
+    /// CPU the test thread pretends to run on
+    static TEST_CPU: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+}
+
+/// Index of the CPU the test thread pretends to run on, GS can't be read on the host
+#[cfg(test)]
+fn cpu_slot() -> usize {
+    TEST_CPU.get()
+}
+
+/// Marks the current CPU as running an interrupt handler until dropped.
+///
+/// Does nothing in release builds.
+pub struct HandlerGuard {
+    slot: Option<usize>,
+}
+
+impl HandlerGuard {
+    pub fn enter() -> Self {
+        let slot = cfg!(debug_assertions).then(cpu_slot);
+        if let Some(slot) = slot {
+            HANDLER_DEPTH[slot].fetch_add(1, Ordering::Relaxed);
+        }
+        Self { slot }
+    }
+}
+
+impl Drop for HandlerGuard {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            HANDLER_DEPTH[slot].fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Whether the current CPU is running an interrupt handler.
+///
+/// Always `false` in release builds, where the nesting depth isn't tracked.
+pub fn in_interrupt() -> bool {
+    cfg!(debug_assertions) && HANDLER_DEPTH[cpu_slot()].load(Ordering::Relaxed) != 0
+}
+
+/// Asserts, in debug builds, that the current CPU isn't running an interrupt handler.
+///
+/// Called before taking locks that are held with interrupts enabled: an interrupt handler
+/// taking one while the code it interrupted holds it would spin forever.
+///
+/// Interrupt-unsafe locks:
+/// - the kernel heap, in [`KAllocator`](crate::memory::allocator::KAllocator)
+///
+/// The console is interrupt-safe, [`writer`](crate::console::writer) disables interrupts for
+/// as long as it's held.
+#[track_caller]
+pub fn assert_not_in_interrupt(lock: &str) {
+    assert!(!in_interrupt(), "{lock} locked in an interrupt handler");
+}
+
+#[inline]
+fn count_interrupt(vector: u8) {
+    INTERRUPT_COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of times each vector was raised, skipping vectors never raised.
+pub fn interrupt_counts() -> impl Iterator<Item = (u8, u64)> {
+    (0..=u8::MAX)
+        .zip(&INTERRUPT_COUNTS)
+        .map(|(vector, count)| (vector, count.load(Ordering::Relaxed)))
+        .filter(|(_, count)| *count != 0)
+}
+
+/// Writes the interrupt counts to `w`, one vector per line.
+pub fn print_interrupts<W: core::fmt::Write>(w: &mut W) -> core::fmt::Result {
+    for (vector, count) in interrupt_counts() {
+        write!(w, "{vector:#04x}: {count:>12}")?;
+        match vector_name(vector) {
+            Some(name) => writeln!(w, "  {name}")?,
+            None => writeln!(w)?,
+        }
+    }
+    Ok(())
+}
 
+const fn vector_name(vector: u8) -> Option<&'static str> {
+    match vector {
+        0x08 => Some("double fault"),
+        0x0e => Some("page fault"),
+        IRQ0 => Some("timer"),
+        _ if vector == IRQ0 + IRQ_COM1 => Some("COM1"),
+        _ if vector == IRQ0 + IRQ_COM2 => Some("COM2"),
+        _ => None,
+    }
+}
+
+/// Error returned by [`register_handler`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HandlerError {
+    /// The vector is a CPU exception or has a handler in the IDT
+    Reserved,
+    /// A handler is already registered for the vector
+    AlreadyRegistered,
+}
+
+/// Whether `vector` can't be registered: CPU exceptions, and the IRQs with IDT handlers.
+const fn is_reserved(vector: u8) -> bool {
+    vector <= IRQ0 || vector == IRQ0 + IRQ_COM1 || vector == IRQ0 + IRQ_COM2
+}
+
+/// Registers `handler` to be called, with the vector, whenever `vector` is raised.
+///
+/// The local APIC is acknowledged after the handler returns. Fails if the vector is reserved
+/// or already has a handler.
+pub fn register_handler(vector: u8, handler: fn(u8)) -> Result<(), HandlerError> {
+    if is_reserved(vector) {
+        return Err(HandlerError::Reserved);
+    }
+    HANDLERS[usize::from(vector)]
+        .compare_exchange(
+            ptr::null_mut(),
+            handler as *mut (),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .map(|_| ())
+        .map_err(|_| HandlerError::AlreadyRegistered)
+}
+
+/// Removes the handler registered for `vector`, returning whether there was one
+pub fn unregister_handler(vector: u8) -> bool {
+    !HANDLERS[usize::from(vector)]
+        .swap(ptr::null_mut(), Ordering::AcqRel)
+        .is_null()
+}
+
+fn registered_handler(vector: u8) -> Option<fn(u8)> {
+    let handler = HANDLERS[usize::from(vector)].load(Ordering::Acquire);
+    // Only ever set from a `fn(u8)` in `register_handler`
+    (!handler.is_null()).then(|| unsafe { core::mem::transmute::<*mut (), fn(u8)>(handler) })
+}
+
+#[cfg(not(test))]
 #[inline]
 fn ack_lapic() {
+    use x86::apic::ApicControl;
+
     crate::apic::LAPIC.lock().eoi();
 }
 
-fn general_handler(_: InterruptStackFrame, idx: u8, errcode: Option<u64>) {
-    kprintln!("Interrupt!:");
-    kprintln!("\tidx: {:x}", idx);
-    kprintln!("\terrcode: {:?}", errcode);
-    panic!("Interrupt!");
+/// Counts the acknowledgement, the local APIC can't be accessed on the host
+#[cfg(test)]
+fn ack_lapic() {
+    tests::ACKS.set(tests::ACKS.get() + 1);
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn general_handler(frame: InterruptStackFrame, idx: u8, errcode: Option<u64>) {
+    // Dropped only if a registered handler ran, otherwise the handler panics
+    let _guard = HandlerGuard::enter();
+    if dispatch(idx) {
+        return;
+    }
+    FaultContext::new(&frame, idx, errcode).record();
+    panic!("Interrupt {idx:#x}!");
+}
+
+/// Counts `vector` and calls its registered handler, acknowledging the local APIC afterward.
+///
+/// Returns whether a handler was registered.
+fn dispatch(vector: u8) -> bool {
+    count_interrupt(vector);
+    let Some(handler) = registered_handler(vector) else {
+        return false;
+    };
+    handler(vector);
+    ack_lapic();
+    true
 }
 
 extern "x86-interrupt" fn timer_handler(_: InterruptStackFrame) {
+    let _guard = HandlerGuard::enter();
+    count_interrupt(IRQ0);
     crate::time::TICKS.inc();
     ack_lapic();
 }
 
-extern "x86-interrupt" fn com1_handler(_: InterruptStackFrame) {
-    crate::serial::COM1.lock().handle_interrupt();
+/// Shared body of the serial port handlers
+fn serial_interrupt(irq: u8, serial: &Mutex<Serial>) {
+    let _guard = HandlerGuard::enter();
+    count_interrupt(IRQ0 + irq);
+    serial.lock().handle_interrupt();
     ack_lapic();
 }
 
-extern "x86-interrupt" fn page_fault_handler(_: InterruptStackFrame, errcode: PageFaultErrorCode) {
+extern "x86-interrupt" fn com1_handler(_: InterruptStackFrame) {
+    serial_interrupt(IRQ_COM1, &serial::COM1);
+}
+
+extern "x86-interrupt" fn com2_handler(_: InterruptStackFrame) {
+    if let Some(com2) = &*serial::COM2 {
+        serial_interrupt(IRQ_COM2, com2);
+    }
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    frame: InterruptStackFrame,
+    errcode: PageFaultErrorCode,
+) {
+    // Never dropped, the handler panics
+    let _guard = HandlerGuard::enter();
+    count_interrupt(0x0e);
+    FaultContext {
+        cr2: Some(Cr2::read().as_u64()),
+        ..FaultContext::new(&frame, 0x0e, Some(errcode.bits()))
+    }
+    .record();
+
     kprintln!("Page fault!");
     kprintln!("\terr code: {:?}", errcode);
-    kprintln!(
-        "\taddress accessed: {:x}",
-        x86_64::registers::control::Cr2::read().as_u64()
-    );
     panic!("Page fault!");
 }
 
-lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-        set_general_handler!(&mut idt, general_handler);
-        idt[IRQ0.into()].set_handler_fn(timer_handler);
-        idt[(IRQ0 + IRQ_COM1).into()].set_handler_fn(com1_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt
-    };
+/// Runs on its own stack, so faults that leave the kernel stack unusable, like a stack
+/// overflow, still get reported instead of resetting the machine.
+extern "x86-interrupt" fn double_fault_handler(frame: InterruptStackFrame, errcode: u64) -> ! {
+    // Never dropped, the handler panics
+    let _guard = HandlerGuard::enter();
+    count_interrupt(0x08);
+    FaultContext::new(&frame, 0x08, Some(errcode)).record();
+
+    kprintln!("Double fault!");
+    kprintln!("{:#?}", frame);
+    panic!("Double fault!");
 }
 
+static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
+    let mut idt = InterruptDescriptorTable::new();
+    set_general_handler!(&mut idt, general_handler);
+    idt[IRQ0.into()].set_handler_fn(timer_handler);
+    idt[(IRQ0 + IRQ_COM1).into()].set_handler_fn(com1_handler);
+    idt[(IRQ0 + IRQ_COM2).into()].set_handler_fn(com2_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    idt
+});
+
 pub fn init_idt() {
     // Load the IDT
     IDT.load();
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, vec::Vec};
+    use core::alloc::{Allocator, Layout};
+
+    use super::*;
+    use crate::memory::allocator::KAllocator;
+
+    #[test]
+    #[should_panic(expected = "kernel heap locked in an interrupt handler")]
+    fn heap_in_handler_panics() {
+        // Away from CPU 0, where the other tests allocate
+        TEST_CPU.set(1);
+        let heap = KAllocator::new();
+        let _guard = HandlerGuard::enter();
+        let _ = heap.allocate(Layout::new::<u64>());
+    }
+
+    #[test]
+    fn handler_depth_nests() {
+        TEST_CPU.set(2);
+        assert!(!in_interrupt());
+        {
+            let _outer = HandlerGuard::enter();
+            let inner = HandlerGuard::enter();
+            drop(inner);
+            assert!(in_interrupt());
+        }
+        assert!(!in_interrupt());
+    }
+
+    #[test]
+    fn raised_vectors_are_counted() {
+        // Not raised by anything else in the tests
+        const VECTOR: u8 = 0xf7;
+        let count = || {
+            interrupt_counts()
+                .find(|&(vector, _)| vector == VECTOR)
+                .map_or(0, |(_, count)| count)
+        };
+        assert_eq!(count(), 0);
+
+        for _ in 0..5 {
+            count_interrupt(VECTOR);
+        }
+        assert_eq!(count(), 5);
+        count_interrupt(VECTOR);
+        assert_eq!(count(), 6);
+
+        let mut out = String::new();
+        print_interrupts(&mut out).unwrap();
+        assert!(out.contains("0xf7:            6\n"), "{out}");
+    }
+
+    std::thread_local! {
+        /// Number of local APIC acknowledgements on the test thread
+        pub static ACKS: core::cell::Cell<u64> = const { core::cell::Cell::new(0) };
+        /// Vectors passed to [`record_vector`] on the test thread
+        static HANDLED: core::cell::RefCell<Vec<u8>> = const { core::cell::RefCell::new(Vec::new()) };
+    }
+
+    fn record_vector(vector: u8) {
+        HANDLED.with_borrow_mut(|handled| handled.push(vector));
+    }
+
+    #[test]
+    fn registered_handler_is_dispatched_to() {
+        // Not registered by anything else in the tests
+        const VECTOR: u8 = 0xf0;
+        assert!(!dispatch(VECTOR));
+        assert_eq!(ACKS.get(), 0);
+
+        register_handler(VECTOR, record_vector).unwrap();
+        assert!(dispatch(VECTOR));
+        assert!(dispatch(VECTOR));
+        assert_eq!(HANDLED.take(), [VECTOR, VECTOR]);
+        assert_eq!(ACKS.get(), 2);
+
+        assert_eq!(
+            register_handler(VECTOR, |_| {}),
+            Err(HandlerError::AlreadyRegistered)
+        );
+        assert!(unregister_handler(VECTOR));
+        assert!(!unregister_handler(VECTOR));
+        assert!(!dispatch(VECTOR));
+        assert_eq!(HANDLED.take(), []);
+
+        // CPU exceptions and the IRQs with their own IDT handlers
+        for vector in [0x00, 0x0e, IRQ0, IRQ0 + IRQ_COM1, IRQ0 + IRQ_COM2] {
+            assert_eq!(
+                register_handler(vector, record_vector),
+                Err(HandlerError::Reserved),
+                "{vector:#x}"
+            );
+        }
+    }
+
+    #[test]
+    fn fault_context_lists_the_registers() {
+        let mut ctx = FaultContext {
+            vector: 0x0e,
+            rip: 0xffff_8000_0010_2030,
+            rsp: 0xffff_ff80_0000_1ff8,
+            rflags: 0x246,
+            cr2: None,
+            error_code: None,
+        };
+        assert_eq!(
+            ctx.to_string(),
+            "vector=0x0e RIP=0xffff800000102030 RSP=0xffffff8000001ff8 RFLAGS=0x00000246"
+        );
+
+        ctx.cr2 = Some(0xdead_b000);
+        ctx.error_code = Some(0x2);
+        assert_eq!(
+            ctx.to_string(),
+            "vector=0x0e RIP=0xffff800000102030 RSP=0xffffff8000001ff8 RFLAGS=0x00000246 \
+             CR2=0x00000000deadb000 ERR=0x2"
+        );
+    }
+}
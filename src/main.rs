@@ -1,4 +1,11 @@
-use std::process::Command;
+use std::process::{exit, Command};
+
+/// OVMF firmware used when `RUSTYOS_OVMF` isn't set
+const DEFAULT_OVMF: &str = "/usr/share/ovmf/OVMF.fd";
+
+/// QEMU exit statuses of the kernel's debug exit codes, `(code << 1) | 1`
+const QEMU_SUCCESS: i32 = (0x10 << 1) | 1;
+const QEMU_FAILED: i32 = (0x11 << 1) | 1;
 
 fn set_debug(cmd: &mut Command) {
     // Set qemu to wait for a debugger to attach
@@ -21,27 +28,204 @@ fn set_debug(cmd: &mut Command) {
     println!("Run `gdb` to debug the kernel");
 }
 
-fn main() {
-    let bios_path = env!("BIOS_PATH");
+/// QEMU options, from the command line and environment.
+///
+/// Usage: `rustyos [debug] [--serial-stdio] [-- <extra QEMU args>...]`
+///
+/// `RUSTYOS_MEM` and `RUSTYOS_SMP` set the memory size and CPU count, and setting
+/// `RUSTYOS_GRAPHICS` to `1` or `true` opens a display window.
+///
+/// The BIOS image is booted by default. Setting `RUSTYOS_FIRMWARE` to `uefi` boots the UEFI
+/// image with the OVMF firmware at `RUSTYOS_OVMF`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Config {
+    firmware: Firmware,
+    mem: String,
+    smp: String,
+    graphics: bool,
+    serial_stdio: bool,
+    debug: bool,
+    /// Raw QEMU arguments given after `--`
+    extra: Vec<String>,
+}
 
-    let args = std::env::args().collect::<Vec<_>>();
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Firmware {
+    Bios,
+    /// UEFI, with the path to the OVMF firmware
+    Uefi(String),
+}
 
-    let debug = args.get(1) == Some(&"debug".to_string());
+impl Config {
+    fn from_env() -> Self {
+        let firmware = match std::env::var("RUSTYOS_FIRMWARE").as_deref() {
+            Ok("uefi") => Firmware::Uefi(
+                std::env::var("RUSTYOS_OVMF").unwrap_or_else(|_| DEFAULT_OVMF.to_string()),
+            ),
+            Ok("bios") | Err(_) => Firmware::Bios,
+            Ok(other) => {
+                eprintln!("Unknown firmware: {other}");
+                exit(2);
+            }
+        };
 
-    let mut cmd = Command::new("qemu-system-x86_64");
-    cmd.arg("-drive")
-        .arg(format!("format=raw,file={bios_path}"));
+        let mut config = Self {
+            firmware,
+            mem: std::env::var("RUSTYOS_MEM").unwrap_or_else(|_| "1G".to_string()),
+            smp: std::env::var("RUSTYOS_SMP").unwrap_or_else(|_| "4".to_string()),
+            graphics: std::env::var("RUSTYOS_GRAPHICS").is_ok_and(|v| v == "1" || v == "true"),
+            serial_stdio: false,
+            debug: false,
+            extra: Vec::new(),
+        };
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "debug" => config.debug = true,
+                "--serial-stdio" => config.serial_stdio = true,
+                "--" => {
+                    config.extra.extend(args);
+                    break;
+                }
+                _ => {
+                    eprintln!("Unknown argument: {arg}");
+                    exit(2);
+                }
+            }
+        }
+        config
+    }
 
-    // cmd.arg("-serial").arg("stdio");
+    /// Assembles the QEMU arguments to boot the BIOS image at `bios` or the UEFI image at
+    /// `uefi`
+    fn qemu_args(&self, bios: &str, uefi: &str) -> Vec<String> {
+        let image = match &self.firmware {
+            Firmware::Bios => bios,
+            Firmware::Uefi(_) => uefi,
+        };
+        let mut args = vec!["-drive".to_string(), format!("format=raw,file={image}")];
 
-    cmd.arg("-m").arg("1G");
-    cmd.arg("-smp").arg("4");
-    cmd.arg("-nographic");
+        if let Firmware::Uefi(ovmf) = &self.firmware {
+            args.extend(["-bios".to_string(), ovmf.clone()]);
+        }
+
+        args.extend(["-m".to_string(), self.mem.clone()]);
+        args.extend(["-smp".to_string(), self.smp.clone()]);
+        if self.serial_stdio {
+            args.extend(["-serial".to_string(), "stdio".to_string()]);
+            // -nographic would also put the serial port and monitor on stdio
+            if !self.graphics {
+                args.extend(["-display".to_string(), "none".to_string()]);
+            }
+        } else if !self.graphics {
+            args.push("-nographic".to_string());
+        }
+        // Lets the kernel exit QEMU with a status code
+        args.extend([
+            "-device".to_string(),
+            "isa-debug-exit,iobase=0xf4,iosize=0x04".to_string(),
+        ]);
+
+        args.extend(self.extra.iter().cloned());
+        args
+    }
+}
+
+fn main() {
+    let bios_path = env!("BIOS_PATH");
+    let uefi_path = env!("UEFI_PATH");
+    let config = Config::from_env();
+
+    let mut cmd = Command::new("qemu-system-x86_64");
+    cmd.args(config.qemu_args(bios_path, uefi_path));
 
-    if debug {
+    if config.debug {
         set_debug(&mut cmd);
     }
 
     let mut child = cmd.spawn().unwrap();
-    child.wait().unwrap();
+    let status = child.wait().unwrap();
+
+    // Map the kernel's exit codes back to success & failure
+    match status.code() {
+        Some(QEMU_SUCCESS) => exit(0),
+        Some(QEMU_FAILED) | None => exit(1),
+        Some(code) => exit(code),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            firmware: Firmware::Bios,
+            mem: "1G".to_string(),
+            smp: "4".to_string(),
+            graphics: false,
+            serial_stdio: false,
+            debug: false,
+            extra: Vec::new(),
+        }
+    }
+
+    fn has_pair(args: &[String], flag: &str, value: &str) -> bool {
+        args.windows(2)
+            .any(|pair| pair[0] == flag && pair[1] == value)
+    }
+
+    #[test]
+    fn bios_boots_without_a_display() {
+        let args = config().qemu_args("bios.img", "uefi.img");
+        assert!(has_pair(&args, "-drive", "format=raw,file=bios.img"));
+        assert!(has_pair(&args, "-m", "1G"));
+        assert!(has_pair(&args, "-smp", "4"));
+        assert!(args.contains(&"-nographic".to_string()));
+        assert!(!args.contains(&"-bios".to_string()));
+        assert!(!args.contains(&"-serial".to_string()));
+    }
+
+    #[test]
+    fn uefi_boots_with_ovmf() {
+        let config = Config {
+            firmware: Firmware::Uefi("ovmf.fd".to_string()),
+            ..config()
+        };
+        let args = config.qemu_args("bios.img", "uefi.img");
+        assert!(has_pair(&args, "-drive", "format=raw,file=uefi.img"));
+        assert!(has_pair(&args, "-bios", "ovmf.fd"));
+    }
+
+    #[test]
+    fn serial_stdio_replaces_nographic() {
+        let config = Config {
+            serial_stdio: true,
+            ..config()
+        };
+        let args = config.qemu_args("bios.img", "uefi.img");
+        assert!(has_pair(&args, "-serial", "stdio"));
+        assert!(has_pair(&args, "-display", "none"));
+        assert!(!args.contains(&"-nographic".to_string()));
+
+        // The window stays open with graphics
+        let config = Config {
+            graphics: true,
+            ..config
+        };
+        let args = config.qemu_args("bios.img", "uefi.img");
+        assert!(has_pair(&args, "-serial", "stdio"));
+        assert!(!args.contains(&"-display".to_string()));
+    }
+
+    #[test]
+    fn extra_args_come_last() {
+        let config = Config {
+            extra: vec!["-d".to_string(), "int".to_string()],
+            ..config()
+        };
+        let args = config.qemu_args("bios.img", "uefi.img");
+        assert_eq!(args[args.len() - 2..], ["-d", "int"]);
+    }
 }